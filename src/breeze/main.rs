@@ -8,26 +8,91 @@ extern crate env_logger;
 extern crate breeze_core;
 extern crate breeze_backends;
 extern crate breeze_backend;
+extern crate breeze_script;
+extern crate wdc65816;
 
 mod input;
+mod ram_search;
 
 use input::attach_default_input;
 
+use breeze_core::archive;
+use breeze_core::cdl::CodeDataLog;
+use breeze_core::cheat::{Cheat, CheatList};
+use breeze_core::error::ErrorPolicy;
+use breeze_core::gamedb::GameDatabase;
+use breeze_core::patch;
+use breeze_core::ram_search::{Memory, Size};
+use breeze_core::coprocessor::rtc::DateTime;
+use breeze_core::region::Region;
 use breeze_core::rom::Rom;
 use breeze_core::snes::Emulator;
+use breeze_core::sram::SramFile;
 use breeze_core::save::SaveStateFormat;
 use breeze_core::record::{RecordingFormat, create_recorder, create_replayer};
 use breeze_backend::Renderer;
+use breeze_script::Script;
+use wdc65816::trace::{TraceFormat, TraceWriter};
 
 use clap::ArgMatches;
 
 use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
 
 
+/// Loads the bundled game database and the files passed with `--gamedb`.
+fn load_game_database(args: &ArgMatches) -> io::Result<GameDatabase> {
+    let mut db = GameDatabase::bundled();
+    for path in args.values_of("gamedb").into_iter().flat_map(|paths| paths) {
+        try!(db.load_file(Path::new(path)));
+    }
+    info!("game database contains {} entries", db.len());
+    Ok(db)
+}
+
+/// Loads the cheat list stored next to the ROM at `rom_path` (a `.bml` or `.cht` file), or the
+/// one given with `--cheats`, and adds the codes given with `--cheat`.
+fn load_cheats(args: &ArgMatches, rom_path: &Path) -> io::Result<CheatList> {
+    let path = match args.value_of("cheats") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => ["bml", "cht"].iter()
+            .map(|ext| rom_path.with_extension(ext))
+            .find(|path| path.is_file()),
+    };
+    let mut cheats = match path {
+        Some(path) => {
+            let cheats = try!(CheatList::load(&path));
+            info!("loaded {} cheats from '{}'", cheats.len(), path.display());
+            cheats
+        }
+        None => CheatList::new(),
+    };
+    for code in args.values_of("cheat").into_iter().flat_map(|codes| codes) {
+        cheats.add(try!(Cheat::new(code, code)));
+    }
+    Ok(cheats)
+}
+
+/// Implements `breeze info`: Prints information about a ROM without running it.
+fn print_info(args: &ArgMatches) -> Result<(), Box<Error>> {
+    let db = try!(load_game_database(args));
+    let file = try!(archive::read_rom_file(args.value_of("rom").unwrap()));
+    let rom = try!(Rom::from_bytes_with_database(&file.data, file.warnings, &db));
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if args.is_present("json") {
+        try!(rom.info().write_json(&mut stdout));
+    } else {
+        try!(rom.info().write_text(&mut stdout));
+    }
+    Ok(())
+}
+
 fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
     if args.value_of("record").is_some() && args.value_of("replay").is_some() {
         return Err("`record` and `replay` may not be specified together!".into());
@@ -92,12 +157,29 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
     };
 
     // Load the ROM into memory
+    // (`rom_path` is the path of the ROM or the archive containing it, save files are put next to
+    // it)
     let filename = args.value_of("rom").unwrap();
-    let mut file = try!(File::open(&filename));
-    let mut buf = Vec::new();
-    try!(file.read_to_end(&mut buf));
+    let rom_path = archive::rom_path(filename);
+    let file = try!(archive::read_rom_file(filename));
+    let mut buf = file.data;
+
+    // Apply patches. Those next to the ROM are only used if none are given explicitly.
+    let patches = match args.values_of("patch") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => patch::find_patches(&rom_path),
+    };
+    for path in patches {
+        let mut data = Vec::new();
+        try!(try!(File::open(&path)).read_to_end(&mut data));
+        buf = try!(patch::apply(&buf, &data)
+            .map_err(|e| format!("couldn't apply patch '{}': {}", path.display(), e)));
+        info!("applied patch '{}'", path.display());
+    }
 
-    let rom = try!(Rom::from_bytes(&buf));
+    let db = try!(load_game_database(args));
+    let mut rom = try!(Rom::from_bytes_with_database(&buf, file.warnings, &db));
+    try!(rom.load_firmware(Path::new(args.value_of("firmware").unwrap())));
 
     // Create the backend parts
     info!("using {} renderer", renderer_name);
@@ -113,6 +195,40 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
     let mut emu = Emulator::new(rom, renderer, audio);
     attach_default_input(&mut emu.peripherals_mut().input, renderer_name);
 
+    match args.value_of("region").unwrap_or("auto") {
+        "auto" => {}
+        name => {
+            let region = try!(Region::from_name(name)
+                .ok_or_else(|| format!("unknown region '{}'", name)));
+            emu.snes.set_region(region);
+        }
+    }
+
+    if let Some(name) = args.value_of("on-error") {
+        let policy = try!(ErrorPolicy::from_name(name)
+            .ok_or_else(|| format!("unknown error policy '{}'", name)));
+        emu.snes.set_error_policy(policy);
+    }
+
+    emu.snes.set_cheats(try!(load_cheats(args, &rom_path)));
+
+    let movie = args.value_of("record").is_some() || args.value_of("replay").is_some();
+    if movie {
+        // Recordings must not depend on the time they were made at
+        emu.peripherals_mut().rom.set_rtc_time(DateTime::movie_start());
+    }
+    if !emu.peripherals().rom.sram().is_empty() {
+        let path = rom_path.with_extension("srm");
+        let sram = if movie {
+            // Recordings must also start from a clean cartridge RAM, but the game may still save
+            warn!("not loading '{}' while recording or replaying (it is overwritten if the game \
+                   saves)", path.display());
+            SramFile::new(&path, &emu.peripherals().rom)
+        } else {
+            try!(SramFile::open(&path, &mut emu.peripherals_mut().rom))
+        };
+        emu.sram = Some(sram);
+    }
     if let Some(record_file) = args.value_of("record") {
         let writer = Box::new(File::create(record_file).unwrap());
         let recorder = create_recorder(RecordingFormat::default(), writer, &emu.snes).unwrap();
@@ -128,6 +244,53 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
         let mut bufrd = BufReader::new(file);
         emu.snes.restore_save_state(SaveStateFormat::default(), &mut bufrd).unwrap()
     }
+    if let Some(files) = args.values_of("symbols") {
+        for path in files {
+            let mut text = String::new();
+            try!(try!(File::open(path)).read_to_string(&mut text));
+            try!(emu.snes.symbols_mut().parse(&text));
+        }
+        info!("loaded {} symbols", emu.snes.symbols().len());
+    }
+
+    let cdl_path = rom_path.with_extension("cdl");
+    if args.is_present("cdl") {
+        let rom_size = emu.peripherals().rom.rom_size();
+        let log = if cdl_path.exists() {
+            // Continue logging where the last session stopped
+            let log = try!(CodeDataLog::read(&mut BufReader::new(try!(File::open(&cdl_path)))));
+            if log.rom_size() != rom_size {
+                return Err(format!("code/data log '{}' was created for a ROM of {} bytes, but \
+                    the ROM has {} bytes", cdl_path.display(), log.rom_size(), rom_size).into());
+            }
+            info!("continuing code/data log '{}'", cdl_path.display());
+            log
+        } else {
+            CodeDataLog::new(rom_size)
+        };
+        emu.peripherals_mut().cdl = Some(log);
+    }
+    if let Some(path) = args.value_of("trace") {
+        let format_name = args.value_of("trace-format").unwrap_or("bsnes");
+        let format = try!(TraceFormat::from_name(format_name)
+            .ok_or_else(|| format!("unknown trace format '{}'", format_name)));
+        let file = BufWriter::new(try!(File::create(path)));
+        emu.snes.set_trace_writer(Some(TraceWriter::new(format, Box::new(file))));
+    }
+    if args.is_present("events") {
+        let frame = match args.value_of("events-frame") {
+            Some(frame) => try!(frame.parse::<u32>().map_err(|e| format!("invalid frame: {}", e))),
+            None => 60,
+        };
+        emu.snes.record_events(frame);
+    }
+    if args.is_present("profile") || args.is_present("profile-folded") {
+        emu.snes.enable_profiler();
+    }
+    if let Some(script_file) = args.value_of("script") {
+        let script = try!(Script::load(script_file, &mut emu.snes));
+        emu.snes.set_hooks(Box::new(script));
+    }
 
     if cfg!(debug_assertions) && args.is_present("oneframe") {
         debug!("PPU H={}, V={}",
@@ -139,16 +302,75 @@ fn process_args(args: &ArgMatches) -> Result<(), Box<Error>> {
 
         // Keep rendering, but don't run emulation
         // Copy out the frame buffer because the damn borrow checker doesn't like it otherwise
-        let framebuf = emu.peripherals().ppu.framebuf.clone();
+        let frame = emu.peripherals().ppu.frame().to_vec();
         loop {
-            let actions = try!(emu.renderer.render(&*framebuf));
+            let actions = try!(emu.renderer.render(&frame));
             for a in actions {
                 if emu.handle_action(a) { break }
             }
         }
     } else {
-        // Run normally
-        try!(emu.run());
+        // Run normally, or let the user search the RAM
+        let result = match args.value_of("ram-search") {
+            Some(name) => {
+                let memory = Memory::from_name(name).unwrap();
+                let bits = try!(args.value_of("search-size").unwrap_or("8").parse()
+                    .map_err(|e| format!("invalid value size: {}", e)));
+                let size = try!(Size::from_bits(bits)
+                    .ok_or_else(|| format!("values can't have {} bits", bits)));
+                ram_search::run(&mut emu, memory, size, args.is_present("search-signed"))
+            }
+            None => emu.run(),
+        };
+        // Also print the diagnostics when emulation was stopped by an error, they might explain it
+        if args.is_present("diagnostics") {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            try!(emu.snes.diagnostics().write(&mut stdout));
+        }
+        if emu.snes.cheats().is_modified() {
+            let path = rom_path.with_extension("bml");
+            let mut file = BufWriter::new(try!(File::create(&path)));
+            try!(emu.snes.cheats().write_bml(&mut file));
+            info!("saved cheats to '{}'", path.display());
+        }
+        try!(result);
+    }
+
+    if let Some(profiler) = emu.snes.take_profiler() {
+        if args.is_present("profile") {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            try!(profiler.write_flat(&mut stdout, 30, emu.snes.symbols()));
+            try!(writeln!(stdout, ""));
+            try!(profiler.write_call_tree(&mut stdout, 0.5, emu.snes.symbols()));
+        }
+        if let Some(path) = args.value_of("profile-folded") {
+            let mut file = BufWriter::new(try!(File::create(path)));
+            try!(profiler.write_folded(&mut file, emu.snes.symbols()));
+            info!("wrote folded stacks to '{}'", path);
+        }
+    }
+
+    if let Some(path) = args.value_of("events") {
+        let log = emu.peripherals().events.as_ref().unwrap();
+        if log.is_done() {
+            let mut file = BufWriter::new(try!(File::create(path)));
+            match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+                Some("json") => try!(log.write_json(&mut file)),
+                Some("ppm") => try!(log.write_timeline_ppm(&mut file)),
+                _ => try!(log.write_csv(&mut file)),
+            }
+            info!("wrote {} events to '{}'", log.events().len(), path);
+        } else {
+            warn!("the emulator exited before the frame to log events for was complete");
+        }
+    }
+
+    if let Some(ref log) = emu.peripherals().cdl {
+        let mut file = BufWriter::new(try!(File::create(&cdl_path)));
+        try!(log.write(&mut file));
+        info!("wrote code/data log to '{}'", cdl_path.display());
     }
 
     Ok(())
@@ -163,11 +385,60 @@ fn main() {
     let mut app = clap::App::new("breeze")
         .version(env!("CARGO_PKG_VERSION"))
         .about("SNES emulator")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
+        .subcommand(clap::SubCommand::with_name("info")
+            .about("Prints the ROM header, hashes and loading problems of a ROM")
+            .arg(clap::Arg::with_name("rom")
+                .required(true)
+                .value_name("ROM_PATH")
+                .takes_value(true)
+                .help("The ROM file to inspect"))
+            .arg(clap::Arg::with_name("json")
+                .long("json")
+                .help("Print the information as a JSON object"))
+            .arg(clap::Arg::with_name("gamedb")
+                .long("gamedb")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("FILE")
+                .help("Load additional game database entries (may be given multiple times)")))
         .arg(clap::Arg::with_name("rom")
             .required(true)
             .value_name("ROM_PATH")
             .takes_value(true)
-            .help("The ROM file to execute"))
+            .help("The ROM file to execute (may be zipped or gzipped, use `archive.zip#name.sfc` \
+                   to pick a file from a zip archive)"))
+        .arg(clap::Arg::with_name("patch")
+            .long("patch")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Apply an IPS, BPS or UPS patch to the ROM (may be given multiple times, \
+                   disables loading patches next to the ROM)"))
+        .arg(clap::Arg::with_name("cheat")
+            .long("cheat")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("CODE")
+            .help("Add a Game Genie, Pro Action Replay or raw (`7e0dbe=05`) cheat code to the \
+                   ROM's cheat list (may be given multiple times, the list is saved next to \
+                   the ROM)"))
+        .arg(clap::Arg::with_name("cheats")
+            .long("cheats")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Load cheats from a bsnes .bml or Snes9x .cht file instead of the one next to \
+                   the ROM"))
+        .arg(clap::Arg::with_name("gamedb")
+            .long("gamedb")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Load additional game database entries (may be given multiple times)"))
         .arg(clap::Arg::with_name("renderer")
             .short("R")
             .long("renderer")
@@ -178,6 +449,27 @@ fn main() {
             .long("audio")
             .takes_value(true)
             .help("The audio backend to use"))
+        .arg(clap::Arg::with_name("firmware")
+            .long("firmware")
+            .takes_value(true)
+            .value_name("DIR")
+            .default_value("firmware")
+            .help("Directory containing coprocessor firmware (eg. dsp1b.rom)"))
+        .arg(clap::Arg::with_name("region")
+            .long("region")
+            .takes_value(true)
+            .possible_values(&["auto", "ntsc", "pal"])
+            .help("Console region to emulate (default: auto, detected from the ROM header)"))
+        .arg(clap::Arg::with_name("on-error")
+            .long("on-error")
+            .takes_value(true)
+            .possible_values(&["stop", "continue"])
+            .help("What to do when the game does something invalid or unimplemented: stop \
+                   emulation (default), or log it and continue"))
+        .arg(clap::Arg::with_name("diagnostics")
+            .long("diagnostics")
+            .help("Print a report of the unimplemented features and suspicious register accesses \
+                   the game used on exit"))
         .arg(clap::Arg::with_name("savestate")
             .long("savestate")
             .takes_value(true)
@@ -185,11 +477,75 @@ fn main() {
         .arg(clap::Arg::with_name("record")
             .long("record")
             .takes_value(true)
-            .help("Record input to a text file"))
+            .help("Record input to a text file. The game starts with empty cartridge RAM, which \
+                   is still written to its .srm file when the game saves"))
         .arg(clap::Arg::with_name("replay")
             .long("replay")
             .takes_value(true)
-            .help("Replay a recording from a text file"));
+            .help("Replay a recording from a text file. Like when recording, the .srm file is \
+                   not loaded, but written when the game saves"))
+        .arg(clap::Arg::with_name("script")
+            .long("script")
+            .takes_value(true)
+            .help("Run a Rhai script alongside the emulated game"))
+        .arg(clap::Arg::with_name("symbols")
+            .long("symbols")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+            .help("Load labels from a WLA-DX .sym, ca65 .dbg, ld65 label or `bank:addr name` file"))
+        .arg(clap::Arg::with_name("cdl")
+            .long("cdl")
+            .help("Log which ROM bytes are code and data to a .cdl file next to the ROM"))
+        .arg(clap::Arg::with_name("trace")
+            .long("trace")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write a CPU trace line for every executed instruction to a file"))
+        .arg(clap::Arg::with_name("trace-format")
+            .long("trace-format")
+            .takes_value(true)
+            .possible_values(&["bsnes", "mesen"])
+            .requires("trace")
+            .help("Format of the trace lines written by --trace (default: bsnes)"))
+        .arg(clap::Arg::with_name("events")
+            .long("events")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Log PPU/APU writes, DMA and interrupts of one frame (.csv, .json or .ppm \
+                   timeline image)"))
+        .arg(clap::Arg::with_name("events-frame")
+            .long("events-frame")
+            .takes_value(true)
+            .value_name("N")
+            .requires("events")
+            .help("Number of frames to skip before logging events (default: 60)"))
+        .arg(clap::Arg::with_name("ram-search")
+            .long("ram-search")
+            .takes_value(true)
+            .value_name("MEMORY")
+            .possible_values(&["wram", "cart", "aram"])
+            .help("Search WRAM, cartridge RAM or APU RAM for a value, using commands read from \
+                   stdin (emulation only runs when asked to)"))
+        .arg(clap::Arg::with_name("search-size")
+            .long("search-size")
+            .takes_value(true)
+            .possible_values(&["8", "16", "24"])
+            .requires("ram-search")
+            .help("Size of the searched values in bits (default: 8)"))
+        .arg(clap::Arg::with_name("search-signed")
+            .long("search-signed")
+            .requires("ram-search")
+            .help("Search for signed values"))
+        .arg(clap::Arg::with_name("profile")
+            .long("profile")
+            .help("Print a flat and a call tree profile of CPU execution on exit"))
+        .arg(clap::Arg::with_name("profile-folded")
+            .long("profile-folded")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the CPU profile as folded stacks (for flamegraph tools) on exit"));
 
     // Add debugging options
     if cfg!(debug_assertions) {
@@ -199,7 +555,11 @@ fn main() {
     }
 
     let args = app.get_matches();
-    match process_args(&args) {
+    let result = match args.subcommand() {
+        ("info", Some(info_args)) => print_info(info_args),
+        _ => process_args(&args),
+    };
+    match result {
         Ok(()) => {},
         Err(e) => {
             // FIXME: Glium swallows useful information when using {} instead of {:?}
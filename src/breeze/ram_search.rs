@@ -0,0 +1,152 @@
+//! Interactive RAM search (`--ram-search`)
+//!
+//! Commands are read from stdin. Emulation only runs when asked to, so the game can be played for
+//! a few frames between the filters.
+
+use breeze_core::ram_search::{self, Filter, Memory, RamSearch, Size};
+use breeze_core::snes::Emulator;
+use breeze_backend::{AudioSink, BackendResult, Renderer};
+
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+const HELP: &'static str = "\
+commands:
+  run [FRAMES]       emulate FRAMES frames (default: 1)
+  equal              keep the candidates whose value is equal to the last filter's
+  changed            ... whose value changed since the last filter
+  increased          ... whose value increased since the last filter
+  decreased          ... whose value decreased since the last filter
+  VALUE              ... whose value is VALUE (decimal, or hex with `$`)
+  reset              start over with all values as candidates
+  list [COUNT]       print the first COUNT candidates (default: 20)
+  freeze N [VALUE]   write the current value of candidate N (or VALUE) every frame
+  freezes            list the frozen values
+  unfreeze N         stop writing frozen value N
+  cheat N            add frozen value N to the cheat list (WRAM only)
+  quit               exit the emulator";
+
+/// Parses the argument at `index`, or returns `default` if there are fewer arguments.
+fn arg<T: FromStr>(words: &[&str], index: usize, default: Option<T>) -> Result<T, String> {
+    match words.get(index) {
+        Some(word) => word.parse().map_err(|_| format!("invalid argument '{}'", word)),
+        None => default.ok_or_else(|| format!("`{}` needs more arguments", words[0])),
+    }
+}
+
+/// Runs the RAM search prompt until the user or the backend exits. Writes the cartridge RAM to
+/// its save file afterwards (like `Emulator::run`).
+pub fn run<R: Renderer, A: AudioSink>(emu: &mut Emulator<R, A>, memory: Memory, size: Size,
+                                      signed: bool) -> BackendResult<()> {
+    let result = prompt(emu, memory, size, signed);
+    try!(emu.flush_sram());
+    result
+}
+
+fn prompt<R: Renderer, A: AudioSink>(emu: &mut Emulator<R, A>, memory: Memory, size: Size,
+                                     signed: bool) -> BackendResult<()> {
+    let mut search = RamSearch::new(&emu.snes, memory, size, signed);
+    println!("searching {}-bit {} values in {} ({} candidates), type `help` for a list of commands",
+        size.bytes() * 8, if signed { "signed" } else { "unsigned" }, memory.name(),
+        search.candidates().len());
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        try!(io::stdout().flush());
+        let line = match lines.next() {
+            Some(line) => try!(line),
+            None => return Ok(()),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+
+        let result = match words[0] {
+            "run" => match arg(&words, 1, Some(1u32)) {
+                Ok(frames) => {
+                    for _ in 0..frames {
+                        if try!(emu.render_frame()) {
+                            return Ok(());
+                        }
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            "reset" => {
+                search.reset(&emu.snes);
+                println!("{} candidates", search.candidates().len());
+                Ok(())
+            }
+            "list" => arg(&words, 1, Some(20)).map(|count| list(emu, &search, count)),
+            "freeze" => arg(&words, 1, None).and_then(|index: usize| {
+                let value = match words.get(2) {
+                    Some(word) => Some(try!(ram_search::parse_value(word)
+                        .ok_or_else(|| format!("invalid value '{}'", word)))),
+                    None => None,
+                };
+                let offset = try!(search.candidates().get(index).cloned()
+                    .ok_or_else(|| format!("there is no candidate {}", index)));
+                let freeze = search.freeze(&emu.snes, offset, value);
+                emu.snes.freeze(freeze);
+                Ok(())
+            }),
+            "freezes" => {
+                for (i, freeze) in emu.snes.freezes().iter().enumerate() {
+                    let bytes: Vec<_> = freeze.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                    println!("{:>4}  {} {}  {}", i, freeze.memory.name(),
+                        freeze.memory.format_addr(freeze.offset), bytes.join(" "));
+                }
+                Ok(())
+            }
+            "unfreeze" => arg(&words, 1, None).and_then(|index: usize| {
+                if index < emu.snes.freezes().len() {
+                    emu.snes.unfreeze(index);
+                    Ok(())
+                } else {
+                    Err(format!("there is no frozen value {}", index))
+                }
+            }),
+            "cheat" => arg(&words, 1, None).and_then(|index: usize| {
+                let freeze = try!(emu.snes.freezes().get(index).cloned()
+                    .ok_or_else(|| format!("there is no frozen value {}", index)));
+                let cheat = try!(freeze.to_cheat()
+                    .ok_or_else(|| "only WRAM values can become cheats".to_owned()));
+                println!("added cheat '{}'", cheat.code());
+                emu.snes.add_cheat(cheat);
+                Ok(())
+            }),
+            "help" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            "quit" | "exit" => return Ok(()),
+            word => match Filter::parse(word) {
+                Some(filter) => {
+                    search.filter(&emu.snes, filter);
+                    println!("{} candidates", search.candidates().len());
+                    Ok(())
+                }
+                None => Err(format!("unknown command '{}' (type `help` for a list)", word)),
+            },
+        };
+        if let Err(e) = result {
+            println!("error: {}", e);
+        }
+    }
+}
+
+/// Prints the first `count` candidates with their last and current values.
+fn list<R: Renderer, A: AudioSink>(emu: &Emulator<R, A>, search: &RamSearch, count: usize) {
+    println!("{:>4}  {:<8}  {:>10}  {:>10}", "#", "address", "previous", "current");
+    for (i, &offset) in search.candidates().iter().take(count).enumerate() {
+        println!("{:>4}  {:<8}  {:>10}  {:>10}", i, search.memory().format_addr(offset),
+            search.previous(offset), search.current(&emu.snes, offset));
+    }
+    if search.candidates().len() > count {
+        println!("({} more)", search.candidates().len() - count);
+    }
+}
@@ -7,6 +7,7 @@
 ///
 /// Discriminants are the button's bit numbers in `JoypadState` (the highest number will be read
 /// first).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum JoypadButton {
     A = 7,
     B = 15,
@@ -17,6 +17,8 @@ pub enum BackendAction {
     SaveState,
     /// Restore the last save state
     LoadState,
+    /// Turn all cheats on or off
+    ToggleCheats,
 }
 
 /// Result with an erased error type.
@@ -5,4 +5,6 @@
 pub const SCREEN_WIDTH: u32 = 256;
 /// Physical screen height
 /// (this is the height of a field, or a half-frame)
-pub const SCREEN_HEIGHT: u32 = 224;     // 224px for 60 Hz NTSC, 264 for 50 Hz PAL
+pub const SCREEN_HEIGHT: u32 = 224;
+/// Screen height when the overscan bit in `$2133` is set (on both NTSC and PAL consoles)
+pub const MAX_SCREEN_HEIGHT: u32 = 239;
@@ -0,0 +1,238 @@
+//! Implements the multiplication and division unit of the CPU.
+//!
+//! Writing `$4203` (WRMPYB) starts an unsigned 8x8 bit multiplication, writing `$4206` (WRDIVB)
+//! an unsigned 16/8 bit division. The results aren't available at once: The unit computes one bit
+//! per CPU cycle, like long multiplication and division on paper, so a multiplication takes 8 and
+//! a division 16 cycles. The result registers (`$4214-$4217`) are updated in every step, and some
+//! games and test ROMs read the intermediate values.
+//!
+//! The ALU is run by the `Snes` after every CPU instruction, for the master cycles that passed
+//! (including time the CPU was stalled by DMA, HDMA or wait states). This includes the instruction
+//! that started the operation, which makes up for the cycles the reading instruction spends before
+//! the actual read (the ALU is only stepped between instructions).
+
+/// The state of the ALU and its registers
+pub struct Alu {
+    /// `$4202` - WRMPYA: Multiplicand 1
+    wrmpya: u8,
+    /// `$4203` - WRMPYB: Multiplicand 2
+    wrmpyb: u8,
+    /// `$4204`/`$4205` - WRDIVL/WRDIVH: Dividend
+    wrdiv: u16,
+    /// `$4206` - WRDIVB: Divisor
+    wrdivb: u8,
+    /// `$4214`/`$4215` - RDDIVL/RDDIVH: Unsigned Division Result (Quotient)
+    ///
+    /// During a multiplication, this holds the part of `wrmpya` that wasn't processed yet (and
+    /// `wrmpyb` when the multiplication is done).
+    rddiv: u16,
+    /// `$4216`/`$4217` - RDMPYL/RDMPYH: Unsigned Division Remainder / Multiply Product
+    rdmpy: u16,
+    /// Remaining steps of the running multiplication (0 if there is none)
+    mpy_steps: u8,
+    /// Remaining steps of the running division (0 if there is none)
+    div_steps: u8,
+    /// The multiplicand (shifted left in every step) or divisor (shifted right in every step)
+    shift: u32,
+}
+
+impl_save_state!(Alu { wrmpya, wrmpyb, wrdiv, wrdivb, rddiv, rdmpy, mpy_steps, div_steps, shift }
+    ignore {});
+
+impl Alu {
+    pub fn new() -> Self {
+        Alu {
+            wrmpya: 0xff,
+            wrmpyb: 0,
+            wrdiv: 0xffff,
+            wrdivb: 0,
+            rddiv: 0,
+            rdmpy: 0,
+            mpy_steps: 0,
+            div_steps: 0,
+            shift: 0,
+        }
+    }
+
+    fn busy(&self) -> bool { self.mpy_steps != 0 || self.div_steps != 0 }
+
+    /// Reads a result register (`$4214-$4217`). Returns `None` if `addr` isn't one, the input
+    /// registers are write-only.
+    pub fn load(&self, addr: u16) -> Option<u8> {
+        Some(match addr {
+            0x4214 => self.rddiv as u8,
+            0x4215 => (self.rddiv >> 8) as u8,
+            0x4216 => self.rdmpy as u8,
+            0x4217 => (self.rdmpy >> 8) as u8,
+            _ => return None,
+        })
+    }
+
+    /// Writes an ALU register (`$4202-$4206`). Returns `false` if `addr` isn't one. Writing
+    /// `$4203` or `$4206` while an operation is running only clears or sets the result register,
+    /// the operation isn't restarted.
+    pub fn store(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x4202 => self.wrmpya = value,
+            // WRMPYB: Starts the multiplication
+            0x4203 => {
+                self.rdmpy = 0;
+                if !self.busy() {
+                    self.wrmpyb = value;
+                    self.rddiv = (value as u16) << 8 | self.wrmpya as u16;
+                    self.shift = value as u32;
+                    self.mpy_steps = 8;
+                }
+            }
+            0x4204 => self.wrdiv = (self.wrdiv & 0xff00) | value as u16,
+            0x4205 => self.wrdiv = ((value as u16) << 8) | (self.wrdiv & 0xff),
+            // WRDIVB: Starts the division
+            0x4206 => {
+                self.rdmpy = self.wrdiv;
+                if !self.busy() {
+                    self.wrdivb = value;
+                    self.shift = (value as u32) << 16;
+                    self.div_steps = 16;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Runs the ALU for `cycles` CPU cycles.
+    pub fn run(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if !self.busy() {
+                break;
+            }
+            self.step();
+        }
+    }
+
+    /// Computes one bit of the running operation.
+    fn step(&mut self) {
+        if self.mpy_steps != 0 {
+            // Add the multiplicand for every set bit of `wrmpya`
+            self.mpy_steps -= 1;
+            if self.rddiv & 1 != 0 {
+                self.rdmpy = self.rdmpy.wrapping_add(self.shift as u16);
+            }
+            self.rddiv >>= 1;
+            self.shift <<= 1;
+        }
+        if self.div_steps != 0 {
+            // Subtract the divisor from the remainder if it fits. Division by zero results in a
+            // quotient of `$FFFF` and leaves the dividend in the remainder.
+            self.div_steps -= 1;
+            self.rddiv <<= 1;
+            self.shift >>= 1;
+            if self.rdmpy as u32 >= self.shift {
+                self.rdmpy -= self.shift as u16;
+                self.rddiv |= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Alu;
+
+    fn quotient(alu: &Alu) -> u16 {
+        alu.load(0x4214).unwrap() as u16 | (alu.load(0x4215).unwrap() as u16) << 8
+    }
+
+    fn product(alu: &Alu) -> u16 {
+        alu.load(0x4216).unwrap() as u16 | (alu.load(0x4217).unwrap() as u16) << 8
+    }
+
+    fn multiply(a: u8, b: u8) -> Alu {
+        let mut alu = Alu::new();
+        alu.store(0x4202, a);
+        alu.store(0x4203, b);
+        alu
+    }
+
+    fn divide(dividend: u16, divisor: u8) -> Alu {
+        let mut alu = Alu::new();
+        alu.store(0x4204, dividend as u8);
+        alu.store(0x4205, (dividend >> 8) as u8);
+        alu.store(0x4206, divisor);
+        alu
+    }
+
+    #[test]
+    fn multiplication() {
+        let mut alu = multiply(171, 123);
+        alu.run(7);
+        assert!(alu.busy());
+        alu.run(100);
+        assert!(!alu.busy());
+        assert_eq!(product(&alu), 171 * 123);
+        assert_eq!(quotient(&alu), 123);
+    }
+
+    #[test]
+    fn division() {
+        let mut alu = divide(50000, 123);
+        alu.run(15);
+        assert!(alu.busy());
+        alu.run(100);
+        assert!(!alu.busy());
+        assert_eq!(quotient(&alu), 50000 / 123);
+        assert_eq!(product(&alu), 50000 % 123);
+    }
+
+    #[test]
+    fn intermediate_results() {
+        // Every step processes the next bit of `$4202`, starting with the lowest
+        let mut alu = multiply(171, 123);
+        alu.run(4);
+        assert_eq!(product(&alu), (171 & 0xf) * 123);
+        assert_eq!(quotient(&alu), 123 << 4 | 171 >> 4);
+
+        // Every step computes the next bit of the quotient, starting with the highest
+        let mut alu = divide(50000, 123);
+        alu.run(8);
+        assert_eq!(quotient(&alu), (50000 >> 8) / 123);
+        assert_eq!(product(&alu), 50000 - ((50000 >> 8) / 123 * 123 << 8));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut alu = divide(0x1234, 0);
+        alu.run(16);
+        assert_eq!(quotient(&alu), 0xffff);
+        assert_eq!(product(&alu), 0x1234);
+    }
+
+    #[test]
+    fn write_while_busy() {
+        // Writing `$4203` clears the product, but the multiplication continues with the old
+        // factors
+        let mut alu = multiply(171, 123);
+        alu.run(3);
+        alu.store(0x4203, 5);
+        alu.run(5);
+        assert!(!alu.busy());
+        assert_eq!(product(&alu), (171 & !0x7) * 123);
+
+        // Writing `$4206` resets the remainder to the whole dividend, the division continues with
+        // the old divisor (so the remaining quotient bits are all set)
+        let mut alu = divide(50000, 123);
+        alu.run(8);
+        alu.store(0x4206, 7);
+        alu.run(8);
+        assert!(!alu.busy());
+        assert_eq!(quotient(&alu), (50000 >> 8) / 123 << 8 | 0xff);
+    }
+
+    #[test]
+    fn other_registers() {
+        let mut alu = Alu::new();
+        assert_eq!(alu.load(0x4202), None);
+        assert_eq!(alu.load(0x4203), None);
+        assert!(!alu.store(0x4207, 0));
+    }
+}
@@ -0,0 +1,458 @@
+//! Loading ROMs from zip and gzip archives
+//!
+//! ROM files may be compressed with gzip or stored in a zip archive. The format is detected from
+//! the file contents, so uncompressed ROMs are loaded as is. A zip archive may contain several
+//! files: By default, the first `.sfc` or `.smc` file is loaded, but a specific one can be chosen
+//! by appending its name to the archive path, separated by `#` (eg. `roms.zip#game.sfc`).
+//!
+//! Decompression is done by the pure-Rust `inflate` crate.
+//!
+//! Some copiers split large ROMs into several files (`game.1`, `game.2`, ...). When the first part
+//! is loaded, the others are read as well and joined.
+
+use rom_info::crc32;
+
+use inflate::InflateStream;
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+fn invalid_data<S: Into<String>>(err: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.into())
+}
+
+/// Splits a ROM path into the path of the file and the name of the zip archive entry to load.
+///
+/// `roms.zip#game.sfc` is split into `roms.zip` and `game.sfc`. Paths are only split if the whole
+/// path doesn't name an existing file (since file names can contain `#`).
+pub fn split_path(path: &str) -> (&Path, Option<&str>) {
+    if !Path::new(path).exists() {
+        if let Some(pos) = path.rfind('#') {
+            return (Path::new(&path[..pos]), Some(&path[pos + 1..]));
+        }
+    }
+    (Path::new(path), None)
+}
+
+/// Returns the path other files belonging to the ROM (like `.srm` save files and patches) are
+/// located relative to, by replacing its extension.
+///
+/// For `roms.zip#game.sfc` this is `roms.zip`, for `game.sfc.gz` it's `game.sfc`.
+pub fn rom_path(path: &str) -> PathBuf {
+    let path = split_path(path).0;
+    match path.extension() {
+        Some(ext) if ext == "gz" => path.with_extension(""),
+        _ => path.to_owned(),
+    }
+}
+
+/// The contents of a ROM file
+pub struct RomFile {
+    /// The ROM image (still including any copier header)
+    pub data: Vec<u8>,
+    /// Problems and unusual things noticed while reading the file, to be reported along with the
+    /// ROM's load warnings (see `Rom::from_bytes_with_database`)
+    pub warnings: Vec<String>,
+}
+
+/// Reads a ROM file, extracting it if it's a zip or gzip archive (see `split_path` for the syntax
+/// used to select a zip archive entry). If the file is the first part of a split ROM image, the
+/// remaining parts are appended.
+pub fn read_rom_file(path: &str) -> io::Result<RomFile> {
+    let (path, entry) = split_path(path);
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    let mut rom = RomFile {
+        data: try!(extract(data, entry)),
+        warnings: Vec::new(),
+    };
+
+    if entry.is_none() && path.extension().map_or(false, |ext| ext == "1") {
+        try!(join_parts(path, &mut rom));
+    }
+    Ok(rom)
+}
+
+/// Appends the parts `.2`, `.3`, ... of a split ROM image to its first part. Only the first part
+/// keeps its copier header.
+fn join_parts(first: &Path, rom: &mut RomFile) -> io::Result<()> {
+    let mut parts = 1;
+    loop {
+        let path = first.with_extension((parts + 1).to_string());
+        if !path.is_file() { break; }
+
+        let mut part = Vec::new();
+        try!(try!(File::open(&path)).read_to_end(&mut part));
+        let start = if part.len() % 1024 == 512 { 512 } else { 0 };
+        rom.data.extend_from_slice(&part[start..]);
+        parts += 1;
+    }
+
+    if parts > 1 {
+        let msg = format!("joined {} parts of a split ROM image", parts);
+        info!("{}", msg);
+        rom.warnings.push(msg);
+    }
+    Ok(())
+}
+
+/// Extracts a ROM from `data`, if it's a zip or gzip archive. Otherwise, `data` is returned
+/// unchanged (and `entry` must be `None`).
+pub fn extract(data: Vec<u8>, entry: Option<&str>) -> io::Result<Vec<u8>> {
+    if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        extract_zip(&data, entry)
+    } else if entry.is_some() {
+        Err(invalid_data("an archive entry was given, but the file isn't a zip archive"))
+    } else if data.starts_with(&[0x1f, 0x8b]) {
+        extract_gzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    data[offset] as u16 | (data[offset + 1] as u16) << 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    read_u16(data, offset) as u32 | (read_u16(data, offset + 2) as u32) << 16
+}
+
+/// Decompresses raw DEFLATE data.
+fn inflate(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = InflateStream::new();
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let (consumed, bytes) = try!(stream.update(data).map_err(invalid_data));
+        if consumed == 0 && bytes.is_empty() {
+            // End of the compressed stream
+            break;
+        }
+        out.extend_from_slice(bytes);
+        data = &data[consumed..];
+    }
+    Ok(out)
+}
+
+/// Checks the CRC32 of extracted data.
+fn check_crc(data: &[u8], expected: u32, name: &str) -> io::Result<()> {
+    let actual = crc32(data);
+    if actual != expected {
+        Err(invalid_data(format!("'{}' is corrupted: CRC32 is {:08x}, expected {:08x}",
+            name, actual, expected)))
+    } else {
+        Ok(())
+    }
+}
+
+/// A file in a zip archive, read from the central directory
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    local_header: usize,
+}
+
+/// Reads the central directory of a zip archive.
+fn zip_entries(data: &[u8]) -> io::Result<Vec<ZipEntry>> {
+    let truncated = || invalid_data("zip archive is truncated");
+
+    // The end of central directory record is at the end of the file, followed by a comment of up
+    // to 64 KB
+    if data.len() < 22 { return Err(truncated()); }
+    let search_start = data.len().saturating_sub(22 + 0xffff);
+    let eocd = try!((search_start..data.len() - 21).rev()
+        .find(|&i| read_u32(data, i) == 0x06054b50)
+        .ok_or_else(|| invalid_data("zip archive has no central directory")));
+
+    let count = read_u16(data, eocd + 10) as usize;
+    let mut pos = read_u32(data, eocd + 16) as usize;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if pos + 46 > data.len() { return Err(truncated()); }
+        if read_u32(data, pos) != 0x02014b50 {
+            return Err(invalid_data("invalid zip central directory entry"));
+        }
+        let name_len = read_u16(data, pos + 28) as usize;
+        let extra_len = read_u16(data, pos + 30) as usize;
+        let comment_len = read_u16(data, pos + 32) as usize;
+        if pos + 46 + name_len > data.len() { return Err(truncated()); }
+
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(&data[pos + 46..pos + 46 + name_len]).into_owned(),
+            method: read_u16(data, pos + 10),
+            crc32: read_u32(data, pos + 16),
+            compressed_size: read_u32(data, pos + 20) as usize,
+            local_header: read_u32(data, pos + 42) as usize,
+        });
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn extract_zip(data: &[u8], entry_name: Option<&str>) -> io::Result<Vec<u8>> {
+    let entries = try!(zip_entries(data));
+    let entry = match entry_name {
+        Some(name) => entries.iter().find(|e| e.name == name),
+        None => entries.iter().find(|e| {
+            let name = e.name.to_lowercase();
+            name.ends_with(".sfc") || name.ends_with(".smc")
+        }),
+    };
+    let entry = try!(entry.ok_or_else(|| {
+        let names: Vec<_> = entries.iter().map(|e| &*e.name).collect();
+        invalid_data(match entry_name {
+            Some(name) => format!("zip archive has no entry named '{}' (entries: {})", name,
+                names.join(", ")),
+            None => format!("zip archive contains no .sfc or .smc file (entries: {})",
+                names.join(", ")),
+        })
+    }));
+    info!("loading '{}' from zip archive", entry.name);
+
+    // The local header has its own (variable-length) name and extra fields
+    let header = entry.local_header;
+    if header + 30 > data.len() || read_u32(data, header) != 0x04034b50 {
+        return Err(invalid_data("invalid zip local file header"));
+    }
+    let start = header + 30 + read_u16(data, header + 26) as usize +
+        read_u16(data, header + 28) as usize;
+    if start + entry.compressed_size > data.len() {
+        return Err(invalid_data("zip archive is truncated"));
+    }
+    let compressed = &data[start..start + entry.compressed_size];
+
+    let rom = match entry.method {
+        0 => compressed.to_vec(),
+        8 => try!(inflate(compressed)),
+        method => return Err(invalid_data(format!("unsupported zip compression method {} \
+            (only stored and deflated entries are supported)", method))),
+    };
+    try!(check_crc(&rom, entry.crc32, &entry.name));
+    Ok(rom)
+}
+
+fn extract_gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let truncated = || invalid_data("gzip file is truncated");
+    if data.len() < 18 { return Err(truncated()); }
+    if data[2] != 8 {
+        return Err(invalid_data(format!("unsupported gzip compression method {}", data[2])));
+    }
+
+    // Skip the optional header fields
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 0x04 != 0 {
+        pos += 2 + read_u16(data, pos) as usize;
+    }
+    for &flag in &[0x08, 0x10] {
+        if flags & flag != 0 {
+            // Zero-terminated file name or comment
+            let len = try!(data[cmp::min(pos, data.len())..].iter().position(|&b| b == 0)
+                .ok_or_else(&truncated));
+            pos += len + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    if pos > data.len() - 8 { return Err(truncated()); }
+
+    let trailer = data.len() - 8;
+    let rom = try!(inflate(&data[pos..trailer]));
+    try!(check_crc(&rom, read_u32(data, trailer), "gzip file"));
+    if rom.len() as u32 != read_u32(data, trailer + 4) {
+        return Err(invalid_data("gzip file is corrupted: size doesn't match"));
+    }
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `"breeze "` repeated 8 times
+    fn data() -> Vec<u8> { b"breeze ".iter().cycle().take(56).cloned().collect() }
+
+    /// `data()`, compressed with DEFLATE
+    const DEFLATED: &'static [u8] = &[
+        0x4b, 0x2a, 0x4a, 0x4d, 0xad, 0x4a, 0x55, 0x48, 0x22, 0x95, 0x02, 0x00,
+    ];
+
+    /// `data()`, compressed with gzip (including the file name `game.sfc`)
+    const GZIP: &'static [u8] = &[
+        0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0x67, 0x61, 0x6d, 0x65, 0x2e,
+        0x73, 0x66, 0x63, 0x00, 0x4b, 0x2a, 0x4a, 0x4d, 0xad, 0x4a, 0x55, 0x48, 0x22, 0x95, 0x02,
+        0x00, 0xdc, 0x3c, 0x3d, 0x7c, 0x38, 0x00, 0x00, 0x00,
+    ];
+
+    fn push_u16(out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        push_u16(out, value as u16);
+        push_u16(out, (value >> 16) as u16);
+    }
+
+    /// Builds a zip archive. Each entry is given as its name, compression method, stored data and
+    /// CRC32.
+    fn zip(entries: &[(&str, u16, &[u8], u32)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, method, stored, crc) in entries {
+            let offset = out.len() as u32;
+            push_u32(&mut out, 0x04034b50);
+            push_u16(&mut out, 20);
+            push_u16(&mut out, 0);
+            push_u16(&mut out, method);
+            push_u32(&mut out, 0);
+            push_u32(&mut out, crc);
+            push_u32(&mut out, stored.len() as u32);
+            push_u32(&mut out, 0);
+            push_u16(&mut out, name.len() as u16);
+            push_u16(&mut out, 0);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(stored);
+
+            push_u32(&mut central, 0x02014b50);
+            push_u16(&mut central, 20);
+            push_u16(&mut central, 20);
+            push_u16(&mut central, 0);
+            push_u16(&mut central, method);
+            push_u32(&mut central, 0);
+            push_u32(&mut central, crc);
+            push_u32(&mut central, stored.len() as u32);
+            push_u32(&mut central, 0);
+            push_u16(&mut central, name.len() as u16);
+            for _ in 0..4 { push_u16(&mut central, 0); }
+            push_u32(&mut central, 0);
+            push_u32(&mut central, offset);
+            central.extend_from_slice(name.as_bytes());
+        }
+
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        push_u32(&mut out, 0x06054b50);
+        push_u32(&mut out, 0);
+        push_u16(&mut out, entries.len() as u16);
+        push_u16(&mut out, entries.len() as u16);
+        push_u32(&mut out, central.len() as u32);
+        push_u32(&mut out, central_offset);
+        push_u16(&mut out, 0);
+        out
+    }
+
+    fn error(result: io::Result<Vec<u8>>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(extract(data(), None).unwrap(), data());
+        assert!(extract(data(), Some("game.sfc")).is_err());
+    }
+
+    #[test]
+    fn gzip() {
+        assert_eq!(extract(GZIP.to_vec(), None).unwrap(), data());
+
+        let mut corrupted = GZIP.to_vec();
+        corrupted[31] ^= 1;
+        assert!(error(extract(corrupted, None)).contains("CRC32"));
+        let mut wrong_size = GZIP.to_vec();
+        wrong_size[35] += 1;
+        assert!(error(extract(wrong_size, None)).contains("size"));
+        let mut unsupported = GZIP.to_vec();
+        unsupported[2] = 9;
+        assert!(error(extract(unsupported, None)).contains("compression method 9"));
+        assert!(error(extract(GZIP[..16].to_vec(), None)).contains("truncated"));
+    }
+
+    #[test]
+    fn zip_entry_selection() {
+        let readme = b"not a ROM";
+        let archive = zip(&[
+            ("readme.txt", 0, readme, crc32(readme)),
+            ("GAME.SFC", 8, DEFLATED, crc32(&data())),
+            ("other.smc", 0, readme, crc32(readme)),
+        ]);
+
+        // The first .sfc or .smc file is loaded by default
+        assert_eq!(extract(archive.clone(), None).unwrap(), data());
+        assert_eq!(extract(archive.clone(), Some("readme.txt")).unwrap(), readme);
+        assert_eq!(extract(archive.clone(), Some("other.smc")).unwrap(), readme);
+        let msg = error(extract(archive, Some("game.sfc")));
+        assert!(msg.contains("no entry named 'game.sfc'"), "{}", msg);
+        assert!(msg.contains("readme.txt, GAME.SFC, other.smc"), "{}", msg);
+
+        let archive = zip(&[("readme.txt", 0, readme, crc32(readme))]);
+        assert!(error(extract(archive, None)).contains("no .sfc or .smc file"));
+    }
+
+    #[test]
+    fn zip_errors() {
+        let archive = zip(&[("game.sfc", 8, DEFLATED, crc32(&data()) ^ 1)]);
+        assert!(error(extract(archive, None)).contains("corrupted"));
+
+        // bzip2
+        let archive = zip(&[("game.sfc", 12, DEFLATED, crc32(&data()))]);
+        assert!(error(extract(archive, None)).contains("unsupported zip compression method 12"));
+
+        let archive = zip(&[("game.sfc", 8, DEFLATED, crc32(&data()))]);
+        assert!(error(extract(archive[..archive.len() - 10].to_vec(), None))
+            .contains("no central directory"));
+        assert!(extract(archive[..20].to_vec(), None).is_err());
+    }
+
+    #[test]
+    fn paths() {
+        let (path, entry) = split_path("/nonexistent/roms.zip#game.sfc");
+        assert_eq!(path, Path::new("/nonexistent/roms.zip"));
+        assert_eq!(entry, Some("game.sfc"));
+        assert_eq!(split_path("/nonexistent/game.sfc"), (Path::new("/nonexistent/game.sfc"), None));
+
+        assert_eq!(rom_path("/nonexistent/roms.zip#game.sfc"), Path::new("/nonexistent/roms.zip"));
+        assert_eq!(rom_path("/nonexistent/game.sfc.gz"), Path::new("/nonexistent/game.sfc"));
+        assert_eq!(rom_path("/nonexistent/game.sfc"), Path::new("/nonexistent/game.sfc"));
+    }
+
+    #[test]
+    fn split_rom() {
+        use std::env;
+        use std::fs::{self, File};
+        use std::io::Write;
+        use std::process;
+
+        let base = env::temp_dir().join(format!("breeze-test-{}-split", process::id()));
+        let part = |n: u32| base.with_extension(n.to_string());
+        // The first part keeps its copier header, the others have one as well that's stripped
+        let mut first = vec![0xee; 512];
+        first.extend_from_slice(&[1; 1024]);
+        let mut second = vec![0xee; 512];
+        second.extend_from_slice(&[2; 1024]);
+        File::create(part(1)).unwrap().write_all(&first).unwrap();
+        File::create(part(2)).unwrap().write_all(&second).unwrap();
+        File::create(part(3)).unwrap().write_all(&[3; 1024]).unwrap();
+        let _ = fs::remove_file(part(4));
+
+        let rom = read_rom_file(part(1).to_str().unwrap()).unwrap();
+        let mut expected = first.clone();
+        expected.extend_from_slice(&[2; 1024]);
+        expected.extend_from_slice(&[3; 1024]);
+        assert!(rom.data == expected);
+        assert_eq!(rom.warnings, ["joined 3 parts of a split ROM image"]);
+
+        // Other parts are loaded on their own
+        let rom = read_rom_file(part(2).to_str().unwrap()).unwrap();
+        assert!(rom.data == second);
+        assert!(rom.warnings.is_empty());
+
+        for n in 1..4 {
+            fs::remove_file(part(n)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Code/Data Logger (CDL)
+//!
+//! The code/data logger records how every byte of the ROM was accessed while the game was running:
+//! Whether it was executed as an opcode or as an operand, read as data by the CPU, or read by the
+//! DMA controller. This information can be used to separate code from data when disassembling a
+//! ROM.
+//!
+//! Logging is enabled by setting `Peripherals::cdl` to `Some(CodeDataLog)`.
+//!
+//! # File format
+//!
+//! A CDL file is usually stored next to the ROM, with a `.cdl` extension. All numbers are stored
+//! in little-endian byte order.
+//!
+//! ```text
+//! Offset  Size  Content
+//! 0       8     Magic bytes: "BREEZCDL"
+//! 8       1     Format version (currently 1)
+//! 9       4     Size of the ROM image in bytes (N)
+//! 13      N     One flag byte per ROM byte (see below)
+//! ```
+//!
+//! Each flag byte is a combination of the following bits:
+//!
+//! ```text
+//! Bit   Meaning
+//! 0x01  Executed as an opcode
+//! 0x02  Executed as an operand
+//! 0x04  Read as data by the CPU
+//! 0x08  Read by the DMA controller (DMA source or HDMA table)
+//! 0x10  X flag (8-bit index registers) was set when executed
+//! 0x20  M flag (8-bit accumulator) was set when executed
+//! ```
+//!
+//! Bits `0x10` and `0x20` are only meaningful if the byte was executed, and match the bits of the
+//! CPU's status register. Since a byte may be executed with different flag settings, they are
+//! combined with a logical or.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use std::io::{self, Read, Write};
+
+/// Executed as an opcode
+pub const OPCODE: u8 = 0x01;
+/// Executed as an operand
+pub const OPERAND: u8 = 0x02;
+/// Read as data by the CPU
+pub const DATA: u8 = 0x04;
+/// Read by the DMA controller
+pub const DMA: u8 = 0x08;
+/// The X flag was set (8-bit index registers) when this byte was executed
+pub const INDEX_8BIT: u8 = 0x10;
+/// The M flag was set (8-bit accumulator) when this byte was executed
+pub const ACC_8BIT: u8 = 0x20;
+
+const MAGIC: &'static [u8; 8] = b"BREEZCDL";
+const VERSION: u8 = 1;
+
+/// A code/data log of a ROM image
+pub struct CodeDataLog {
+    flags: Vec<u8>,
+}
+
+impl CodeDataLog {
+    /// Creates an empty log for a ROM of `rom_size` bytes.
+    pub fn new(rom_size: usize) -> Self {
+        CodeDataLog {
+            flags: vec![0; rom_size],
+        }
+    }
+
+    /// Reads a log in the format described in the module documentation.
+    pub fn read(r: &mut Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        try!(r.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a Breeze CDL file"));
+        }
+        let version = try!(r.read_u8());
+        if version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("unsupported CDL version {} (expected {})", version, VERSION)));
+        }
+
+        let size = try!(r.read_u32::<LittleEndian>()) as usize;
+        let mut flags = vec![0; size];
+        try!(r.read_exact(&mut flags));
+        Ok(CodeDataLog {
+            flags: flags,
+        })
+    }
+
+    /// Writes the log in the format described in the module documentation.
+    pub fn write(&self, w: &mut Write) -> io::Result<()> {
+        try!(w.write_all(MAGIC));
+        try!(w.write_u8(VERSION));
+        try!(w.write_u32::<LittleEndian>(self.flags.len() as u32));
+        w.write_all(&self.flags)
+    }
+
+    /// Returns the size of the logged ROM image in bytes.
+    pub fn rom_size(&self) -> usize { self.flags.len() }
+
+    /// Returns the flags of all ROM bytes.
+    pub fn flags(&self) -> &[u8] { &self.flags }
+
+    /// Adds `flags` to the flags logged for the ROM byte at `offset`.
+    pub fn mark(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.flags.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mark() {
+        let mut cdl = CodeDataLog::new(4);
+        cdl.mark(0, OPCODE | ACC_8BIT);
+        cdl.mark(0, OPCODE | INDEX_8BIT);
+        cdl.mark(1, OPERAND);
+        cdl.mark(1, DATA);
+        cdl.mark(3, DMA);
+        // Out of range offsets are ignored
+        cdl.mark(4, DATA);
+        assert_eq!(cdl.flags(), &[0x31, 0x06, 0x00, 0x08]);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut cdl = CodeDataLog::new(3);
+        cdl.mark(0, OPCODE);
+        cdl.mark(2, DMA | DATA);
+
+        let mut buf = Vec::new();
+        cdl.write(&mut buf).unwrap();
+        assert_eq!(buf, b"BREEZCDL\x01\x03\x00\x00\x00\x01\x00\x0c");
+
+        let read = CodeDataLog::read(&mut &buf[..]).unwrap();
+        assert_eq!(read.rom_size(), 3);
+        assert_eq!(read.flags(), cdl.flags());
+    }
+
+    #[test]
+    fn read_invalid() {
+        assert!(CodeDataLog::read(&mut &b"BREEZCDX\x01\x00\x00\x00\x00"[..]).is_err());
+        assert!(CodeDataLog::read(&mut &b"BREEZCDL\x02\x00\x00\x00\x00"[..]).is_err());
+        // Truncated flags
+        assert!(CodeDataLog::read(&mut &b"BREEZCDL\x01\x02\x00\x00\x00\x01"[..]).is_err());
+    }
+}
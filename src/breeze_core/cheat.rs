@@ -0,0 +1,374 @@
+//! Cheat codes
+//!
+//! A cheat consists of one or more codes, each of which changes a single byte. Three code formats
+//! are understood:
+//!
+//! * Game Genie: `DDAA-AAAA`, using the Game Genie alphabet (`DF4709156BC8A23E`) and with the
+//!   address bits scrambled
+//! * Pro Action Replay: `AAAAAADD`, 6 hex digits of address followed by the value
+//! * Raw codes as used by bsnes and Snes9x: `AAAAAA=DD` (or `AAAAAA:DD`), and `AAAAAA=CC?DD`, which
+//!   only applies if the byte is `CC`
+//!
+//! Codes for addresses in WRAM are applied by writing the value to RAM once per frame (at the
+//! start of V-Blank). All other codes replace the value the CPU reads from the cartridge (see
+//! `Rom::load`), which is how Game Genie codes for the ROM work. The address must match exactly,
+//! codes don't apply to mirrors of the address.
+//!
+//! Cheat lists are loaded from bsnes `.bml` and Snes9x `.cht` files (both the binary format of
+//! older Snes9x versions and the text format of newer ones), and are stored next to the ROM as
+//! `.bml` files.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str;
+
+/// Game Genie characters, in the order of the hex digits they stand for
+const GAME_GENIE_DIGITS: &'static [u8; 16] = b"DF4709156BC8A23E";
+
+/// Size of a cheat in binary Snes9x `.cht` files
+const SNES9X_RECORD_SIZE: usize = 28;
+
+fn invalid_data<S: Into<String>>(err: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.into())
+}
+
+/// Parses a string of hex digits (at most 8).
+fn parse_hex(s: &str) -> Option<u32> {
+    if s.is_empty() || s.len() > 8 || !s.bytes().all(|b| (b as char).is_digit(16)) {
+        return None;
+    }
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// A single byte change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Code {
+    /// 24-bit address on the CPU bus
+    pub addr: u32,
+    pub value: u8,
+    /// The code only applies if the byte at `addr` has this value
+    pub compare: Option<u8>,
+}
+
+impl Code {
+    /// Decodes a Game Genie, Pro Action Replay or raw code.
+    pub fn parse(code: &str) -> io::Result<Code> {
+        let code = code.trim();
+        let err = || invalid_data(format!("invalid cheat code '{}'", code));
+
+        if code.len() == 9 && code.as_bytes()[4] == b'-' {
+            let mut digits = 0;
+            for &c in code[..4].as_bytes().iter().chain(code[5..].as_bytes()) {
+                let c = (c as char).to_ascii_uppercase() as u8;
+                let digit = try!(GAME_GENIE_DIGITS.iter().position(|&d| d == c).ok_or_else(&err));
+                digits = digits << 4 | digit as u32;
+            }
+            let addr = digits & 0xffffff;
+            // Game Genie address `ijklqrst opabcduv wxefghmn` is `abcdefgh ijklmnop qrstuvwx`
+            let addr = (addr & 0x003c00) << 10 | (addr & 0x00003c) << 14 |
+                (addr & 0xf00000) >> 8 | (addr & 0x000003) << 10 | (addr & 0x00c000) >> 6 |
+                (addr & 0x0f0000) >> 12 | (addr & 0x0003c0) >> 6;
+            return Ok(Code {
+                addr: addr,
+                value: (digits >> 24) as u8,
+                compare: None,
+            });
+        }
+
+        if let Some(sep) = code.find(|c| c == '=' || c == ':') {
+            let addr = try!(parse_hex(&code[..sep]).ok_or_else(&err));
+            let rest = &code[sep + 1..];
+            let (compare, value) = match rest.find('?') {
+                Some(q) => (Some(try!(parse_hex(&rest[..q]).ok_or_else(&err))), &rest[q + 1..]),
+                None => (None, rest),
+            };
+            let value = try!(parse_hex(value).ok_or_else(&err));
+            if addr > 0xffffff || value > 0xff || compare.map_or(false, |c| c > 0xff) {
+                return Err(err());
+            }
+            return Ok(Code {
+                addr: addr,
+                value: value as u8,
+                compare: compare.map(|c| c as u8),
+            });
+        }
+
+        if code.len() == 8 {
+            let digits = try!(parse_hex(code).ok_or_else(&err));
+            return Ok(Code {
+                addr: digits >> 8,
+                value: digits as u8,
+                compare: None,
+            });
+        }
+
+        Err(err())
+    }
+
+    /// Returns the offset of the code's address in WRAM, or `None` if it's not a WRAM address.
+    pub fn wram_offset(&self) -> Option<usize> {
+        let (bank, addr) = ((self.addr >> 16) as u8, self.addr as u16);
+        match bank {
+            0x7e | 0x7f => Some((self.addr - 0x7e0000) as usize),
+            0x00 ... 0x3f | 0x80 ... 0xbf if addr < 0x2000 => Some(addr as usize),
+            _ => None,
+        }
+    }
+
+    /// Applies the code to the byte `value` read from its address.
+    pub fn apply(&self, value: u8) -> u8 {
+        match self.compare {
+            Some(compare) if compare != value => value,
+            _ => self.value,
+        }
+    }
+}
+
+/// A named list of codes that are enabled or disabled together
+#[derive(Clone, Debug)]
+pub struct Cheat {
+    pub description: String,
+    /// The codes as entered, separated by `+`
+    code: String,
+    codes: Vec<Code>,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// Creates an enabled cheat from codes separated by `+`.
+    pub fn new(description: &str, code: &str) -> io::Result<Cheat> {
+        let codes = try!(code.split('+').map(Code::parse).collect::<io::Result<Vec<_>>>());
+        Ok(Cheat {
+            description: description.to_owned(),
+            code: code.trim().to_owned(),
+            codes: codes,
+            enabled: true,
+        })
+    }
+
+    /// Returns the codes as entered.
+    pub fn code(&self) -> &str { &self.code }
+
+    pub fn codes(&self) -> &[Code] { &self.codes }
+}
+
+/// The cheats of a game
+#[derive(Clone, Debug, Default)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+    /// Whether cheats were added, removed or enabled/disabled since the list was loaded
+    modified: bool,
+}
+
+impl CheatList {
+    pub fn new() -> Self {
+        CheatList::default()
+    }
+
+    /// Loads a cheat list from a bsnes `.bml` or Snes9x `.cht` file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut data = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut data));
+        Self::parse(&data).map_err(|e| io::Error::new(e.kind(),
+            format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses a cheat list. Binary Snes9x cheat files are detected by their size, everything else
+    /// is parsed as BML.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        match str::from_utf8(data) {
+            Ok(text) if text.trim_left().is_empty() || text.trim_left().starts_with("cheat") => {
+                Self::parse_bml(text)
+            }
+            _ if data.len() % SNES9X_RECORD_SIZE == 0 => Self::parse_snes9x(data),
+            Ok(text) => Self::parse_bml(text),
+            Err(_) => Err(invalid_data("unknown cheat file format")),
+        }
+    }
+
+    /// Parses a BML cheat list, as written by bsnes and newer Snes9x versions:
+    ///
+    /// ```text
+    /// cheat
+    ///   description: Infinite lives
+    ///   code: 7e0dbe=05
+    ///   enable
+    /// ```
+    ///
+    /// Snes9x uses `name` instead of `description`.
+    pub fn parse_bml(text: &str) -> io::Result<Self> {
+        // (description, code, enabled) of the cheats
+        let mut entries: Vec<(String, String, bool)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: &str| invalid_data(format!("cheat file line {}: {}", i + 1, msg));
+            let indented = line.starts_with(|c: char| c.is_whitespace());
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            let (key, value) = match line.find(':') {
+                Some(colon) => (line[..colon].trim(), line[colon + 1..].trim()),
+                None => (line, ""),
+            };
+            if !indented {
+                if key != "cheat" {
+                    return Err(err("expected `cheat`"));
+                }
+                entries.push((String::new(), String::new(), false));
+                continue;
+            }
+
+            let entry = try!(entries.last_mut().ok_or_else(|| err("expected `cheat`")));
+            match key {
+                "description" | "name" => entry.0 = value.to_owned(),
+                "code" if entry.1.is_empty() => entry.1 = value.to_owned(),
+                "code" => {
+                    entry.1.push('+');
+                    entry.1.push_str(value);
+                }
+                "enable" | "enabled" => entry.2 = true,
+                _ => {}
+            }
+        }
+
+        let mut list = CheatList::new();
+        for (description, code, enabled) in entries {
+            let mut cheat = try!(Cheat::new(&description, &code));
+            cheat.enabled = enabled;
+            list.cheats.push(cheat);
+        }
+        Ok(list)
+    }
+
+    /// Parses the binary cheat files of older Snes9x versions. They consist of 28-byte records:
+    /// Flags (bit 2 set if disabled), value, 24-bit address, 3 unused bytes and a 20-byte name.
+    pub fn parse_snes9x(data: &[u8]) -> io::Result<Self> {
+        if data.len() % SNES9X_RECORD_SIZE != 0 {
+            return Err(invalid_data("Snes9x cheat file is truncated"));
+        }
+
+        let mut list = CheatList::new();
+        for record in data.chunks(SNES9X_RECORD_SIZE) {
+            let addr = record[2] as u32 | (record[3] as u32) << 8 | (record[4] as u32) << 16;
+            let name = &record[8..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            let mut cheat = try!(Cheat::new(&String::from_utf8_lossy(name),
+                &format!("{:06x}={:02x}", addr, record[1])));
+            cheat.enabled = record[0] & 0x04 == 0;
+            list.cheats.push(cheat);
+        }
+        Ok(list)
+    }
+
+    /// Writes the list in the BML format used by bsnes.
+    pub fn write_bml<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for cheat in &self.cheats {
+            try!(writeln!(w, "cheat"));
+            try!(writeln!(w, "  description: {}", cheat.description));
+            try!(writeln!(w, "  code: {}", cheat.code));
+            if cheat.enabled {
+                try!(writeln!(w, "  enable"));
+            }
+            try!(writeln!(w, ""));
+        }
+        Ok(())
+    }
+
+    pub fn cheats(&self) -> &[Cheat] { &self.cheats }
+
+    pub fn len(&self) -> usize { self.cheats.len() }
+
+    pub fn is_empty(&self) -> bool { self.cheats.is_empty() }
+
+    /// Whether cheats were added, removed or enabled/disabled since the list was loaded.
+    pub fn is_modified(&self) -> bool { self.modified }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+        self.modified = true;
+    }
+
+    /// Removes the cheat at `index` (panics if it doesn't exist).
+    pub fn remove(&mut self, index: usize) -> Cheat {
+        self.modified = true;
+        self.cheats.remove(index)
+    }
+
+    /// Enables or disables the cheat at `index` (panics if it doesn't exist).
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        if self.cheats[index].enabled != enabled {
+            self.cheats[index].enabled = enabled;
+            self.modified = true;
+        }
+    }
+
+    /// Returns the enabled codes that don't apply to WRAM. These are applied by the cartridge.
+    pub fn rom_codes(&self) -> Vec<Code> {
+        let mut codes = Vec::new();
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            codes.extend(cheat.codes.iter().filter(|code| code.wram_offset().is_none()));
+        }
+        codes
+    }
+
+    /// Applies the enabled codes for WRAM addresses to `wram`.
+    pub fn apply_to_wram(&self, wram: &mut [u8]) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            for code in &cheat.codes {
+                if let Some(offset) = code.wram_offset() {
+                    wram[offset] = code.apply(wram[offset]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cheat, Code};
+
+    fn code(addr: u32, value: u8, compare: Option<u8>) -> Code {
+        Code { addr: addr, value: value, compare: compare }
+    }
+
+    #[test]
+    fn game_genie() {
+        // Encoded by hand from the address layout `ijklqrst opabcduv wxefghmn`
+        assert_eq!(Code::parse("1AC8-A9D6").unwrap(), code(0x12a3b4, 0x6c, None));
+        assert_eq!(Code::parse("04E3-E767").unwrap(), code(0xc0ffee, 0x42, None));
+        assert_eq!(Code::parse("3cef-add7").unwrap(), code(0x00ff10, 0xea, None));
+        assert!(Code::parse("3CEF-ADDX").is_err());
+    }
+
+    #[test]
+    fn pro_action_replay() {
+        assert_eq!(Code::parse("7E0DBE05").unwrap(), code(0x7e0dbe, 0x05, None));
+        assert!(Code::parse("7E0DBEG5").is_err());
+    }
+
+    #[test]
+    fn raw() {
+        assert_eq!(Code::parse("7e0dbe=05").unwrap(), code(0x7e0dbe, 0x05, None));
+        assert_eq!(Code::parse("00ff10:ea").unwrap(), code(0x00ff10, 0xea, None));
+        assert_eq!(Code::parse("c0ffee=a9?42").unwrap(), code(0xc0ffee, 0x42, Some(0xa9)));
+        assert!(Code::parse("1000000=00").is_err());
+        assert!(Code::parse("7e0dbe=100").is_err());
+        assert!(Code::parse("7e0dbe=?05").is_err());
+    }
+
+    #[test]
+    fn compare() {
+        let code = Code::parse("c0ffee=a9?42").unwrap();
+        assert_eq!(code.apply(0xa9), 0x42);
+        assert_eq!(code.apply(0xa8), 0xa8);
+    }
+
+    #[test]
+    fn multiple_codes() {
+        let cheat = Cheat::new("Lives", "7e0dbe=05+7e0dbf=09").unwrap();
+        assert_eq!(cheat.codes(), &[code(0x7e0dbe, 0x05, None), code(0x7e0dbf, 0x09, None)]);
+        assert_eq!(cheat.codes()[0].wram_offset(), Some(0x0dbe));
+    }
+}
@@ -0,0 +1,880 @@
+//! Capcom Cx4 coprocessor (Mega Man X2 and X3)
+//!
+//! The Cx4 is a Hitachi HG51B169 DSP that builds the OAM for the game, draws wireframes and scales
+//! and rotates sprites. It runs a program from the game ROM, which the games only use in a few
+//! ways, so we emulate the commands at a high level instead (based on the implementation in
+//! Snes9x).
+//!
+//! The Cx4 maps 3 KB of RAM to `$6000-$6BFF` and its registers to `$7F40-$7FFF` in banks
+//! `$00-$3F` and `$80-$BF`. Writing to `$7F47` copies data from ROM to the RAM, writing to `$7F4F`
+//! starts a command. The parameters and results of most commands are stored in the registers at
+//! `$7F80` and up (`R0` to `R15`, 24 bits each).
+
+use diagnostics::Diagnostics;
+
+use std::f64::consts::PI;
+
+/// Constants the Cx4 can load into its registers. Written to RAM by the "immediate register"
+/// commands, which the games use to test the chip.
+const IMMEDIATE_DATA: [u8; 48] = [
+    0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff,
+    0xff, 0xff, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x80, 0xff, 0xff, 0x7f,
+    0x00, 0x80, 0x00, 0xff, 0x7f, 0x00, 0xff, 0x7f, 0xff, 0x7f, 0xff, 0xff,
+    0x00, 0x00, 0x01, 0xff, 0xff, 0xfe, 0x00, 0x01, 0x00, 0xff, 0xfe, 0x00,
+];
+
+#[derive(Clone)]
+pub struct Cx4 {
+    /// Everything mapped to `$6000-$7FFF`. Only the RAM and the registers are used.
+    ram: Vec<u8>,
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+impl_save_state!(Cx4 { ram } ignore { diagnostics });
+
+impl Cx4 {
+    pub fn new() -> Self {
+        Cx4 {
+            ram: vec![0; 0x2000],
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    fn is_mapped(bank: u8, addr: u16) -> bool {
+        match (bank & 0x7f, addr) {
+            (0x00 ... 0x3f, 0x6000 ... 0x7fff) => true,
+            _ => false,
+        }
+    }
+
+    /// Performs a load from the SNES CPU. Returns `None` if the Cx4 isn't mapped to `bank:addr`.
+    pub fn load(&mut self, bank: u8, addr: u16) -> Option<u8> {
+        self.peek(bank, addr)
+    }
+
+    /// Reads from the Cx4 without side effects. Returns `None` if the Cx4 isn't mapped there.
+    pub fn peek(&self, bank: u8, addr: u16) -> Option<u8> {
+        if !Self::is_mapped(bank, addr) { return None; }
+        match addr & 0x1fff {
+            // Busy flag (commands complete immediately)
+            0x1f5e => Some(0),
+            offset => Some(self.ram[offset as usize]),
+        }
+    }
+
+    /// Performs a store from the SNES CPU, which may start a command. Returns `false` if the Cx4
+    /// isn't mapped to `bank:addr`.
+    pub fn store(&mut self, rom: &[u8], bank: u8, addr: u16, value: u8) -> bool {
+        if !Self::is_mapped(bank, addr) { return false; }
+        let offset = addr as usize & 0x1fff;
+        self.ram[offset] = value;
+        match offset {
+            0x1f47 => self.transfer(rom),
+            0x1f4f => self.command(rom, value),
+            _ => {}
+        }
+        true
+    }
+
+    /// Overwrites a byte of RAM or a register without starting a command.
+    pub fn poke(&mut self, bank: u8, addr: u16, value: u8) -> bool {
+        if !Self::is_mapped(bank, addr) { return false; }
+        self.ram[addr as usize & 0x1fff] = value;
+        true
+    }
+
+    fn read_word(&self, offset: usize) -> u16 {
+        self.ram[offset & 0x1fff] as u16 | (self.ram[(offset + 1) & 0x1fff] as u16) << 8
+    }
+
+    fn write_word(&mut self, offset: usize, value: u16) {
+        self.ram[offset & 0x1fff] = value as u8;
+        self.ram[(offset + 1) & 0x1fff] = (value >> 8) as u8;
+    }
+
+    fn read_long(&self, offset: usize) -> u32 {
+        self.read_word(offset) as u32 | (self.ram[(offset + 2) & 0x1fff] as u32) << 16
+    }
+
+    fn write_long(&mut self, offset: usize, value: u32) {
+        self.write_word(offset, value as u16);
+        self.ram[(offset + 2) & 0x1fff] = (value >> 16) as u8;
+    }
+
+    /// Reads register `R<n>`.
+    fn reg(&self, n: usize) -> u32 { self.read_long(0x1f80 + n * 3) }
+
+    fn set_reg(&mut self, n: usize, value: u32) { self.write_long(0x1f80 + n * 3, value) }
+
+    /// `$7F47`: Copies `$7F43` bytes from ROM address `$7F40` to RAM address `$7F45`.
+    fn transfer(&mut self, rom: &[u8]) {
+        let src = rom_offset(self.read_long(0x1f40));
+        let count = self.read_word(0x1f43) as usize;
+        let dest = self.read_word(0x1f45) as usize;
+        for i in 0..count {
+            self.ram[(dest + i) & 0x1fff] = rom_byte(rom, src + i);
+        }
+    }
+
+    /// `$7F4F`: Runs a command.
+    fn command(&mut self, rom: &[u8], cmd: u8) {
+        if self.ram[0x1f4d] == 0x0e && cmd & 0xc3 == 0 {
+            // Test command
+            self.ram[0x1f80] = cmd >> 2;
+            return;
+        }
+
+        match cmd {
+            // Sprite functions, selected by `$7F4D`
+            0x00 => match self.ram[0x1f4d] {
+                0x00 => self.build_oam(rom),
+                0x03 => self.scale_rotate(0),
+                0x05 => self.transform_lines(),
+                0x07 => self.scale_rotate(64),
+                0x08 => self.draw_wireframe(rom),
+                0x0b => self.disintegrate(),
+                0x0c => self.bitplane_wave(),
+                n => diag!(self.diagnostics, "unimplemented Cx4 sprite function ${:02X}", n),
+            },
+            0x01 => {
+                for byte in &mut self.ram[0x300..0x300 + 2304] {
+                    *byte = 0;
+                }
+                self.draw_wireframe(rom);
+            }
+            0x05 => self.propulsion(),
+            0x0d => self.set_vector_length(),
+            0x10 => self.polar_to_rect(),
+            0x13 => self.polar_to_rect2(),
+            0x15 => self.pythagorean(),
+            0x1f => self.atan(),
+            0x22 => self.trapezoid(),
+            0x25 => {
+                let (lo, hi) = mul(self.reg(0), self.reg(1));
+                self.set_reg(0, lo);
+                self.set_reg(1, hi);
+            }
+            0x2d => self.transform_coords(),
+            0x40 => {
+                let sum = self.ram[..0x800].iter().fold(0, |sum, &b| sum + b as u32);
+                self.set_reg(0, sum);
+            }
+            0x54 => {
+                let r0 = self.reg(0);
+                let (lo, hi) = mul(r0, r0);
+                self.set_reg(1, lo);
+                self.set_reg(2, hi);
+            }
+            0x5c => {
+                self.set_reg(0, 0);
+                self.immediate_reg(0);
+            }
+            0x5e ... 0x7c if cmd & 1 == 0 => {
+                let start = (cmd - 0x5e) as usize / 2 * 3;
+                self.immediate_reg(start);
+            }
+            0x89 => {
+                self.set_reg(0, 0x054336);
+                self.set_reg(1, 0xffffff);
+            }
+            _ => diag!(self.diagnostics, "unimplemented Cx4 command ${:02X}", cmd),
+        }
+    }
+
+    /// Writes the constants in `IMMEDIATE_DATA`, starting at `start`, to RAM address `R0`.
+    fn immediate_reg(&mut self, start: usize) {
+        let mut r0 = self.reg(0);
+        for &byte in &IMMEDIATE_DATA[start..] {
+            if r0 & 0xfff < 0xc00 {
+                self.ram[r0 as usize & 0xfff] = byte;
+            }
+            r0 = r0.wrapping_add(1);
+        }
+        self.set_reg(0, r0);
+    }
+
+    /// Builds the OAM from a list of objects, each made of multiple sprites.
+    ///
+    /// The OAM is written to `$6000-$621F`, starting at the sprite specified in `$6626`. The
+    /// object list at `$6220` has `$6620` entries of 16 bytes each (position, attributes and a ROM
+    /// pointer to the sprite list).
+    fn build_oam(&mut self, rom: &[u8]) {
+        let first = self.ram[0x626] as usize;
+        // Hide the sprites we might write
+        let mut i = 0x1fd as isize;
+        while i > (first << 2) as isize {
+            self.ram[i as usize] = 0xe0;
+            i -= 4;
+        }
+
+        let objects = self.ram[0x620];
+        if objects == 0 { return; }
+
+        let global_x = self.read_word(0x621);
+        let global_y = self.read_word(0x623);
+        let mut oam = first << 2;
+        let mut oam_hi = 0x200 + (first >> 2);
+        let mut shift = (first & 3) * 2;
+        let mut remaining = 128 - first as i32;
+        let mut src = 0x220;
+
+        // Writes one sprite to the OAM
+        macro_rules! push_sprite {
+            ( $x:expr, $y:expr, $name:expr, $attr:expr, $hi:expr ) => {{
+                self.ram[oam] = $x as u8;
+                self.ram[oam + 1] = $y as u8;
+                self.ram[oam + 2] = $name;
+                self.ram[oam + 3] = $attr;
+                self.ram[oam_hi] = self.ram[oam_hi] & !(3 << shift) | $hi << shift;
+                oam += 4;
+                remaining -= 1;
+                shift = (shift + 2) & 6;
+                if shift == 0 { oam_hi += 1; }
+            }};
+        }
+
+        for _ in 0..objects {
+            if remaining <= 0 { break; }
+            let obj_x = self.read_word(src).wrapping_sub(global_x) as i16;
+            let obj_y = self.read_word(src + 2).wrapping_sub(global_y) as i16;
+            let name = self.ram[src + 5];
+            let attr = self.ram[src + 4] | self.ram[src + 6];
+
+            let mut sprites = rom_offset(self.read_long(src + 7));
+            let count = rom_byte(rom, sprites);
+            sprites += 1;
+            if count != 0 {
+                for _ in 0..count {
+                    if remaining <= 0 { break; }
+                    let flags = rom_byte(rom, sprites);
+                    let size = if flags & 0x20 != 0 { 16 } else { 8 };
+
+                    let mut x = rom_byte(rom, sprites + 1) as i8 as i16;
+                    if attr & 0x40 != 0 { x = -x - size; }
+                    let x = x.wrapping_add(obj_x);
+                    let mut y = rom_byte(rom, sprites + 2) as i8 as i16;
+                    if attr & 0x80 != 0 { y = -y - size; }
+                    let y = y.wrapping_add(obj_y);
+
+                    if x >= -16 && x <= 272 && y >= -16 && y <= 224 {
+                        let sprite_name = name.wrapping_add(rom_byte(rom, sprites + 3));
+                        let hi = (x >> 8) as u8 & 1 | if size == 16 { 2 } else { 0 };
+                        push_sprite!(x, y, sprite_name, attr ^ (flags & 0xc0), hi);
+                    }
+                    sprites += 4;
+                }
+            } else {
+                // A single large sprite
+                let hi = if obj_x & 0x100 != 0 { 3 } else { 2 };
+                push_sprite!(obj_x, obj_y, name, attr, hi);
+            }
+            src += 16;
+        }
+    }
+
+    /// Scales and rotates a 4bpp bitmap (stored as one pixel per nibble at `$6600`) and converts
+    /// the result to SNES tiles at `$6000`.
+    fn scale_rotate(&mut self, row_padding: usize) {
+        let angle = self.read_word(0x1f80);
+        // Negative scales are clamped
+        let scale = |s: u16| if s & 0x8000 != 0 { 0x7fff } else { s as i32 };
+        let x_scale = scale(self.read_word(0x1f8f));
+        let y_scale = scale(self.read_word(0x1f92));
+
+        // The transformation matrix (4.12 fixed point)
+        let (a, b, c, d) = match angle {
+            0 => (x_scale, 0, 0, y_scale),
+            128 => (0, -y_scale, x_scale, 0),
+            256 => (-x_scale, 0, 0, -y_scale),
+            384 => (0, y_scale, -x_scale, 0),
+            _ => {
+                let (sin, cos) = (sin(angle), cos(angle));
+                ((cos * x_scale) >> 15, -((sin * y_scale) >> 15),
+                 (sin * x_scale) >> 15, (cos * y_scale) >> 15)
+            }
+        };
+        let (a, b, c, d) = (a as i16 as i32, b as i16 as i32, c as i16 as i32, d as i16 as i32);
+
+        let w = self.ram[0x1f89] as usize & !7;
+        let h = self.ram[0x1f8c] as usize & !7;
+        let clear_len = ::std::cmp::min((w + row_padding / 4) * h / 2, 0x2000);
+        for byte in &mut self.ram[..clear_len] {
+            *byte = 0;
+        }
+
+        // The center of rotation
+        let cx = self.read_word(0x1f83) as i16 as i32;
+        let cy = self.read_word(0x1f86) as i16 as i32;
+
+        // Source position of the top left output pixel
+        let mut line_x = (cx << 12).wrapping_sub(cx * a).wrapping_sub(cx * b);
+        let mut line_y = (cy << 12).wrapping_sub(cy * c).wrapping_sub(cy * d);
+
+        let mut out = 0;
+        let mut bit = 0x80;
+        for _ in 0..h {
+            let mut x = line_x as u32;
+            let mut y = line_y as u32;
+            for _ in 0..w {
+                let pixel = if (x >> 12) as usize >= w || (y >> 12) as usize >= h {
+                    0
+                } else {
+                    let addr = (y >> 12) as usize * w + (x >> 12) as usize;
+                    let byte = self.ram[(0x600 + (addr >> 1)) & 0x1fff];
+                    if addr & 1 != 0 { byte >> 4 } else { byte }
+                };
+                self.plot(out, bit, pixel);
+
+                bit >>= 1;
+                if bit == 0 {
+                    bit = 0x80;
+                    out += 32;
+                }
+                x = x.wrapping_add(a as u32);
+                y = y.wrapping_add(c as u32);
+            }
+
+            out += 2 + row_padding;
+            if out & 0x10 != 0 {
+                out &= !0x10;
+            } else {
+                out = out.wrapping_sub(w * 4 + row_padding);
+            }
+            line_x = line_x.wrapping_add(b);
+            line_y = line_y.wrapping_add(d);
+        }
+    }
+
+    /// Sets the pixel selected by `bit` in the 4bpp tile row at `offset` to the color `pixel`
+    /// (only setting bits, the tile must have been cleared before).
+    fn plot(&mut self, offset: usize, bit: u8, pixel: u8) {
+        if pixel & 1 != 0 { self.ram[offset & 0x1fff] |= bit; }
+        if pixel & 2 != 0 { self.ram[(offset + 1) & 0x1fff] |= bit; }
+        if pixel & 4 != 0 { self.ram[(offset + 16) & 0x1fff] |= bit; }
+        if pixel & 8 != 0 { self.ram[(offset + 17) & 0x1fff] |= bit; }
+    }
+
+    /// Scales a 4bpp bitmap at `$6600` around a center point, converting it to SNES tiles.
+    fn disintegrate(&mut self) {
+        let width = self.ram[0x1f89] as u32;
+        let height = self.ram[0x1f8c] as u32;
+        let cx = self.read_word(0x1f80) as i16 as i32;
+        let cy = self.read_word(0x1f83) as i16 as i32;
+        let scale_x = self.read_word(0x1f86) as i16 as i32;
+        let scale_y = self.read_word(0x1f8f) as i16 as i32;
+        let start_x = (-cx * scale_x + (cx << 8)) as u32;
+        let start_y = (-cy * scale_y + (cy << 8)) as u32;
+
+        let clear_len = ::std::cmp::min((width * height / 2) as usize, 0x2000);
+        for byte in &mut self.ram[..clear_len] {
+            *byte = 0;
+        }
+
+        let mut src = 0x600;
+        let mut y = start_y;
+        for _ in 0..height {
+            let mut x = start_x;
+            for j in 0..width {
+                let (px, py) = (x >> 8, y >> 8);
+                if px < width && py < height && py * width + px < 0x2000 {
+                    let byte = self.ram[src & 0x1fff];
+                    let pixel = if j & 1 != 0 { byte >> 4 } else { byte };
+                    let offset = (y >> 11) * width * 4 + (x >> 11) * 32 + (py & 7) * 2;
+                    self.plot(offset as usize, 0x80 >> (px & 7), pixel);
+                }
+                if j & 1 != 0 { src += 1; }
+                x = x.wrapping_add(scale_x as u32);
+            }
+            y = y.wrapping_add(scale_y as u32);
+        }
+    }
+
+    /// Applies a wave effect to the tiles at `$6000`. The wave heights are at `$6B00`, the
+    /// pattern to fill in at `$6A00`.
+    fn bitplane_wave(&mut self) {
+        let mut dst = 0;
+        let mut wave = self.ram[0x1f83] as usize;
+        let mut mask1: u16 = 0xc0c0;
+        let mut mask2: u16 = 0x3f3f;
+
+        for _ in 0..0x10 {
+            for &pattern in &[0xa00, 0xa10] {
+                loop {
+                    let mut height = -(self.ram[wave + 0xb00] as i8 as i16) - 16;
+                    for i in 0..40 {
+                        let offset = dst + (i / 8) * 0x200 + (i % 8) * 2;
+                        let mut value = self.read_word(offset) & mask2;
+                        if height >= 0 {
+                            value |= mask1 & if height < 8 {
+                                self.read_word(pattern + height as usize * 2)
+                            } else {
+                                0xff00
+                            };
+                        }
+                        self.write_word(offset, value);
+                        height += 1;
+                    }
+
+                    wave = (wave + 1) & 0x7f;
+                    mask1 = mask1.rotate_right(2);
+                    mask2 = mask2.rotate_right(2);
+                    if mask1 == 0xc0c0 { break; }
+                }
+                dst += 16;
+            }
+        }
+    }
+
+    /// Transforms the vertices at `$6000` (16 bytes each) and computes the lines between them
+    /// (the vertex pairs at `$6B02`), writing the line list to `$6600`.
+    fn transform_lines(&mut self) {
+        let mut wf = Wireframe {
+            x2: self.ram[0x1f83] as i16,
+            y2: self.ram[0x1f86] as i16,
+            dist: self.ram[0x1f89] as i16,
+            scale: self.ram[0x1f8c] as i16,
+            ..Wireframe::default()
+        };
+
+        let vertices = self.read_word(0x1f80) as usize;
+        for i in 0..vertices {
+            let ptr = i * 0x10;
+            wf.x = self.read_word(ptr + 1) as i16;
+            wf.y = self.read_word(ptr + 5) as i16;
+            wf.z = self.read_word(ptr + 9) as i16;
+            wf.transform();
+            self.write_word(ptr + 1, wf.x.wrapping_add(0x80) as u16);
+            self.write_word(ptr + 5, wf.y.wrapping_add(0x50) as u16);
+        }
+
+        for &base in &[0x600, 0x608] {
+            self.write_word(base, 23);
+            self.write_word(base + 2, 0x60);
+            self.write_word(base + 5, 0x40);
+        }
+
+        let lines = self.read_word(0xb00) as usize;
+        for i in 0..lines {
+            let from = (self.ram[(0xb02 + i * 2) & 0x1fff] as usize) << 4;
+            let to = (self.ram[(0xb03 + i * 2) & 0x1fff] as usize) << 4;
+            wf.x = self.read_word(from + 1) as i16;
+            wf.y = self.read_word(from + 5) as i16;
+            wf.x2 = self.read_word(to + 1) as i16;
+            wf.y2 = self.read_word(to + 5) as i16;
+            wf.calc_line();
+
+            let out = 0x600 + i * 8;
+            let steps = if wf.dist != 0 { wf.dist } else { 1 };
+            self.write_word(out, steps as u16);
+            self.write_word(out + 2, wf.x as u16);
+            self.write_word(out + 5, wf.y as u16);
+        }
+    }
+
+    /// Draws the wireframe model whose line list is at ROM address `$7F80` into the tiles at
+    /// `$6300`.
+    fn draw_wireframe(&mut self, rom: &[u8]) {
+        let bank = (self.ram[0x1f82] as u32) << 16;
+        let point = |addr: u32| {
+            let offset = rom_offset(addr);
+            let coord = |i| ((rom_byte(rom, offset + i) as u16) << 8 |
+                rom_byte(rom, offset + i + 1) as u16) as i16;
+            (coord(0), coord(2), coord(4))
+        };
+
+        let mut line = rom_offset(self.read_long(0x1f80));
+        for _ in 0..self.ram[0x295] {
+            let start = if rom_byte(rom, line) == 0xff && rom_byte(rom, line + 1) == 0xff {
+                // Continue at the end point of the last line
+                let mut prev = line.wrapping_sub(5);
+                while rom_byte(rom, prev + 2) == 0xff && rom_byte(rom, prev + 3) == 0xff {
+                    prev = prev.wrapping_sub(5);
+                }
+                point(bank | (rom_byte(rom, prev + 2) as u32) << 8 | rom_byte(rom, prev + 3) as u32)
+            } else {
+                point(bank | (rom_byte(rom, line) as u32) << 8 | rom_byte(rom, line + 1) as u32)
+            };
+            let end = point(bank | (rom_byte(rom, line + 2) as u32) << 8 |
+                rom_byte(rom, line + 3) as u32);
+            let color = rom_byte(rom, line + 4);
+
+            self.draw_line(start, end, color);
+            line += 5;
+        }
+    }
+
+    fn draw_line(&mut self, start: (i16, i16, i16), end: (i16, i16, i16), color: u8) {
+        let mut wf = Wireframe {
+            x: start.0,
+            y: start.1,
+            z: start.2,
+            x2: self.ram[0x1f86] as i16,
+            y2: self.ram[0x1f87] as i16,
+            dist: self.ram[0x1f88] as i16,
+            scale: self.ram[0x1f90] as i16,
+        };
+        wf.transform2();
+        let mut x = (wf.x as i32 + 48) << 8;
+        let mut y = (wf.y as i32 + 48) << 8;
+
+        wf.x = end.0;
+        wf.y = end.1;
+        wf.z = end.2;
+        wf.transform2();
+        let (end_x, end_y) = ((wf.x as i32 + 48) << 8, (wf.y as i32 + 48) << 8);
+
+        wf.x = (x >> 8) as i16;
+        wf.y = (y >> 8) as i16;
+        wf.x2 = (end_x >> 8) as i16;
+        wf.y2 = (end_y >> 8) as i16;
+        wf.calc_line();
+        let (step_x, step_y) = (wf.x as i32, wf.y as i32);
+
+        let steps = if wf.dist != 0 { wf.dist } else { 1 };
+        for _ in 0..steps {
+            if x > 0xff && y > 0xff && x < 0x6000 && y < 0x6000 {
+                let (px, py) = ((x >> 8) as usize, (y >> 8) as usize);
+                let offset = 0x300 + (py >> 3) * 0xc0 + (px >> 3) * 0x10 + (py & 7) * 2;
+                let bit = 0x80 >> (px & 7);
+                self.ram[offset] = self.ram[offset] & !bit | if color & 1 != 0 { bit } else { 0 };
+                self.ram[offset + 1] =
+                    self.ram[offset + 1] & !bit | if color & 2 != 0 { bit } else { 0 };
+            }
+            x += step_x;
+            y += step_y;
+        }
+    }
+
+    /// Rotates and scales the point in `$7F81`, `$7F84` and `$7F87`.
+    fn transform_coords(&mut self) {
+        let mut wf = Wireframe {
+            x: self.read_word(0x1f81) as i16,
+            y: self.read_word(0x1f84) as i16,
+            z: self.read_word(0x1f87) as i16,
+            x2: self.ram[0x1f89] as i16,
+            y2: self.ram[0x1f8a] as i16,
+            dist: self.ram[0x1f8b] as i16,
+            scale: self.read_word(0x1f90) as i16,
+        };
+        wf.transform2();
+        self.write_word(0x1f80, wf.x as u16);
+        self.write_word(0x1f83, wf.y as u16);
+    }
+
+    fn propulsion(&mut self) {
+        let divisor = self.read_word(0x1f83) as i32;
+        let value = if divisor != 0 {
+            (0x10000 / divisor).wrapping_mul(self.read_word(0x1f81) as i32) >> 8
+        } else {
+            0x10000
+        };
+        self.write_word(0x1f80, value as u16);
+    }
+
+    fn set_vector_length(&mut self) {
+        let x = self.read_word(0x1f80) as i16 as f64;
+        let y = self.read_word(0x1f83) as i16 as f64;
+        let length = self.read_word(0x1f86) as i16 as f64;
+        let factor = length / (x * x + y * y).sqrt();
+        self.write_word(0x1f89, (x * factor * 0.98) as i16 as u16);
+        self.write_word(0x1f8c, (y * factor * 0.99) as i16 as u16);
+    }
+
+    fn polar_to_rect(&mut self) {
+        let angle = self.read_word(0x1f80);
+        let radius = self.read_word(0x1f83) as i16 as i32;
+        let x = radius.wrapping_mul(cos(angle) * 2) >> 16;
+        let y = radius.wrapping_mul(sin(angle) * 2) >> 16;
+        self.write_long(0x1f86, x as u32);
+        self.write_long(0x1f89, (y - (y >> 6)) as u32);
+    }
+
+    fn polar_to_rect2(&mut self) {
+        let angle = self.read_word(0x1f80);
+        let radius = self.read_word(0x1f83) as i32;
+        self.write_long(0x1f86, (radius.wrapping_mul(cos(angle) * 2) >> 8) as u32);
+        self.write_long(0x1f89, (radius.wrapping_mul(sin(angle) * 2) >> 8) as u32);
+    }
+
+    fn pythagorean(&mut self) {
+        let x = self.read_word(0x1f80) as i16 as f64;
+        let y = self.read_word(0x1f83) as i16 as f64;
+        self.write_word(0x1f80, (x * x + y * y).sqrt() as i16 as u16);
+    }
+
+    /// Computes the angle of the vector in `$7F80` and `$7F83` (512 steps per revolution).
+    fn atan(&mut self) {
+        let x = self.read_word(0x1f80) as i16;
+        let y = self.read_word(0x1f83) as i16;
+        let angle = if x == 0 {
+            if y > 0 { 0x080 } else { 0x180 }
+        } else {
+            let mut angle = ((y as f64 / x as f64).atan() / (PI * 2.0) * 512.0) as i16;
+            if x < 0 { angle = angle.wrapping_add(0x100); }
+            angle & 0x1ff
+        };
+        self.write_word(0x1f86, angle as u16);
+    }
+
+    /// Computes the left and right edges of a trapezoid for each scanline (written to `$6800` and
+    /// `$6900`).
+    fn trapezoid(&mut self) {
+        let tan = |angle: u16| {
+            let cos = cos(angle);
+            if cos != 0 { (sin(angle) << 16) / cos } else { i32::min_value() }
+        };
+        let tan1 = tan(self.read_word(0x1f8c));
+        let tan2 = tan(self.read_word(0x1f8f));
+        let offset = self.read_word(0x1f86) as i32 - self.read_word(0x1f80) as i32;
+        let width = self.read_word(0x1f93) as i32;
+        let mut y = self.read_word(0x1f83).wrapping_sub(self.read_word(0x1f89)) as i16;
+
+        for j in 0..225 {
+            let (left, right) = if y >= 0 {
+                let left = ((tan1.wrapping_mul(y as i32) >> 16) + offset) as i16;
+                let right = ((tan2.wrapping_mul(y as i32) >> 16) + offset + width) as i16;
+                let (left, right) = match (left < 0, right < 0) {
+                    (true, true) => (1, 0),
+                    (true, false) => (0, right),
+                    (false, true) => (left, 0),
+                    (false, false) => (left, right),
+                };
+                match (left > 255, right > 255) {
+                    (true, true) => (255, 254),
+                    (true, false) => (255, right),
+                    (false, true) => (left, 255),
+                    (false, false) => (left, right),
+                }
+            } else {
+                (1, 0)
+            };
+            self.ram[0x800 + j] = left as u8;
+            self.ram[0x900 + j] = right as u8;
+            y = y.wrapping_add(1);
+        }
+    }
+}
+
+/// State of the wireframe calculations (these are shared by several commands)
+#[derive(Clone, Default)]
+struct Wireframe {
+    x: i16,
+    y: i16,
+    z: i16,
+    /// Rotation around the X axis, or the end point's X coordinate for `calc_line`
+    x2: i16,
+    /// Rotation around the Y axis, or the end point's Y coordinate for `calc_line`
+    y2: i16,
+    /// Rotation around the Z axis, or the length of the line computed by `calc_line`
+    dist: i16,
+    scale: i16,
+}
+
+impl Wireframe {
+    /// Rotates `(x, y, z)` around the X, Y and Z axes (128 steps per revolution) and returns the
+    /// rotated coordinates.
+    fn rotate(&self, z_offset: f64) -> (f64, f64, f64) {
+        let (x, y, z) = (self.x as f64, self.y as f64, self.z as f64 - z_offset);
+        let angle = |steps: i16| -(steps as f64) * PI * 2.0 / 128.0;
+
+        let a = angle(self.x2);
+        let y2 = y * a.cos() - z * a.sin();
+        let z2 = y * a.sin() + z * a.cos();
+
+        let a = angle(self.y2);
+        let x2 = x * a.cos() + z2 * a.sin();
+        let z = x * -a.sin() + z2 * a.cos();
+
+        let a = angle(self.dist);
+        let x = x2 * a.cos() - y2 * a.sin();
+        let y = x2 * a.sin() + y2 * a.cos();
+        (x, y, z)
+    }
+
+    /// Rotates the point and projects it onto the screen.
+    fn transform(&mut self) {
+        let (x, y, z) = self.rotate(0x95 as f64);
+        let factor = self.scale as f64 / (0x90 as f64 * (z + 0x95 as f64)) * 0x95 as f64;
+        self.x = (x * factor) as i16;
+        self.y = (y * factor) as i16;
+    }
+
+    /// Rotates and scales the point (without perspective).
+    fn transform2(&mut self) {
+        let (x, y, _) = self.rotate(0.0);
+        self.x = (x * self.scale as f64 / 256.0) as i16;
+        self.y = (y * self.scale as f64 / 256.0) as i16;
+    }
+
+    /// Computes the step (8.8 fixed point) and number of steps to draw a line from `(x, y)` to
+    /// `(x2, y2)`.
+    fn calc_line(&mut self) {
+        let dx = self.x2 as i32 - self.x as i32;
+        let dy = self.y2 as i32 - self.y as i32;
+        if dx.abs() > dy.abs() {
+            self.dist = (dx.abs() + 1) as i16;
+            self.y = (256.0 * dy as f64 / dx.abs() as f64) as i16;
+            self.x = if dx < 0 { -256 } else { 256 };
+        } else if dy != 0 {
+            self.dist = (dy.abs() + 1) as i16;
+            self.x = (256.0 * dx as f64 / dy.abs() as f64) as i16;
+            self.y = if dy < 0 { -256 } else { 256 };
+        } else {
+            self.x = dx as i16;
+            self.y = dy as i16;
+            self.dist = 0;
+        }
+    }
+}
+
+/// Sine of `angle` (512 steps per revolution), scaled to `-32767...32767`.
+fn sin(angle: u16) -> i32 {
+    (((angle & 0x1ff) as f64 * PI / 256.0).sin() * 32767.0).round() as i32
+}
+
+fn cos(angle: u16) -> i32 {
+    (((angle & 0x1ff) as f64 * PI / 256.0).cos() * 32767.0).round() as i32
+}
+
+/// Multiplies two signed 24-bit numbers, returning the low and high 24 bits of the result.
+fn mul(a: u32, b: u32) -> (u32, u32) {
+    let sign_extend = |x: u32| ((x << 8) as i32 >> 8) as i64;
+    let result = sign_extend(a) * sign_extend(b);
+    (result as u32 & 0xffffff, (result >> 24) as u32 & 0xffffff)
+}
+
+/// Converts a (LoROM) CPU address to an offset into the ROM.
+fn rom_offset(addr: u32) -> usize {
+    ((addr >> 16) as usize & 0x7f) * 0x8000 + (addr as usize & 0x7fff)
+}
+
+fn rom_byte(rom: &[u8], offset: usize) -> u8 {
+    rom[offset % rom.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes little-endian `bytes` to the registers at `$7F00 + offset`.
+    fn write(cx4: &mut Cx4, offset: u16, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            assert!(cx4.poke(0x00, 0x7f00 + offset + i as u16, byte));
+        }
+    }
+
+    fn command(cx4: &mut Cx4, rom: &[u8], cmd: u8) {
+        assert!(cx4.store(rom, 0x00, 0x7f4f, cmd));
+    }
+
+    fn word(cx4: &Cx4, offset: usize) -> i16 { cx4.read_word(0x1f00 + offset) as i16 }
+
+    fn long(cx4: &Cx4, offset: usize) -> i32 { (cx4.read_long(0x1f00 + offset) << 8) as i32 >> 8 }
+
+    #[test]
+    fn trig() {
+        let rom = [0; 0x8000];
+        let mut cx4 = Cx4::new();
+
+        // Polar to rectangular coordinates (512 steps per revolution)
+        write(&mut cx4, 0x80, &[0x00, 0x00, 0x00, 100, 0]);
+        command(&mut cx4, &rom, 0x10);
+        assert_eq!((long(&cx4, 0x86), long(&cx4, 0x89)), (99, 0));
+        write(&mut cx4, 0x80, &[0x80, 0x00, 0x00, 100, 0]);
+        command(&mut cx4, &rom, 0x10);
+        assert_eq!((long(&cx4, 0x86), long(&cx4, 0x89)), (0, 98));
+        write(&mut cx4, 0x80, &[0x00, 0x01, 0x00, 0x00, 0x01]);
+        command(&mut cx4, &rom, 0x13);
+        assert_eq!((long(&cx4, 0x86), long(&cx4, 0x89)), (-0xfffe, 0));
+
+        // Angle of a vector
+        let atan = |cx4: &mut Cx4, x: i16, y: i16| {
+            write(cx4, 0x80, &[x as u8, (x >> 8) as u8, 0, y as u8, (y >> 8) as u8]);
+            command(cx4, &rom, 0x1f);
+            word(cx4, 0x86)
+        };
+        assert_eq!(atan(&mut cx4, 100, 100), 0x040);
+        assert_eq!(atan(&mut cx4, -100, 0), 0x100);
+        assert_eq!(atan(&mut cx4, 100, -100), 0x1c0);
+        assert_eq!(atan(&mut cx4, 0, 5), 0x080);
+        assert_eq!(atan(&mut cx4, 0, -5), 0x180);
+
+        write(&mut cx4, 0x80, &[3, 0, 0, 4, 0]);
+        command(&mut cx4, &rom, 0x15);
+        assert_eq!(word(&cx4, 0x80), 5);
+
+        // Signed 24-bit multiplication
+        write(&mut cx4, 0x80, &[0xfe, 0xff, 0xff, 0x03, 0x00, 0x00]);
+        command(&mut cx4, &rom, 0x25);
+        assert_eq!((cx4.reg(0), cx4.reg(1)), (0xfffffa, 0xffffff));
+    }
+
+    #[test]
+    fn transform_coords() {
+        let rom = [0; 0x8000];
+        let mut cx4 = Cx4::new();
+        // Point (10, -20, 5), scale 1.0
+        let point = [0, 10, 0, 0, 0xec, 0xff, 0, 5, 0];
+        write(&mut cx4, 0x80, &point);
+        write(&mut cx4, 0x90, &[0x00, 0x01]);
+        command(&mut cx4, &rom, 0x2d);
+        assert_eq!((word(&cx4, 0x80), word(&cx4, 0x83)), (10, -20));
+
+        // Half a revolution around the Z axis (128 steps per revolution), scale 0.5
+        write(&mut cx4, 0x80, &point);
+        write(&mut cx4, 0x8b, &[64]);
+        write(&mut cx4, 0x90, &[0x80, 0x00]);
+        command(&mut cx4, &rom, 0x2d);
+        assert_eq!((word(&cx4, 0x80), word(&cx4, 0x83)), (-5, 10));
+    }
+
+    #[test]
+    fn calc_line() {
+        let line = |x, y, x2, y2| {
+            let mut wf = Wireframe { x: x, y: y, x2: x2, y2: y2, ..Wireframe::default() };
+            wf.calc_line();
+            (wf.x, wf.y, wf.dist)
+        };
+        assert_eq!(line(0, 0, 10, 5), (256, 128, 11));
+        assert_eq!(line(10, 0, 0, 5), (-256, 128, 11));
+        assert_eq!(line(0, 0, 2, -4), (128, -256, 5));
+        assert_eq!(line(3, 3, 3, 3), (0, 0, 0));
+    }
+
+    #[test]
+    fn draw_wireframe() {
+        // Two points at `$00:8000`, (0, 0, 0) and (8, 0, 0), and a line between them at
+        // `$00:8100`
+        let mut rom = vec![0; 0x8000];
+        rom[6..12].copy_from_slice(&[0x00, 0x08, 0x00, 0x00, 0x00, 0x00]);
+        rom[0x100..0x105].copy_from_slice(&[0x80, 0x00, 0x80, 0x06, 0x03]);
+
+        let mut cx4 = Cx4::new();
+        assert!(cx4.poke(0x00, 0x6295, 1));
+        assert!(cx4.poke(0x00, 0x6300, 0xff));
+        // Line list address, no rotation, scale 255/256
+        write(&mut cx4, 0x80, &[0x00, 0x81, 0x00]);
+        write(&mut cx4, 0x90, &[0xff]);
+        command(&mut cx4, &rom, 0x01);
+
+        // The tiles were cleared, then a line from (48, 48) to (55, 48) was drawn in color 3
+        assert_eq!(cx4.peek(0x00, 0x6300), Some(0x00));
+        let tile = 0x6300 + 6 * 0xc0 + 6 * 0x10;
+        assert_eq!(cx4.peek(0x00, tile), Some(0xff));
+        assert_eq!(cx4.peek(0x00, tile + 1), Some(0xff));
+        assert_eq!(cx4.peek(0x00, tile + 2), Some(0x00));
+        assert_eq!(cx4.peek(0x00, tile + 0x10), Some(0x00));
+    }
+
+    #[test]
+    fn test_command() {
+        let rom = [0; 0x8000];
+        let mut cx4 = Cx4::new();
+        write(&mut cx4, 0x4d, &[0x0e]);
+        command(&mut cx4, &rom, 0x1c);
+        assert_eq!(cx4.peek(0x80, 0x7f80), Some(0x07));
+        assert_eq!(cx4.peek(0x80, 0x7f5e), Some(0));
+        assert_eq!(cx4.peek(0x40, 0x7f80), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! Cartridge coprocessors
+//!
+//! Some cartridges contain additional chips that are accessed by the SNES CPU through the
+//! cartridge bus. The coprocessor is selected by the chipset byte in the ROM header and is owned by
+//! the `Rom`, which forwards memory accesses and lets it run alongside the CPU.
+
+pub mod cx4;
+pub mod necdsp;
+pub mod obc1;
+pub mod rtc;
+pub mod sa1;
+pub mod sdd1;
+pub mod spc7110;
+pub mod srtc;
+pub mod superfx;
+
+use self::cx4::Cx4;
+use self::necdsp::{Board, Chip, NecDsp};
+use self::obc1::Obc1;
+use self::rtc::{Clock, DateTime};
+use self::sa1::Sa1;
+use self::sdd1::Sdd1;
+use self::spc7110::Spc7110;
+use self::srtc::Srtc;
+use self::superfx::SuperFx;
+
+use diagnostics::Diagnostics;
+use rom::RomType;
+
+use libsavestate::SaveState;
+
+use std::io::{self, Read, Write};
+
+/// Coprocessor names accepted by `Coprocessor::from_name` (used by the game database)
+pub const NAMES: &'static [&'static str] = &[
+    "none", "cx4", "dsp1", "dsp2", "dsp3", "dsp4", "st010", "st011", "obc1", "sa1", "sdd1",
+    "spc7110", "spc7110-rtc", "srtc", "superfx",
+];
+
+/// The coprocessor on a cartridge (if any)
+#[derive(Clone)]
+pub enum Coprocessor {
+    None,
+    Cx4(Box<Cx4>),
+    NecDsp(Box<NecDsp>),
+    Obc1(Box<Obc1>),
+    Sa1(Box<Sa1>),
+    Sdd1(Box<Sdd1>),
+    Spc7110(Box<Spc7110>),
+    Srtc(Box<Srtc>),
+    SuperFx(Box<SuperFx>),
+}
+
+impl Coprocessor {
+    /// Creates the coprocessor indicated by the ROM header's chipset byte (`$FFD6`). The other
+    /// arguments are header fields needed to tell apart the DSP variants (see `NecDsp::detect`).
+    pub fn detect(map_mode: u8, chipset: u8, maker: u8, subtype: u8, rom_size: usize) -> Self {
+        // The low nibble is `3`-`6` if there's a coprocessor (`9` for the SPC7110 with RTC, `A` for
+        // GSU-2 carts with a battery), the high nibble selects which one
+        let has_coprocessor = match chipset & 0x0f {
+            3 ... 6 | 9 | 0xa => true,
+            _ => false,
+        };
+        if !has_coprocessor { return Coprocessor::None; }
+
+        if let Some((chip, board)) = NecDsp::detect(map_mode, chipset, maker, subtype, rom_size) {
+            info!("cartridge contains a {} ({:?} board)", chip.name(), board);
+            return Coprocessor::NecDsp(Box::new(NecDsp::new(chip, board)));
+        }
+
+        match chipset >> 4 {
+            1 => {
+                info!("cartridge contains a SuperFX (GSU)");
+                Coprocessor::SuperFx(Box::new(SuperFx::new()))
+            }
+            2 => {
+                info!("cartridge contains an OBC-1");
+                Coprocessor::Obc1(Box::new(Obc1::new()))
+            }
+            3 => {
+                info!("cartridge contains an SA-1");
+                Coprocessor::Sa1(Box::new(Sa1::new()))
+            }
+            4 => {
+                info!("cartridge contains an S-DD1");
+                Coprocessor::Sdd1(Box::new(Sdd1::new()))
+            }
+            5 => {
+                info!("cartridge contains an S-RTC");
+                Coprocessor::Srtc(Box::new(Srtc::new()))
+            }
+            0xf if chipset == 0xf3 => {
+                info!("cartridge contains a Cx4");
+                Coprocessor::Cx4(Box::new(Cx4::new()))
+            }
+            // Chipset $F5 with subtype $02 is the ST018, which isn't supported
+            0xf if (chipset == 0xf5 || chipset == 0xf9) && subtype == 0 => {
+                let rtc = chipset == 0xf9;
+                info!("cartridge contains an SPC7110{}", if rtc { " and an RTC-4513" } else { "" });
+                Coprocessor::Spc7110(Box::new(Spc7110::new(rtc)))
+            }
+            n => {
+                warn!("unimplemented coprocessor type ${:X} (chipset ${:02X})", n, chipset);
+                Coprocessor::None
+            }
+        }
+    }
+
+    /// Creates a coprocessor by name (one of `NAMES`), ignoring the ROM header. The DSP board is
+    /// chosen based on the ROM's mapping and size.
+    pub fn from_name(name: &str, rom_type: RomType, rom_size: usize) -> Option<Self> {
+        let dsp = |chip| {
+            let board = match (chip, rom_type) {
+                (Chip::Dsp1, RomType::HiRom) | (Chip::Dsp1, RomType::ExHiRom) => Board::HiRom,
+                (Chip::Dsp1, _) if rom_size > 0x100000 => Board::LoRom2Mb,
+                (Chip::Dsp4, _) => Board::Dsp4,
+                (Chip::St010, _) | (Chip::St011, _) => Board::St01x,
+                _ => Board::LoRom1Mb,
+            };
+            Coprocessor::NecDsp(Box::new(NecDsp::new(chip, board)))
+        };
+
+        Some(match name {
+            "none" => Coprocessor::None,
+            "cx4" => Coprocessor::Cx4(Box::new(Cx4::new())),
+            "dsp1" => dsp(Chip::Dsp1),
+            "dsp2" => dsp(Chip::Dsp2),
+            "dsp3" => dsp(Chip::Dsp3),
+            "dsp4" => dsp(Chip::Dsp4),
+            "st010" => dsp(Chip::St010),
+            "st011" => dsp(Chip::St011),
+            "obc1" => Coprocessor::Obc1(Box::new(Obc1::new())),
+            "sa1" => Coprocessor::Sa1(Box::new(Sa1::new())),
+            "sdd1" => Coprocessor::Sdd1(Box::new(Sdd1::new())),
+            "spc7110" => Coprocessor::Spc7110(Box::new(Spc7110::new(false))),
+            "spc7110-rtc" => Coprocessor::Spc7110(Box::new(Spc7110::new(true))),
+            "srtc" => Coprocessor::Srtc(Box::new(Srtc::new())),
+            "superfx" => Coprocessor::SuperFx(Box::new(SuperFx::new())),
+            _ => return None,
+        })
+    }
+
+    /// Returns the minimum amount of cartridge RAM the coprocessor needs. Some headers specify
+    /// less RAM than the cartridge actually contains.
+    pub fn min_ram_size(&self) -> usize {
+        match *self {
+            // Early SuperFX games don't specify their RAM size in the header
+            Coprocessor::SuperFx(_) => 64 * 1024,
+            // The OBC-1 registers are in the RAM
+            Coprocessor::Obc1(_) => 8 * 1024,
+            _ => 0,
+        }
+    }
+
+    /// Returns the name of the coprocessor, or `None` if there is none.
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match *self {
+            Coprocessor::None => return None,
+            Coprocessor::Cx4(_) => "Cx4",
+            Coprocessor::NecDsp(ref dsp) => dsp.chip().name(),
+            Coprocessor::Obc1(_) => "OBC-1",
+            Coprocessor::Sa1(_) => "SA-1",
+            Coprocessor::Sdd1(_) => "S-DD1",
+            Coprocessor::Spc7110(ref spc) => if spc.clock().is_some() {
+                "SPC7110 + RTC-4513"
+            } else {
+                "SPC7110"
+            },
+            Coprocessor::Srtc(_) => "S-RTC",
+            Coprocessor::SuperFx(_) => "SuperFX",
+        })
+    }
+
+    /// Returns the real-time clock on the cartridge (if any).
+    pub fn clock(&self) -> Option<&Clock> {
+        match *self {
+            Coprocessor::Spc7110(ref spc) => spc.clock(),
+            Coprocessor::Srtc(ref srtc) => Some(srtc.clock()),
+            _ => None,
+        }
+    }
+
+    pub fn clock_mut(&mut self) -> Option<&mut Clock> {
+        match *self {
+            Coprocessor::Spc7110(ref mut spc) => spc.clock_mut(),
+            Coprocessor::Srtc(ref mut srtc) => Some(srtc.clock_mut()),
+            _ => None,
+        }
+    }
+
+    /// Sets the real-time clock on the cartridge (if any) to `time`.
+    pub fn set_time(&mut self, time: DateTime) {
+        if let Some(clock) = self.clock_mut() {
+            clock.set(time);
+        }
+    }
+
+    /// Returns the diagnostics the coprocessor recorded since they were last collected (if it
+    /// records any).
+    pub fn diagnostics_mut(&mut self) -> Option<&mut Diagnostics> {
+        match *self {
+            Coprocessor::None | Coprocessor::Obc1(_) => None,
+            Coprocessor::Cx4(ref mut cx4) => Some(&mut cx4.diagnostics),
+            Coprocessor::NecDsp(ref mut dsp) => Some(dsp.diagnostics_mut()),
+            Coprocessor::Sa1(ref mut sa1) => Some(sa1.diagnostics_mut()),
+            Coprocessor::Sdd1(ref mut sdd1) => Some(&mut sdd1.diagnostics),
+            Coprocessor::Spc7110(ref mut spc) => Some(&mut spc.diagnostics),
+            Coprocessor::Srtc(ref mut srtc) => Some(&mut srtc.diagnostics),
+            Coprocessor::SuperFx(ref mut fx) => Some(&mut fx.diagnostics),
+        }
+    }
+}
+
+// The coprocessor can't change when restoring a save state (it's determined by the ROM), so we
+// don't need to store which one is present. The OBC-1 keeps all its state in the cartridge RAM.
+impl SaveState for Coprocessor {
+    fn save_state<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        match *self {
+            Coprocessor::None | Coprocessor::Obc1(_) => Ok(()),
+            Coprocessor::Cx4(ref cx4) => cx4.save_state(w),
+            Coprocessor::NecDsp(ref dsp) => dsp.save_state(w),
+            Coprocessor::Sa1(ref sa1) => sa1.save_state(w),
+            Coprocessor::Sdd1(ref sdd1) => sdd1.save_state(w),
+            Coprocessor::Spc7110(ref spc) => spc.save_state(w),
+            Coprocessor::Srtc(ref srtc) => srtc.save_state(w),
+            Coprocessor::SuperFx(ref fx) => fx.save_state(w),
+        }
+    }
+
+    fn restore_state<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<()> {
+        match *self {
+            Coprocessor::None | Coprocessor::Obc1(_) => Ok(()),
+            Coprocessor::Cx4(ref mut cx4) => cx4.restore_state(r),
+            Coprocessor::NecDsp(ref mut dsp) => dsp.restore_state(r),
+            Coprocessor::Sa1(ref mut sa1) => sa1.restore_state(r),
+            Coprocessor::Sdd1(ref mut sdd1) => sdd1.restore_state(r),
+            Coprocessor::Spc7110(ref mut spc) => spc.restore_state(r),
+            Coprocessor::Srtc(ref mut srtc) => srtc.restore_state(r),
+            Coprocessor::SuperFx(ref mut fx) => fx.restore_state(r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_spc7110() {
+        let is_spc7110 = |chipset, subtype| {
+            match Coprocessor::detect(0x3a, chipset, 0x33, subtype, 0x500000) {
+                Coprocessor::Spc7110(_) => true,
+                _ => false,
+            }
+        };
+        assert!(is_spc7110(0xf5, 0x00));
+        assert!(is_spc7110(0xf9, 0x00));
+        // ST018
+        assert!(!is_spc7110(0xf5, 0x02));
+    }
+}
@@ -0,0 +1,494 @@
+//! High-level emulation of the DSP-1 command set
+//!
+//! Used when no DSP-1 firmware is available. Commands are computed with floating point math, so
+//! the results can differ slightly from the real chip (which uses lookup tables and 16-bit fixed
+//! point arithmetic). The memory dump command can't be emulated without the data ROM and returns
+//! zeros.
+//!
+//! The SNES CPU writes a command byte followed by its 16-bit parameters (low byte first) to the
+//! data register, then reads the 16-bit results.
+
+use diagnostics::Diagnostics;
+
+use std::f64::consts::PI;
+
+/// Converts a DSP-1 angle (`0x10000` is a full turn) to radians.
+fn radians(angle: i16) -> f64 { angle as f64 * PI / 32768.0 }
+
+/// Converts an angle in radians back to DSP-1 format.
+fn angle(radians: f64) -> i16 { (radians * 32768.0 / PI).round() as i64 as i16 }
+
+/// Converts a floating point value to a saturated 16-bit result.
+fn clamp(value: f64) -> i16 {
+    if value.is_nan() { return 0; }
+    let value = value.round();
+    if value > 32767.0 { 32767 } else if value < -32768.0 { -32768 } else { value as i16 }
+}
+
+/// Multiplies two 1.15 fixed point values.
+fn mul(a: i16, b: i16) -> i16 { ((a as i32 * b as i32) >> 15) as i16 }
+
+/// Returns `sin(angle)` and `cos(angle)` as 1.15 fixed point values.
+fn sin_cos(angle: i16) -> (i16, i16) {
+    let r = radians(angle);
+    (clamp(r.sin() * 32767.0), clamp(r.cos() * 32767.0))
+}
+
+/// Returns the number of parameters of a command, or `None` if the command doesn't exist.
+fn param_count(command: u8) -> Option<usize> {
+    Some(match command & 0x3f {
+        0x00 | 0x20 => 2,                             // Multiply
+        0x10 | 0x30 => 2,                             // Inverse
+        0x04 | 0x24 => 2,                             // Triangle
+        0x08 => 3,                                    // Radius
+        0x18 | 0x38 => 4,                             // Range
+        0x28 => 3,                                    // Distance
+        0x0c | 0x2c => 3,                             // Rotate
+        0x1c | 0x3c => 6,                             // Polar
+        0x02 | 0x12 | 0x22 | 0x32 => 7,               // Parameter
+        0x0a | 0x1a | 0x2a | 0x3a => 1,               // Raster
+        0x06 | 0x16 | 0x26 | 0x36 => 3,               // Project
+        0x0e | 0x1e | 0x2e | 0x3e => 2,               // Target
+        0x01 | 0x05 | 0x31 | 0x35 |
+        0x11 | 0x15 | 0x21 | 0x25 => 4,               // Attitude A/B/C
+        0x0d | 0x09 | 0x39 | 0x3d |
+        0x19 | 0x1d | 0x29 | 0x2d => 3,               // Objective A/B/C
+        0x03 | 0x33 | 0x13 | 0x23 => 3,               // Subjective A/B/C
+        0x0b | 0x3b | 0x1b | 0x2b => 3,               // Scalar A/B/C
+        0x14 | 0x34 => 6,                             // Gyrate
+        0x07 | 0x0f | 0x27 | 0x2f => 1,               // Memory test / size
+        0x17 | 0x37 | 0x3f | 0x1f => 1,               // Memory dump
+        _ => return None,
+    })
+}
+
+/// Projection state set up by the Parameter command
+#[derive(Clone, Copy, Default)]
+struct Projection {
+    /// Viewpoint (center of projection)
+    eye: [f64; 3],
+    /// Distance between viewpoint and screen
+    les: f64,
+    sin_aas: f64,
+    cos_aas: f64,
+    /// Sine and cosine of the (clipped) zenith angle
+    sin_azs: f64,
+    cos_azs: f64,
+}
+
+impl_save_state!(Projection { eye, les, sin_aas, cos_aas, sin_azs, cos_azs } ignore {});
+
+impl Projection {
+    /// Normal vector of the screen plane (pointing from the screen to the viewpoint)
+    fn normal(&self) -> [f64; 3] {
+        [-self.sin_azs * self.sin_aas, self.sin_azs * self.cos_aas, self.cos_azs]
+    }
+
+    /// Horizontal screen axis
+    fn horizontal(&self) -> [f64; 3] { [self.cos_aas, self.sin_aas, 0.0] }
+
+    /// Vertical screen axis (pointing down)
+    fn vertical(&self) -> [f64; 3] {
+        [-self.sin_aas * self.cos_azs, self.cos_aas * self.cos_azs, -self.sin_azs]
+    }
+
+    /// Returns the distance from the viewpoint to the ground along the ray through screen line
+    /// `v`, in units of the ray's screen distance.
+    fn ground_scale(&self, v: f64) -> f64 {
+        self.eye[2] / (self.les * self.cos_azs + v * self.sin_azs)
+    }
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 { a[0] * b[0] + a[1] * b[1] + a[2] * b[2] }
+
+/// DSP-1 HLE state
+#[derive(Clone)]
+pub struct Dsp1 {
+    /// The command being processed, `None` when waiting for a command
+    command: Option<u8>,
+    /// Received parameters
+    params: Vec<i16>,
+    /// Pending results (read from the front)
+    output: Vec<i16>,
+    /// Set after the low byte of a 16-bit word was transferred
+    high_byte: bool,
+    /// Low byte of a parameter being written
+    low: u8,
+
+    /// Attitude matrices A, B and C
+    matrices: [[[i16; 3]; 3]; 3],
+    projection: Projection,
+    /// Next raster line to output
+    raster_line: i16,
+
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+impl_save_state!(Dsp1 {
+    command, params, output, high_byte, low, matrices, projection, raster_line
+} ignore { diagnostics });
+
+impl Dsp1 {
+    pub fn new() -> Self {
+        Dsp1 {
+            command: None,
+            params: Vec::new(),
+            output: Vec::new(),
+            high_byte: false,
+            low: 0,
+            matrices: [[[0; 3]; 3]; 3],
+            projection: Projection::default(),
+            raster_line: 0,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// Reads the high byte of the status register. The HLE is always ready.
+    pub fn read_sr(&self) -> u8 { 0x80 }
+
+    pub fn read_dr(&mut self) -> u8 {
+        let word = match self.output.first() {
+            Some(&word) => word as u16,
+            None => return 0xff,
+        };
+        if !self.high_byte {
+            self.high_byte = true;
+            return word as u8;
+        }
+
+        self.high_byte = false;
+        self.output.remove(0);
+        if self.output.is_empty() {
+            match self.command {
+                // Raster continues with the next line until another command is written
+                Some(c) if c & 0x0f == 0x0a => {
+                    let line = self.raster_line;
+                    self.raster(line);
+                }
+                _ => self.command = None,
+            }
+        }
+        (word >> 8) as u8
+    }
+
+    pub fn write_dr(&mut self, value: u8) {
+        let command = match self.command {
+            // Writing while results are pending starts a new command
+            Some(command) if self.output.is_empty() => command,
+            _ => {
+                self.start(value);
+                return;
+            }
+        };
+
+        if !self.high_byte {
+            self.high_byte = true;
+            self.low = value;
+            return;
+        }
+        self.high_byte = false;
+        self.params.push(((value as u16) << 8 | self.low as u16) as i16);
+
+        if Some(self.params.len()) == param_count(command) {
+            self.execute(command);
+            if self.output.is_empty() {
+                self.command = None;
+            }
+        }
+    }
+
+    fn start(&mut self, command: u8) {
+        self.params.clear();
+        self.output.clear();
+        self.high_byte = false;
+        match param_count(command) {
+            Some(_) => self.command = Some(command),
+            None => {
+                diag!(self.diagnostics, "DSP-1 HLE: unknown command ${:02X}", command);
+                self.command = None;
+            }
+        }
+    }
+
+    fn execute(&mut self, command: u8) {
+        let p = self.params.clone();
+        let out = match command & 0x3f {
+            0x00 => vec![mul(p[0], p[1])],
+            0x20 => vec![mul(p[0], p[1]).wrapping_add(1)],
+            0x10 | 0x30 => self.inverse(p[0], p[1]),
+            0x04 | 0x24 => {
+                let (sin, cos) = sin_cos(p[0]);
+                vec![mul(sin, p[1]), mul(cos, p[1])]
+            }
+            0x08 => {
+                let size = (p[0] as i64 * p[0] as i64 + p[1] as i64 * p[1] as i64 +
+                            p[2] as i64 * p[2] as i64) << 1;
+                vec![size as i16, (size >> 16) as i16]
+            }
+            0x18 | 0x38 => {
+                let d = (p[0] as i64 * p[0] as i64 + p[1] as i64 * p[1] as i64 +
+                         p[2] as i64 * p[2] as i64 - p[3] as i64 * p[3] as i64) >> 15;
+                let d = if command & 0x20 != 0 { d + 1 } else { d };
+                vec![d as i16]
+            }
+            0x28 => {
+                let (x, y, z) = (p[0] as f64, p[1] as f64, p[2] as f64);
+                vec![clamp((x * x + y * y + z * z).sqrt())]
+            }
+            0x0c | 0x2c => {
+                let (sin, cos) = sin_cos(p[0]);
+                vec![
+                    mul(p[2], sin).wrapping_add(mul(p[1], cos)),
+                    mul(p[2], cos).wrapping_sub(mul(p[1], sin)),
+                ]
+            }
+            0x1c | 0x3c => self.polar(&p),
+            0x02 | 0x12 | 0x22 | 0x32 => self.parameter(&p),
+            0x0a | 0x1a | 0x2a | 0x3a => {
+                self.raster(p[0]);
+                return;
+            }
+            0x06 | 0x16 | 0x26 | 0x36 => self.project(&p),
+            0x0e | 0x1e | 0x2e | 0x3e => self.target(p[0], p[1]),
+            0x01 | 0x05 | 0x31 | 0x35 => { self.attitude(0, &p); vec![] }
+            0x11 | 0x15 => { self.attitude(1, &p); vec![] }
+            0x21 | 0x25 => { self.attitude(2, &p); vec![] }
+            0x0d | 0x09 | 0x39 | 0x3d => self.objective(0, &p),
+            0x19 | 0x1d => self.objective(1, &p),
+            0x29 | 0x2d => self.objective(2, &p),
+            0x03 | 0x33 => self.subjective(0, &p),
+            0x13 => self.subjective(1, &p),
+            0x23 => self.subjective(2, &p),
+            0x0b | 0x3b => vec![self.objective(0, &p)[0]],
+            0x1b => vec![self.objective(1, &p)[0]],
+            0x2b => vec![self.objective(2, &p)[0]],
+            0x14 | 0x34 => self.gyrate(&p),
+            // Memory test: no errors
+            0x07 | 0x0f => vec![0],
+            // Memory size
+            0x27 | 0x2f => vec![0x0100],
+            0x17 | 0x37 | 0x3f | 0x1f => {
+                diag!(self.diagnostics, "DSP-1 HLE: data ROM dump requires the DSP-1 firmware");
+                vec![0; 1024]
+            }
+            _ => unreachable!(),
+        };
+        self.output = out;
+    }
+
+    /// Computes `1 / (coefficient * 2^exponent)` as a normalized coefficient and exponent.
+    fn inverse(&self, coefficient: i16, exponent: i16) -> Vec<i16> {
+        if coefficient == 0 {
+            return vec![0x7fff, 0x002f];
+        }
+        let value = coefficient as f64 / 32768.0 * (exponent as f64).exp2();
+        let inverse = 1.0 / value;
+        // Normalize to a coefficient in `[0.5, 1)`
+        let mut e = inverse.abs().log2().floor() as i32 + 1;
+        let mut c = inverse / (e as f64).exp2() * 32768.0;
+        if c.abs() >= 32768.0 {
+            e += 1;
+            c /= 2.0;
+        }
+        vec![clamp(c), e as i16]
+    }
+
+    fn polar(&self, p: &[i16]) -> Vec<i16> {
+        let (az, ay, ax) = (radians(p[0]), radians(p[1]), radians(p[2]));
+        let (x, y, z) = (p[3] as f64, p[4] as f64, p[5] as f64);
+        // Rotate around Z, Y and X
+        let (x, y) = (x * az.cos() + y * az.sin(), y * az.cos() - x * az.sin());
+        let (x, z) = (x * ay.cos() - z * ay.sin(), x * ay.sin() + z * ay.cos());
+        let (y, z) = (y * ax.cos() + z * ax.sin(), z * ax.cos() - y * ax.sin());
+        vec![clamp(x), clamp(y), clamp(z)]
+    }
+
+    fn attitude(&mut self, index: usize, p: &[i16]) {
+        let m = p[0] >> 1;
+        let (sin_az, cos_az) = sin_cos(p[1]);
+        let (sin_ay, cos_ay) = sin_cos(p[2]);
+        let (sin_ax, cos_ax) = sin_cos(p[3]);
+
+        let m_cos_az = mul(m, cos_az);
+        let m_sin_az = mul(m, sin_az);
+        self.matrices[index] = [
+            [
+                mul(m_cos_az, cos_ay),
+                mul(m_sin_az, cos_ay).wrapping_neg(),
+                mul(m, sin_ay),
+            ],
+            [
+                mul(m_sin_az, cos_ax).wrapping_add(mul(mul(m_cos_az, sin_ax), sin_ay)),
+                mul(m_cos_az, cos_ax).wrapping_sub(mul(mul(m_sin_az, sin_ax), sin_ay)),
+                mul(mul(m, sin_ax), cos_ay).wrapping_neg(),
+            ],
+            [
+                mul(m_sin_az, sin_ax).wrapping_sub(mul(mul(m_cos_az, cos_ax), sin_ay)),
+                mul(m_cos_az, sin_ax).wrapping_add(mul(mul(m_sin_az, cos_ax), sin_ay)),
+                mul(mul(m, cos_ax), cos_ay),
+            ],
+        ];
+    }
+
+    /// Transforms a vector by an attitude matrix.
+    fn objective(&self, index: usize, p: &[i16]) -> Vec<i16> {
+        let m = &self.matrices[index];
+        (0..3).map(|row| {
+            ((p[0] as i32 * m[row][0] as i32 + p[1] as i32 * m[row][1] as i32 +
+              p[2] as i32 * m[row][2] as i32) >> 15) as i16
+        }).collect()
+    }
+
+    /// Transforms a vector by the transposed attitude matrix.
+    fn subjective(&self, index: usize, p: &[i16]) -> Vec<i16> {
+        let m = &self.matrices[index];
+        (0..3).map(|col| {
+            ((p[0] as i32 * m[0][col] as i32 + p[1] as i32 * m[1][col] as i32 +
+              p[2] as i32 * m[2][col] as i32) >> 15) as i16
+        }).collect()
+    }
+
+    fn gyrate(&self, p: &[i16]) -> Vec<i16> {
+        let (zr, xr, yr) = (radians(p[0]), radians(p[1]), radians(p[2]));
+        let (u, f, l) = (radians(p[3]), radians(p[4]), radians(p[5]));
+        let zrr = zr + (u * yr.cos() - f * yr.sin()) / xr.cos();
+        let xrr = xr + u * yr.sin() + f * yr.cos();
+        let yrr = yr - xr.tan() * (u * yr.cos() + f * yr.sin()) + l;
+        vec![angle(zrr), angle(xrr), angle(yrr)]
+    }
+
+    fn parameter(&mut self, p: &[i16]) -> Vec<i16> {
+        let (fx, fy, fz) = (p[0] as f64, p[1] as f64, p[2] as f64);
+        let (lfe, les) = (p[3] as f64, p[4] as f64);
+        let aas = radians(p[5]);
+        // Keep the zenith angle away from the horizon to avoid dividing by zero
+        let max_azs = radians(0x3f00);
+        let azs = radians(p[6]).max(-max_azs).min(max_azs);
+
+        let mut proj = Projection {
+            eye: [0.0; 3],
+            les: les,
+            sin_aas: aas.sin(),
+            cos_aas: aas.cos(),
+            sin_azs: azs.sin(),
+            cos_azs: azs.cos(),
+        };
+        let n = proj.normal();
+        proj.eye = [fx + lfe * n[0], fy + lfe * n[1], fz + lfe * n[2]];
+        self.projection = proj;
+
+        // Ground point at the center of the screen
+        let t = proj.eye[2] / n[2];
+        let cx = proj.eye[0] - t * n[0];
+        let cy = proj.eye[1] - t * n[1];
+
+        // Raster line of the horizon
+        let vva = -les * proj.cos_azs / proj.sin_azs;
+        vec![0, clamp(vva), clamp(cx), clamp(cy)]
+    }
+
+    /// Outputs the Mode 7 matrix parameters for screen line `line`.
+    fn raster(&mut self, line: i16) {
+        let proj = self.projection;
+        let scale = proj.ground_scale(line as f64);
+        let vscale = scale / proj.cos_azs;
+        self.output = vec![
+            clamp(256.0 * scale * proj.cos_aas),
+            clamp(256.0 * vscale * -proj.sin_aas),
+            clamp(256.0 * scale * proj.sin_aas),
+            clamp(256.0 * vscale * proj.cos_aas),
+        ];
+        self.raster_line = line.wrapping_add(1);
+    }
+
+    fn project(&self, p: &[i16]) -> Vec<i16> {
+        let proj = &self.projection;
+        let d = [
+            p[0] as f64 - proj.eye[0],
+            p[1] as f64 - proj.eye[1],
+            p[2] as f64 - proj.eye[2],
+        ];
+        let depth = -dot(d, proj.normal());
+        if depth <= 0.0 {
+            return vec![0, 0, 0];
+        }
+        let h = proj.les * dot(d, proj.horizontal()) / depth;
+        let v = proj.les * dot(d, proj.vertical()) / depth;
+        let m = 256.0 * proj.les / depth;
+        vec![clamp(h), clamp(v), clamp(m)]
+    }
+
+    fn target(&self, h: i16, v: i16) -> Vec<i16> {
+        let proj = &self.projection;
+        let (n, hv, vv) = (proj.normal(), proj.horizontal(), proj.vertical());
+        let t = proj.ground_scale(v as f64);
+        let ray: Vec<f64> = (0..3).map(|i| {
+            -n[i] * proj.les + hv[i] * h as f64 + vv[i] * v as f64
+        }).collect();
+        vec![clamp(proj.eye[0] + t * ray[0]), clamp(proj.eye[1] + t * ray[1])]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dsp1;
+
+    /// Executes a command and returns its results.
+    fn command(dsp: &mut Dsp1, command: u8, params: &[i16], results: usize) -> Vec<i16> {
+        dsp.write_dr(command);
+        for &param in params {
+            dsp.write_dr(param as u8);
+            dsp.write_dr((param as u16 >> 8) as u8);
+        }
+        (0..results).map(|_| {
+            let lo = dsp.read_dr() as u16;
+            let hi = dsp.read_dr() as u16;
+            (hi << 8 | lo) as i16
+        }).collect()
+    }
+
+    #[test]
+    fn multiply() {
+        let mut dsp = Dsp1::new();
+        assert_eq!(command(&mut dsp, 0x00, &[0x4000, 0x4000], 1), [0x2000]);
+        assert_eq!(command(&mut dsp, 0x00, &[-0x8000, 0x4000], 1), [-0x4000]);
+        assert_eq!(command(&mut dsp, 0x00, &[0x1234, 0], 1), [0]);
+        // $20 rounds up
+        assert_eq!(command(&mut dsp, 0x20, &[0x4000, 0x4000], 1), [0x2001]);
+        // No more results
+        assert_eq!(dsp.read_dr(), 0xff);
+    }
+
+    #[test]
+    fn inverse() {
+        let mut dsp = Dsp1::new();
+        // 1 / 0.5 = 0.5 * 2^2
+        assert_eq!(command(&mut dsp, 0x10, &[0x4000, 0], 2), [0x4000, 2]);
+        // 1 / (-0.5 * 2^3) = -0.5 * 2^-1
+        assert_eq!(command(&mut dsp, 0x10, &[-0x4000, 3], 2), [-0x4000, -1]);
+        // 1 / (0.75 * 2^-2) = 0.6667 * 2^3
+        assert_eq!(command(&mut dsp, 0x10, &[0x6000, -2], 2), [0x5555, 3]);
+        // Division by zero returns the largest value
+        assert_eq!(command(&mut dsp, 0x10, &[0, 0], 2), [0x7fff, 0x002f]);
+    }
+
+    #[test]
+    fn triangle() {
+        let mut dsp = Dsp1::new();
+        // Returns `radius * sin(angle)` and `radius * cos(angle)`
+        assert_eq!(command(&mut dsp, 0x04, &[0x0000, 0x1000], 2), [0, 0x0fff]);
+        assert_eq!(command(&mut dsp, 0x04, &[0x4000, 0x1000], 2), [0x0fff, 0]);
+        assert_eq!(command(&mut dsp, 0x04, &[0x2000, 0x1000], 2), [0x0b50, 0x0b50]);
+        assert_eq!(command(&mut dsp, 0x04, &[-0x8000, 0x1000], 2), [0, -0x1000]);
+    }
+
+    #[test]
+    fn new_command_while_results_pending() {
+        let mut dsp = Dsp1::new();
+        dsp.write_dr(0x00);
+        for &b in &[0x00, 0x40, 0x00, 0x40] { dsp.write_dr(b); }
+        // The result isn't read, the next write starts a new command
+        assert_eq!(command(&mut dsp, 0x00, &[0x2000, 0x4000], 1), [0x1000]);
+    }
+}
@@ -0,0 +1,380 @@
+//! NEC DSP coprocessors (DSP-1 to DSP-4, ST010 and ST011)
+//!
+//! These are NEC uPD77C25 (DSP-n) and uPD96050 (ST010/ST011) chips running custom firmware. The
+//! firmware isn't part of the game ROM, so it has to be supplied by the user: `load_firmware`
+//! looks for the program and data ROM (concatenated into one file, like the `dsp1b.rom` used by
+//! other emulators) in a firmware directory. If no DSP-1 firmware is found, the DSP-1 command set
+//! is emulated at a high level instead.
+//!
+//! The DSP is accessed through a data register (DR) and a status register (SR) mapped into the
+//! cartridge address space. Where they're mapped depends on the board (see `Board`).
+
+pub mod dsp1;
+pub mod upd77c25;
+
+use diagnostics::Diagnostics;
+
+use self::dsp1::Dsp1;
+use self::upd77c25::{Model, Upd77c25};
+
+use libsavestate::SaveState;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// The chips (and firmwares) that are supported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip {
+    Dsp1,
+    Dsp2,
+    Dsp3,
+    Dsp4,
+    St010,
+    St011,
+}
+
+impl Chip {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Chip::Dsp1 => "DSP-1",
+            Chip::Dsp2 => "DSP-2",
+            Chip::Dsp3 => "DSP-3",
+            Chip::Dsp4 => "DSP-4",
+            Chip::St010 => "ST010",
+            Chip::St011 => "ST011",
+        }
+    }
+
+    fn model(&self) -> Model {
+        match *self {
+            Chip::St010 | Chip::St011 => Model::Upd96050,
+            _ => Model::Upd7725,
+        }
+    }
+
+    /// Firmware file names to look for, in order of preference
+    pub fn firmware_files(&self) -> &'static [&'static str] {
+        match *self {
+            Chip::Dsp1 => &["dsp1b.rom", "dsp1.rom"],
+            Chip::Dsp2 => &["dsp2.rom"],
+            Chip::Dsp3 => &["dsp3.rom"],
+            Chip::Dsp4 => &["dsp4.rom"],
+            Chip::St010 => &["st010.rom"],
+            Chip::St011 => &["st011.rom"],
+        }
+    }
+
+    /// Master clock cycles per DSP instruction
+    fn cycle(&self) -> i32 {
+        match self.model() {
+            // 7.6 MHz
+            Model::Upd7725 => 3,
+            // 10-15 MHz
+            Model::Upd96050 => 2,
+        }
+    }
+}
+
+/// Where the DSP registers are mapped. Banks `$80-$FF` mirror `$00-$7F`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Board {
+    /// LoROM (up to 1 MB): DR at `$20-$3F:8000-$BFFF`, SR at `$20-$3F:C000-$FFFF`
+    LoRom1Mb,
+    /// LoROM (2 MB): DR at `$60-$6F:0000-$3FFF`, SR at `$60-$6F:4000-$7FFF`
+    LoRom2Mb,
+    /// HiROM: DR at `$00-$1F:6000-$6FFF`, SR at `$00-$1F:7000-$7FFF`
+    HiRom,
+    /// DSP-4: DR at `$30-$3F:8000-$BFFF`, SR at `$30-$3F:C000-$FFFF`
+    Dsp4,
+    /// ST010/ST011: DR at `$60-$67:0000`, SR at `$60-$67:0001`, data RAM at `$68-$6F:0000-$0FFF`
+    St01x,
+}
+
+/// A DSP register or memory location as seen by the SNES CPU
+#[derive(Clone, Copy, Debug)]
+pub enum Port {
+    Data,
+    Status,
+    /// Byte offset into the data RAM (uPD96050 only)
+    Ram(usize),
+}
+
+impl Board {
+    /// Returns the DSP port mapped to `bank:addr`, or `None` if the DSP isn't mapped there.
+    pub fn port(&self, bank: u8, addr: u16) -> Option<Port> {
+        let bank = bank & 0x7f;
+        let select = |status: bool| if status { Port::Status } else { Port::Data };
+        match *self {
+            Board::LoRom1Mb => match (bank, addr) {
+                (0x20 ... 0x3f, 0x8000 ... 0xffff) => Some(select(addr >= 0xc000)),
+                _ => None,
+            },
+            Board::LoRom2Mb => match (bank, addr) {
+                (0x60 ... 0x6f, 0x0000 ... 0x7fff) => Some(select(addr >= 0x4000)),
+                _ => None,
+            },
+            Board::HiRom => match (bank, addr) {
+                (0x00 ... 0x1f, 0x6000 ... 0x7fff) => Some(select(addr >= 0x7000)),
+                _ => None,
+            },
+            Board::Dsp4 => match (bank, addr) {
+                (0x30 ... 0x3f, 0x8000 ... 0xffff) => Some(select(addr >= 0xc000)),
+                _ => None,
+            },
+            Board::St01x => match (bank, addr) {
+                (0x60 ... 0x67, 0x0000 ... 0x3fff) => Some(select(addr & 1 != 0)),
+                (0x68 ... 0x6f, 0x0000 ... 0x0fff) => Some(Port::Ram(addr as usize)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The emulation backend
+#[derive(Clone)]
+enum Core {
+    Lle(Box<Upd77c25>),
+    Hle(Box<Dsp1>),
+    /// No firmware was loaded (and there's no HLE)
+    Missing,
+}
+
+/// A DSP on the cartridge
+#[derive(Clone)]
+pub struct NecDsp {
+    chip: Chip,
+    board: Board,
+    core: Core,
+    /// Master clock cycles the DSP has to run to catch up with the SNES CPU
+    master_cy_debt: i32,
+    /// Diagnostics that weren't collected by the `Snes` yet (the backend's are moved here by
+    /// `diagnostics_mut`)
+    diagnostics: Diagnostics,
+}
+
+impl NecDsp {
+    /// Determines the DSP chip and board from the ROM header. Returns `None` if the header doesn't
+    /// match a known DSP board.
+    ///
+    /// `map_mode` is the header byte at `$FFD5`, `chipset` at `$FFD6`, `maker` at `$FFDA` and
+    /// `subtype` at `$FFBF`.
+    pub fn detect(map_mode: u8, chipset: u8, maker: u8, subtype: u8, rom_size: usize)
+    -> Option<(Chip, Board)> {
+        match (map_mode, chipset) {
+            (0x20, 0x03) | (0x20, 0x05) => {
+                let board = if rom_size <= 0x100000 { Board::LoRom1Mb } else { Board::LoRom2Mb };
+                Some((Chip::Dsp1, board))
+            }
+            (0x21, 0x03) | (0x21, 0x05) | (0x31, 0x03) | (0x31, 0x05) => {
+                Some((Chip::Dsp1, Board::HiRom))
+            }
+            (0x30, 0x05) if maker == 0xb2 => Some((Chip::Dsp3, Board::LoRom1Mb)),
+            (0x30, 0x05) => Some((Chip::Dsp1, Board::LoRom1Mb)),
+            (0x30, 0x03) => Some((Chip::Dsp4, Board::Dsp4)),
+            (0x30, 0xf6) if subtype == 0x01 => {
+                let chip = if rom_size >= 0x100000 { Chip::St010 } else { Chip::St011 };
+                Some((chip, Board::St01x))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn new(chip: Chip, board: Board) -> Self {
+        NecDsp {
+            chip: chip,
+            board: board,
+            core: match chip {
+                Chip::Dsp1 => Core::Hle(Box::new(Dsp1::new())),
+                _ => Core::Missing,
+            },
+            master_cy_debt: 0,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    pub fn chip(&self) -> Chip { self.chip }
+
+    pub fn board(&self) -> Board { self.board }
+
+    /// Loads the firmware from `dir`. Fails if no firmware was found, unless there's an HLE
+    /// fallback for the chip.
+    pub fn load_firmware(&mut self, dir: &Path) -> io::Result<()> {
+        let model = self.chip.model();
+        for name in self.chip.firmware_files() {
+            let path = dir.join(name);
+            let mut buf = Vec::new();
+            match File::open(&path) {
+                Ok(mut file) => { try!(file.read_to_end(&mut buf)); }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+
+            match Upd77c25::new(model, &buf) {
+                Some(core) => {
+                    info!("loaded {} firmware from {}", self.chip.name(), path.display());
+                    self.core = Core::Lle(Box::new(core));
+                    return Ok(());
+                }
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "{} firmware {} has the wrong size (expected {} bytes, got {})",
+                        self.chip.name(), path.display(), model.firmware_size(), buf.len())));
+                }
+            }
+        }
+
+        match self.core {
+            Core::Hle(_) => {
+                info!("no {} firmware found in {}, using high-level emulation",
+                    self.chip.name(), dir.display());
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, format!(
+                "{} firmware not found (looked for {} in {})", self.chip.name(),
+                self.chip.firmware_files().join(", "), dir.display()))),
+        }
+    }
+
+    /// Returns the diagnostics that weren't collected by the `Snes` yet.
+    pub fn diagnostics_mut(&mut self) -> &mut Diagnostics {
+        match self.core {
+            Core::Lle(ref mut core) => self.diagnostics.append(&mut core.diagnostics),
+            Core::Hle(ref mut hle) => self.diagnostics.append(&mut hle.diagnostics),
+            Core::Missing => {}
+        }
+        &mut self.diagnostics
+    }
+
+    /// Runs the DSP for (about) `master_cy` master clock cycles.
+    pub fn run(&mut self, master_cy: u32) {
+        let core = match self.core {
+            Core::Lle(ref mut core) => core,
+            _ => return,
+        };
+        self.master_cy_debt += master_cy as i32;
+        while self.master_cy_debt > 0 {
+            core.step();
+            self.master_cy_debt -= self.chip.cycle();
+        }
+    }
+
+    /// Performs a read from the SNES CPU.
+    pub fn read(&mut self, port: Port) -> u8 {
+        match (&mut self.core, port) {
+            (&mut Core::Lle(ref mut core), Port::Data) => core.read_dr(),
+            (&mut Core::Lle(ref core), Port::Status) => core.read_sr(),
+            (&mut Core::Lle(ref core), Port::Ram(offset)) => core.read_ram(offset),
+            (&mut Core::Hle(ref mut hle), Port::Data) => hle.read_dr(),
+            (&mut Core::Hle(ref hle), Port::Status) => hle.read_sr(),
+            _ => 0,
+        }
+    }
+
+    /// Reads a port without side effects. Returns `None` for the data register.
+    pub fn peek(&self, port: Port) -> Option<u8> {
+        match (&self.core, port) {
+            (_, Port::Data) => None,
+            (&Core::Lle(ref core), Port::Status) => Some(core.read_sr()),
+            (&Core::Lle(ref core), Port::Ram(offset)) => Some(core.read_ram(offset)),
+            (&Core::Hle(ref hle), Port::Status) => Some(hle.read_sr()),
+            _ => None,
+        }
+    }
+
+    /// Performs a write from the SNES CPU.
+    pub fn write(&mut self, port: Port, value: u8) {
+        match (&mut self.core, port) {
+            (&mut Core::Lle(ref mut core), Port::Data) => core.write_dr(value),
+            (&mut Core::Lle(ref mut core), Port::Ram(offset)) => core.write_ram(offset, value),
+            (&mut Core::Hle(ref mut hle), Port::Data) => hle.write_dr(value),
+            (_, Port::Status) => {}
+            _ => diag!(self.diagnostics, "write of ${:02X} to {:?} ignored ({} firmware missing?)",
+                value, port, self.chip.name()),
+        }
+    }
+}
+
+// The backend is determined by the ROM and the available firmware, so it isn't stored. Restoring a
+// save state requires the same firmware that was used to create it.
+impl SaveState for NecDsp {
+    fn save_state<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        try!(self.master_cy_debt.save_state(w));
+        match self.core {
+            Core::Lle(ref core) => core.save_state(w),
+            Core::Hle(ref hle) => hle.save_state(w),
+            Core::Missing => Ok(()),
+        }
+    }
+
+    fn restore_state<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<()> {
+        try!(self.master_cy_debt.restore_state(r));
+        match self.core {
+            Core::Lle(ref mut core) => core.restore_state(r),
+            Core::Hle(ref mut hle) => hle.restore_state(r),
+            Core::Missing => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Board, Chip, NecDsp};
+
+    fn port(board: Board, bank: u8, addr: u16) -> String {
+        format!("{:?}", board.port(bank, addr))
+    }
+
+    #[test]
+    fn board_ports() {
+        assert_eq!(port(Board::LoRom1Mb, 0x20, 0x8000), "Some(Data)");
+        assert_eq!(port(Board::LoRom1Mb, 0xbf, 0xbfff), "Some(Data)");
+        assert_eq!(port(Board::LoRom1Mb, 0x3f, 0xc000), "Some(Status)");
+        assert_eq!(port(Board::LoRom1Mb, 0x1f, 0x8000), "None");
+        assert_eq!(port(Board::LoRom1Mb, 0x20, 0x7fff), "None");
+
+        assert_eq!(port(Board::LoRom2Mb, 0x60, 0x0000), "Some(Data)");
+        assert_eq!(port(Board::LoRom2Mb, 0xef, 0x4000), "Some(Status)");
+        assert_eq!(port(Board::LoRom2Mb, 0x60, 0x8000), "None");
+        assert_eq!(port(Board::LoRom2Mb, 0x70, 0x0000), "None");
+
+        assert_eq!(port(Board::HiRom, 0x00, 0x6000), "Some(Data)");
+        assert_eq!(port(Board::HiRom, 0x9f, 0x7000), "Some(Status)");
+        assert_eq!(port(Board::HiRom, 0x20, 0x6000), "None");
+        assert_eq!(port(Board::HiRom, 0x00, 0x8000), "None");
+
+        assert_eq!(port(Board::Dsp4, 0x30, 0x8000), "Some(Data)");
+        assert_eq!(port(Board::Dsp4, 0x3f, 0xffff), "Some(Status)");
+        assert_eq!(port(Board::Dsp4, 0x20, 0x8000), "None");
+
+        assert_eq!(port(Board::St01x, 0x60, 0x0000), "Some(Data)");
+        assert_eq!(port(Board::St01x, 0x60, 0x0001), "Some(Status)");
+        assert_eq!(port(Board::St01x, 0xe8, 0x0123), "Some(Ram(291))");
+        assert_eq!(port(Board::St01x, 0x68, 0x1000), "None");
+    }
+
+    #[test]
+    fn detect() {
+        let detect = |map_mode, chipset, maker, subtype, rom_size| {
+            NecDsp::detect(map_mode, chipset, maker, subtype, rom_size)
+        };
+        assert_eq!(detect(0x20, 0x03, 0, 0, 0x80000), Some((Chip::Dsp1, Board::LoRom1Mb)));
+        assert_eq!(detect(0x20, 0x05, 0, 0, 0x200000), Some((Chip::Dsp1, Board::LoRom2Mb)));
+        assert_eq!(detect(0x21, 0x03, 0, 0, 0x100000), Some((Chip::Dsp1, Board::HiRom)));
+        assert_eq!(detect(0x30, 0x05, 0xb2, 0, 0x100000), Some((Chip::Dsp3, Board::LoRom1Mb)));
+        assert_eq!(detect(0x30, 0x03, 0, 0, 0x100000), Some((Chip::Dsp4, Board::Dsp4)));
+        assert_eq!(detect(0x30, 0xf6, 0, 0x01, 0x100000), Some((Chip::St010, Board::St01x)));
+        assert_eq!(detect(0x30, 0xf6, 0, 0x01, 0x80000), Some((Chip::St011, Board::St01x)));
+        assert_eq!(detect(0x30, 0xf6, 0, 0x02, 0x100000), None);
+        assert_eq!(detect(0x20, 0x13, 0, 0, 0x100000), None);
+    }
+
+    #[test]
+    fn hle_fallback() {
+        // The DSP-1 works without firmware, the others don't
+        let mut dsp = NecDsp::new(Chip::Dsp1, Board::LoRom1Mb);
+        assert!(dsp.load_firmware("/nonexistent".as_ref()).is_ok());
+        let mut dsp = NecDsp::new(Chip::Dsp2, Board::LoRom1Mb);
+        assert!(dsp.load_firmware("/nonexistent".as_ref()).is_err());
+    }
+}
@@ -0,0 +1,500 @@
+//! NEC uPD77C25 / uPD96050 low-level emulation
+//!
+//! Both chips are 16-bit fixed point DSPs with a 24-bit instruction word and separate program ROM,
+//! data ROM and data RAM. The uPD96050 (used by the ST010) has larger memories and an 8-level
+//! stack. Every instruction executes in a single cycle.
+//!
+//! Each instruction can perform an ALU operation on one of the two accumulators, move a value
+//! between two registers, and modify the data RAM and data ROM pointers. The SNES CPU talks to the
+//! DSP through the data register (DR) and the high byte of the status register (SR).
+
+use diagnostics::Diagnostics;
+
+/// Chip variant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// uPD77C25 (DSP-1 to DSP-4): 2K program words, 1K data ROM words, 256 data RAM words
+    Upd7725,
+    /// uPD96050 (ST010, ST011): 16K program words, 2K data ROM words, 2K data RAM words
+    Upd96050,
+}
+
+impl Model {
+    fn program_size(&self) -> usize {
+        match *self { Model::Upd7725 => 2048, Model::Upd96050 => 16384 }
+    }
+
+    fn data_rom_size(&self) -> usize {
+        match *self { Model::Upd7725 => 1024, Model::Upd96050 => 2048 }
+    }
+
+    fn data_ram_size(&self) -> usize {
+        match *self { Model::Upd7725 => 256, Model::Upd96050 => 2048 }
+    }
+
+    fn stack_size(&self) -> usize {
+        match *self { Model::Upd7725 => 4, Model::Upd96050 => 8 }
+    }
+
+    /// Size of a firmware image (program ROM followed by data ROM, both little-endian)
+    pub fn firmware_size(&self) -> usize {
+        self.program_size() * 3 + self.data_rom_size() * 2
+    }
+}
+
+// Status register bits
+const SR_RQM: u16 = 0x8000;
+const SR_DRS: u16 = 0x1000;
+const SR_DRC: u16 = 0x0400;
+/// These bits can't be modified by the DSP program
+const SR_READ_ONLY: u16 = 0x907c;
+
+/// Flags of one of the accumulators
+#[derive(Clone, Copy, Default)]
+struct Flags {
+    s0: bool,
+    s1: bool,
+    c: bool,
+    z: bool,
+    ov0: bool,
+    ov1: bool,
+}
+
+impl_save_state!(Flags { s0, s1, c, z, ov0, ov1 } ignore {});
+
+/// The DSP core
+#[derive(Clone)]
+pub struct Upd77c25 {
+    model: Model,
+    program: Vec<u32>,
+    data_rom: Vec<u16>,
+    data_ram: Vec<u16>,
+
+    pc: u16,
+    stack: [u16; 8],
+    sp: u8,
+    /// Data ROM pointer
+    rp: u16,
+    /// Data RAM pointer
+    dp: u16,
+    /// Multiplier inputs
+    k: u16,
+    l: u16,
+    /// Multiplier outputs (high and low word)
+    m: u16,
+    n: u16,
+    a: u16,
+    b: u16,
+    flags_a: Flags,
+    flags_b: Flags,
+    /// Temporary registers
+    tr: u16,
+    trb: u16,
+    /// Status register
+    sr: u16,
+    /// Data register
+    dr: u16,
+    /// Serial input and output registers (not connected on the SNES)
+    si: u16,
+    so: u16,
+
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+impl_save_state!(Upd77c25 {
+    data_ram, pc, stack, sp, rp, dp, k, l, m, n, a, b, flags_a, flags_b, tr, trb, sr, dr, si, so
+} ignore { model, program, data_rom, diagnostics });
+
+impl Upd77c25 {
+    /// Creates a DSP running the given firmware image (the program ROM followed by the data ROM,
+    /// both stored as little-endian words). Returns `None` if the image has the wrong size.
+    pub fn new(model: Model, firmware: &[u8]) -> Option<Self> {
+        if firmware.len() != model.firmware_size() { return None; }
+
+        let (program, data) = firmware.split_at(model.program_size() * 3);
+        Some(Upd77c25 {
+            model: model,
+            program: program.chunks(3)
+                .map(|w| w[0] as u32 | (w[1] as u32) << 8 | (w[2] as u32) << 16)
+                .collect(),
+            data_rom: data.chunks(2).map(|w| w[0] as u16 | (w[1] as u16) << 8).collect(),
+            data_ram: vec![0; model.data_ram_size()],
+            pc: 0,
+            stack: [0; 8],
+            sp: 0,
+            rp: 0x3ff,
+            dp: 0,
+            k: 0,
+            l: 0,
+            m: 0,
+            n: 0,
+            a: 0,
+            b: 0,
+            flags_a: Flags::default(),
+            flags_b: Flags::default(),
+            tr: 0,
+            trb: 0,
+            sr: 0,
+            dr: 0,
+            si: 0,
+            so: 0,
+            diagnostics: Diagnostics::default(),
+        })
+    }
+
+    pub fn model(&self) -> Model { self.model }
+
+    /// Reads the high byte of the status register.
+    pub fn read_sr(&self) -> u8 { (self.sr >> 8) as u8 }
+
+    /// Reads a byte from the data register. In 16-bit mode (`DRC` clear), the low byte is read
+    /// first and reading the high byte completes the transfer.
+    pub fn read_dr(&mut self) -> u8 {
+        if self.sr & SR_DRC != 0 {
+            self.sr &= !SR_RQM;
+            self.dr as u8
+        } else if self.sr & SR_DRS == 0 {
+            self.sr |= SR_DRS;
+            self.dr as u8
+        } else {
+            self.sr &= !(SR_RQM | SR_DRS);
+            (self.dr >> 8) as u8
+        }
+    }
+
+    /// Writes a byte to the data register.
+    pub fn write_dr(&mut self, value: u8) {
+        if self.sr & SR_DRC != 0 {
+            self.sr &= !SR_RQM;
+            self.dr = self.dr & 0xff00 | value as u16;
+        } else if self.sr & SR_DRS == 0 {
+            self.sr |= SR_DRS;
+            self.dr = self.dr & 0xff00 | value as u16;
+        } else {
+            self.sr &= !(SR_RQM | SR_DRS);
+            self.dr = self.dr & 0x00ff | (value as u16) << 8;
+        }
+    }
+
+    /// Reads a byte of the data RAM (uPD96050 only, mapped into the SNES address space).
+    pub fn read_ram(&self, offset: usize) -> u8 {
+        let word = self.data_ram[(offset >> 1) % self.data_ram.len()];
+        if offset & 1 == 0 { word as u8 } else { (word >> 8) as u8 }
+    }
+
+    /// Writes a byte of the data RAM.
+    pub fn write_ram(&mut self, offset: usize, value: u8) {
+        let len = self.data_ram.len();
+        let word = &mut self.data_ram[(offset >> 1) % len];
+        *word = if offset & 1 == 0 {
+            *word & 0xff00 | value as u16
+        } else {
+            *word & 0x00ff | (value as u16) << 8
+        };
+    }
+
+    fn pc_mask(&self) -> u16 { self.model.program_size() as u16 - 1 }
+    fn rp_mask(&self) -> u16 { self.model.data_rom_size() as u16 - 1 }
+    fn dp_mask(&self) -> u16 { self.model.data_ram_size() as u16 - 1 }
+
+    fn data_ram(&self) -> u16 { self.data_ram[(self.dp & self.dp_mask()) as usize] }
+    fn data_rom(&self) -> u16 { self.data_rom[(self.rp & self.rp_mask()) as usize] }
+
+    fn push(&mut self) {
+        let size = self.model.stack_size() as u8;
+        self.stack[self.sp as usize] = self.pc;
+        self.sp = (self.sp + 1) % size;
+    }
+
+    fn pop(&mut self) {
+        let size = self.model.stack_size() as u8;
+        self.sp = (self.sp + size - 1) % size;
+        self.pc = self.stack[self.sp as usize];
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) {
+        let op = self.program[(self.pc & self.pc_mask()) as usize];
+        self.pc = (self.pc + 1) & self.pc_mask();
+        match op >> 22 {
+            0 => self.exec_op(op),
+            1 => {
+                // RT: OP followed by a return
+                self.exec_op(op);
+                self.pop();
+            }
+            2 => self.exec_jp(op),
+            _ => self.exec_ld(op),
+        }
+
+        // The multiplier runs continuously
+        let product = (self.k as i16 as i32) * (self.l as i16 as i32);
+        self.m = (product >> 15) as u16;
+        self.n = (product << 1) as u16;
+    }
+
+    fn exec_op(&mut self, op: u32) {
+        let pselect = (op >> 20) & 3;
+        let alu = (op >> 16) & 0xf;
+        let asl = (op >> 15) & 1;
+        let dpl = (op >> 13) & 3;
+        let dphm = ((op >> 9) & 0xf) as u16;
+        let rpdcr = (op >> 8) & 1;
+        let src = (op >> 4) & 0xf;
+        let dst = op & 0xf;
+
+        let idb = match src {
+            0 => self.trb,
+            1 => self.a,
+            2 => self.b,
+            3 => self.tr,
+            4 => self.dp,
+            5 => self.rp,
+            6 => self.data_rom(),
+            7 => 0x8000 - self.flags_a.s1 as u16,
+            8 => {
+                self.sr |= SR_RQM;
+                self.dr
+            }
+            9 => self.dr,
+            10 => self.sr,
+            11 | 12 => self.si,
+            13 => self.k,
+            14 => self.l,
+            _ => self.data_ram(),
+        };
+
+        if alu != 0 {
+            let p = match pselect {
+                0 => self.data_ram(),
+                1 => idb,
+                2 => self.m,
+                _ => self.n,
+            };
+            let (q, mut flags, c) = if asl == 0 {
+                (self.a, self.flags_a, self.flags_b.c)
+            } else {
+                (self.b, self.flags_b, self.flags_a.c)
+            };
+
+            let (r, p) = match alu {
+                1 => (q | p, p),
+                2 => (q & p, p),
+                3 => (q ^ p, p),
+                4 => (q.wrapping_sub(p), p),
+                5 => (q.wrapping_add(p), p),
+                6 => (q.wrapping_sub(p).wrapping_sub(c as u16), p),
+                7 => (q.wrapping_add(p).wrapping_add(c as u16), p),
+                8 => (q.wrapping_sub(1), 1),
+                9 => (q.wrapping_add(1), 1),
+                10 => (!q, p),
+                11 => (q >> 1 | q & 0x8000, p),
+                12 => (q << 1 | c as u16, p),
+                13 => (q << 2 | 3, p),
+                14 => (q << 4 | 15, p),
+                _ => (q.rotate_left(8), p),
+            };
+
+            flags.z = r == 0;
+            flags.s0 = r & 0x8000 != 0;
+            match alu {
+                4 ... 9 => {
+                    if alu & 1 != 0 {
+                        flags.ov0 = (q ^ r) & (p ^ r) & 0x8000 != 0;
+                        flags.c = r < q;
+                    } else {
+                        flags.ov0 = (q ^ r) & (q ^ p) & 0x8000 != 0;
+                        flags.c = r > q;
+                    }
+                    if flags.ov0 {
+                        flags.s1 = flags.ov1 ^ (r & 0x8000 == 0);
+                        flags.ov1 = !flags.ov1;
+                    }
+                }
+                11 => {
+                    flags.c = q & 1 != 0;
+                    flags.ov0 = false;
+                    flags.ov1 = false;
+                }
+                12 => {
+                    flags.c = q & 0x8000 != 0;
+                    flags.ov0 = false;
+                    flags.ov1 = false;
+                }
+                _ => {
+                    flags.c = false;
+                    flags.ov0 = false;
+                    flags.ov1 = false;
+                }
+            }
+
+            if asl == 0 {
+                self.a = r;
+                self.flags_a = flags;
+            } else {
+                self.b = r;
+                self.flags_b = flags;
+            }
+        }
+
+        self.exec_ld((idb as u32) << 6 | dst);
+
+        match dpl {
+            1 => self.dp = self.dp & !0x0f | (self.dp + 1) & 0x0f,
+            2 => self.dp = self.dp & !0x0f | self.dp.wrapping_sub(1) & 0x0f,
+            3 => self.dp &= !0x0f,
+            _ => {}
+        }
+        self.dp ^= dphm << 4;
+
+        if rpdcr != 0 {
+            self.rp = self.rp.wrapping_sub(1) & self.rp_mask();
+        }
+    }
+
+    fn exec_jp(&mut self, op: u32) {
+        let brch = (op >> 13) & 0x1ff;
+        let na = ((op >> 2) & 0x7ff) as u16;
+        let bank = (op & 3) as u16;
+        let target = (self.pc & 0x2000 | bank << 11 | na) & self.pc_mask();
+
+        let (fa, fb) = (self.flags_a, self.flags_b);
+        let cond = match brch {
+            0x000 => {
+                // JMPSO
+                self.pc = self.so & self.pc_mask();
+                return;
+            }
+            0x080 => !fa.c,
+            0x082 => fa.c,
+            0x084 => !fb.c,
+            0x086 => fb.c,
+            0x088 => !fa.z,
+            0x08a => fa.z,
+            0x08c => !fb.z,
+            0x08e => fb.z,
+            0x090 => !fa.ov0,
+            0x092 => fa.ov0,
+            0x094 => !fb.ov0,
+            0x096 => fb.ov0,
+            0x098 => !fa.ov1,
+            0x09a => fa.ov1,
+            0x09c => !fb.ov1,
+            0x09e => fb.ov1,
+            0x0a0 => !fa.s0,
+            0x0a2 => fa.s0,
+            0x0a4 => !fb.s0,
+            0x0a6 => fb.s0,
+            0x0a8 => !fa.s1,
+            0x0aa => fa.s1,
+            0x0ac => !fb.s1,
+            0x0ae => fb.s1,
+            0x0b0 => self.dp & 0x0f == 0x00,
+            0x0b1 => self.dp & 0x0f != 0x00,
+            0x0b2 => self.dp & 0x0f == 0x0f,
+            0x0b3 => self.dp & 0x0f != 0x0f,
+            // The serial interface isn't connected, so it always acknowledges immediately
+            0x0b4 => false,
+            0x0b6 => true,
+            0x0b8 => false,
+            0x0ba => true,
+            0x0bc => self.sr & SR_RQM == 0,
+            0x0be => self.sr & SR_RQM != 0,
+            0x100 => {
+                // LJMP
+                self.pc = target & !0x2000;
+                return;
+            }
+            0x101 => {
+                // HJMP
+                self.pc = (target | 0x2000) & self.pc_mask();
+                return;
+            }
+            0x140 | 0x141 => {
+                // LCALL / HCALL
+                self.push();
+                self.pc = if brch == 0x140 { target & !0x2000 } else { target | 0x2000 };
+                self.pc &= self.pc_mask();
+                return;
+            }
+            _ => {
+                diag!(self.diagnostics, "DSP: unknown jump condition ${:03X}", brch);
+                false
+            }
+        };
+
+        if cond {
+            self.pc = target;
+        }
+    }
+
+    fn exec_ld(&mut self, op: u32) {
+        let id = (op >> 6) as u16;
+        match op & 0xf {
+            0 => {}
+            1 => self.a = id,
+            2 => self.b = id,
+            3 => self.tr = id,
+            4 => self.dp = id & self.dp_mask(),
+            5 => self.rp = id & self.rp_mask(),
+            6 => {
+                self.dr = id;
+                self.sr |= SR_RQM;
+            }
+            7 => self.sr = self.sr & SR_READ_ONLY | id & !SR_READ_ONLY,
+            8 | 9 => self.so = id,
+            10 => self.k = id,
+            11 => {
+                self.k = id;
+                self.l = self.data_rom();
+            }
+            12 => {
+                self.l = id;
+                self.k = self.data_ram[((self.dp | 0x40) & self.dp_mask()) as usize];
+            }
+            13 => self.l = id,
+            14 => self.trb = id,
+            _ => {
+                let dp = (self.dp & self.dp_mask()) as usize;
+                self.data_ram[dp] = id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Model, Upd77c25};
+
+    #[test]
+    fn firmware_size() {
+        assert_eq!(Model::Upd7725.firmware_size(), 8192);
+        assert_eq!(Model::Upd96050.firmware_size(), 53248);
+
+        assert!(Upd77c25::new(Model::Upd7725, &[0; 8191]).is_none());
+        assert!(Upd77c25::new(Model::Upd7725, &[0; 8193]).is_none());
+        assert!(Upd77c25::new(Model::Upd7725, &[0; 53248]).is_none());
+        assert!(Upd77c25::new(Model::Upd96050, &[0; 8192]).is_none());
+        assert!(Upd77c25::new(Model::Upd96050, &[0; 53248]).is_some());
+    }
+
+    #[test]
+    fn firmware_layout() {
+        let mut firmware = vec![0; 8192];
+        firmware[..3].copy_from_slice(&[0x12, 0x34, 0x56]);
+        firmware[6141..6144].copy_from_slice(&[0xab, 0xcd, 0xef]);
+        firmware[6144..6146].copy_from_slice(&[0x78, 0x9a]);
+        firmware[8190..].copy_from_slice(&[0x11, 0x22]);
+
+        let dsp = Upd77c25::new(Model::Upd7725, &firmware).unwrap();
+        assert_eq!(dsp.model(), Model::Upd7725);
+        assert_eq!(dsp.program.len(), 2048);
+        assert_eq!(dsp.program[0], 0x563412);
+        assert_eq!(dsp.program[2047], 0xefcdab);
+        assert_eq!(dsp.data_rom.len(), 1024);
+        assert_eq!(dsp.data_rom[0], 0x9a78);
+        assert_eq!(dsp.data_rom[1023], 0x2211);
+        assert_eq!(dsp.data_ram.len(), 256);
+    }
+}
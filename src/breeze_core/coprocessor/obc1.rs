@@ -0,0 +1,104 @@
+//! OBC-1 sprite manager (Metal Combat: Falcon's Revenge)
+//!
+//! The OBC-1 is mapped to `$6000-$7FFF` in banks `$00-$3F` and `$80-$BF` and provides access to
+//! the 8 KB cartridge RAM. The RAM contains two OAM-like sprite tables (at `$1800` and `$1C00`),
+//! which the OBC-1 lets the game access by sprite index:
+//!
+//! * `$7FF0-$7FF3`: The 4 bytes of the selected sprite (X, Y, tile, attributes)
+//! * `$7FF4`: The high table byte containing the selected sprite's 2 extra bits (only those 2 bits
+//!   are written)
+//! * `$7FF5`: Bit 0 selects the table (0: `$1C00`, 1: `$1800`)
+//! * `$7FF6`: Selected sprite (0-127)
+//!
+//! The registers are part of the RAM, so the chip itself has no state.
+
+#[derive(Clone)]
+pub struct Obc1;
+
+impl Obc1 {
+    pub fn new() -> Self { Obc1 }
+
+    fn is_mapped(bank: u8, addr: u16) -> bool {
+        match (bank & 0x7f, addr) {
+            (0x00 ... 0x3f, 0x6000 ... 0x7fff) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the offset of the selected sprite table.
+    fn table(ram: &[u8]) -> usize {
+        if ram[0x1ff5] & 1 != 0 { 0x1800 } else { 0x1c00 }
+    }
+
+    fn sprite(ram: &[u8]) -> usize { ram[0x1ff6] as usize & 0x7f }
+
+    /// Resolves an address to the RAM offset it accesses (sprite registers are redirected to the
+    /// selected sprite).
+    fn ram_offset(ram: &[u8], addr: u16) -> usize {
+        match addr & 0x1fff {
+            offset @ 0x1ff0 ... 0x1ff3 => {
+                Self::table(ram) + (Self::sprite(ram) << 2) + (offset as usize & 3)
+            }
+            0x1ff4 => Self::table(ram) + 0x200 + (Self::sprite(ram) >> 2),
+            offset => offset as usize,
+        }
+    }
+
+    /// Performs a load from the SNES CPU. Returns `None` if the OBC-1 isn't mapped to `bank:addr`.
+    pub fn load(&self, ram: &[u8], bank: u8, addr: u16) -> Option<u8> {
+        if !Self::is_mapped(bank, addr) { return None; }
+        Some(ram[Self::ram_offset(ram, addr)])
+    }
+
+    /// Performs a store from the SNES CPU. Returns `false` if the OBC-1 isn't mapped to
+    /// `bank:addr`.
+    pub fn store(&mut self, ram: &mut [u8], bank: u8, addr: u16, value: u8) -> bool {
+        if !Self::is_mapped(bank, addr) { return false; }
+        let offset = Self::ram_offset(ram, addr);
+        if addr & 0x1fff == 0x1ff4 {
+            let shift = (Self::sprite(ram) & 3) << 1;
+            ram[offset] = ram[offset] & !(3 << shift) | (value & 3) << shift;
+        } else {
+            ram[offset] = value;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprite_registers() {
+        let mut obc1 = Obc1::new();
+        let mut ram = vec![0; 0x2000];
+        let store = |obc1: &mut Obc1, ram: &mut Vec<u8>, addr, value| {
+            assert!(obc1.store(ram, 0x00, addr, value));
+        };
+
+        // Sprite 5 in the table at `$1C00`
+        store(&mut obc1, &mut ram, 0x7ff6, 5);
+        store(&mut obc1, &mut ram, 0x7ff0, 0x12);
+        store(&mut obc1, &mut ram, 0x7ff3, 0x34);
+        assert_eq!(&ram[0x1c14..0x1c18], &[0x12, 0, 0, 0x34]);
+        assert_eq!(obc1.load(&ram, 0x80, 0x7ff3), Some(0x34));
+
+        // Only the sprite's 2 bits of the high table byte are written
+        ram[0x1e01] = 0xff;
+        store(&mut obc1, &mut ram, 0x7ff4, 0x02);
+        assert_eq!(ram[0x1e01], 0xfb);
+        assert_eq!(obc1.load(&ram, 0x00, 0x7ff4), Some(0xfb));
+
+        // Table at `$1800`
+        store(&mut obc1, &mut ram, 0x7ff5, 1);
+        store(&mut obc1, &mut ram, 0x7ff1, 0x56);
+        assert_eq!(ram[0x1815], 0x56);
+
+        // Other addresses access the RAM directly
+        store(&mut obc1, &mut ram, 0x6123, 0x78);
+        assert_eq!(ram[0x0123], 0x78);
+        assert_eq!(obc1.load(&ram, 0x40, 0x6123), None);
+        assert!(!obc1.store(&mut ram, 0x00, 0x8000, 0));
+    }
+}
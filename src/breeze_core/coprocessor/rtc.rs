@@ -0,0 +1,369 @@
+//! Date and time keeping for cartridge real-time clocks
+//!
+//! Clock chips on cartridges count seconds, minutes, hours, days, months and years. Instead of
+//! reading the host's clock whenever a game accesses them, we start the clock at the host's current
+//! (UTC) time and advance it with the emulated master clock. This keeps the clock consistent with
+//! save states and input recordings (which start the clock at a fixed time, see `movie_start`).
+
+use region::Region;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A calendar date and time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DateTime {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    /// Day of the month (1-31)
+    pub day: u8,
+    /// 1-12
+    pub month: u8,
+    pub year: u16,
+    /// Day of the week (0 = Sunday)
+    pub weekday: u8,
+}
+
+impl_save_state!(DateTime { second, minute, hour, day, month, year, weekday } ignore {});
+
+impl DateTime {
+    /// Returns the host's current date and time (in UTC).
+    pub fn now() -> Self {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Self::from_unix(secs)
+    }
+
+    /// The time clocks are set to when recording or replaying input, so recordings don't depend on
+    /// when they were made (2000-01-01 00:00:00, a Saturday).
+    pub fn movie_start() -> Self {
+        DateTime {
+            second: 0,
+            minute: 0,
+            hour: 0,
+            day: 1,
+            month: 1,
+            year: 2000,
+            weekday: 6,
+        }
+    }
+
+    /// Converts a Unix timestamp to a date and time.
+    fn from_unix(secs: u64) -> Self {
+        let days = secs / 86400;
+        let secs = secs % 86400;
+
+        // Converts the day count to a date in the proleptic Gregorian calendar, see
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            second: (secs % 60) as u8,
+            minute: (secs / 60 % 60) as u8,
+            hour: (secs / 3600) as u8,
+            day: day as u8,
+            month: month as u8,
+            year: year as u16,
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as u8,
+        }
+    }
+
+    /// Converts the date and time to a Unix timestamp. Dates before 1970 result in 0.
+    fn to_unix(&self) -> u64 {
+        // The inverse of `from_unix`, see
+        // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let yoe = year - era * 400;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        let secs = days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 +
+            self.second as i64;
+        if secs < 0 { 0 } else { secs as u64 }
+    }
+
+    fn is_leap_year(&self) -> bool {
+        self.year % 4 == 0 && (self.year % 100 != 0 || self.year % 400 == 0)
+    }
+
+    /// Returns the number of days in the current month.
+    pub fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 => if self.is_leap_year() { 29 } else { 28 },
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    /// Advances the time by one second.
+    ///
+    /// Games can write invalid values into the clock registers, so every field that exceeds its
+    /// range simply wraps around to its first value.
+    pub fn tick(&mut self) {
+        self.second += 1;
+        if self.second < 60 { return; }
+        self.second = 0;
+        self.minute += 1;
+        if self.minute < 60 { return; }
+        self.minute = 0;
+        self.hour += 1;
+        if self.hour < 24 { return; }
+        self.hour = 0;
+        self.weekday = (self.weekday + 1) % 7;
+        self.day += 1;
+        if self.day <= self.days_in_month() { return; }
+        self.day = 1;
+        self.month += 1;
+        if self.month <= 12 { return; }
+        self.month = 1;
+        self.year += 1;
+    }
+}
+
+/// A clock that is advanced by the emulated master clock
+#[derive(Clone)]
+pub struct Clock {
+    pub time: DateTime,
+    /// Master clock cycles since the last full second
+    master_cy: u32,
+    /// Master clock cycles per second (depends on the console region)
+    freq: u32,
+    /// Number of times the time was set, so save files know when to store the clock again
+    adjustments: u32,
+}
+
+impl_save_state!(Clock { time, master_cy } ignore { freq, adjustments });
+
+impl Clock {
+    /// Creates a clock that starts at the host's current time.
+    pub fn new() -> Self {
+        Clock {
+            time: DateTime::now(),
+            master_cy: 0,
+            freq: Region::default().master_clock_freq(),
+            adjustments: 0,
+        }
+    }
+
+    /// Sets the frequency of the master clock driving the clock.
+    pub fn set_master_clock_freq(&mut self, freq: u32) {
+        self.freq = freq;
+    }
+
+    /// Advances the clock by `master_cy` master clock cycles.
+    pub fn run(&mut self, master_cy: u32) {
+        self.master_cy += master_cy;
+        while self.master_cy >= self.freq {
+            self.master_cy -= self.freq;
+            self.time.tick();
+        }
+    }
+
+    /// Sets the clock to `time`, starting a new second.
+    pub fn set(&mut self, time: DateTime) {
+        self.time = time;
+        self.master_cy = 0;
+        self.adjusted();
+    }
+
+    /// Records that `time` was changed by something other than the clock running. Must be called
+    /// when modifying `time` directly.
+    pub fn adjusted(&mut self) {
+        self.adjustments = self.adjustments.wrapping_add(1);
+    }
+
+    /// Returns how often the time was set (see `adjusted`). The value only matters when it
+    /// changes.
+    pub fn adjustments(&self) -> u32 { self.adjustments }
+
+    /// Serializes the time for storing it alongside the cartridge RAM in save files. Along with
+    /// the time, we store the host's current time so the clock keeps running while the emulator
+    /// isn't (see `load_data`).
+    ///
+    /// The data is 16 bytes long: The year (little-endian), month, day, hour, minute, second and
+    /// weekday, followed by the Unix timestamp of the host's time (64-bit little-endian).
+    pub fn save_data(&self) -> Vec<u8> {
+        let t = &self.time;
+        let mut data = vec![t.year as u8, (t.year >> 8) as u8, t.month, t.day, t.hour, t.minute,
+            t.second, t.weekday];
+        let host_time = DateTime::now().to_unix();
+        for i in 0..8 {
+            data.push((host_time >> (i * 8)) as u8);
+        }
+        data
+    }
+
+    /// Restores the time saved by `save_data` and advances it by the (host) time that has passed
+    /// since then. Returns `false` if `data` is invalid.
+    pub fn load_data(&mut self, data: &[u8]) -> bool {
+        if data.len() != 16 { return false; }
+
+        let time = DateTime {
+            year: data[0] as u16 | (data[1] as u16) << 8,
+            month: data[2],
+            day: data[3],
+            hour: data[4],
+            minute: data[5],
+            second: data[6],
+            weekday: data[7],
+        };
+        let saved_at = data[8..16].iter().rev().fold(0, |acc, &b| acc << 8 | b as u64);
+        let elapsed = DateTime::now().to_unix().saturating_sub(saved_at);
+        self.set(time);
+        // Advancing a date with invalid fields would normalize them, so we tick them one by one
+        // if there aren't too many seconds to catch up on
+        if elapsed < 24 * 3600 {
+            for _ in 0..elapsed {
+                self.time.tick();
+            }
+        } else {
+            self.time = DateTime::from_unix(time.to_unix() + elapsed);
+        }
+        true
+    }
+
+    /// Restarts the current second (clock chips do this when their seconds are reset).
+    pub fn reset_fraction(&mut self) {
+        self.master_cy = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            second: second,
+            minute: minute,
+            hour: hour,
+            day: day,
+            month: month,
+            year: year,
+            weekday: 0,
+        }
+    }
+
+    fn fields(t: &DateTime) -> (u16, u8, u8, u8, u8, u8) {
+        (t.year, t.month, t.day, t.hour, t.minute, t.second)
+    }
+
+    #[test]
+    fn days_in_month() {
+        assert_eq!(date(2001, 1, 1, 0, 0, 0).days_in_month(), 31);
+        assert_eq!(date(2001, 2, 1, 0, 0, 0).days_in_month(), 28);
+        assert_eq!(date(2004, 2, 1, 0, 0, 0).days_in_month(), 29);
+        assert_eq!(date(1900, 2, 1, 0, 0, 0).days_in_month(), 28);
+        assert_eq!(date(2000, 2, 1, 0, 0, 0).days_in_month(), 29);
+        assert_eq!(date(2001, 4, 1, 0, 0, 0).days_in_month(), 30);
+        assert_eq!(date(2001, 12, 1, 0, 0, 0).days_in_month(), 31);
+    }
+
+    #[test]
+    fn tick() {
+        let ticked = |mut t: DateTime| {
+            t.tick();
+            t
+        };
+
+        let t = ticked(date(2001, 5, 6, 7, 8, 9));
+        assert_eq!(fields(&t), (2001, 5, 6, 7, 8, 10));
+        assert_eq!(t.weekday, 0);
+
+        let t = ticked(DateTime { weekday: 6, ..date(2016, 2, 28, 23, 59, 59) });
+        assert_eq!(fields(&t), (2016, 2, 29, 0, 0, 0));
+        assert_eq!(t.weekday, 0);
+        let t = ticked(date(2015, 2, 28, 23, 59, 59));
+        assert_eq!(fields(&t), (2015, 3, 1, 0, 0, 0));
+        let t = ticked(date(1999, 12, 31, 23, 59, 59));
+        assert_eq!(fields(&t), (2000, 1, 1, 0, 0, 0));
+
+        // Invalid fields wrap around
+        let t = ticked(date(2001, 13, 31, 23, 59, 59));
+        assert_eq!(fields(&t), (2002, 1, 1, 0, 0, 0));
+        let t = ticked(date(2001, 1, 1, 0, 75, 80));
+        assert_eq!(fields(&t), (2001, 1, 1, 1, 0, 0));
+    }
+
+    #[test]
+    fn unix_time() {
+        let t = DateTime::from_unix(0);
+        assert_eq!(fields(&t), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(t.weekday, 4);
+
+        let t = DateTime::from_unix(951827696);
+        assert_eq!(fields(&t), (2000, 2, 29, 12, 34, 56));
+        assert_eq!(t.weekday, 2);
+        assert_eq!(t.to_unix(), 951827696);
+
+        assert_eq!(date(1969, 12, 31, 23, 59, 59).to_unix(), 0);
+    }
+
+    #[test]
+    fn run() {
+        let mut clock = Clock::new();
+        clock.set(date(2001, 1, 1, 0, 0, 0));
+        clock.run(21_477_271);
+        assert_eq!(clock.time.second, 0);
+        clock.run(1);
+        assert_eq!(clock.time.second, 1);
+        clock.run(10 * 21_477_272);
+        assert_eq!(clock.time.second, 11);
+    }
+
+    /// Creates save data for `time`, saved `secs_ago` seconds ago.
+    fn data(time: DateTime, secs_ago: u64) -> Vec<u8> {
+        let mut clock = Clock::new();
+        clock.set(time);
+        let mut data = clock.save_data();
+        let saved_at = DateTime::now().to_unix() - secs_ago;
+        for i in 0..8 {
+            data[8 + i] = (saved_at >> (i * 8)) as u8;
+        }
+        data
+    }
+
+    #[test]
+    fn save_data() {
+        let mut clock = Clock::new();
+        clock.set(DateTime { weekday: 3, ..date(2017, 8, 23, 12, 34, 56) });
+        let data = clock.save_data();
+        assert_eq!(data.len(), 16);
+        assert_eq!(data[..8], [0xe1, 0x07, 8, 23, 12, 34, 56, 3]);
+        let saved_at = data[8..].iter().rev().fold(0, |acc, &b| acc << 8 | b as u64);
+        assert!(DateTime::now().to_unix() - saved_at <= 1);
+    }
+
+    #[test]
+    fn load_data() {
+        let mut clock = Clock::new();
+        assert!(!clock.load_data(&[0; 15]));
+
+        // The seconds that passed since saving are added (the host clock may tick meanwhile)
+        let start = date(2000, 1, 1, 0, 0, 0);
+        assert!(clock.load_data(&data(start, 90)));
+        let elapsed = clock.time.to_unix() - start.to_unix();
+        assert!(elapsed == 90 || elapsed == 91, "{}", elapsed);
+
+        assert!(clock.load_data(&data(start, 2 * 86400 + 5)));
+        let elapsed = clock.time.to_unix() - start.to_unix();
+        assert!(elapsed == 2 * 86400 + 5 || elapsed == 2 * 86400 + 6, "{}", elapsed);
+        assert_eq!(clock.time.weekday, 1);
+
+        // Invalid fields are kept as long as there are few seconds to catch up on
+        assert!(clock.load_data(&data(date(2000, 1, 1, 0, 0, 75), 0)));
+        assert!(clock.time.second >= 75);
+    }
+}
@@ -0,0 +1,1102 @@
+//! SA-1 coprocessor emulation
+//!
+//! The SA-1 contains a second 65816 core running at 10.74 MHz, 2 KB of internal RAM (I-RAM), a
+//! memory controller (Super MMC) that maps 1 MB blocks of the ROM into the address space of both
+//! CPUs, a DMA controller that can convert bitmaps to SNES characters, and an arithmetic unit.
+//! The cartridge RAM is called BW-RAM and can also be viewed as a bitmap with one pixel per byte
+//! by the SA-1 CPU.
+//!
+//! Both CPUs access the SA-1 registers at `$2200-$23FF`. Registers at `$2200-$22FF` are written,
+//! `$2300-$23FF` are read. The SA-1 timer and the variable-length bit processing registers are not
+//! yet implemented.
+//!
+//! The ROM image and BW-RAM are owned by the `Rom`. They are lent to the SA-1 (by swapping them
+//! into `Sa1Bus`) whenever it needs to access them.
+
+use diagnostics::Diagnostics;
+
+use wdc65816::{Cpu, Mem};
+
+use std::cmp;
+use std::mem;
+
+/// Master clock cycles per SA-1 CPU cycle (it runs at 10.74 MHz)
+const SA1_CYCLE: i32 = 2;
+
+/// Value read from `$230E` (VC - Version Code)
+const VERSION: u8 = 0x23;
+
+pub const IRAM_SIZE: usize = 2 * 1024;
+byte_array!(pub Iram[IRAM_SIZE] with save state please);
+// Bitmap register file (`$2240-$224F`)
+byte_array!(Brf[16] with save state please);
+
+/// The SA-1 chip
+#[derive(Clone)]
+pub struct Sa1 {
+    cpu: Cpu<Sa1Bus>,
+    /// Master clock cycles the SA-1 CPU has to run to catch up with the SNES CPU
+    master_cy_debt: i32,
+}
+
+impl_save_state!(Sa1 { cpu, master_cy_debt } ignore {});
+
+impl Sa1 {
+    pub fn new() -> Self {
+        Sa1 {
+            cpu: Cpu::new(Sa1Bus::new()),
+            master_cy_debt: 0,
+        }
+    }
+
+    /// Swaps the ROM image and BW-RAM into the SA-1 bus while `f` runs.
+    fn lend<T, F>(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, f: F) -> T
+    where F: FnOnce(&mut Sa1) -> T {
+        mem::swap(rom, &mut self.cpu.mem.rom);
+        mem::swap(bwram, &mut self.cpu.mem.bwram);
+        let result = f(self);
+        mem::swap(rom, &mut self.cpu.mem.rom);
+        mem::swap(bwram, &mut self.cpu.mem.bwram);
+        result
+    }
+
+    /// Runs the SA-1 CPU for (about) `master_cy` master clock cycles.
+    pub fn run(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, master_cy: u32) {
+        // CCNT: The CPU is stopped while in reset or waiting
+        if self.cpu.mem.ccnt & 0x60 != 0 {
+            self.master_cy_debt = 0;
+            return;
+        }
+
+        self.master_cy_debt += master_cy as i32;
+        if self.master_cy_debt <= 0 { return; }
+
+        self.lend(rom, bwram, |sa1| {
+            while sa1.master_cy_debt > 0 {
+                if mem::replace(&mut sa1.cpu.mem.nmi_pending, false) {
+                    sa1.cpu.trigger_nmi();
+                } else if sa1.cpu.mem.sa1_irq() {
+                    sa1.cpu.trigger_irq();
+                }
+
+                // `dispatch` returns 0 while waiting for an interrupt
+                let cy = cmp::max(1, sa1.cpu.dispatch()) as i32;
+                sa1.master_cy_debt -= cy * SA1_CYCLE;
+            }
+        });
+    }
+
+    /// Returns `true` if the SA-1 asserts the IRQ line of the SNES CPU.
+    pub fn irq_pending(&self) -> bool { self.cpu.mem.snes_irq() }
+
+    /// Returns the diagnostics that weren't collected by the `Snes` yet.
+    pub fn diagnostics_mut(&mut self) -> &mut Diagnostics { &mut self.cpu.mem.diagnostics }
+
+    /// Performs a load from the SNES CPU.
+    pub fn load(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, bank: u8, addr: u16) -> u8 {
+        self.lend(rom, bwram, |sa1| {
+            let target = sa1.cpu.mem.snes_target(bank, addr);
+            sa1.cpu.mem.load_target(target, Side::Snes)
+        })
+    }
+
+    /// Performs a store from the SNES CPU.
+    pub fn store(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, bank: u8, addr: u16,
+                 value: u8) {
+        self.lend(rom, bwram, |sa1| {
+            match sa1.cpu.mem.snes_target(bank, addr) {
+                Target::Reg(0x2200) => {
+                    // CCNT: Releasing the reset line restarts the SA-1 CPU at the vector in CRV
+                    let was_reset = sa1.cpu.mem.ccnt & 0x20 != 0;
+                    sa1.cpu.mem.write_reg(0x2200, value);
+                    if was_reset && value & 0x20 == 0 {
+                        sa1.cpu.reset();
+                        sa1.master_cy_debt = 0;
+                    }
+                }
+                target => sa1.cpu.mem.store_target(target, value, Side::Snes),
+            }
+        })
+    }
+
+    /// Reads the byte the SNES CPU would see at `bank:addr` without side effects. Returns `None`
+    /// for registers and unmapped addresses.
+    pub fn peek(&self, rom: &[u8], bwram: &[u8], bank: u8, addr: u16) -> Option<u8> {
+        let bus = &self.cpu.mem;
+        match bus.snes_target(bank, addr) {
+            Target::Rom(offset) => mirror(rom, offset).map(|i| rom[i]),
+            Target::Bwram(offset) => mirror(bwram, offset).map(|i| bwram[i]),
+            Target::Iram(offset) => Some(bus.iram[offset]),
+            Target::Value(value) => Some(value),
+            Target::Bitmap(_) | Target::Reg(_) | Target::Unmapped => None,
+        }
+    }
+
+    /// Overwrites the memory the SNES CPU sees at `bank:addr`, ignoring write protection. Returns
+    /// `false` if no memory is mapped there.
+    pub fn poke(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, bank: u8, addr: u16,
+                value: u8) -> bool {
+        match self.cpu.mem.snes_target(bank, addr) {
+            Target::Rom(offset) => match mirror(rom, offset) {
+                Some(i) => { rom[i] = value; true }
+                None => false,
+            },
+            Target::Bwram(offset) => match mirror(bwram, offset) {
+                Some(i) => { bwram[i] = value; true }
+                None => false,
+            },
+            Target::Iram(offset) => {
+                self.cpu.mem.iram[offset] = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the offset into the ROM image the SNES CPU accesses at `bank:addr` (according to
+    /// the current Super MMC configuration).
+    pub fn rom_offset(&self, rom_len: usize, bank: u8, addr: u16) -> Option<usize> {
+        match self.cpu.mem.snes_target(bank, addr) {
+            Target::Rom(offset) if rom_len != 0 => Some(offset % rom_len),
+            _ => None,
+        }
+    }
+
+    /// Handles a load from `$2200-$3FFF` (in the system banks). Returns `None` if the SA-1 doesn't
+    /// map anything there.
+    pub fn load_io(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, addr: u16) -> Option<u8> {
+        match addr {
+            0x2200 ... 0x23ff | 0x3000 ... 0x37ff => Some(self.load(rom, bwram, 0x00, addr)),
+            _ => None,
+        }
+    }
+
+    /// Handles a store to `$2200-$3FFF` (in the system banks). Returns `false` if the SA-1 doesn't
+    /// map anything there.
+    pub fn store_io(&mut self, rom: &mut Vec<u8>, bwram: &mut Vec<u8>, addr: u16, value: u8)
+    -> bool {
+        match addr {
+            0x2200 ... 0x23ff | 0x3000 ... 0x37ff => {
+                self.store(rom, bwram, 0x00, addr, value);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns the index `offset` is mirrored to in `mem`, or `None` if `mem` is empty.
+fn mirror(mem: &[u8], offset: usize) -> Option<usize> {
+    if mem.is_empty() { None } else { Some(offset % mem.len()) }
+}
+
+/// Returns the low or high byte of an interrupt vector register, depending on `addr`.
+fn vector_byte(vector: u16, addr: u16) -> u8 {
+    if addr & 1 == 0 { vector as u8 } else { (vector >> 8) as u8 }
+}
+
+/// The CPU performing an access (they see different memory maps and write protection settings)
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Snes,
+    Sa1,
+}
+
+/// What an address is mapped to
+#[derive(Clone, Copy)]
+enum Target {
+    /// Offset into the ROM image (not yet mirrored to its size)
+    Rom(usize),
+    /// Offset into BW-RAM (not yet mirrored to its size)
+    Bwram(usize),
+    /// Pixel index in the bitmap view of BW-RAM
+    Bitmap(usize),
+    /// Offset into I-RAM
+    Iram(usize),
+    /// SA-1 register
+    Reg(u16),
+    /// A fixed value (used for the interrupt vectors that are replaced by registers)
+    Value(u8),
+    Unmapped,
+}
+
+/// The memory bus of the SA-1 CPU. Also contains the SA-1 registers, which are accessible from both
+/// CPUs.
+#[derive(Clone)]
+pub struct Sa1Bus {
+    /// The ROM image, only valid while lent by the `Rom` (empty otherwise)
+    rom: Vec<u8>,
+    /// The cartridge RAM, only valid while lent by the `Rom` (empty otherwise)
+    bwram: Vec<u8>,
+    iram: Iram,
+
+    /// `$2200` CCNT - SA-1 CPU control
+    /// `irwn mmmm`
+    /// * `i`: IRQ to the SA-1
+    /// * `r`: SA-1 reset
+    /// * `w`: SA-1 wait
+    /// * `n`: NMI to the SA-1
+    /// * `m`: Message to the SA-1
+    ccnt: u8,
+    /// `$2201` SIE - SNES interrupt enable (`i-c-----`: IRQ from SA-1, character conversion IRQ)
+    sie: u8,
+    /// `$2203/$2204` CRV - SA-1 reset vector
+    crv: u16,
+    /// `$2205/$2206` CNV - SA-1 NMI vector
+    cnv: u16,
+    /// `$2207/$2208` CIV - SA-1 IRQ vector
+    civ: u16,
+    /// `$2209` SCNT - SNES CPU control
+    /// `iv-n mmmm`
+    /// * `i`: IRQ to the SNES
+    /// * `v`: Use SIV as the SNES IRQ vector
+    /// * `n`: Use SNV as the SNES NMI vector
+    /// * `m`: Message to the SNES
+    scnt: u8,
+    /// `$220A` CIE - SA-1 interrupt enable (`itdn----`: IRQ from SNES, timer, DMA end, NMI)
+    cie: u8,
+    /// `$220C/$220D` SNV - SNES NMI vector
+    snv: u16,
+    /// `$220E/$220F` SIV - SNES IRQ vector
+    siv: u16,
+    /// `$2220-$2223` CXB, DXB, EXB, FXB - Super MMC bank registers
+    /// `m---- bbb`
+    /// * `m`: Map block `b` to the LoROM area (otherwise, the default block is mapped there)
+    /// * `b`: 1 MB ROM block mapped to the HiROM area (`$C0-$FF`)
+    mmc: [u8; 4],
+    /// `$2224` BMAPS - BW-RAM block mapped to `$6000-$7FFF` for the SNES
+    bmaps: u8,
+    /// `$2225` BMAP - BW-RAM block mapped to `$6000-$7FFF` for the SA-1 (bit 7 selects the bitmap
+    /// view)
+    bmap: u8,
+    /// `$2226` SBWE - SNES BW-RAM write enable (bit 7)
+    sbwe: u8,
+    /// `$2227` CBWE - SA-1 BW-RAM write enable (bit 7)
+    cbwe: u8,
+    /// `$2228` BWPA - Size of the write-protected BW-RAM area (`256 << n` bytes)
+    bwpa: u8,
+    /// `$2229` SIWP - SNES I-RAM write enable (one bit per 256 Byte page)
+    siwp: u8,
+    /// `$222A` CIWP - SA-1 I-RAM write enable (one bit per 256 Byte page)
+    ciwp: u8,
+    /// `$2230` DCNT - DMA control
+    /// `e-ct -dss`
+    /// * `e`: DMA enable
+    /// * `c`: Character conversion
+    /// * `t`: Character conversion type (1 = type 1, 0 = type 2)
+    /// * `d`: Destination (0 = I-RAM, 1 = BW-RAM)
+    /// * `s`: Source (0 = ROM, 1 = BW-RAM, 2 = I-RAM)
+    dcnt: u8,
+    /// `$2231` CDMA - Character conversion DMA parameters
+    /// `e--s sscc`
+    /// * `e`: End of character conversion (write only)
+    /// * `s`: Number of characters per line of the bitmap (`1 << s`)
+    /// * `c`: Color depth (0 = 8 bpp, 1 = 4 bpp, 2 = 2 bpp)
+    cdma: u8,
+    /// `$2232-$2234` SDA - DMA source address
+    sda: u32,
+    /// `$2235-$2237` DDA - DMA destination address
+    dda: u32,
+    /// `$2238/$2239` DTC - DMA byte count
+    dtc: u16,
+    /// `$223F` BBF - Bitmap format (bit 7: 0 = 4 bpp, 1 = 2 bpp)
+    bbf: u8,
+    brf: Brf,
+    /// The line of the next character written by type 2 character conversion (0-15)
+    cc2_line: u8,
+    /// Set while type 1 character conversion is active (SNES reads from BW-RAM return converted
+    /// characters)
+    cc1_active: bool,
+    /// `$2250` MCNT - Arithmetic control (0 = multiply, 1 = divide, 2 = multiply and accumulate)
+    mcnt: u8,
+    /// `$2251/$2252` MA - Multiplicand / Dividend
+    ma: u16,
+    /// `$2253/$2254` MB - Multiplier / Divisor
+    mb: u16,
+    /// `$2306-$230A` MR - Arithmetic result (40 bits)
+    mr: u64,
+    /// `$230B` OF - Arithmetic overflow flag
+    overflow: bool,
+
+    /// SFR bit 7: IRQ from the SA-1 to the SNES
+    snes_irq_flag: bool,
+    /// SFR bit 5: Character conversion IRQ to the SNES
+    snes_cc_irq_flag: bool,
+    /// CFR bit 7: IRQ from the SNES to the SA-1
+    sa1_irq_flag: bool,
+    /// CFR bit 6: Timer IRQ
+    sa1_timer_irq_flag: bool,
+    /// CFR bit 5: DMA end IRQ
+    sa1_dma_irq_flag: bool,
+    /// CFR bit 4: NMI from the SNES to the SA-1
+    sa1_nmi_flag: bool,
+    /// Set when the SNES sends an NMI to the SA-1 that hasn't been handled yet
+    nmi_pending: bool,
+
+    /// Diagnostics that weren't collected by the `Snes` yet
+    diagnostics: Diagnostics,
+}
+
+impl_save_state!(Sa1Bus {
+    iram, ccnt, sie, crv, cnv, civ, scnt, cie, snv, siv, mmc, bmaps, bmap, sbwe, cbwe, bwpa, siwp,
+    ciwp, dcnt, cdma, sda, dda, dtc, bbf, brf, cc2_line, cc1_active, mcnt, ma, mb, mr, overflow,
+    snes_irq_flag, snes_cc_irq_flag, sa1_irq_flag, sa1_timer_irq_flag, sa1_dma_irq_flag,
+    sa1_nmi_flag, nmi_pending
+} ignore { rom, bwram, diagnostics });
+
+impl Sa1Bus {
+    fn new() -> Self {
+        Sa1Bus {
+            rom: Vec::new(),
+            bwram: Vec::new(),
+            iram: Iram::default(),
+            // The SA-1 is held in reset until the SNES releases it
+            ccnt: 0x20,
+            sie: 0,
+            crv: 0,
+            cnv: 0,
+            civ: 0,
+            scnt: 0,
+            cie: 0,
+            snv: 0,
+            siv: 0,
+            mmc: [0, 1, 2, 3],
+            bmaps: 0,
+            bmap: 0,
+            sbwe: 0,
+            cbwe: 0,
+            bwpa: 0xff,
+            siwp: 0,
+            ciwp: 0,
+            dcnt: 0,
+            cdma: 0,
+            sda: 0,
+            dda: 0,
+            dtc: 0,
+            bbf: 0,
+            brf: Brf::default(),
+            cc2_line: 0,
+            cc1_active: false,
+            mcnt: 0,
+            ma: 0,
+            mb: 0,
+            mr: 0,
+            overflow: false,
+            snes_irq_flag: false,
+            snes_cc_irq_flag: false,
+            sa1_irq_flag: false,
+            sa1_timer_irq_flag: false,
+            sa1_dma_irq_flag: false,
+            sa1_nmi_flag: false,
+            nmi_pending: false,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    fn snes_irq(&self) -> bool {
+        (self.snes_irq_flag && self.sie & 0x80 != 0) ||
+        (self.snes_cc_irq_flag && self.sie & 0x20 != 0)
+    }
+
+    fn sa1_irq(&self) -> bool {
+        (self.sa1_irq_flag && self.cie & 0x80 != 0) ||
+        (self.sa1_timer_irq_flag && self.cie & 0x40 != 0) ||
+        (self.sa1_dma_irq_flag && self.cie & 0x20 != 0)
+    }
+
+    /// Translates an address in one of the ROM areas using the Super MMC registers.
+    fn rom_offset(&self, bank: u8, addr: u16) -> Option<usize> {
+        match bank {
+            0x00 ... 0x3f | 0x80 ... 0xbf if addr >= 0x8000 => {
+                // LoROM area: `$00-$1F` uses CXB, `$20-$3F` DXB, `$80-$9F` EXB, `$A0-$BF` FXB
+                let slot = (bank as usize >> 5 & 1) | (bank as usize >> 6 & 2);
+                let reg = self.mmc[slot];
+                let block = if reg & 0x80 != 0 { reg as usize & 0x07 } else { slot };
+                Some(block << 20 | (bank as usize & 0x1f) << 15 | (addr as usize & 0x7fff))
+            }
+            0xc0 ... 0xff => {
+                // HiROM area: `$C0-$CF` uses CXB, `$D0-$DF` DXB, ...
+                let block = self.mmc[bank as usize >> 4 & 3] as usize & 0x07;
+                Some(block << 20 | (bank as usize & 0x0f) << 16 | addr as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolves an address accessed by the SNES CPU.
+    fn snes_target(&self, bank: u8, addr: u16) -> Target {
+        match (bank, addr) {
+            (0x00 ... 0x3f, 0x2200 ... 0x23ff) | (0x80 ... 0xbf, 0x2200 ... 0x23ff) => {
+                Target::Reg(addr)
+            }
+            (0x00 ... 0x3f, 0x3000 ... 0x37ff) | (0x80 ... 0xbf, 0x3000 ... 0x37ff) => {
+                Target::Iram(addr as usize & 0x7ff)
+            }
+            (0x00 ... 0x3f, 0x6000 ... 0x7fff) | (0x80 ... 0xbf, 0x6000 ... 0x7fff) => {
+                Target::Bwram((self.bmaps as usize & 0x1f) << 13 | (addr as usize & 0x1fff))
+            }
+            (0x00, 0xffea ... 0xffeb) if self.scnt & 0x10 != 0 => {
+                Target::Value(vector_byte(self.snv, addr))
+            }
+            (0x00, 0xffee ... 0xffef) if self.scnt & 0x40 != 0 => {
+                Target::Value(vector_byte(self.siv, addr))
+            }
+            (0x40 ... 0x4f, _) => Target::Bwram((bank as usize & 0x0f) << 16 | addr as usize),
+            _ => match self.rom_offset(bank, addr) {
+                Some(offset) => Target::Rom(offset),
+                None => Target::Unmapped,
+            },
+        }
+    }
+
+    /// Resolves an address accessed by the SA-1 CPU.
+    fn sa1_target(&self, bank: u8, addr: u16) -> Target {
+        match (bank, addr) {
+            (0x00 ... 0x3f, 0x0000 ... 0x07ff) | (0x80 ... 0xbf, 0x0000 ... 0x07ff) |
+            (0x00 ... 0x3f, 0x3000 ... 0x37ff) | (0x80 ... 0xbf, 0x3000 ... 0x37ff) => {
+                Target::Iram(addr as usize & 0x7ff)
+            }
+            (0x00 ... 0x3f, 0x2200 ... 0x23ff) | (0x80 ... 0xbf, 0x2200 ... 0x23ff) => {
+                Target::Reg(addr)
+            }
+            (0x00 ... 0x3f, 0x6000 ... 0x7fff) | (0x80 ... 0xbf, 0x6000 ... 0x7fff) => {
+                let offset = addr as usize & 0x1fff;
+                if self.bmap & 0x80 != 0 {
+                    Target::Bitmap((self.bmap as usize & 0x7f) << 13 | offset)
+                } else {
+                    Target::Bwram((self.bmap as usize & 0x1f) << 13 | offset)
+                }
+            }
+            // The SA-1 always takes its vectors from the registers
+            (0x00, 0xfffc ... 0xfffd) => Target::Value(vector_byte(self.crv, addr)),
+            (0x00, 0xffea ... 0xffeb) | (0x00, 0xfffa ... 0xfffb) => {
+                Target::Value(vector_byte(self.cnv, addr))
+            }
+            (0x00, 0xffee ... 0xffef) | (0x00, 0xfffe ... 0xffff) => {
+                Target::Value(vector_byte(self.civ, addr))
+            }
+            (0x40 ... 0x4f, _) => Target::Bwram((bank as usize & 0x0f) << 16 | addr as usize),
+            (0x60 ... 0x6f, _) => Target::Bitmap((bank as usize & 0x0f) << 16 | addr as usize),
+            _ => match self.rom_offset(bank, addr) {
+                Some(offset) => Target::Rom(offset),
+                None => Target::Unmapped,
+            },
+        }
+    }
+
+    fn load_target(&mut self, target: Target, side: Side) -> u8 {
+        match target {
+            Target::Rom(offset) => mirror(&self.rom, offset).map(|i| self.rom[i]).unwrap_or(0),
+            Target::Bwram(offset) if side == Side::Snes && self.cc1_active => {
+                self.cc1_read(offset)
+            }
+            Target::Bwram(offset) => self.read_bwram(offset),
+            Target::Bitmap(pixel) => self.read_bitmap(pixel),
+            Target::Iram(offset) => self.iram[offset],
+            Target::Reg(addr) => self.read_reg(addr),
+            Target::Value(value) => value,
+            Target::Unmapped => {
+                diag!(self.diagnostics, "SA-1: load from unmapped address");
+                0
+            }
+        }
+    }
+
+    fn store_target(&mut self, target: Target, value: u8, side: Side) {
+        match target {
+            Target::Rom(offset) => {
+                diag!(self.diagnostics, "SA-1: attempted to write ${:02X} to ROM offset ${:06X}",
+                    value, offset);
+            }
+            Target::Bwram(offset) => {
+                let enable = if side == Side::Snes { self.sbwe } else { self.cbwe };
+                // The write-protected area at the start of BW-RAM can only be written when
+                // writes are enabled
+                if enable & 0x80 != 0 || offset >= 0x100 << (self.bwpa & 0x0f) {
+                    self.write_bwram(offset, value);
+                }
+            }
+            Target::Bitmap(pixel) => self.write_bitmap(pixel, value),
+            Target::Iram(offset) => {
+                let enable = if side == Side::Snes { self.siwp } else { self.ciwp };
+                if enable & (1 << (offset >> 8)) != 0 {
+                    self.iram[offset] = value;
+                }
+            }
+            Target::Reg(addr) => self.write_reg(addr, value),
+            Target::Value(_) => {}
+            Target::Unmapped => {
+                diag!(self.diagnostics, "SA-1: store of ${:02X} to unmapped address", value)
+            }
+        }
+    }
+
+    fn read_bwram(&self, offset: usize) -> u8 {
+        mirror(&self.bwram, offset).map(|i| self.bwram[i]).unwrap_or(0)
+    }
+
+    fn write_bwram(&mut self, offset: usize, value: u8) {
+        if let Some(i) = mirror(&self.bwram, offset) {
+            self.bwram[i] = value;
+        }
+    }
+
+    /// Returns `(bits per pixel, pixels per byte)` of the bitmap view.
+    fn bitmap_format(&self) -> (usize, usize) {
+        if self.bbf & 0x80 != 0 { (2, 4) } else { (4, 2) }
+    }
+
+    fn read_bitmap(&self, pixel: usize) -> u8 {
+        let (bits, per_byte) = self.bitmap_format();
+        let byte = self.read_bwram(pixel / per_byte);
+        (byte >> ((pixel % per_byte) * bits)) & ((1 << bits) - 1)
+    }
+
+    fn write_bitmap(&mut self, pixel: usize, value: u8) {
+        let (bits, per_byte) = self.bitmap_format();
+        let shift = (pixel % per_byte) * bits;
+        let mask = ((1 << bits) - 1) << shift;
+        let byte = self.read_bwram(pixel / per_byte);
+        self.write_bwram(pixel / per_byte, (byte & !mask) | ((value << shift) & mask));
+    }
+
+    fn read_reg(&mut self, addr: u16) -> u8 {
+        match addr {
+            // SFR - SNES CPU flags
+            0x2300 => {
+                (if self.snes_irq_flag { 0x80 } else { 0 }) |
+                (self.scnt & 0x40) |
+                (if self.snes_cc_irq_flag { 0x20 } else { 0 }) |
+                (self.scnt & 0x1f)
+            }
+            // CFR - SA-1 CPU flags
+            0x2301 => {
+                (if self.sa1_irq_flag { 0x80 } else { 0 }) |
+                (if self.sa1_timer_irq_flag { 0x40 } else { 0 }) |
+                (if self.sa1_dma_irq_flag { 0x20 } else { 0 }) |
+                (if self.sa1_nmi_flag { 0x10 } else { 0 }) |
+                (self.ccnt & 0x0f)
+            }
+            0x2302 ... 0x2305 => {
+                diag!(self.diagnostics, "SA-1 timer is not implemented (read from ${:04X})", addr);
+                0
+            }
+            0x2306 ... 0x230a => (self.mr >> ((addr - 0x2306) * 8)) as u8,
+            0x230b => if self.overflow { 0x80 } else { 0 },
+            0x230c | 0x230d => {
+                diag!(self.diagnostics, "SA-1 variable-length bit processing is not implemented");
+                0
+            }
+            0x230e => VERSION,
+            _ => {
+                diag!(self.diagnostics, "SA-1: read from invalid register ${:04X}", addr);
+                0
+            }
+        }
+    }
+
+    fn write_reg(&mut self, addr: u16, value: u8) {
+        fn set_lo(reg: &mut u16, value: u8) { *reg = (*reg & 0xff00) | value as u16 }
+        fn set_hi(reg: &mut u16, value: u8) { *reg = (*reg & 0x00ff) | (value as u16) << 8 }
+
+        match addr {
+            0x2200 => {
+                if value & 0x80 != 0 { self.sa1_irq_flag = true; }
+                if value & 0x10 != 0 {
+                    self.sa1_nmi_flag = true;
+                    if self.cie & 0x10 != 0 { self.nmi_pending = true; }
+                }
+                self.ccnt = value;
+            }
+            0x2201 => self.sie = value,
+            // SIC - SNES interrupt clear
+            0x2202 => {
+                if value & 0x80 != 0 { self.snes_irq_flag = false; }
+                if value & 0x20 != 0 { self.snes_cc_irq_flag = false; }
+            }
+            0x2203 => set_lo(&mut self.crv, value),
+            0x2204 => set_hi(&mut self.crv, value),
+            0x2205 => set_lo(&mut self.cnv, value),
+            0x2206 => set_hi(&mut self.cnv, value),
+            0x2207 => set_lo(&mut self.civ, value),
+            0x2208 => set_hi(&mut self.civ, value),
+            0x2209 => {
+                if value & 0x80 != 0 { self.snes_irq_flag = true; }
+                self.scnt = value;
+            }
+            0x220a => self.cie = value,
+            // CIC - SA-1 interrupt clear
+            0x220b => {
+                if value & 0x80 != 0 { self.sa1_irq_flag = false; }
+                if value & 0x40 != 0 { self.sa1_timer_irq_flag = false; }
+                if value & 0x20 != 0 { self.sa1_dma_irq_flag = false; }
+                if value & 0x10 != 0 { self.sa1_nmi_flag = false; }
+            }
+            0x220c => set_lo(&mut self.snv, value),
+            0x220d => set_hi(&mut self.snv, value),
+            0x220e => set_lo(&mut self.siv, value),
+            0x220f => set_hi(&mut self.siv, value),
+            0x2210 ... 0x2215 => if value != 0 {
+                diag!(self.diagnostics, "SA-1 timer is not implemented (write to ${:04X})", addr);
+            },
+            0x2220 ... 0x2223 => self.mmc[addr as usize - 0x2220] = value,
+            0x2224 => self.bmaps = value,
+            0x2225 => self.bmap = value,
+            0x2226 => self.sbwe = value,
+            0x2227 => self.cbwe = value,
+            0x2228 => self.bwpa = value,
+            0x2229 => self.siwp = value,
+            0x222a => self.ciwp = value,
+            0x2230 => {
+                self.dcnt = value;
+                if value & 0x80 == 0 { self.cc2_line = 0; }
+            }
+            0x2231 => {
+                self.cdma = value & 0x1f;
+                if value & 0x80 != 0 { self.cc1_active = false; }
+            }
+            0x2232 => self.sda = (self.sda & 0xffff00) | value as u32,
+            0x2233 => self.sda = (self.sda & 0xff00ff) | (value as u32) << 8,
+            0x2234 => self.sda = (self.sda & 0x00ffff) | (value as u32) << 16,
+            0x2235 => self.dda = (self.dda & 0xffff00) | value as u32,
+            0x2236 => {
+                self.dda = (self.dda & 0xff00ff) | (value as u32) << 8;
+                if self.dcnt & 0x80 != 0 {
+                    match self.dcnt & 0x34 {
+                        // Normal DMA to I-RAM starts here
+                        0x00 => self.dma_normal(),
+                        // Type 1 character conversion
+                        0x30 => {
+                            self.cc1_active = true;
+                            self.snes_cc_irq_flag = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            0x2237 => {
+                self.dda = (self.dda & 0x00ffff) | (value as u32) << 16;
+                // Normal DMA to BW-RAM starts here
+                if self.dcnt & 0xa4 == 0x84 { self.dma_normal(); }
+            }
+            0x2238 => set_lo(&mut self.dtc, value),
+            0x2239 => set_hi(&mut self.dtc, value),
+            0x223f => self.bbf = value,
+            0x2240 ... 0x224f => {
+                let index = addr as usize - 0x2240;
+                self.brf[index] = value;
+                // Type 2 character conversion converts a line after 8 pixels were written
+                if index & 7 == 7 && self.dcnt & 0xb0 == 0xa0 {
+                    self.cc2_line();
+                }
+            }
+            0x2250 => {
+                self.mcnt = value & 0x03;
+                // Selecting multiply-and-accumulate clears the result
+                if value & 0x02 != 0 { self.mr = 0; }
+            }
+            0x2251 => set_lo(&mut self.ma, value),
+            0x2252 => set_hi(&mut self.ma, value),
+            0x2253 => set_lo(&mut self.mb, value),
+            0x2254 => {
+                set_hi(&mut self.mb, value);
+                self.arithmetic();
+            }
+            0x2258 ... 0x225b => {
+                diag!(self.diagnostics, "SA-1 variable-length bit processing is not implemented");
+            }
+            _ => diag!(self.diagnostics, "SA-1: write of ${:02X} to invalid register ${:04X}",
+                value, addr),
+        }
+    }
+
+    /// Performs the operation selected in MCNT (started by writing the high byte of MB).
+    fn arithmetic(&mut self) {
+        let ma = self.ma as i16 as i64;
+        match self.mcnt {
+            0 => {
+                // Signed multiplication
+                self.mr = (ma * self.mb as i16 as i64) as u32 as u64;
+            }
+            1 => {
+                // Signed dividend, unsigned divisor. The remainder is always positive.
+                self.mr = if self.mb == 0 {
+                    0
+                } else {
+                    let divisor = self.mb as i64;
+                    let remainder = (ma % divisor + divisor) % divisor;
+                    let quotient = (ma - remainder) / divisor;
+                    (remainder as u16 as u64) << 16 | quotient as u16 as u64
+                };
+                self.ma = 0;
+            }
+            _ => {
+                // Multiply and accumulate into the 40-bit (signed) result
+                let acc = ((self.mr << 24) as i64) >> 24;
+                let sum = acc + ma * self.mb as i16 as i64;
+                self.overflow = sum < -(1 << 39) || sum >= 1 << 39;
+                self.mr = sum as u64 & ((1 << 40) - 1);
+            }
+        }
+        self.mb = 0;
+    }
+
+    /// Performs a normal (non-converting) DMA transfer.
+    fn dma_normal(&mut self) {
+        for i in 0..self.dtc as u32 {
+            let src = self.sda.wrapping_add(i) & 0xffffff;
+            let value = match self.dcnt & 0x03 {
+                0 => match self.rom_offset((src >> 16) as u8, src as u16) {
+                    Some(offset) => mirror(&self.rom, offset).map(|i| self.rom[i]).unwrap_or(0),
+                    None => 0,
+                },
+                1 => self.read_bwram(src as usize),
+                2 => self.iram[src as usize & 0x7ff],
+                _ => {
+                    diag!(self.diagnostics, "SA-1: invalid DMA source (DCNT = ${:02X})", self.dcnt);
+                    0
+                }
+            };
+
+            let dest = self.dda.wrapping_add(i) as usize;
+            if self.dcnt & 0x04 == 0 {
+                self.iram[dest & 0x7ff] = value;
+            } else {
+                self.write_bwram(dest, value);
+            }
+        }
+
+        self.sa1_dma_irq_flag = true;
+    }
+
+    /// Returns `(color depth setting, characters per bitmap line (log2))` from CDMA.
+    fn cc_params(&self) -> (usize, usize) {
+        (cmp::min(self.cdma & 0x03, 2) as usize, cmp::min((self.cdma >> 2) & 0x07, 5) as usize)
+    }
+
+    /// Handles a read of the SNES from BW-RAM during type 1 character conversion.
+    ///
+    /// The SNES reads characters from the bitmap at SDA (usually via DMA). Whenever it starts
+    /// reading a new character, it is converted from the bitmap into I-RAM at DDA, which is where
+    /// the returned data comes from.
+    fn cc1_read(&mut self, offset: usize) -> u8 {
+        let (depth, width) = self.cc_params();
+        let char_mask = (1 << (6 - depth)) - 1;
+        if offset & char_mask == 0 && !self.bwram.is_empty() {
+            // Bits per pixel (= bytes per line of a character)
+            let bpp = 2 << (2 - depth);
+            // Bytes per line of the bitmap
+            let bitmap_line = bpp << width;
+            let bw_mask = self.bwram.len() - 1;
+            let tile = (offset.wrapping_sub(self.sda as usize) & bw_mask) >> (6 - depth);
+            let (tx, ty) = (tile & ((1 << width) - 1), tile >> width);
+            let mut bw_addr = self.sda as usize + ty * 8 * bitmap_line + tx * bpp;
+
+            for y in 0..8 {
+                let mut data: u64 = 0;
+                for byte in 0..bpp {
+                    data |= (self.read_bwram(bw_addr + byte) as u64) << (byte * 8);
+                }
+                bw_addr += bitmap_line;
+
+                // Split the packed pixels into bitplanes
+                let mut planes = [0u8; 8];
+                for x in 0..8 {
+                    for plane in planes.iter_mut().take(bpp) {
+                        *plane |= ((data & 1) as u8) << (7 - x);
+                        data >>= 1;
+                    }
+                }
+
+                for (byte, &plane) in planes.iter().enumerate().take(bpp) {
+                    let addr = self.dda as usize + (y << 1) + ((byte & 6) << 3) + (byte & 1);
+                    self.iram[addr & 0x7ff] = plane;
+                }
+            }
+        }
+
+        self.iram[(self.dda as usize + (offset & char_mask)) & 0x7ff]
+    }
+
+    /// Converts a line of 8 pixels from the bitmap register file into I-RAM (type 2 character
+    /// conversion).
+    fn cc2_line(&mut self) {
+        let (depth, _) = self.cc_params();
+        let bpp = 2 << (2 - depth);
+        let line = self.cc2_line as usize;
+        let brf_start = (line & 1) * 8;
+
+        let mut addr = self.dda as usize & 0x7ff & !((1 << (7 - depth)) - 1);
+        addr += (line & 8) * bpp + (line & 7) * 2;
+        for byte in 0..bpp {
+            let mut plane = 0;
+            for bit in 0..8 {
+                plane |= ((self.brf[brf_start + bit] >> byte) & 1) << (7 - bit);
+            }
+            self.iram[(addr + ((byte & 6) << 3) + (byte & 1)) & 0x7ff] = plane;
+        }
+
+        self.cc2_line = (self.cc2_line + 1) & 15;
+    }
+}
+
+impl Mem for Sa1Bus {
+    fn load(&mut self, bank: u8, addr: u16) -> u8 {
+        let target = self.sa1_target(bank, addr);
+        self.load_target(target, Side::Sa1)
+    }
+
+    fn store(&mut self, bank: u8, addr: u16, value: u8) {
+        let target = self.sa1_target(bank, addr);
+        self.store_target(target, value, Side::Sa1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sa1;
+
+    /// An SA-1 cartridge, as seen by the SNES CPU
+    struct Cart {
+        sa1: Sa1,
+        rom: Vec<u8>,
+        bwram: Vec<u8>,
+    }
+
+    impl Cart {
+        fn new(rom: Vec<u8>) -> Self {
+            Cart {
+                sa1: Sa1::new(),
+                rom: rom,
+                bwram: vec![0; 0x2000],
+            }
+        }
+
+        fn load(&mut self, bank: u8, addr: u16) -> u8 {
+            self.sa1.load(&mut self.rom, &mut self.bwram, bank, addr)
+        }
+
+        fn store(&mut self, bank: u8, addr: u16, value: u8) {
+            self.sa1.store(&mut self.rom, &mut self.bwram, bank, addr, value)
+        }
+
+        fn store16(&mut self, addr: u16, value: u16) {
+            self.store(0x00, addr, value as u8);
+            self.store(0x00, addr + 1, (value >> 8) as u8);
+        }
+
+        fn run(&mut self, master_cy: u32) {
+            self.sa1.run(&mut self.rom, &mut self.bwram, master_cy)
+        }
+
+        /// Reads the 40-bit arithmetic result (MR).
+        fn mr(&mut self) -> u64 {
+            (0..5).fold(0, |mr, i| mr | (self.load(0x00, 0x2306 + i) as u64) << (i * 8))
+        }
+    }
+
+    #[test]
+    fn super_mmc() {
+        let mut cart = Cart::new(Vec::new());
+        let offset = |cart: &Cart, bank, addr| cart.sa1.rom_offset(0x800000, bank, addr);
+
+        // Default mapping: CXB-FXB select blocks 0-3
+        assert_eq!(offset(&cart, 0x00, 0x8000), Some(0x000000));
+        assert_eq!(offset(&cart, 0x20, 0x8000), Some(0x100000));
+        assert_eq!(offset(&cart, 0x80, 0x8000), Some(0x200000));
+        assert_eq!(offset(&cart, 0xbf, 0xffff), Some(0x3fffff));
+        assert_eq!(offset(&cart, 0xc0, 0x0000), Some(0x000000));
+        assert_eq!(offset(&cart, 0xf0, 0x1234), Some(0x301234));
+        assert_eq!(offset(&cart, 0x00, 0x7fff), None);
+
+        // The HiROM area always uses the selected block, the LoROM area only if bit 7 is set
+        cart.store(0x00, 0x2220, 0x04);
+        cart.store(0x00, 0x2221, 0x85);
+        cart.store(0x00, 0x2222, 0x06);
+        cart.store(0x00, 0x2223, 0x87);
+        assert_eq!(offset(&cart, 0x00, 0x8000), Some(0x000000));
+        assert_eq!(offset(&cart, 0x20, 0x8000), Some(0x500000));
+        assert_eq!(offset(&cart, 0x80, 0x8000), Some(0x200000));
+        assert_eq!(offset(&cart, 0xa0, 0x8000), Some(0x700000));
+        assert_eq!(offset(&cart, 0xc0, 0x0000), Some(0x400000));
+        assert_eq!(offset(&cart, 0xd0, 0x0000), Some(0x500000));
+        assert_eq!(offset(&cart, 0xe0, 0x0000), Some(0x600000));
+        assert_eq!(offset(&cart, 0xff, 0xffff), Some(0x7fffff));
+    }
+
+    #[test]
+    fn multiply() {
+        let mut cart = Cart::new(Vec::new());
+        cart.store(0x00, 0x2250, 0x00);
+        cart.store16(0x2251, -3i16 as u16);
+        cart.store16(0x2253, 1000);
+        assert_eq!(cart.mr(), -3000i32 as u32 as u64);
+
+        cart.store16(0x2253, 0x8000);
+        assert_eq!(cart.mr(), 3 << 15);
+    }
+
+    #[test]
+    fn divide() {
+        let mut cart = Cart::new(Vec::new());
+        cart.store(0x00, 0x2250, 0x01);
+        cart.store16(0x2251, 1000);
+        cart.store16(0x2253, 7);
+        // Remainder in the high word, quotient in the low word
+        assert_eq!(cart.mr(), 6 << 16 | 142);
+
+        // The remainder is positive for negative dividends
+        cart.store16(0x2251, -7i16 as u16);
+        cart.store16(0x2253, 2);
+        assert_eq!(cart.mr(), 1 << 16 | 0xfffc);
+
+        cart.store16(0x2251, 5);
+        cart.store16(0x2253, 0);
+        assert_eq!(cart.mr(), 0);
+    }
+
+    #[test]
+    fn cumulative_sum() {
+        let mut cart = Cart::new(Vec::new());
+        cart.store(0x00, 0x2250, 0x00);
+        cart.store16(0x2251, 2);
+        cart.store16(0x2253, 2);
+        // Selecting multiply-and-accumulate clears the result
+        cart.store(0x00, 0x2250, 0x02);
+        assert_eq!(cart.mr(), 0);
+
+        cart.store16(0x2251, 1000);
+        for _ in 0..3 {
+            cart.store16(0x2253, 1000);
+        }
+        assert_eq!(cart.mr(), 3000000);
+        cart.store16(0x2251, -1i16 as u16);
+        cart.store16(0x2253, 5000);
+        assert_eq!(cart.mr(), 2995000);
+
+        // Negative sums are stored as 40-bit two's complement numbers
+        cart.store16(0x2251, 0x8000);
+        cart.store16(0x2253, 0x7fff);
+        let sum = 2995000 - 32768 * 32767;
+        assert_eq!(cart.mr(), (1 << 40) - (-sum as u64));
+        assert_eq!(cart.load(0x00, 0x230b), 0x00);
+    }
+
+    #[test]
+    fn cumulative_sum_overflow() {
+        let mut cart = Cart::new(Vec::new());
+        cart.store(0x00, 0x2250, 0x02);
+        cart.store16(0x2251, 0x8000);
+        for _ in 0..511 {
+            cart.store16(0x2253, 0x8000);
+        }
+        assert_eq!(cart.mr(), 511 << 30);
+        assert_eq!(cart.load(0x00, 0x230b), 0x00);
+
+        // The result is now 2^39, which doesn't fit
+        cart.store16(0x2253, 0x8000);
+        assert_eq!(cart.load(0x00, 0x230b), 0x80);
+        assert_eq!(cart.mr(), 1 << 39);
+    }
+
+    /// Returns the color of pixel `x`, `y` of the test bitmap.
+    fn pixel(x: usize, y: usize) -> u8 { ((x * 3 + y * 5) & 15) as u8 }
+
+    /// Returns the 4 bpp SNES character containing the test bitmap.
+    fn character() -> Vec<u8> {
+        let mut chr = vec![0; 32];
+        for y in 0..8 {
+            for x in 0..8 {
+                for plane in 0..4 {
+                    let bit = (pixel(x, y) >> plane) & 1;
+                    chr[(plane / 2) * 16 + y * 2 + (plane & 1)] |= bit << (7 - x);
+                }
+            }
+        }
+        chr
+    }
+
+    #[test]
+    fn character_conversion_type1() {
+        let mut cart = Cart::new(Vec::new());
+        // 4 bpp bitmap, 1 character per line, 4 bytes per line at the start of BW-RAM
+        for y in 0..8 {
+            for x in 0..8 {
+                cart.bwram[y * 4 + x / 2] |= pixel(x, y) << (x % 2 * 4);
+            }
+        }
+
+        cart.store(0x00, 0x2231, 0x01);
+        cart.store(0x00, 0x2230, 0xb0);
+        cart.store(0x00, 0x2232, 0x00);
+        cart.store(0x00, 0x2233, 0x00);
+        cart.store(0x00, 0x2234, 0x00);
+        cart.store(0x00, 0x2235, 0x00);
+        cart.store(0x00, 0x2236, 0x31);
+        // The SNES is notified that the conversion can start
+        assert_eq!(cart.load(0x00, 0x2300) & 0x20, 0x20);
+
+        // Reading the bitmap from BW-RAM returns the converted character, which is also stored in
+        // I-RAM
+        let converted: Vec<u8> = (0..32).map(|i| cart.load(0x40, i)).collect();
+        assert_eq!(converted, character());
+        let iram: Vec<u8> = (0..32).map(|i| cart.load(0x00, 0x3100 + i)).collect();
+        assert_eq!(iram, character());
+
+        // After the end of the conversion, BW-RAM is read directly again
+        cart.store(0x00, 0x2231, 0x81);
+        assert_eq!(cart.load(0x40, 0x0000), cart.bwram[0]);
+    }
+
+    #[test]
+    fn character_conversion_type2() {
+        let mut cart = Cart::new(Vec::new());
+        cart.store(0x00, 0x2231, 0x01);
+        cart.store(0x00, 0x2230, 0xa0);
+        cart.store(0x00, 0x2235, 0x00);
+        cart.store(0x00, 0x2236, 0x31);
+
+        // The SA-1 writes the pixels to the bitmap register file, one line at a time
+        for y in 0..8 {
+            for x in 0..8 {
+                cart.store(0x00, 0x2240 + (y % 2 * 8 + x) as u16, pixel(x, y));
+            }
+        }
+        let iram: Vec<u8> = (0..32).map(|i| cart.load(0x00, 0x3100 + i)).collect();
+        assert_eq!(iram, character());
+    }
+
+    #[test]
+    fn reset() {
+        let mut rom = vec![0; 0x8000];
+        // $00:8000: lda #$81 ; sta $2209 (message and IRQ to the SNES) ; bra *
+        rom[..7].copy_from_slice(&[0xa9, 0x81, 0x8d, 0x09, 0x22, 0x80, 0xfe]);
+        // $00:8100: lda #$02 ; sta $2209 ; bra *
+        rom[0x100..0x107].copy_from_slice(&[0xa9, 0x02, 0x8d, 0x09, 0x22, 0x80, 0xfe]);
+        let mut cart = Cart::new(rom);
+        cart.store(0x00, 0x2201, 0x80);
+
+        // The SA-1 starts in reset
+        cart.run(1000);
+        assert_eq!(cart.load(0x00, 0x2300), 0x00);
+        assert!(!cart.sa1.irq_pending());
+
+        // Releasing the reset starts it at CRV
+        cart.store16(0x2203, 0x8000);
+        cart.store(0x00, 0x2200, 0x00);
+        cart.run(1000);
+        assert_eq!(cart.load(0x00, 0x2300), 0x81);
+        assert!(cart.sa1.irq_pending());
+        cart.store(0x00, 0x2202, 0x80);
+        assert!(!cart.sa1.irq_pending());
+
+        // While in reset, it doesn't run. Releasing it again restarts it at the new CRV.
+        cart.store(0x00, 0x2200, 0x20);
+        cart.store16(0x2203, 0x8100);
+        cart.run(1000);
+        assert_eq!(cart.load(0x00, 0x2300), 0x01);
+        cart.store(0x00, 0x2200, 0x00);
+        cart.run(1000);
+        assert_eq!(cart.load(0x00, 0x2300), 0x02);
+    }
+}
@@ -0,0 +1,605 @@
+//! S-DD1 graphics decompression chip
+//!
+//! The S-DD1 maps the first 2 MB of the ROM like a normal LoROM cartridge and switches 1 MB pages
+//! of the ROM into banks `$C0-$FF` (one register per 16 banks). It also decompresses data on the
+//! fly: When a DMA channel enabled in `$4800` and `$4801` reads from the ROM, the S-DD1 returns
+//! decompressed data starting at the DMA source address instead of the raw ROM contents.
+//!
+//! The decompression algorithm was reverse engineered by Andreas Naive. It decodes a Golomb coded
+//! bit stream using context-dependent probability estimation, and outputs 2, 4 or 8 bit planes
+//! (or 8-bit pixels in mode 3).
+
+use diagnostics::Diagnostics;
+
+/// The S-DD1 chip
+#[derive(Clone)]
+pub struct Sdd1 {
+    /// `$4800`: DMA channels that may decompress
+    dma_enable: u8,
+    /// `$4801`: DMA channels that will decompress on their next transfer. Cleared after the
+    /// transfer.
+    dma_ready: u8,
+    /// `$4804-$4807`: 1 MB ROM pages mapped to `$C0-$CF`, `$D0-$DF`, `$E0-$EF` and `$F0-$FF`
+    banks: [u8; 4],
+    /// The DMA channel that's currently transferring decompressed data
+    decompressing: Option<u8>,
+    decompressor: Decompressor,
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+// A DMA transfer always completes in a single CPU instruction, so there's no decompression in
+// progress when a save state is created.
+impl_save_state!(Sdd1 { dma_enable, dma_ready, banks } ignore { decompressing, decompressor,
+    diagnostics });
+
+impl Sdd1 {
+    pub fn new() -> Self {
+        Sdd1 {
+            dma_enable: 0,
+            dma_ready: 0,
+            banks: [0, 1, 2, 3],
+            decompressing: None,
+            decompressor: Decompressor::new(),
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// Performs a load from the SNES CPU.
+    pub fn load(&mut self, rom: &[u8], ram: &[u8], bank: u8, addr: u16) -> u8 {
+        match self.target(bank, addr) {
+            Target::Rom(_) if self.decompressing.is_some() => {
+                let banks = self.banks;
+                self.decompressor.decompress(&Mmc { rom: rom, banks: banks })
+            }
+            Target::Rom(offset) => read_mirrored(rom, offset),
+            Target::Ram(offset) => read_mirrored(ram, offset),
+            Target::Unmapped => {
+                diag!(self.diagnostics, "S-DD1: unmapped load from ${:02X}:{:04X}", bank, addr);
+                0
+            }
+        }
+    }
+
+    /// Performs a store from the SNES CPU.
+    pub fn store(&mut self, ram: &mut [u8], bank: u8, addr: u16, value: u8) {
+        match self.target(bank, addr) {
+            Target::Ram(offset) => {
+                if !ram.is_empty() {
+                    let len = ram.len();
+                    ram[offset % len] = value;
+                }
+            }
+            Target::Rom(_) | Target::Unmapped => {
+                diag!(self.diagnostics, "S-DD1: invalid store of ${:02X} to ${:02X}:{:04X}", value,
+                    bank, addr);
+            }
+        }
+    }
+
+    /// Reads the byte the SNES CPU would see at `bank:addr` (outside of DMA) without side effects.
+    /// Returns `None` for unmapped addresses.
+    pub fn peek(&self, rom: &[u8], ram: &[u8], bank: u8, addr: u16) -> Option<u8> {
+        let (mem, offset) = match self.target(bank, addr) {
+            Target::Rom(offset) => (rom, offset),
+            Target::Ram(offset) => (ram, offset),
+            Target::Unmapped => return None,
+        };
+        if mem.is_empty() { None } else { Some(read_mirrored(mem, offset)) }
+    }
+
+    /// Overwrites the ROM or RAM byte mapped to `bank:addr`. Returns `false` if nothing is mapped
+    /// there.
+    pub fn poke(&mut self, rom: &mut [u8], ram: &mut [u8], bank: u8, addr: u16, value: u8)
+    -> bool {
+        let (mem, offset) = match self.target(bank, addr) {
+            Target::Rom(offset) => (rom, offset),
+            Target::Ram(offset) => (ram, offset),
+            Target::Unmapped => return false,
+        };
+        if mem.is_empty() { return false; }
+        let len = mem.len();
+        mem[offset % len] = value;
+        true
+    }
+
+    /// Returns the offset into the ROM image the SNES CPU accesses at `bank:addr`.
+    pub fn rom_offset(&self, rom_len: usize, bank: u8, addr: u16) -> Option<usize> {
+        match self.target(bank, addr) {
+            Target::Rom(offset) if rom_len != 0 => Some(offset % rom_len),
+            _ => None,
+        }
+    }
+
+    /// Handles a load from the cartridge I/O area. Returns `None` if the S-DD1 doesn't map
+    /// anything there.
+    pub fn load_io(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800 => Some(self.dma_enable),
+            0x4801 => Some(self.dma_ready),
+            0x4804 ... 0x4807 => Some(self.banks[addr as usize - 0x4804]),
+            _ => None,
+        }
+    }
+
+    /// Handles a store to the cartridge I/O area. Returns `false` if the S-DD1 doesn't map
+    /// anything there.
+    pub fn store_io(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x4800 => self.dma_enable = value,
+            0x4801 => self.dma_ready = value,
+            // Unknown, written by Star Ocean
+            0x4802 ... 0x4803 => {}
+            0x4804 ... 0x4807 => self.banks[addr as usize - 0x4804] = value & 0x07,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Called when a DMA transfer reading from `bank:addr` starts on `channel`. If decompression
+    /// is enabled for the channel, all ROM reads until `dma_end` return decompressed data.
+    pub fn dma_start(&mut self, rom: &[u8], channel: u8, bank: u8, addr: u16) {
+        if self.dma_enable & self.dma_ready & (1 << channel) == 0 { return; }
+        let banks = self.banks;
+        self.decompressor.init(&Mmc { rom: rom, banks: banks }, (bank as u32) << 16 | addr as u32);
+        self.decompressing = Some(channel);
+    }
+
+    /// Called when a DMA transfer has finished.
+    pub fn dma_end(&mut self) {
+        if let Some(channel) = self.decompressing.take() {
+            self.dma_ready &= !(1 << channel);
+        }
+    }
+
+    fn target(&self, bank: u8, addr: u16) -> Target {
+        match (bank, addr) {
+            (0x00 ... 0x3f, 0x8000 ... 0xffff) | (0x80 ... 0xbf, 0x8000 ... 0xffff) => {
+                Target::Rom((bank as usize & 0x3f) * 0x8000 + (addr as usize & 0x7fff))
+            }
+            (0x70 ... 0x7d, 0x0000 ... 0x7fff) => {
+                Target::Ram((bank as usize - 0x70) * 0x8000 + addr as usize)
+            }
+            (0xc0 ... 0xff, _) => Target::Rom(mmc_offset(&self.banks, (bank as u32) << 16 |
+                addr as u32)),
+            _ => Target::Unmapped,
+        }
+    }
+}
+
+/// What an address accessed by the SNES CPU is mapped to
+#[derive(Clone, Copy)]
+enum Target {
+    Rom(usize),
+    Ram(usize),
+    Unmapped,
+}
+
+/// Reads `mem[offset]`, mirroring `offset` into `mem`. Returns 0 if `mem` is empty.
+fn read_mirrored(mem: &[u8], offset: usize) -> u8 {
+    if mem.is_empty() { 0 } else { mem[offset % mem.len()] }
+}
+
+/// Translates a 24-bit address to a ROM offset using the bank registers. The decompressor always
+/// reads through the bank registers, even when the DMA source isn't in banks `$C0-$FF`.
+fn mmc_offset(banks: &[u8; 4], addr: u32) -> usize {
+    (banks[(addr as usize >> 20) & 3] as usize) << 20 | (addr as usize & 0xfffff)
+}
+
+/// The ROM as seen by the decompressor
+struct Mmc<'a> {
+    rom: &'a [u8],
+    banks: [u8; 4],
+}
+
+impl<'a> Mmc<'a> {
+    fn read(&self, addr: u32) -> u8 {
+        read_mirrored(self.rom, mmc_offset(&self.banks, addr))
+    }
+}
+
+/// Probability estimation states: Golomb code order, next state after an MPS run and next state
+/// after an LPS
+const EVOLUTION: [(u8, u8, u8); 33] = [
+    (0, 25, 25), (0,  2,  1), (0,  3,  1), (0,  4,  2), (0,  5,  3), (1,  6,  4), (1,  7,  5),
+    (1,  8,  6), (1,  9,  7), (2, 10,  8), (2, 11,  9), (2, 12, 10), (2, 13, 11), (3, 14, 12),
+    (3, 15, 13), (3, 16, 14), (3, 17, 15), (4, 18, 16), (4, 19, 17), (5, 20, 18), (5, 21, 19),
+    (6, 22, 20), (6, 23, 21), (7, 24, 22), (7, 24, 23), (0, 26,  1), (1, 27,  2), (2, 28,  4),
+    (3, 29,  8), (4, 30, 12), (5, 31, 16), (6, 32, 18), (7, 24, 22),
+];
+
+/// Returns the number of MPS bits preceding the LPS encoded in a Golomb code word of order
+/// `order`. `code` is the code word without its leading 1 bit.
+fn lps_run_length(code: u8, order: u8) -> u8 {
+    // The run length is stored inverted, least significant bit first
+    let mut run = 0;
+    for i in 0..order {
+        if code & (1 << i) == 0 {
+            run |= 1 << (order - 1 - i);
+        }
+    }
+    run
+}
+
+/// The S-DD1 decompressor state
+#[derive(Clone)]
+struct Decompressor {
+    // Input manager
+    /// Address of the next input byte
+    offset: u32,
+    /// Number of bits of the current input byte that were already consumed
+    bit_count: u8,
+
+    // Bit generators (one per Golomb code order)
+    /// Remaining MPS bits in the current run
+    mps_count: [u8; 8],
+    /// Whether the current run ends with an LPS
+    lps_pending: [bool; 8],
+
+    // Probability estimation
+    /// State in `EVOLUTION` of each context
+    context_state: [u8; 32],
+    /// The most probable symbol of each context
+    context_mps: [u8; 32],
+
+    // Context model
+    /// Bit plane configuration (`0x00`: 2bpp, `0x40`: 8bpp, `0x80`: 4bpp, `0xC0`: 8-bit pixels)
+    bitplanes: u8,
+    /// Which previous bits make up the context
+    context_bits: u8,
+    bit_number: u8,
+    bitplane: u8,
+    previous_bits: [u16; 8],
+
+    // Output logic
+    r0: u8,
+    r1: u8,
+    r2: u8,
+}
+
+impl Decompressor {
+    fn new() -> Self {
+        Decompressor {
+            offset: 0,
+            bit_count: 0,
+            mps_count: [0; 8],
+            lps_pending: [false; 8],
+            context_state: [0; 32],
+            context_mps: [0; 32],
+            bitplanes: 0,
+            context_bits: 0,
+            bit_number: 0,
+            bitplane: 0,
+            previous_bits: [0; 8],
+            r0: 0,
+            r1: 0,
+            r2: 0,
+        }
+    }
+
+    /// Starts decompressing the data at `addr`.
+    fn init(&mut self, mmc: &Mmc, addr: u32) {
+        // The first 4 bits contain the bit plane and context configuration
+        let header = mmc.read(addr);
+        *self = Decompressor::new();
+        self.offset = addr;
+        self.bit_count = 4;
+        self.bitplanes = header & 0xc0;
+        self.context_bits = header & 0x30;
+        self.bitplane = match self.bitplanes {
+            0x00 => 1,
+            0x40 => 7,
+            0x80 => 3,
+            _ => 0,
+        };
+        self.r0 = 0x01;
+    }
+
+    /// Reads a Golomb code word of order `order` from the input. The first bit is the flag
+    /// indicating whether an LPS follows, the rest (if the flag is set) is left-aligned after it.
+    fn read_codeword(&mut self, mmc: &Mmc, order: u8) -> u8 {
+        let mut code = mmc.read(self.offset) << self.bit_count;
+        self.bit_count += 1;
+        if code & 0x80 != 0 {
+            code |= (mmc.read(self.offset + 1) as u16 >> (9 - self.bit_count)) as u8;
+            self.bit_count += order;
+        }
+        if self.bit_count & 0x08 != 0 {
+            self.offset += 1;
+            self.bit_count &= 0x07;
+        }
+        code
+    }
+
+    /// Gets the next bit from the bit generator for codes of order `order`. Returns the bit and
+    /// whether it ended the current run.
+    fn generate_bit(&mut self, mmc: &Mmc, order: u8) -> (u8, bool) {
+        let o = order as usize;
+        if self.mps_count[o] == 0 && !self.lps_pending[o] {
+            let code = self.read_codeword(mmc, order);
+            if code & 0x80 != 0 {
+                self.lps_pending[o] = true;
+                self.mps_count[o] = lps_run_length(code >> (7 - order), order);
+            } else {
+                self.mps_count[o] = 1 << order;
+            }
+        }
+
+        let bit = if self.mps_count[o] > 0 {
+            self.mps_count[o] -= 1;
+            0
+        } else {
+            self.lps_pending[o] = false;
+            1
+        };
+        (bit, self.mps_count[o] == 0 && !self.lps_pending[o])
+    }
+
+    /// Decodes a bit in the given context.
+    fn probability_bit(&mut self, mmc: &Mmc, context: u8) -> u8 {
+        let c = context as usize;
+        let state = self.context_state[c];
+        let mps = self.context_mps[c];
+        let (order, next_mps, next_lps) = EVOLUTION[state as usize];
+
+        let (bit, end_of_run) = self.generate_bit(mmc, order);
+        if end_of_run {
+            if bit == 1 {
+                if state & 0xfe == 0 { self.context_mps[c] ^= 1; }
+                self.context_state[c] = next_lps;
+            } else {
+                self.context_state[c] = next_mps;
+            }
+        }
+        bit ^ mps
+    }
+
+    /// Decodes the next bit of the current bit plane.
+    fn model_bit(&mut self, mmc: &Mmc) -> u8 {
+        let context = self.next_context();
+        let bit = self.probability_bit(mmc, context);
+        self.push_bit(bit);
+        bit
+    }
+
+    /// Advances to the bit plane of the next bit and returns its context.
+    fn next_context(&mut self) -> u8 {
+        match self.bitplanes {
+            0x00 => self.bitplane ^= 1,
+            0x40 => {
+                self.bitplane ^= 1;
+                if self.bit_number & 0x7f == 0 { self.bitplane = (self.bitplane + 2) & 0x07; }
+            }
+            0x80 => {
+                self.bitplane ^= 1;
+                if self.bit_number & 0x7f == 0 { self.bitplane ^= 2; }
+            }
+            _ => self.bitplane = self.bit_number & 0x07,
+        }
+
+        let prev = self.previous_bits[self.bitplane as usize];
+        (self.bitplane & 1) << 4 | match self.context_bits {
+            0x00 => ((prev & 0x01c0) >> 5) | (prev & 0x0001),
+            0x10 => ((prev & 0x0180) >> 5) | (prev & 0x0001),
+            0x20 => ((prev & 0x00c0) >> 5) | (prev & 0x0001),
+            _ => ((prev & 0x0180) >> 5) | (prev & 0x0003),
+        } as u8
+    }
+
+    /// Records a decoded bit of the current bit plane.
+    fn push_bit(&mut self, bit: u8) {
+        let plane = self.bitplane as usize;
+        self.previous_bits[plane] = self.previous_bits[plane] << 1 | bit as u16;
+        self.bit_number = self.bit_number.wrapping_add(1);
+    }
+
+    /// Returns the next decompressed byte.
+    fn decompress(&mut self, mmc: &Mmc) -> u8 {
+        if self.bitplanes == 0xc0 {
+            // 8-bit pixels, least significant bit first
+            let mut value = 0;
+            for i in 0..8 {
+                if self.model_bit(mmc) != 0 { value |= 1 << i; }
+            }
+            return value;
+        }
+
+        // Two interleaved bit planes: Decode both at once and return the second one on the next
+        // call
+        if self.r0 == 0 {
+            self.r0 = 0xff;
+            return self.r2;
+        }
+        self.r1 = 0;
+        self.r2 = 0;
+        self.r0 = 0x80;
+        while self.r0 != 0 {
+            if self.model_bit(mmc) != 0 { self.r1 |= self.r0; }
+            if self.model_bit(mmc) != 0 { self.r2 |= self.r0; }
+            self.r0 >>= 1;
+        }
+        self.r1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compresses `data` into a stream the S-DD1 decompresses back to it, using the given header
+    /// (bit plane and context configuration in the upper nibble).
+    fn compress(header: u8, data: &[u8]) -> Vec<u8> {
+        // Bits in the order the output logic requests them
+        let mut bits = Vec::new();
+        if header & 0xc0 == 0xc0 {
+            for &byte in data {
+                for i in 0..8 { bits.push(byte >> i & 1); }
+            }
+        } else {
+            for pair in data.chunks(2) {
+                for i in (0..8).rev() {
+                    bits.push(pair[0] >> i & 1);
+                    bits.push(pair[1] >> i & 1);
+                }
+            }
+        }
+
+        // Run the decompressor's model, emitting the code words its bit generators will read. A
+        // code word is read when a run starts, but only known once the run ends.
+        let mut model = Decompressor::new();
+        model.init(&Mmc { rom: &[header], banks: [0, 1, 2, 3] }, 0);
+        let mut codes: Vec<Vec<bool>> = Vec::new();
+        // Code word index and number of MPS so far of each generator's current run
+        let mut runs: [Option<(usize, u16)>; 8] = [None; 8];
+        for bit in bits {
+            let c = model.next_context() as usize;
+            let state = model.context_state[c];
+            let (order, next_mps, next_lps) = EVOLUTION[state as usize];
+            let lps = bit != model.context_mps[c];
+            let (index, count) = runs[order as usize].unwrap_or_else(|| {
+                codes.push(Vec::new());
+                (codes.len() - 1, 0)
+            });
+
+            let end_of_run = if lps {
+                codes[index].push(true);
+                for i in 0..order { codes[index].push(count >> i & 1 == 0); }
+                true
+            } else if count + 1 == 1 << order {
+                codes[index].push(false);
+                true
+            } else {
+                false
+            };
+            if end_of_run {
+                runs[order as usize] = None;
+                if lps {
+                    if state & 0xfe == 0 { model.context_mps[c] ^= 1; }
+                    model.context_state[c] = next_lps;
+                } else {
+                    model.context_state[c] = next_mps;
+                }
+            } else {
+                runs[order as usize] = Some((index, count + 1));
+            }
+            model.push_bit(bit);
+        }
+
+        let mut stream = vec![header & 0xf0];
+        let mut bit_count = 4;
+        for code in codes {
+            // Unfinished runs are completed with MPS
+            let code = if code.is_empty() { vec![false] } else { code };
+            for bit in code {
+                if bit_count == 8 {
+                    stream.push(0);
+                    bit_count = 0;
+                }
+                if bit { *stream.last_mut().unwrap() |= 0x80 >> bit_count; }
+                bit_count += 1;
+            }
+        }
+        stream.extend_from_slice(&[0, 0]);
+        stream
+    }
+
+    /// Decompresses `len` bytes starting at `$C0:1000` with a DMA transfer on channel 2.
+    fn decompress(sdd1: &mut Sdd1, rom: &[u8], len: usize) -> Vec<u8> {
+        sdd1.store_io(0x4800, 0x04);
+        sdd1.store_io(0x4801, 0x04);
+        sdd1.dma_start(rom, 2, 0xc0, 0x1000);
+        let data = (0..len).map(|i| sdd1.load(rom, &[], 0xc0, 0x1000 + i as u16)).collect();
+        sdd1.dma_end();
+        data
+    }
+
+    /// Tile-like data with some noise
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut seed = 1u32;
+        (0..len).map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            if seed >> 28 == 0 { (seed >> 16) as u8 } else { (i / 16) as u8 & 0x3c }
+        }).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for header in 0..16 {
+            for data in &[test_data(512), vec![0; 64], vec![0xff; 64]] {
+                let mut rom = vec![0; 0x2000];
+                let stream = compress(header << 4, data);
+                rom[0x1000..0x1000 + stream.len()].copy_from_slice(&stream);
+                let output = decompress(&mut Sdd1::new(), &rom, data.len());
+                assert!(output == *data, "header ${:X}0", header);
+            }
+        }
+    }
+
+    #[test]
+    fn zeros() {
+        // Code words without LPS flag only produce MPS, which starts out as 0 in every context
+        for &header in &[0x00, 0x40, 0x80, 0xc0] {
+            let mut rom = vec![0; 0x2000];
+            rom[0x1000] = header;
+            assert_eq!(decompress(&mut Sdd1::new(), &rom, 32), vec![0; 32]);
+        }
+    }
+
+    #[test]
+    fn decompress_through_banks() {
+        let data = test_data(64);
+        let stream = compress(0x00, &data);
+        let mut rom = vec![0; 0x400000];
+        rom[0x301000..0x301000 + stream.len()].copy_from_slice(&stream);
+
+        let mut sdd1 = Sdd1::new();
+        sdd1.store_io(0x4804, 3);
+        assert_eq!(decompress(&mut sdd1, &rom, data.len()), data);
+    }
+
+    #[test]
+    fn dma_channels() {
+        // The decompressed data is all zeros and doesn't depend on the address read
+        let mut rom = vec![0; 0x2000];
+        rom[0x1800] = 0xff;
+        let mut sdd1 = Sdd1::new();
+
+        // Channel 2 is ready, but not enabled
+        sdd1.store_io(0x4801, 0x04);
+        sdd1.dma_start(&rom, 2, 0xc0, 0x1000);
+        assert_eq!(sdd1.load(&rom, &[], 0xc0, 0x1800), 0xff);
+        sdd1.dma_end();
+
+        // Only the channel that transferred is no longer ready
+        sdd1.store_io(0x4800, 0x06);
+        sdd1.store_io(0x4801, 0x06);
+        sdd1.dma_start(&rom, 1, 0xc0, 0x1000);
+        assert_eq!(sdd1.load(&rom, &[], 0xc0, 0x1800), 0x00);
+        sdd1.dma_end();
+        assert_eq!(sdd1.load_io(0x4801), Some(0x04));
+        assert_eq!(sdd1.load(&rom, &[], 0xc0, 0x1800), 0xff);
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut sdd1 = Sdd1::new();
+        assert!(sdd1.store_io(0x4805, 0x0d));
+        assert_eq!(sdd1.load_io(0x4805), Some(0x05));
+        assert!(!sdd1.store_io(0x4808, 0));
+
+        let len = 0x800000;
+        assert_eq!(sdd1.rom_offset(len, 0xc1, 0x2345), Some(0x012345));
+        assert_eq!(sdd1.rom_offset(len, 0xd2, 0x3456), Some(0x523456));
+        assert_eq!(sdd1.rom_offset(len, 0xf0, 0x0000), Some(0x300000));
+        // The first 2 MB are mapped LoROM-style
+        assert_eq!(sdd1.rom_offset(len, 0x00, 0x8000), Some(0x000000));
+        assert_eq!(sdd1.rom_offset(len, 0x81, 0x9234), Some(0x009234));
+        assert_eq!(sdd1.rom_offset(len, 0x3f, 0xffff), Some(0x1fffff));
+        assert_eq!(sdd1.rom_offset(len, 0x00, 0x7fff), None);
+
+        let mut ram = vec![0; 0x2000];
+        sdd1.store(&mut ram, 0x70, 0x0123, 0x42);
+        assert_eq!(ram[0x0123], 0x42);
+        assert_eq!(sdd1.load(&[], &ram, 0x70, 0x2123), 0x42);
+    }
+}
@@ -0,0 +1,1356 @@
+//! SPC7110 data ROM decompression chip
+//!
+//! SPC7110 cartridges contain a 1 MB program ROM, followed by a data ROM of up to 7 MB. The
+//! program ROM is mapped to `$C0-$CF` (and mirrored HiROM-style in `$00-$0F:8000-$FFFF`), while
+//! banks `$D0-$DF`, `$E0-$EF` and `$F0-$FF` can each be switched to any 1 MB page of the data ROM.
+//!
+//! The SPC7110 has a few more features, all accessed through registers at `$4800-$4842`:
+//!
+//! * A decompression unit that decodes data compressed with an arithmetic coder (1, 2 or 4 bits
+//!   per pixel). The compressed streams are described by a directory in the data ROM.
+//! * A data port for sequential (or offset based) reads from the data ROM.
+//! * A multiplication and division unit.
+//! * Far East of Eden Zero also has an Epson RTC-4513 real-time clock.
+
+use diagnostics::Diagnostics;
+
+use super::rtc::Clock;
+
+use libsavestate::SaveState;
+
+use std::io::{self, Read, Write};
+
+/// Size of the program ROM, which starts the ROM image. The data ROM follows it.
+const PROGRAM_ROM_SIZE: usize = 0x100000;
+
+/// The SPC7110 chip
+#[derive(Clone)]
+pub struct Spc7110 {
+    // Decompression unit
+    /// `$4801-$4803`: Address of the compressed stream directory in the data ROM
+    dir_base: u32,
+    /// `$4804`: Directory entry to decompress
+    dir_index: u8,
+    /// `$4805/$4806`: Number of (2bpp/4bpp: tiles times 8) bytes to skip. Writing `$4806` starts
+    /// decompression.
+    skip: u16,
+    /// `$4807/$4808`: Unknown
+    r4807: u8,
+    r4808: u8,
+    /// `$4809/$480A`: Decompression byte counter, decremented on every read from `$4800`
+    decomp_len: u16,
+    /// `$480B`: Decompression mode (unused)
+    r480b: u8,
+    /// `$480C`: Decompression status (bit 7 set when data is ready, cleared on read)
+    decomp_status: u8,
+    decompressor: Decompressor,
+
+    // Data port
+    /// `$4811-$4813`: Data ROM pointer
+    data_ptr: u32,
+    /// `$4814/$4815`: Offset added to the pointer
+    data_adjust: u16,
+    /// `$4816/$4817`: Pointer increment
+    data_step: u16,
+    /// `$4818`: Data port mode
+    data_mode: u8,
+    /// Which bytes of the pointer were written (the data port only works once all have been)
+    data_ptr_written: u8,
+    adjust_lo_written: bool,
+    adjust_hi_written: bool,
+
+    // Multiplication/division unit
+    /// `$4820-$4823`: Dividend (or multiplicand in the low 16 bits)
+    dividend: u32,
+    /// `$4824/$4825`: Multiplier. Writing `$4825` starts the multiplication.
+    multiplier: u16,
+    /// `$4826/$4827`: Divisor. Writing `$4827` starts the division.
+    divisor: u16,
+    /// `$4828-$482B`: Product or quotient
+    result: u32,
+    /// `$482C/$482D`: Remainder
+    remainder: u16,
+    /// `$482E`: Bit 0 selects signed arithmetic
+    alu_mode: u8,
+
+    // Memory mapping
+    /// `$4830`: Bit 7 enables the cartridge RAM
+    ram_enable: u8,
+    /// `$4831-$4833`: Data ROM pages mapped to `$D0-$DF`, `$E0-$EF` and `$F0-$FF`
+    banks: [u8; 3],
+    /// `$4834`: Data ROM size (unused, the data ROM is mirrored instead)
+    r4834: u8,
+
+    rtc: Option<Rtc4513>,
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+impl_save_state!(Spc7110 {
+    dir_base, dir_index, skip, r4807, r4808, decomp_len, r480b, decomp_status, decompressor,
+    data_ptr, data_adjust, data_step, data_mode, data_ptr_written, adjust_lo_written,
+    adjust_hi_written, dividend, multiplier, divisor, result, remainder, alu_mode, ram_enable,
+    banks, r4834, rtc
+} ignore { diagnostics });
+
+impl Spc7110 {
+    /// Creates an SPC7110. If `rtc` is `true`, the cartridge also contains an RTC-4513.
+    pub fn new(rtc: bool) -> Self {
+        Spc7110 {
+            dir_base: 0,
+            dir_index: 0,
+            skip: 0,
+            r4807: 0,
+            r4808: 0,
+            decomp_len: 0,
+            r480b: 0,
+            decomp_status: 0,
+            decompressor: Decompressor::new(),
+            data_ptr: 0,
+            data_adjust: 0,
+            data_step: 0,
+            data_mode: 0,
+            data_ptr_written: 0,
+            adjust_lo_written: false,
+            adjust_hi_written: false,
+            dividend: 0,
+            multiplier: 0,
+            divisor: 0,
+            result: 0,
+            remainder: 0,
+            alu_mode: 0,
+            ram_enable: 0,
+            banks: [1, 2, 3],
+            r4834: 0,
+            rtc: if rtc { Some(Rtc4513::new()) } else { None },
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// Returns the clock of the RTC (if there is one).
+    pub fn clock(&self) -> Option<&Clock> {
+        self.rtc.as_ref().map(|rtc| &rtc.clock)
+    }
+
+    pub fn clock_mut(&mut self) -> Option<&mut Clock> {
+        self.rtc.as_mut().map(|rtc| &mut rtc.clock)
+    }
+
+    /// Lets the RTC (if any) run for `master_cy` master clock cycles.
+    pub fn run(&mut self, master_cy: u32) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.run(master_cy);
+        }
+    }
+
+    /// Performs a load from the SNES CPU.
+    pub fn load(&mut self, rom: &[u8], ram: &[u8], bank: u8, addr: u16) -> u8 {
+        match self.target(bank, addr) {
+            Target::Rom(offset) => read_mirrored(rom, offset),
+            Target::DataRom(offset) => read_data_rom(rom, offset),
+            Target::Ram(offset) => read_mirrored(ram, offset),
+            Target::Decompressor => self.read_decompressed(rom),
+            Target::RamDisabled => 0,
+            Target::Unmapped => {
+                diag!(self.diagnostics, "SPC7110: unmapped load from ${:02X}:{:04X}", bank, addr);
+                0
+            }
+        }
+    }
+
+    /// Performs a store from the SNES CPU.
+    pub fn store(&mut self, ram: &mut [u8], bank: u8, addr: u16, value: u8) {
+        match self.target(bank, addr) {
+            Target::Ram(offset) => {
+                if !ram.is_empty() {
+                    let len = ram.len();
+                    ram[offset % len] = value;
+                }
+            }
+            Target::RamDisabled => {}
+            _ => {
+                diag!(self.diagnostics, "SPC7110: invalid store of ${:02X} to ${:02X}:{:04X}",
+                    value, bank, addr);
+            }
+        }
+    }
+
+    /// Reads the byte the SNES CPU would see at `bank:addr` without side effects. Returns `None`
+    /// for unmapped addresses and the decompression port.
+    pub fn peek(&self, rom: &[u8], ram: &[u8], bank: u8, addr: u16) -> Option<u8> {
+        match self.target(bank, addr) {
+            Target::Rom(offset) if !rom.is_empty() => Some(read_mirrored(rom, offset)),
+            Target::DataRom(offset) if rom.len() > PROGRAM_ROM_SIZE => {
+                Some(read_data_rom(rom, offset))
+            }
+            Target::Ram(offset) if !ram.is_empty() => Some(read_mirrored(ram, offset)),
+            _ => None,
+        }
+    }
+
+    /// Overwrites the ROM or RAM byte mapped to `bank:addr`. Returns `false` if no memory is
+    /// mapped there.
+    pub fn poke(&mut self, rom: &mut [u8], ram: &mut [u8], bank: u8, addr: u16, value: u8)
+    -> bool {
+        let (mem, offset) = match self.target(bank, addr) {
+            Target::Rom(offset) => (rom, offset),
+            Target::DataRom(offset) => match data_rom_offset(rom.len(), offset) {
+                Some(offset) => (rom, offset),
+                None => return false,
+            },
+            Target::Ram(offset) => (ram, offset),
+            _ => return false,
+        };
+        if mem.is_empty() { return false; }
+        let len = mem.len();
+        mem[offset % len] = value;
+        true
+    }
+
+    /// Returns the offset into the ROM image the SNES CPU accesses at `bank:addr`.
+    pub fn rom_offset(&self, rom_len: usize, bank: u8, addr: u16) -> Option<usize> {
+        match self.target(bank, addr) {
+            Target::Rom(offset) if rom_len != 0 => Some(offset % rom_len),
+            Target::DataRom(offset) => data_rom_offset(rom_len, offset),
+            _ => None,
+        }
+    }
+
+    /// Handles a load from the cartridge I/O area. Returns `None` if the SPC7110 doesn't map
+    /// anything there.
+    pub fn load_io(&mut self, rom: &[u8], addr: u16) -> Option<u8> {
+        let value = match addr {
+            0x4800 => self.read_decompressed(rom),
+            0x4801 => self.dir_base as u8,
+            0x4802 => (self.dir_base >> 8) as u8,
+            0x4803 => (self.dir_base >> 16) as u8,
+            0x4804 => self.dir_index,
+            0x4805 => self.skip as u8,
+            0x4806 => (self.skip >> 8) as u8,
+            0x4807 => self.r4807,
+            0x4808 => self.r4808,
+            0x4809 => self.decomp_len as u8,
+            0x480a => (self.decomp_len >> 8) as u8,
+            0x480b => self.r480b,
+            0x480c => {
+                let status = self.decomp_status;
+                self.decomp_status &= 0x7f;
+                status
+            }
+            0x4810 => self.read_data_port(rom),
+            0x4811 => self.data_ptr as u8,
+            0x4812 => (self.data_ptr >> 8) as u8,
+            0x4813 => (self.data_ptr >> 16) as u8,
+            0x4814 => self.data_adjust as u8,
+            0x4815 => (self.data_adjust >> 8) as u8,
+            0x4816 => self.data_step as u8,
+            0x4817 => (self.data_step >> 8) as u8,
+            0x4818 => self.data_mode,
+            0x481a => self.read_data_port_adjusted(rom),
+            0x4820 ... 0x4823 => (self.dividend >> ((addr - 0x4820) * 8)) as u8,
+            0x4824 => self.multiplier as u8,
+            0x4825 => (self.multiplier >> 8) as u8,
+            0x4826 => self.divisor as u8,
+            0x4827 => (self.divisor >> 8) as u8,
+            0x4828 ... 0x482b => (self.result >> ((addr - 0x4828) * 8)) as u8,
+            0x482c => self.remainder as u8,
+            0x482d => (self.remainder >> 8) as u8,
+            0x482e => self.alu_mode,
+            // ALU status: Results are available immediately, so it's never busy
+            0x482f => 0,
+            0x4830 => self.ram_enable,
+            0x4831 ... 0x4833 => self.banks[addr as usize - 0x4831],
+            0x4834 => self.r4834,
+            0x4840 ... 0x4842 => match self.rtc {
+                Some(ref mut rtc) => rtc.load(addr),
+                None => return None,
+            },
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Handles a store to the cartridge I/O area. Returns `false` if the SPC7110 doesn't map
+    /// anything there.
+    pub fn store_io(&mut self, rom: &[u8], addr: u16, value: u8) -> bool {
+        match addr {
+            0x4801 => self.dir_base = (self.dir_base & 0xffff00) | value as u32,
+            0x4802 => self.dir_base = (self.dir_base & 0xff00ff) | (value as u32) << 8,
+            0x4803 => self.dir_base = (self.dir_base & 0x00ffff) | (value as u32) << 16,
+            0x4804 => self.dir_index = value,
+            0x4805 => self.skip = (self.skip & 0xff00) | value as u16,
+            0x4806 => {
+                self.skip = (self.skip & 0x00ff) | (value as u16) << 8;
+                self.start_decompression(rom);
+            }
+            0x4807 => self.r4807 = value,
+            0x4808 => self.r4808 = value,
+            0x4809 => self.decomp_len = (self.decomp_len & 0xff00) | value as u16,
+            0x480a => self.decomp_len = (self.decomp_len & 0x00ff) | (value as u16) << 8,
+            0x480b => self.r480b = value,
+            0x4811 => {
+                self.data_ptr = (self.data_ptr & 0xffff00) | value as u32;
+                self.data_ptr_written |= 0x01;
+            }
+            0x4812 => {
+                self.data_ptr = (self.data_ptr & 0xff00ff) | (value as u32) << 8;
+                self.data_ptr_written |= 0x02;
+            }
+            0x4813 => {
+                self.data_ptr = (self.data_ptr & 0x00ffff) | (value as u32) << 16;
+                self.data_ptr_written |= 0x04;
+            }
+            0x4814 => {
+                self.data_adjust = (self.data_adjust & 0xff00) | value as u16;
+                self.adjust_lo_written = true;
+                if self.adjust_hi_written { self.apply_adjust(); }
+            }
+            0x4815 => {
+                self.data_adjust = (self.data_adjust & 0x00ff) | (value as u16) << 8;
+                self.adjust_hi_written = true;
+                if self.adjust_lo_written { self.apply_adjust(); }
+            }
+            0x4816 => self.data_step = (self.data_step & 0xff00) | value as u16,
+            0x4817 => self.data_step = (self.data_step & 0x00ff) | (value as u16) << 8,
+            0x4818 => {
+                if self.data_ptr_written == 0x07 {
+                    self.data_mode = value;
+                    self.adjust_lo_written = false;
+                    self.adjust_hi_written = false;
+                }
+            }
+            0x4820 ... 0x4823 => {
+                let shift = (addr - 0x4820) * 8;
+                self.dividend = (self.dividend & !(0xff << shift)) | (value as u32) << shift;
+            }
+            0x4824 => self.multiplier = (self.multiplier & 0xff00) | value as u16,
+            0x4825 => {
+                self.multiplier = (self.multiplier & 0x00ff) | (value as u16) << 8;
+                self.multiply();
+            }
+            0x4826 => self.divisor = (self.divisor & 0xff00) | value as u16,
+            0x4827 => {
+                self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8;
+                self.divide();
+            }
+            0x482e => {
+                // Resets the ALU
+                self.dividend = 0;
+                self.multiplier = 0;
+                self.divisor = 0;
+                self.result = 0;
+                self.remainder = 0;
+                self.alu_mode = value;
+            }
+            0x4830 => self.ram_enable = value,
+            0x4831 ... 0x4833 => self.banks[addr as usize - 0x4831] = value & 0x07,
+            0x4834 => self.r4834 = value,
+            0x4840 ... 0x4842 => match self.rtc {
+                Some(ref mut rtc) => rtc.store(addr, value),
+                None => return false,
+            },
+            0x4800 ... 0x483f => {
+                diag!(self.diagnostics,
+                    "SPC7110: write of ${:02X} to read-only or unknown register ${:04X}", value,
+                    addr);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn target(&self, bank: u8, addr: u16) -> Target {
+        let offset = (bank as usize & 0x0f) << 16 | addr as usize;
+        match (bank, addr) {
+            (0x00 ... 0x3f, 0x6000 ... 0x7fff) | (0x80 ... 0xbf, 0x6000 ... 0x7fff) => {
+                if self.ram_enable & 0x80 != 0 {
+                    Target::Ram(addr as usize & 0x1fff)
+                } else {
+                    Target::RamDisabled
+                }
+            }
+            (0x00 ... 0x3f, 0x8000 ... 0xffff) | (0x80 ... 0xbf, 0x8000 ... 0xffff) => {
+                match (bank & 0x3f) >> 4 {
+                    0 => Target::Rom(offset),
+                    page => self.data_rom_page(page as usize - 1, offset),
+                }
+            }
+            (0x50, _) | (0x58, _) => Target::Decompressor,
+            (0xc0 ... 0xcf, _) => Target::Rom(offset),
+            (0xd0 ... 0xff, _) => self.data_rom_page((bank as usize >> 4) - 0xd, offset),
+            _ => Target::Unmapped,
+        }
+    }
+
+    fn data_rom_page(&self, page: usize, offset: usize) -> Target {
+        Target::DataRom((self.banks[page] as usize) << 20 | offset)
+    }
+
+    /// Reads the next decompressed byte.
+    fn read_decompressed(&mut self, rom: &[u8]) -> u8 {
+        self.decomp_len = self.decomp_len.wrapping_sub(1);
+        self.decompressor.read(rom)
+    }
+
+    /// Looks up the directory entry and starts decompressing.
+    fn start_decompression(&mut self, rom: &[u8]) {
+        // Every entry consists of the mode byte and the 24-bit big-endian data ROM offset of the
+        // compressed stream
+        let entry = self.dir_base + ((self.dir_index as u32) << 2);
+        let mode = read_data_rom(rom, entry as usize);
+        let offset = (read_data_rom(rom, entry as usize + 1) as u32) << 16 |
+                     (read_data_rom(rom, entry as usize + 2) as u32) << 8 |
+                     read_data_rom(rom, entry as usize + 3) as u32;
+
+        self.decompressor.init(rom, mode, offset);
+        // In 2bpp and 4bpp mode, the skip count is in units of 2 or 4 bytes
+        let skip = if mode <= 2 { (self.skip as u32) << mode } else { 0 };
+        for _ in 0..skip {
+            self.decompressor.read(rom);
+        }
+        self.decomp_status = 0x80;
+    }
+
+    /// Reads from the data port at `$4810`.
+    fn read_data_port(&mut self, rom: &[u8]) -> u8 {
+        if self.data_ptr_written != 0x07 { return 0; }
+
+        let ptr = self.data_ptr;
+        let adjust = self.adjust();
+        let mut addr = ptr;
+        if self.data_mode & 0x02 != 0 {
+            addr = addr.wrapping_add(adjust);
+            self.data_adjust = self.data_adjust.wrapping_add(1);
+        }
+
+        let value = read_data_rom(rom, addr as usize & 0xffffff);
+        if self.data_mode & 0x02 == 0 {
+            let step = if self.data_mode & 0x01 != 0 {
+                if self.data_mode & 0x04 != 0 {
+                    self.data_step as i16 as u32
+                } else {
+                    self.data_step as u32
+                }
+            } else {
+                1
+            };
+            if self.data_mode & 0x10 == 0 {
+                self.data_ptr = ptr.wrapping_add(step) & 0xffffff;
+            } else {
+                self.data_adjust = adjust.wrapping_add(step) as u16;
+            }
+        }
+        value
+    }
+
+    /// Reads from the data port at `$481A` (pointer plus adjust value).
+    fn read_data_port_adjusted(&mut self, rom: &[u8]) -> u8 {
+        if self.data_ptr_written != 0x07 { return 0; }
+
+        let ptr = self.data_ptr;
+        let adjust = self.adjust();
+        let value = read_data_rom(rom, ptr.wrapping_add(adjust) as usize & 0xffffff);
+        if self.data_mode & 0x60 == 0x60 {
+            if self.data_mode & 0x10 == 0 {
+                self.data_ptr = ptr.wrapping_add(adjust) & 0xffffff;
+            } else {
+                self.data_adjust = adjust.wrapping_add(adjust) as u16;
+            }
+        }
+        value
+    }
+
+    /// Returns the adjust value, sign extended if the data port mode says so.
+    fn adjust(&self) -> u32 {
+        if self.data_mode & 0x08 != 0 {
+            self.data_adjust as i16 as u32
+        } else {
+            self.data_adjust as u32
+        }
+    }
+
+    /// Adds the adjust value to the data pointer after both of its bytes were written (if the data
+    /// port mode requests it).
+    fn apply_adjust(&mut self) {
+        if self.data_mode & 0x02 == 0 || self.data_mode & 0x10 != 0 { return; }
+        let signed = self.data_mode & 0x08 != 0;
+        let step = match self.data_mode & 0x60 {
+            // 8-bit adjust value
+            0x20 if signed => self.data_adjust as u8 as i8 as u32,
+            0x20 => self.data_adjust as u8 as u32,
+            // 16-bit adjust value
+            0x40 => self.adjust(),
+            _ => return,
+        };
+        self.data_ptr = self.data_ptr.wrapping_add(step) & 0xffffff;
+    }
+
+    fn multiply(&mut self) {
+        let a = self.dividend as u16;
+        self.result = if self.alu_mode & 0x01 != 0 {
+            (a as i16 as i32 * self.multiplier as i16 as i32) as u32
+        } else {
+            a as u32 * self.multiplier as u32
+        };
+    }
+
+    fn divide(&mut self) {
+        if self.divisor == 0 {
+            self.result = 0;
+            self.remainder = self.dividend as u16;
+            return;
+        }
+        if self.alu_mode & 0x01 != 0 {
+            let dividend = self.dividend as i32;
+            let divisor = self.divisor as i16 as i32;
+            self.result = dividend.wrapping_div(divisor) as u32;
+            self.remainder = dividend.wrapping_rem(divisor) as u16;
+        } else {
+            self.result = self.dividend / self.divisor as u32;
+            self.remainder = (self.dividend % self.divisor as u32) as u16;
+        }
+    }
+}
+
+/// What an address accessed by the SNES CPU is mapped to
+#[derive(Clone, Copy)]
+enum Target {
+    /// Offset into the program ROM
+    Rom(usize),
+    /// Offset into the data ROM
+    DataRom(usize),
+    Ram(usize),
+    /// The cartridge RAM, while it's disabled by `$4830`
+    RamDisabled,
+    /// The decompressed data port (same as `$4800`)
+    Decompressor,
+    Unmapped,
+}
+
+/// Reads `mem[offset]`, mirroring `offset` into `mem`. Returns 0 if `mem` is empty.
+fn read_mirrored(mem: &[u8], offset: usize) -> u8 {
+    if mem.is_empty() { 0 } else { mem[offset % mem.len()] }
+}
+
+/// Returns the offset into the ROM image of the data ROM byte at `offset`. The data ROM is
+/// mirrored. Returns `None` if there is no data ROM.
+fn data_rom_offset(rom_len: usize, offset: usize) -> Option<usize> {
+    if rom_len <= PROGRAM_ROM_SIZE { return None; }
+    Some(PROGRAM_ROM_SIZE + offset % (rom_len - PROGRAM_ROM_SIZE))
+}
+
+fn read_data_rom(rom: &[u8], offset: usize) -> u8 {
+    data_rom_offset(rom.len(), offset).map(|offset| rom[offset]).unwrap_or(0)
+}
+
+/// Probability estimation states: LPS probability, next state after an LPS, next state after a
+/// renormalized MPS, and whether an LPS inverts the context's MPS
+const EVOLUTION: [(u8, u8, u8, bool); 53] = [
+    (0x5a,  1,  1, true), (0x25,  6,  2, false), (0x11,  8,  3, false), (0x08, 10,  4, false),
+    (0x03, 12,  5, false), (0x01, 15,  5, false),
+
+    (0x5a,  7,  7, true), (0x3f, 19,  8, false), (0x2c, 21,  9, false), (0x20, 22, 10, false),
+    (0x17, 23, 11, false), (0x11, 25, 12, false), (0x0c, 26, 13, false), (0x09, 28, 14, false),
+    (0x07, 29, 15, false), (0x05, 31, 16, false), (0x04, 32, 17, false), (0x03, 34, 18, false),
+    (0x02, 35,  5, false),
+
+    (0x5a, 20, 20, true), (0x48, 39, 21, false), (0x3a, 40, 22, false), (0x2e, 42, 23, false),
+    (0x26, 44, 24, false), (0x1f, 45, 25, false), (0x19, 46, 26, false), (0x15, 25, 27, false),
+    (0x11, 26, 28, false), (0x0e, 26, 29, false), (0x0b, 27, 30, false), (0x09, 28, 31, false),
+    (0x08, 29, 32, false), (0x07, 30, 33, false), (0x05, 31, 34, false), (0x04, 33, 35, false),
+    (0x04, 33, 36, false), (0x03, 34, 37, false), (0x02, 35, 38, false), (0x02, 36,  5, false),
+
+    (0x58, 39, 40, true), (0x4d, 47, 41, false), (0x43, 48, 42, false), (0x3b, 49, 43, false),
+    (0x34, 50, 44, false), (0x2e, 51, 45, false), (0x29, 44, 46, false), (0x25, 45, 24, false),
+
+    (0x56, 47, 48, true), (0x4f, 47, 49, false), (0x47, 48, 50, false), (0x41, 49, 51, false),
+    (0x3c, 50, 52, false), (0x37, 51, 43, false),
+];
+
+/// Context transitions for the 4bpp mode, indexed by the previous context and the decoded bit
+const MODE2_CONTEXTS: [[u8; 2]; 15] = [
+    [1, 2],
+    [3, 8], [13, 14],
+    [15, 16], [17, 18], [19, 20], [21, 22], [23, 24], [25, 26], [25, 26], [25, 26], [25, 26],
+    [25, 26], [27, 28], [29, 30],
+];
+
+/// Moves `value` to the front of `order`, shifting the entries before it back.
+fn move_to_front(order: &mut [u8], value: u8) {
+    let pos = order.iter().position(|&v| v == value).unwrap_or(order.len() - 1);
+    for i in (0..pos).rev() {
+        order[i + 1] = order[i];
+    }
+    order[0] = value;
+}
+
+/// The decompression unit
+#[derive(Clone)]
+struct Decompressor {
+    /// 0: 1 bit per "pixel" (generic data), 1: 2bpp tiles, 2: 4bpp tiles
+    mode: u8,
+    /// Offset of the next compressed byte in the data ROM
+    offset: u32,
+    /// Decompressed bytes that haven't been read yet
+    buffer: [u8; 32],
+    buffer_pos: u8,
+    buffer_len: u8,
+
+    /// Arithmetic decoder state
+    value: u8,
+    span: u8,
+    input: u8,
+    input_bits: u8,
+
+    /// Current state of each context
+    context_state: [u8; 32],
+    context_invert: [bool; 32],
+
+    /// Decoded bits or pixels (most recent in the lowest bits)
+    out: u32,
+    /// Mode 2: Pixels of the previous 8 pixel row
+    out_prev: u32,
+    /// History of decoded LPS flags and context inversions
+    lps: u32,
+    inverts: u32,
+
+    /// Mode 1/2: Pixel values, most recently seen first
+    pixel_order: [u8; 16],
+    /// Mode 2: Bit planes 2 and 3 are output after a tile's bit planes 0 and 1
+    upper_planes: [u8; 16],
+    upper_planes_len: u8,
+}
+
+impl_save_state!(Decompressor {
+    mode, offset, buffer, buffer_pos, buffer_len, value, span, input, input_bits, context_state,
+    context_invert, out, out_prev, lps, inverts, pixel_order, upper_planes, upper_planes_len
+} ignore {});
+
+impl Decompressor {
+    fn new() -> Self {
+        Decompressor {
+            mode: 0,
+            offset: 0,
+            buffer: [0; 32],
+            buffer_pos: 0,
+            buffer_len: 0,
+            value: 0,
+            span: 0,
+            input: 0,
+            input_bits: 0,
+            context_state: [0; 32],
+            context_invert: [false; 32],
+            out: 0,
+            out_prev: 0,
+            lps: 0,
+            inverts: 0,
+            pixel_order: [0; 16],
+            upper_planes: [0; 16],
+            upper_planes_len: 0,
+        }
+    }
+
+    /// Starts decompressing the stream at data ROM offset `offset`.
+    fn init(&mut self, rom: &[u8], mode: u8, offset: u32) {
+        *self = Decompressor::new();
+        self.mode = mode;
+        self.offset = offset;
+        for (i, pixel) in self.pixel_order.iter_mut().enumerate() {
+            *pixel = i as u8;
+        }
+        self.span = 0xff;
+        self.value = self.next_input(rom);
+        self.input = self.next_input(rom);
+        self.input_bits = 8;
+    }
+
+    fn next_input(&mut self, rom: &[u8]) -> u8 {
+        let value = read_data_rom(rom, self.offset as usize);
+        self.offset += 1;
+        value
+    }
+
+    fn push(&mut self, value: u8) {
+        let index = (self.buffer_pos + self.buffer_len) as usize % self.buffer.len();
+        self.buffer[index] = value;
+        self.buffer_len += 1;
+    }
+
+    /// Returns the next decompressed byte.
+    fn read(&mut self, rom: &[u8]) -> u8 {
+        if self.buffer_len == 0 {
+            match self.mode {
+                0 => self.decode_mode0(rom),
+                1 => self.decode_mode1(rom),
+                2 => self.decode_mode2(rom),
+                _ => return 0,
+            }
+        }
+        let value = self.buffer[self.buffer_pos as usize];
+        self.buffer_pos = (self.buffer_pos + 1) % self.buffer.len() as u8;
+        self.buffer_len -= 1;
+        value
+    }
+
+    /// Decodes a binary symbol in context `con`. Returns `true` if it's the less probable one.
+    fn decode_bit(&mut self, rom: &[u8], con: usize) -> bool {
+        let (prob, next_lps, next_mps, toggle) = EVOLUTION[self.context_state[con] as usize];
+
+        let lps = if self.value <= self.span - prob {
+            self.span -= prob;
+            false
+        } else {
+            self.value -= self.span - (prob - 1);
+            self.span = prob - 1;
+            true
+        };
+
+        // Renormalize
+        let mut shifted = false;
+        while self.span < 0x7f {
+            shifted = true;
+            self.span = (self.span << 1) + 1;
+            self.value = (self.value << 1) | (self.input >> 7);
+            self.input <<= 1;
+            self.input_bits -= 1;
+            if self.input_bits == 0 {
+                self.input = self.next_input(rom);
+                self.input_bits = 8;
+            }
+        }
+
+        self.lps = self.lps << 1 | lps as u32;
+        self.inverts = self.inverts << 1 | self.context_invert[con] as u32;
+
+        if lps {
+            if toggle { self.context_invert[con] = !self.context_invert[con]; }
+            self.context_state[con] = next_lps;
+        } else if shifted {
+            self.context_state[con] = next_mps;
+        }
+        lps
+    }
+
+    /// Mode 0: Decodes a byte bit by bit.
+    fn decode_mode0(&mut self, rom: &[u8]) {
+        for bit in 0..8 {
+            let mask: u32 = (1 << (bit & 3)) - 1;
+            let mut con = (mask + ((self.inverts & mask) ^ (self.lps & mask))) as usize;
+            if bit > 3 { con += 15; }
+
+            let mps = (self.out >> 15) & 1 ^ self.context_invert[con] as u32;
+            let lps = self.decode_bit(rom, con);
+            self.out = self.out << 1 | (mps ^ lps as u32);
+        }
+        let out = self.out as u8;
+        self.push(out);
+    }
+
+    /// Returns the context for the first bit of a pixel, based on its neighbours `a` (left), `b`
+    /// (above) and `c` (above left).
+    fn reference_context(a: u8, b: u8, c: u8) -> usize {
+        if a == b {
+            (b != c) as usize
+        } else if b == c {
+            2
+        } else {
+            4 - (a == c) as usize
+        }
+    }
+
+    /// Mode 1: Decodes 8 pixels with 2 bits each and outputs them as 2 bit planes.
+    fn decode_mode1(&mut self, rom: &[u8]) {
+        for _ in 0..8 {
+            let a = (self.out >> 2) as u8 & 0x3;
+            let b = (self.out >> 14) as u8 & 0x3;
+            let c = (self.out >> 16) as u8 & 0x3;
+            let mut con = Self::reference_context(a, b, c);
+            let ranking = self.pixel_ranking(a, b, c, 4);
+
+            for _ in 0..2 {
+                self.decode_bit(rom, con);
+                con = 5 + (con << 1) + ((self.lps ^ self.inverts) & 1) as usize;
+            }
+
+            let pixel = ranking[((self.lps ^ self.inverts) & 3) as usize];
+            self.out = self.out << 2 | pixel as u32;
+        }
+
+        // Deinterleave the pixels into bit planes. Note that the higher bit of each pixel goes to
+        // the first bit plane.
+        let (mut plane0, mut plane1) = (0, 0);
+        for i in 0..8 {
+            let pixel = (self.out >> (2 * (7 - i))) & 3;
+            plane0 |= ((pixel >> 1) as u8) << (7 - i);
+            plane1 |= ((pixel & 1) as u8) << (7 - i);
+        }
+        self.push(plane0);
+        self.push(plane1);
+    }
+
+    /// Mode 2: Decodes 8 pixels with 4 bits each. Bit planes 0 and 1 are output right away, planes
+    /// 2 and 3 after 8 rows (a full tile) have been decoded.
+    fn decode_mode2(&mut self, rom: &[u8]) {
+        for _ in 0..8 {
+            let a = self.out as u8 & 0x0f;
+            let b = (self.out >> 28) as u8 & 0x0f;
+            let c = self.out_prev as u8 & 0x0f;
+            let refcon = Self::reference_context(a, b, c);
+            let ranking = self.pixel_ranking(a, b, c, 16);
+
+            let mut con = 0;
+            for _ in 0..4 {
+                let lps = self.decode_bit(rom, con);
+                let bit = lps as usize ^ (self.inverts & 1) as usize;
+                if con < MODE2_CONTEXTS.len() {
+                    con = MODE2_CONTEXTS[con][bit] as usize + if con == 1 { refcon } else { 0 };
+                }
+            }
+
+            let pixel = ranking[(self.lps ^ self.inverts) as usize & 0x0f];
+            self.out_prev = self.out_prev << 4 | (self.out >> 28) & 0x0f;
+            self.out = self.out << 4 | pixel as u32;
+        }
+
+        // Deinterleave into 4 bit planes. Like in mode 1, the highest pixel bit goes to the first
+        // bit plane.
+        let mut planes = [0u8; 4];
+        for i in 0..8 {
+            let pixel = (self.out >> (4 * (7 - i))) & 0x0f;
+            for (plane, value) in planes.iter_mut().enumerate() {
+                *value |= (((pixel >> (3 - plane)) & 1) as u8) << (7 - i);
+            }
+        }
+        self.push(planes[0]);
+        self.push(planes[1]);
+        self.upper_planes[self.upper_planes_len as usize] = planes[2];
+        self.upper_planes[self.upper_planes_len as usize + 1] = planes[3];
+        self.upper_planes_len += 2;
+
+        if self.upper_planes_len as usize == self.upper_planes.len() {
+            for i in 0..self.upper_planes.len() {
+                let value = self.upper_planes[i];
+                self.push(value);
+            }
+            self.upper_planes_len = 0;
+        }
+    }
+
+    /// Returns the first `n` pixel values ordered by how likely they are to be the next pixel,
+    /// based on the neighbouring pixels and the most recently seen values.
+    fn pixel_ranking(&mut self, a: u8, b: u8, c: u8, n: usize) -> [u8; 16] {
+        move_to_front(&mut self.pixel_order[..n], a);
+        let mut ranking = self.pixel_order;
+        move_to_front(&mut ranking[..n], c);
+        move_to_front(&mut ranking[..n], b);
+        move_to_front(&mut ranking[..n], a);
+        ranking
+    }
+}
+
+/// The Epson RTC-4513 real-time clock, accessed through `$4840-$4842`
+///
+/// The clock has 16 4-bit registers: The time as BCD digits (seconds, minutes, hours, day, month,
+/// year and weekday) in registers 0-12, followed by 3 control registers. They are accessed
+/// serially: After enabling the chip via `$4840`, the first byte written to `$4841` selects the
+/// transfer mode (`$03`: write, `$0C`: read), the second one the first register.
+#[derive(Clone)]
+struct Rtc4513 {
+    clock: Clock,
+    /// `$4840`: Chip enable
+    enable: u8,
+    state: RtcState,
+    /// Register accessed next
+    index: u8,
+    /// Control registers D, E and F
+    control: [u8; 3],
+    /// `$4842`: Status (bit 7: ready, cleared on read)
+    status: u8,
+}
+
+impl_save_state!(Rtc4513 { clock, enable, state, index, control, status } ignore {});
+
+impl Default for Rtc4513 {
+    fn default() -> Self { Rtc4513::new() }
+}
+
+/// State of the serial interface of the RTC-4513
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RtcState {
+    Inactive,
+    ModeSelect,
+    IndexSelect { write: bool },
+    Transfer { write: bool },
+}
+
+impl SaveState for RtcState {
+    fn save_state<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let value: u8 = match *self {
+            RtcState::Inactive => 0,
+            RtcState::ModeSelect => 1,
+            RtcState::IndexSelect { write: false } => 2,
+            RtcState::IndexSelect { write: true } => 3,
+            RtcState::Transfer { write: false } => 4,
+            RtcState::Transfer { write: true } => 5,
+        };
+        value.save_state(w)
+    }
+
+    fn restore_state<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<()> {
+        let mut value = 0u8;
+        try!(value.restore_state(r));
+        *self = match value {
+            0 => RtcState::Inactive,
+            1 => RtcState::ModeSelect,
+            2 => RtcState::IndexSelect { write: false },
+            3 => RtcState::IndexSelect { write: true },
+            4 => RtcState::Transfer { write: false },
+            5 => RtcState::Transfer { write: true },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid RTC state {}", value))),
+        };
+        Ok(())
+    }
+}
+
+/// Control register F bits
+const RTC_RESET: u8 = 0x01;
+const RTC_STOP: u8 = 0x02;
+const RTC_24H: u8 = 0x04;
+
+impl Rtc4513 {
+    fn new() -> Self {
+        Rtc4513 {
+            clock: Clock::new(),
+            enable: 0,
+            state: RtcState::Inactive,
+            index: 0,
+            control: [0, 0, RTC_24H],
+            status: 0,
+        }
+    }
+
+    fn run(&mut self, master_cy: u32) {
+        if self.control[2] & (RTC_RESET | RTC_STOP) == 0 {
+            self.clock.run(master_cy);
+        }
+    }
+
+    fn load(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4840 => self.enable,
+            0x4841 => match self.state {
+                RtcState::Transfer { .. } | RtcState::IndexSelect { .. } => {
+                    self.status = 0x80;
+                    let value = self.register(self.index);
+                    self.index = (self.index + 1) & 0x0f;
+                    value
+                }
+                _ => 0,
+            },
+            _ => {
+                let status = self.status;
+                self.status &= 0x7f;
+                status
+            }
+        }
+    }
+
+    fn store(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4840 => {
+                self.enable = value;
+                if value & 0x01 == 0 {
+                    self.state = RtcState::Inactive;
+                } else {
+                    self.status = 0x80;
+                    self.state = RtcState::ModeSelect;
+                }
+            }
+            0x4841 => match self.state {
+                RtcState::Inactive => {}
+                RtcState::ModeSelect => {
+                    let write = match value {
+                        0x03 => true,
+                        0x0c => false,
+                        _ => return,
+                    };
+                    self.status = 0x80;
+                    self.state = RtcState::IndexSelect { write: write };
+                    self.index = 0;
+                }
+                RtcState::IndexSelect { write } => {
+                    self.status = 0x80;
+                    self.index = value & 0x0f;
+                    self.state = RtcState::Transfer { write: write };
+                }
+                RtcState::Transfer { write: true } => {
+                    self.status = 0x80;
+                    let index = self.index;
+                    self.set_register(index, value & 0x0f);
+                    self.index = (self.index + 1) & 0x0f;
+                }
+                RtcState::Transfer { write: false } => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Reads one of the 16 clock registers.
+    fn register(&self, index: u8) -> u8 {
+        let time = &self.clock.time;
+        let hour = if self.control[2] & RTC_24H != 0 {
+            time.hour
+        } else {
+            // 12-hour mode, bit 2 of the tens digit indicates PM
+            let hour = if time.hour % 12 == 0 { 12 } else { time.hour % 12 };
+            hour + if time.hour >= 12 { 40 } else { 0 }
+        };
+        let year = (time.year % 100) as u8;
+        match index {
+            0 => time.second % 10,
+            1 => time.second / 10,
+            2 => time.minute % 10,
+            3 => time.minute / 10,
+            4 => hour % 10,
+            5 => hour / 10,
+            6 => time.day % 10,
+            7 => time.day / 10,
+            8 => time.month % 10,
+            9 => time.month / 10,
+            10 => year % 10,
+            11 => year / 10,
+            12 => time.weekday,
+            _ => self.control[index as usize - 13],
+        }
+    }
+
+    /// Writes one of the 16 clock registers.
+    fn set_register(&mut self, index: u8, value: u8) {
+        fn set_ones(field: &mut u8, value: u8) { *field = *field / 10 * 10 + value; }
+        fn set_tens(field: &mut u8, value: u8) { *field = value * 10 + *field % 10; }
+
+        let mut year = (self.clock.time.year % 100) as u8;
+        {
+            let time = &mut self.clock.time;
+            match index {
+                0 => set_ones(&mut time.second, value),
+                1 => set_tens(&mut time.second, value),
+                2 => set_ones(&mut time.minute, value),
+                3 => set_tens(&mut time.minute, value),
+                4 => set_ones(&mut time.hour, value),
+                5 => set_tens(&mut time.hour, value & 0x03),
+                6 => set_ones(&mut time.day, value),
+                7 => set_tens(&mut time.day, value),
+                8 => set_ones(&mut time.month, value),
+                9 => set_tens(&mut time.month, value),
+                10 => set_ones(&mut year, value),
+                11 => set_tens(&mut year, value),
+                12 => time.weekday = value % 7,
+                _ => {}
+            }
+        }
+        if index == 10 || index == 11 {
+            // The chip only stores 2 year digits, assume 1990-2089
+            self.clock.time.year = if year >= 90 { 1900 } else { 2000 } + year as u16;
+        }
+        if index <= 12 {
+            self.clock.adjusted();
+        }
+
+        match index {
+            13 => {
+                // 30 second adjustment: Round to the nearest minute
+                if value & 0x08 != 0 {
+                    let round_up = self.clock.time.second >= 30;
+                    self.clock.time.second = if round_up { 59 } else { 0 };
+                    self.clock.reset_fraction();
+                    if round_up { self.clock.time.tick(); }
+                    self.clock.adjusted();
+                }
+                self.control[0] = value & !0x08;
+            }
+            14 => self.control[1] = value,
+            15 => {
+                if value & RTC_RESET != 0 {
+                    self.clock.time.second = 0;
+                    self.clock.reset_fraction();
+                    self.clock.adjusted();
+                }
+                self.control[2] = value;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Arithmetic encoder producing streams the decompressor decodes back to the encoded data. It
+    /// drives a `Decompressor` as its model, so the context selection mirrors the decoder.
+    struct Encoder {
+        model: Decompressor,
+        /// Lower bound of the coding interval, one bit per element. The last 8 bits line up with
+        /// the decoder's `value`.
+        low: Vec<u8>,
+    }
+
+    impl Encoder {
+        fn new(mode: u8) -> Self {
+            let mut model = Decompressor::new();
+            model.init(&[], mode, 0);
+            Encoder { model: model, low: vec![0; 8] }
+        }
+
+        /// Encodes a symbol in context `con` (the counterpart of `Decompressor::decode_bit`).
+        fn encode_bit(&mut self, con: usize, lps: bool) {
+            let m = &mut self.model;
+            let (prob, next_lps, next_mps, toggle) = EVOLUTION[m.context_state[con] as usize];
+
+            if lps {
+                // Add the size of the MPS interval to the lower bound
+                let mut carry = (m.span - (prob - 1)) as u16;
+                for bit in self.low.iter_mut().rev() {
+                    carry += *bit as u16;
+                    *bit = carry as u8 & 1;
+                    carry >>= 1;
+                }
+                m.span = prob - 1;
+            } else {
+                m.span -= prob;
+            }
+
+            let mut shifted = false;
+            while m.span < 0x7f {
+                shifted = true;
+                m.span = (m.span << 1) + 1;
+                self.low.push(0);
+            }
+
+            m.lps = m.lps << 1 | lps as u32;
+            m.inverts = m.inverts << 1 | m.context_invert[con] as u32;
+            if lps {
+                if toggle { m.context_invert[con] = !m.context_invert[con]; }
+                m.context_state[con] = next_lps;
+            } else if shifted {
+                m.context_state[con] = next_mps;
+            }
+        }
+
+        fn encode_mode0(&mut self, byte: u8) {
+            for bit in 0..8 {
+                let mask: u32 = (1 << (bit & 3)) - 1;
+                let m = &self.model;
+                let mut con = (mask + ((m.inverts & mask) ^ (m.lps & mask))) as usize;
+                if bit > 3 { con += 15; }
+
+                let mps = (m.out >> 15) & 1 ^ m.context_invert[con] as u32;
+                let value = (byte >> (7 - bit)) as u32 & 1;
+                self.encode_bit(con, value != mps);
+                self.model.out = self.model.out << 1 | value;
+            }
+        }
+
+        /// Encodes the ranking index of a pixel, most significant bit first, starting in context
+        /// `con`. `next` returns the context of the following bit.
+        fn encode_index<F>(&mut self, mut con: usize, index: usize, bits: usize, next: F)
+        where F: Fn(usize, usize) -> usize {
+            for i in (0..bits).rev() {
+                let bit = index >> i & 1;
+                let lps = bit != self.model.context_invert[con] as usize;
+                self.encode_bit(con, lps);
+                con = next(con, bit);
+            }
+        }
+
+        /// Encodes a row of 8 pixels given as 2 bit planes.
+        fn encode_mode1(&mut self, plane0: u8, plane1: u8) {
+            for i in 0..8 {
+                let pixel = (plane0 >> (7 - i) & 1) << 1 | plane1 >> (7 - i) & 1;
+                let a = (self.model.out >> 2) as u8 & 0x3;
+                let b = (self.model.out >> 14) as u8 & 0x3;
+                let c = (self.model.out >> 16) as u8 & 0x3;
+                let con = Decompressor::reference_context(a, b, c);
+                let ranking = self.model.pixel_ranking(a, b, c, 4);
+                let index = ranking.iter().position(|&p| p == pixel).unwrap();
+                self.encode_index(con, index, 2, |con, bit| 5 + (con << 1) + bit);
+                self.model.out = self.model.out << 2 | pixel as u32;
+            }
+        }
+
+        /// Encodes a row of 8 pixels given as 4 bit planes.
+        fn encode_mode2(&mut self, planes: [u8; 4]) {
+            for i in 0..8 {
+                let mut pixel = 0;
+                for (plane, value) in planes.iter().enumerate() {
+                    pixel |= (value >> (7 - i) & 1) << (3 - plane);
+                }
+                let a = self.model.out as u8 & 0x0f;
+                let b = (self.model.out >> 28) as u8 & 0x0f;
+                let c = self.model.out_prev as u8 & 0x0f;
+                let refcon = Decompressor::reference_context(a, b, c);
+                let ranking = self.model.pixel_ranking(a, b, c, 16);
+                let index = ranking.iter().position(|&p| p == pixel).unwrap();
+                self.encode_index(0, index, 4, |con, bit| {
+                    if con < MODE2_CONTEXTS.len() {
+                        MODE2_CONTEXTS[con][bit] as usize + if con == 1 { refcon } else { 0 }
+                    } else {
+                        con
+                    }
+                });
+                self.model.out_prev = self.model.out_prev << 4 | (self.model.out >> 28) & 0x0f;
+                self.model.out = self.model.out << 4 | pixel as u32;
+            }
+        }
+
+        fn finish(self) -> Vec<u8> {
+            let mut stream: Vec<u8> = self.low.chunks(8).map(|bits| {
+                bits.iter().enumerate().fold(0, |byte, (i, &bit)| byte | bit << (7 - i))
+            }).collect();
+            stream.extend_from_slice(&[0; 4]);
+            stream
+        }
+    }
+
+    /// Compresses `data` in the given mode. In mode 2, `data` consists of 32-byte tiles: The 8
+    /// rows of bit planes 0 and 1, followed by the 8 rows of bit planes 2 and 3.
+    fn compress(mode: u8, data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(mode);
+        match mode {
+            0 => for &byte in data { encoder.encode_mode0(byte); },
+            1 => for row in data.chunks(2) { encoder.encode_mode1(row[0], row[1]); },
+            _ => for tile in data.chunks(32) {
+                for row in 0..8 {
+                    encoder.encode_mode2([tile[2 * row], tile[2 * row + 1], tile[16 + 2 * row],
+                        tile[16 + 2 * row + 1]]);
+                }
+            },
+        }
+        encoder.finish()
+    }
+
+    /// Creates a ROM with an empty program ROM and a data ROM containing a directory with a single
+    /// entry at `$00:0000`, pointing to `stream` at `$00:0100`.
+    fn rom_with_stream(mode: u8, stream: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; PROGRAM_ROM_SIZE + 0x10000];
+        rom[PROGRAM_ROM_SIZE..PROGRAM_ROM_SIZE + 4].copy_from_slice(&[mode, 0x00, 0x01, 0x00]);
+        rom[PROGRAM_ROM_SIZE + 0x100..PROGRAM_ROM_SIZE + 0x100 + stream.len()]
+            .copy_from_slice(stream);
+        rom
+    }
+
+    fn decompress(rom: &[u8], skip: u8, len: usize) -> Vec<u8> {
+        let mut spc = Spc7110::new(false);
+        spc.store_io(rom, 0x4805, skip);
+        spc.store_io(rom, 0x4806, 0);
+        assert_eq!(spc.load_io(rom, 0x480c), Some(0x80));
+        assert_eq!(spc.load_io(rom, 0x480c), Some(0x00));
+        (0..len).map(|_| spc.load_io(rom, 0x4800).unwrap()).collect()
+    }
+
+    /// Tile-like data with some noise
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut seed = 1u32;
+        (0..len).map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            if seed >> 28 == 0 { (seed >> 16) as u8 } else { (i / 16) as u8 & 0x3c }
+        }).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for mode in 0..3 {
+            for data in &[test_data(512), vec![0; 64], vec![0xff; 64]] {
+                let rom = rom_with_stream(mode, &compress(mode, data));
+                assert!(decompress(&rom, 0, data.len()) == *data, "mode {}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn skip() {
+        let data = test_data(128);
+        for mode in 0..3 {
+            let rom = rom_with_stream(mode, &compress(mode, &data));
+            // The skip count is in units of 1, 2 or 4 bytes
+            let skipped = 3 << mode;
+            assert!(decompress(&rom, 3, 32)[..] == data[skipped..skipped + 32], "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn multiply() {
+        let mut spc = Spc7110::new(false);
+        let write = |spc: &mut Spc7110, values: &[(u16, u8)]| {
+            for &(addr, value) in values { spc.store_io(&[], addr, value); }
+        };
+        let result = |spc: &mut Spc7110| {
+            (0..4).fold(0, |r, i| r | (spc.load_io(&[], 0x4828 + i).unwrap() as u32) << (i * 8))
+        };
+
+        write(&mut spc, &[(0x4820, 0x34), (0x4821, 0x12), (0x4824, 0x78), (0x4825, 0x56)]);
+        assert_eq!(result(&mut spc), 0x1234 * 0x5678);
+
+        // Signed: -2 * 3
+        write(&mut spc, &[(0x482e, 0x01), (0x4820, 0xfe), (0x4821, 0xff), (0x4824, 0x03),
+            (0x4825, 0x00)]);
+        assert_eq!(result(&mut spc), (-6i32) as u32);
+
+        // Writing the mode resets the ALU
+        write(&mut spc, &[(0x482e, 0x00)]);
+        assert_eq!(result(&mut spc), 0);
+        assert_eq!(spc.load_io(&[], 0x4820), Some(0));
+    }
+
+    #[test]
+    fn divide() {
+        let mut spc = Spc7110::new(false);
+        let divide = |spc: &mut Spc7110, dividend: u32, divisor: u16| {
+            for i in 0..4 { spc.store_io(&[], 0x4820 + i, (dividend >> (i * 8)) as u8); }
+            spc.store_io(&[], 0x4826, divisor as u8);
+            spc.store_io(&[], 0x4827, (divisor >> 8) as u8);
+            let quotient = (0..4).fold(0, |q, i| {
+                q | (spc.load_io(&[], 0x4828 + i).unwrap() as u32) << (i * 8)
+            });
+            let remainder = spc.load_io(&[], 0x482c).unwrap() as u16 |
+                (spc.load_io(&[], 0x482d).unwrap() as u16) << 8;
+            (quotient, remainder)
+        };
+
+        assert_eq!(divide(&mut spc, 100000, 7), (14285, 5));
+        assert_eq!(divide(&mut spc, 0x12345678, 0), (0, 0x5678));
+        spc.store_io(&[], 0x482e, 0x01);
+        assert_eq!(divide(&mut spc, (-100i32) as u32, 7), ((-14i32) as u32, (-2i16) as u16));
+        assert_eq!(divide(&mut spc, 100, (-7i16) as u16), ((-14i32) as u32, 2));
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut spc = Spc7110::new(false);
+        let len = PROGRAM_ROM_SIZE + 0x800000;
+        assert!(spc.store_io(&[], 0x4832, 0x0d));
+        assert_eq!(spc.load_io(&[], 0x4832), Some(0x05));
+
+        assert_eq!(spc.rom_offset(len, 0xc3, 0x4567), Some(0x034567));
+        assert_eq!(spc.rom_offset(len, 0x03, 0xc567), Some(0x03c567));
+        // Data ROM pages
+        assert_eq!(spc.rom_offset(len, 0xd1, 0x2345), Some(PROGRAM_ROM_SIZE + 0x112345));
+        assert_eq!(spc.rom_offset(len, 0xe1, 0x2345), Some(PROGRAM_ROM_SIZE + 0x512345));
+        assert_eq!(spc.rom_offset(len, 0x21, 0x8345), Some(PROGRAM_ROM_SIZE + 0x518345));
+        assert_eq!(spc.rom_offset(len, 0xf0, 0x0000), Some(PROGRAM_ROM_SIZE + 0x300000));
+        // The data ROM is mirrored
+        assert_eq!(spc.rom_offset(PROGRAM_ROM_SIZE + 0x200000, 0xe0, 0x0000),
+            Some(PROGRAM_ROM_SIZE + 0x100000));
+        assert_eq!(spc.rom_offset(PROGRAM_ROM_SIZE, 0xd0, 0x0000), None);
+
+        // The RAM is only accessible while enabled
+        let mut ram = vec![0; 0x2000];
+        spc.store(&mut ram, 0x00, 0x6123, 0x42);
+        assert_eq!(ram[0x0123], 0);
+        spc.store_io(&[], 0x4830, 0x80);
+        spc.store(&mut ram, 0x00, 0x6123, 0x42);
+        assert_eq!(spc.load(&[], &ram, 0x80, 0x6123), 0x42);
+    }
+}
@@ -0,0 +1,268 @@
+//! Sharp S-RTC real-time clock (Daikaijuu Monogatari II)
+//!
+//! The S-RTC is accessed through `$2800` (read) and `$2801` (write) and stores the time as 13
+//! decimal digits: Seconds, minutes, hours and day (2 digits each, least significant first), the
+//! month (1 digit, 1-12), the year (2 digits), the century (9: 1900, 10: 2000, ...) and the day of
+//! the week.
+//!
+//! Writing `$0D` to `$2801` starts reading the time: The first read returns `$0F`, the following
+//! reads return the digits, followed by `$0F` again. Writing `$0E` starts a command: `$00` sets the
+//! time (followed by the first 12 digits, the weekday is computed by the chip), `$04` clears it.
+
+use diagnostics::Diagnostics;
+
+use super::rtc::{Clock, DateTime};
+
+use libsavestate::SaveState;
+
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Read,
+    Command,
+    Write,
+    Ready,
+}
+
+impl SaveState for Mode {
+    fn save_state<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let value: u8 = match *self {
+            Mode::Read => 0,
+            Mode::Command => 1,
+            Mode::Write => 2,
+            Mode::Ready => 3,
+        };
+        value.save_state(w)
+    }
+
+    fn restore_state<R: Read + ?Sized>(&mut self, r: &mut R) -> io::Result<()> {
+        let mut value = 0u8;
+        try!(value.restore_state(r));
+        *self = match value {
+            0 => Mode::Read,
+            1 => Mode::Command,
+            2 => Mode::Write,
+            3 => Mode::Ready,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid S-RTC mode {}", value))),
+        };
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Srtc {
+    clock: Clock,
+    mode: Mode,
+    /// Index of the digit accessed next. -1 if the next read starts a new transfer.
+    index: i8,
+    /// The digits of the time, latched when a read starts (13 used)
+    digits: [u8; 16],
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+impl_save_state!(Srtc { clock, mode, index, digits } ignore { diagnostics });
+
+impl Srtc {
+    pub fn new() -> Self {
+        Srtc {
+            clock: Clock::new(),
+            mode: Mode::Read,
+            index: -1,
+            digits: [0; 16],
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    pub fn clock_mut(&mut self) -> &mut Clock { &mut self.clock }
+
+    pub fn clock(&self) -> &Clock { &self.clock }
+
+    pub fn run(&mut self, master_cy: u32) {
+        self.clock.run(master_cy);
+    }
+
+    /// Splits the current time into digits.
+    fn latch(&mut self) {
+        let t = &self.clock.time;
+        let year = t.year % 100;
+        self.digits = [0; 16];
+        self.digits[..13].copy_from_slice(&[
+            t.second % 10, t.second / 10,
+            t.minute % 10, t.minute / 10,
+            t.hour % 10, t.hour / 10,
+            t.day % 10, t.day / 10,
+            t.month,
+            (year % 10) as u8, (year / 10) as u8,
+            (t.year / 100).saturating_sub(10) as u8,
+            t.weekday,
+        ]);
+    }
+
+    /// Sets the time from the digits written by the game.
+    fn apply_digits(&mut self) {
+        let d = &self.digits;
+        let year = 1000 + d[11] as u16 * 100 + d[10] as u16 * 10 + d[9] as u16;
+        let mut time = DateTime {
+            second: d[0] + d[1] * 10,
+            minute: d[2] + d[3] * 10,
+            hour: d[4] + d[5] * 10,
+            day: d[6] + d[7] * 10,
+            month: d[8],
+            year: year,
+            weekday: 0,
+        };
+        time.weekday = weekday(year, time.month, time.day);
+        self.clock.set(time);
+    }
+
+    pub fn load_io(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x2800 => Some(if self.mode != Mode::Read {
+                0
+            } else if self.index < 0 {
+                self.latch();
+                self.index = 0;
+                0x0f
+            } else if self.index > 12 {
+                self.index = -1;
+                0x0f
+            } else {
+                self.index += 1;
+                self.digits[self.index as usize - 1]
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn store_io(&mut self, addr: u16, value: u8) -> bool {
+        if addr != 0x2801 { return false; }
+
+        let value = value & 0x0f;
+        match value {
+            0x0d => {
+                self.mode = Mode::Read;
+                self.index = -1;
+            }
+            0x0e => self.mode = Mode::Command,
+            0x0f => {}
+            _ => match self.mode {
+                Mode::Write => if self.index >= 0 && self.index < 12 {
+                    self.digits[self.index as usize] = value;
+                    self.index += 1;
+                    if self.index == 12 {
+                        self.apply_digits();
+                        self.index += 1;
+                    }
+                },
+                Mode::Command => match value {
+                    0 => {
+                        self.latch();
+                        self.mode = Mode::Write;
+                        self.index = 0;
+                    }
+                    4 => {
+                        self.mode = Mode::Ready;
+                        self.index = -1;
+                        self.digits = [0; 16];
+                        self.clock.set(DateTime::default());
+                    }
+                    _ => {
+                        diag!(self.diagnostics, "unknown S-RTC command ${:X}", value);
+                        self.mode = Mode::Ready;
+                    }
+                },
+                Mode::Read | Mode::Ready => {}
+            },
+        }
+        true
+    }
+}
+
+/// Computes the day of the week (0 = Sunday), using Zeller's congruence.
+fn weekday(year: u16, month: u8, day: u8) -> u8 {
+    let (year, month) = if month <= 2 {
+        (year as u32 - 1, month as u32 + 12)
+    } else {
+        (year as u32, month as u32)
+    };
+    let h = (day as u32 + 13 * (month + 1) / 5 + year + year / 4 - year / 100 + year / 400) % 7;
+    // Zeller's congruence returns 0 for Saturday
+    ((h + 6) % 7) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(srtc: &mut Srtc, values: &[u8]) {
+        for &value in values {
+            assert!(srtc.store_io(0x2801, value));
+        }
+    }
+
+    fn read(srtc: &mut Srtc, count: usize) -> Vec<u8> {
+        (0..count).map(|_| srtc.load_io(0x2800).unwrap()).collect()
+    }
+
+    #[test]
+    fn read_time() {
+        let mut srtc = Srtc::new();
+        srtc.clock_mut().set(DateTime {
+            second: 59,
+            minute: 45,
+            hour: 13,
+            day: 25,
+            month: 8,
+            year: 2017,
+            weekday: 5,
+        });
+
+        write(&mut srtc, &[0x0d]);
+        assert_eq!(read(&mut srtc, 15),
+            [0x0f, 9, 5, 5, 4, 3, 1, 5, 2, 8, 7, 1, 10, 5, 0x0f]);
+        // The next read starts over
+        assert_eq!(read(&mut srtc, 2), [0x0f, 9]);
+        assert_eq!(srtc.load_io(0x2801), None);
+    }
+
+    #[test]
+    fn set_time() {
+        let mut srtc = Srtc::new();
+        write(&mut srtc, &[0x0e, 0x00, 8, 5, 9, 5, 3, 2, 1, 3, 12, 9, 9, 9]);
+        {
+            let t = &srtc.clock().time;
+            assert_eq!((t.year, t.month, t.day, t.hour, t.minute, t.second),
+                (1999, 12, 31, 23, 59, 58));
+            // Computed by the chip (a Friday)
+            assert_eq!(t.weekday, 5);
+        }
+
+        // Further digits are ignored
+        write(&mut srtc, &[1, 2]);
+        assert_eq!(srtc.clock().time.second, 58);
+
+        // Reads only work in read mode
+        assert_eq!(srtc.load_io(0x2800), Some(0));
+        write(&mut srtc, &[0x0d]);
+        assert_eq!(read(&mut srtc, 14), [0x0f, 8, 5, 9, 5, 3, 2, 1, 3, 12, 9, 9, 9, 5]);
+    }
+
+    #[test]
+    fn clear_time() {
+        let mut srtc = Srtc::new();
+        write(&mut srtc, &[0x0e, 0x04]);
+        assert_eq!(srtc.clock().time.year, 0);
+        assert_eq!(srtc.clock().time.second, 0);
+    }
+
+    #[test]
+    fn weekday() {
+        assert_eq!(super::weekday(2000, 1, 1), 6);
+        assert_eq!(super::weekday(2000, 2, 29), 2);
+        assert_eq!(super::weekday(2017, 8, 25), 5);
+        assert_eq!(super::weekday(1900, 3, 1), 4);
+    }
+}
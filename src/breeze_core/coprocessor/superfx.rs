@@ -0,0 +1,1230 @@
+//! SuperFX (GSU-1 / GSU-2) coprocessor emulation
+//!
+//! The GSU is a 16-bit RISC CPU with 16 general purpose registers (R15 is the program counter), a
+//! one-byte instruction pipeline (so every instruction following a jump or branch is executed
+//! before the jump takes effect), 512 Bytes of instruction cache, and a pixel plotting unit that
+//! writes SNES characters directly into the Game Pak RAM. It runs at 21.47 MHz (or 10.74 MHz if
+//! `CLSR` is cleared).
+//!
+//! The SNES CPU accesses the GSU registers at `$3000-$30FF` and the cache RAM at `$3100-$32FF`.
+//! While the GSU is running, it may own the ROM or RAM bus (`SCMR.RON` and `SCMR.RAN`) and the
+//! SNES CPU reads fixed values from them.
+//!
+//! Instruction timing is approximated: Every byte fetched from the cache takes 1 cycle, every ROM
+//! and RAM access takes `MEM_CYCLES` cycles. Accesses to the ROM and RAM buffers complete
+//! immediately, and the GSU doesn't wait for `RON`/`RAN` before accessing the cartridge.
+
+use diagnostics::Diagnostics;
+
+use std::mem;
+
+/// Master clock cycles per GSU cycle, depending on `CLSR`
+const GSU_CYCLE: [i32; 2] = [2, 1];
+
+/// GSU cycles needed for an access to ROM or Game Pak RAM
+const MEM_CYCLES: u32 = 3;
+
+/// Value read from `$303B` (VCR - Version Code Register)
+const VERSION: u8 = 0x04;
+
+// Bits of the SFR (Status Flag Register)
+const Z: u16 = 0x0002;
+const CY: u16 = 0x0004;
+const S: u16 = 0x0008;
+const OV: u16 = 0x0010;
+const G: u16 = 0x0020;
+const ALT1: u16 = 0x0100;
+const ALT2: u16 = 0x0200;
+const B: u16 = 0x1000;
+const IRQ: u16 = 0x8000;
+
+/// Only these SFR bits can be written by the SNES CPU
+const SFR_WRITE_MASK: u16 = Z | CY | S | OV | G | ALT1 | ALT2 | B | 0x0c00;
+
+// POR (Plot Option Register) bits
+const POR_TRANSPARENT: u8 = 0x01;
+const POR_DITHER: u8 = 0x02;
+const POR_HIGH_NIBBLE: u8 = 0x04;
+const POR_FREEZE_HIGH: u8 = 0x08;
+const POR_OBJ: u8 = 0x10;
+
+// SCMR (Screen Mode Register) bits
+const SCMR_RAN: u8 = 0x08;
+const SCMR_RON: u8 = 0x10;
+
+/// Values the SNES CPU reads from ROM while the GSU owns the ROM bus. This redirects the interrupt
+/// vectors to `$0100-$010F`, where games put their handlers in WRAM.
+const ROM_BUS_VECTORS: [u8; 16] = [
+    0x00, 0x01, 0x00, 0x01, 0x04, 0x01, 0x00, 0x01,
+    0x00, 0x01, 0x08, 0x01, 0x00, 0x01, 0x0c, 0x01,
+];
+
+byte_array!(CacheRam[512] with save state please);
+
+/// One of the two 8-pixel plot buffers
+#[derive(Clone, Copy, Default)]
+struct PixelCache {
+    /// `(y << 5) + (x >> 3)` of the cached pixel row
+    offset: u16,
+    /// Bitmask of the pixels that were plotted
+    bitpend: u8,
+    /// Pixel colors (index 0 is the rightmost pixel)
+    data: [u8; 8],
+}
+
+impl_save_state!(PixelCache { offset, bitpend, data } ignore {});
+
+/// The cartridge memory the GSU is connected to
+struct Bus<'a> {
+    rom: &'a [u8],
+    ram: &'a mut [u8],
+}
+
+impl<'a> Bus<'a> {
+    /// Reads from the GSU's address space.
+    fn read(&self, bank: u8, addr: u16) -> u8 {
+        match bank & 0x7f {
+            0x00 ... 0x3f => read_mirrored(self.rom, lorom_offset(bank, addr)),
+            0x40 ... 0x5f => read_mirrored(self.rom, hirom_offset(bank, addr)),
+            0x60 ... 0x7f => read_mirrored(self.ram, ram_offset(bank, addr)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Reads `mem[offset]`, mirroring `offset` into `mem`. Returns 0 if `mem` is empty.
+fn read_mirrored(mem: &[u8], offset: usize) -> u8 {
+    if mem.is_empty() { 0 } else { mem[offset % mem.len()] }
+}
+
+/// Writes `mem[offset]`, mirroring `offset` into `mem`. Returns `false` if `mem` is empty.
+fn write_mirrored(mem: &mut [u8], offset: usize, value: u8) -> bool {
+    if mem.is_empty() { return false; }
+    let len = mem.len();
+    mem[offset % len] = value;
+    true
+}
+
+/// Offset into the ROM for LoROM-mapped banks (`$00-$3F`)
+fn lorom_offset(bank: u8, addr: u16) -> usize {
+    (bank as usize & 0x3f) * 0x8000 + (addr as usize & 0x7fff)
+}
+
+/// Offset into the ROM for the linear ROM mapping (`$40-$5F`)
+fn hirom_offset(bank: u8, addr: u16) -> usize {
+    (bank as usize & 0x1f) << 16 | addr as usize
+}
+
+/// Offset into the Game Pak RAM for banks `$70-$71`
+fn ram_offset(bank: u8, addr: u16) -> usize {
+    (bank as usize & 0x01) << 16 | addr as usize
+}
+
+/// The SuperFX chip
+#[derive(Clone)]
+pub struct SuperFx {
+    /// General purpose registers R0-R15
+    r: [u16; 16],
+    /// Status Flag Register
+    sfr: u16,
+    /// Program Bank Register
+    pbr: u8,
+    /// ROM Bank Register
+    rombr: u8,
+    /// RAM Bank Register
+    rambr: u8,
+    /// Cache Base Register
+    cbr: u16,
+    /// Screen Base Register (in 1 KB units)
+    scbr: u8,
+    /// Screen Mode Register
+    scmr: u8,
+    /// Color Register
+    colr: u8,
+    /// Plot Option Register
+    por: u8,
+    /// Backup RAM Register
+    bramr: u8,
+    /// Config Register
+    cfgr: u8,
+    /// Clock Select Register
+    clsr: u8,
+
+    /// Source register selected by `FROM`/`WITH`
+    sreg: u8,
+    /// Destination register selected by `TO`/`WITH`
+    dreg: u8,
+    /// The prefetched opcode that will be executed next
+    pipeline: u8,
+    /// Set when the current instruction wrote to R15
+    r15_modified: bool,
+    /// ROM buffer, reloaded whenever R14 is written
+    rom_buffer: u8,
+    /// Address of the last RAM access (used by `SBK`)
+    ram_addr: u16,
+
+    cache: CacheRam,
+    /// Valid flags of the 32 16-Byte cache lines
+    cache_valid: [bool; 32],
+    pixel_caches: [PixelCache; 2],
+
+    /// GSU cycles used by the current instruction
+    cy: u32,
+    /// Master clock cycles the GSU has to run to catch up with the SNES CPU
+    master_cy_debt: i32,
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
+}
+
+impl_save_state!(SuperFx {
+    r, sfr, pbr, rombr, rambr, cbr, scbr, scmr, colr, por, bramr, cfgr, clsr, sreg, dreg,
+    pipeline, r15_modified, rom_buffer, ram_addr, cache, cache_valid, pixel_caches, master_cy_debt
+} ignore { cy, diagnostics });
+
+impl SuperFx {
+    pub fn new() -> Self {
+        SuperFx {
+            r: [0; 16],
+            sfr: 0,
+            pbr: 0,
+            rombr: 0,
+            rambr: 0,
+            cbr: 0,
+            scbr: 0,
+            scmr: 0,
+            colr: 0,
+            por: 0,
+            bramr: 0,
+            cfgr: 0,
+            clsr: 0,
+            sreg: 0,
+            dreg: 0,
+            pipeline: 0x01,     // NOP
+            r15_modified: false,
+            rom_buffer: 0,
+            ram_addr: 0,
+            cache: CacheRam::default(),
+            cache_valid: [false; 32],
+            pixel_caches: [PixelCache::default(); 2],
+            cy: 0,
+            master_cy_debt: 0,
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// Runs the GSU for (about) `master_cy` master clock cycles.
+    pub fn run(&mut self, rom: &[u8], ram: &mut [u8], master_cy: u32) {
+        if self.sfr & G == 0 {
+            self.master_cy_debt = 0;
+            return;
+        }
+
+        let mut bus = Bus { rom: rom, ram: ram };
+        self.master_cy_debt += master_cy as i32;
+        while self.master_cy_debt > 0 && self.sfr & G != 0 {
+            self.cy = 0;
+            let op = self.peek_pipe(&bus);
+            self.execute(&mut bus, op);
+            if !self.r15_modified {
+                self.r[15] = self.r[15].wrapping_add(1);
+            }
+            self.master_cy_debt -= self.cy as i32 * GSU_CYCLE[self.clsr as usize & 1];
+        }
+    }
+
+    /// Returns `true` if the GSU asserts the IRQ line of the SNES CPU.
+    pub fn irq_pending(&self) -> bool { self.sfr & IRQ != 0 }
+
+    /// Returns `true` if the GSU is running and currently owns the ROM bus.
+    fn owns_rom(&self) -> bool { self.sfr & G != 0 && self.scmr & SCMR_RON != 0 }
+
+    /// Returns `true` if the GSU is running and currently owns the RAM bus.
+    fn owns_ram(&self) -> bool { self.sfr & G != 0 && self.scmr & SCMR_RAN != 0 }
+
+    /// Performs a load from the SNES CPU.
+    pub fn load(&mut self, rom: &[u8], ram: &[u8], bank: u8, addr: u16) -> u8 {
+        match self.snes_target(bank, addr) {
+            Target::Rom(_) if self.owns_rom() => ROM_BUS_VECTORS[addr as usize & 0xf],
+            Target::Ram(_) if self.owns_ram() => 0,
+            Target::Rom(offset) => read_mirrored(rom, offset),
+            Target::Ram(offset) => read_mirrored(ram, offset),
+            Target::Unmapped => {
+                diag!(self.diagnostics, "SuperFX: unmapped load from ${:02X}:{:04X}", bank, addr);
+                0
+            }
+        }
+    }
+
+    /// Performs a store from the SNES CPU.
+    pub fn store(&mut self, ram: &mut [u8], bank: u8, addr: u16, value: u8) {
+        match self.snes_target(bank, addr) {
+            Target::Ram(_) if self.owns_ram() => {}
+            Target::Ram(offset) => {
+                write_mirrored(ram, offset, value);
+            }
+            Target::Rom(_) => {
+                diag!(self.diagnostics, "SuperFX: write of ${:02X} to ROM at ${:02X}:{:04X}",
+                    value, bank, addr);
+            }
+            Target::Unmapped => {
+                diag!(self.diagnostics, "SuperFX: unmapped store to ${:02X}:{:04X}", bank, addr);
+            }
+        }
+    }
+
+    /// Reads the byte the SNES CPU would see at `bank:addr` without side effects (ignoring bus
+    /// ownership). Returns `None` for unmapped addresses.
+    pub fn peek(&self, rom: &[u8], ram: &[u8], bank: u8, addr: u16) -> Option<u8> {
+        let (mem, offset) = match self.snes_target(bank, addr) {
+            Target::Rom(offset) => (rom, offset),
+            Target::Ram(offset) => (ram, offset),
+            Target::Unmapped => return None,
+        };
+        if mem.is_empty() { None } else { Some(read_mirrored(mem, offset)) }
+    }
+
+    /// Overwrites the ROM or RAM byte the SNES CPU sees at `bank:addr`. Returns `false` if no
+    /// memory is mapped there.
+    pub fn poke(&mut self, rom: &mut [u8], ram: &mut [u8], bank: u8, addr: u16, value: u8)
+    -> bool {
+        let (mem, offset) = match self.snes_target(bank, addr) {
+            Target::Rom(offset) => (rom, offset),
+            Target::Ram(offset) => (ram, offset),
+            Target::Unmapped => return false,
+        };
+        write_mirrored(mem, offset, value)
+    }
+
+    /// Returns the offset into the ROM image the SNES CPU accesses at `bank:addr`.
+    pub fn rom_offset(&self, rom_len: usize, bank: u8, addr: u16) -> Option<usize> {
+        match self.snes_target(bank, addr) {
+            Target::Rom(offset) if rom_len != 0 => Some(offset % rom_len),
+            _ => None,
+        }
+    }
+
+    /// Handles a load from `$2200-$3FFF` (in the system banks). Returns `None` if the SuperFX
+    /// doesn't map anything there.
+    pub fn load_io(&mut self, addr: u16) -> Option<u8> {
+        let value = match addr {
+            0x3000 ... 0x301f => {
+                let reg = self.r[(addr as usize & 0x1f) >> 1];
+                if addr & 1 == 0 { reg as u8 } else { (reg >> 8) as u8 }
+            }
+            0x3030 => self.sfr as u8,
+            0x3031 => {
+                // Reading the high byte acknowledges the IRQ
+                let value = (self.sfr >> 8) as u8;
+                self.sfr &= !IRQ;
+                value
+            }
+            0x3034 => self.pbr,
+            0x3036 => self.rombr,
+            0x303b => VERSION,
+            0x303c => self.rambr,
+            0x303e => self.cbr as u8,
+            0x303f => (self.cbr >> 8) as u8,
+            0x3100 ... 0x32ff => self.cache[(addr.wrapping_add(self.cbr) & 0x1ff) as usize],
+            0x3000 ... 0x30ff => {
+                diag!(self.diagnostics,
+                    "SuperFX: read from unimplemented or write-only register ${:04X}", addr);
+                0
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Handles a store to `$2200-$3FFF` (in the system banks). Returns `false` if the SuperFX
+    /// doesn't map anything there.
+    pub fn store_io(&mut self, addr: u16, value: u8) -> bool {
+        match addr {
+            0x3000 ... 0x301f => {
+                let reg = &mut self.r[(addr as usize & 0x1f) >> 1];
+                *reg = if addr & 1 == 0 {
+                    *reg & 0xff00 | value as u16
+                } else {
+                    *reg & 0x00ff | (value as u16) << 8
+                };
+                // Writing the high byte of R15 starts the GSU
+                if addr == 0x301f {
+                    self.sfr |= G;
+                }
+            }
+            0x3030 | 0x3031 => {
+                let was_running = self.sfr & G != 0;
+                let sfr = if addr == 0x3030 {
+                    self.sfr & 0xff00 | value as u16
+                } else {
+                    self.sfr & 0x00ff | (value as u16) << 8
+                };
+                self.sfr = self.sfr & !SFR_WRITE_MASK | sfr & SFR_WRITE_MASK;
+                if was_running && self.sfr & G == 0 {
+                    // Stopping the GSU resets the cache
+                    self.cbr = 0;
+                    self.flush_cache();
+                }
+            }
+            0x3033 => self.bramr = value & 0x01,
+            0x3034 => {
+                self.pbr = value & 0x7f;
+                self.flush_cache();
+            }
+            0x3037 => self.cfgr = value,
+            0x3038 => self.scbr = value,
+            0x3039 => self.clsr = value & 0x01,
+            0x303a => self.scmr = value,
+            0x3100 ... 0x32ff => {
+                let offset = (addr.wrapping_add(self.cbr) & 0x1ff) as usize;
+                self.cache[offset] = value;
+                // A cache line becomes valid once its last byte is written
+                if offset & 0xf == 0xf {
+                    self.cache_valid[offset >> 4] = true;
+                }
+            }
+            0x3000 ... 0x30ff => {
+                diag!(self.diagnostics,
+                    "SuperFX: write of ${:02X} to unimplemented or read-only register ${:04X}",
+                    value, addr);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Resolves an address accessed by the SNES CPU.
+    fn snes_target(&self, bank: u8, addr: u16) -> Target {
+        match (bank & 0x7f, addr) {
+            (0x00 ... 0x3f, 0x6000 ... 0x7fff) => Target::Ram(addr as usize & 0x1fff),
+            (0x00 ... 0x3f, 0x8000 ... 0xffff) => Target::Rom(lorom_offset(bank, addr)),
+            (0x40 ... 0x5f, _) => Target::Rom(hirom_offset(bank, addr)),
+            (0x70 ... 0x71, _) => Target::Ram(ram_offset(bank, addr)),
+            _ => Target::Unmapped,
+        }
+    }
+}
+
+/// What an address accessed by the SNES CPU is mapped to
+#[derive(Clone, Copy)]
+enum Target {
+    Rom(usize),
+    Ram(usize),
+    Unmapped,
+}
+
+/// Instruction fetching, memory access and the plotting unit
+impl SuperFx {
+    fn flag(&self, flag: u16) -> bool { self.sfr & flag != 0 }
+
+    fn set_flag(&mut self, flag: u16, value: bool) {
+        if value { self.sfr |= flag } else { self.sfr &= !flag }
+    }
+
+    fn flush_cache(&mut self) {
+        self.cache_valid = [false; 32];
+    }
+
+    /// Fetches an instruction byte from the cache or from memory.
+    fn read_opcode(&mut self, bus: &Bus, addr: u16) -> u8 {
+        let offset = addr.wrapping_sub(self.cbr) as usize;
+        if offset < 512 {
+            let line = offset >> 4;
+            if !self.cache_valid[line] {
+                let start = self.cbr.wrapping_add(offset as u16 & 0xfff0);
+                for i in 0..16 {
+                    let value = bus.read(self.pbr, start.wrapping_add(i as u16));
+                    self.cache[(offset & 0x1f0) + i] = value;
+                }
+                self.cache_valid[line] = true;
+                self.cy += 16 * MEM_CYCLES;
+            } else {
+                self.cy += 1;
+            }
+            self.cache[offset]
+        } else {
+            self.cy += MEM_CYCLES;
+            bus.read(self.pbr, addr)
+        }
+    }
+
+    /// Returns the opcode in the pipeline and fetches the byte at R15.
+    fn peek_pipe(&mut self, bus: &Bus) -> u8 {
+        let r15 = self.r[15];
+        let next = self.read_opcode(bus, r15);
+        let op = mem::replace(&mut self.pipeline, next);
+        self.r15_modified = false;
+        op
+    }
+
+    /// Returns the byte in the pipeline and fetches the next one (used for immediate operands).
+    fn pipe(&mut self, bus: &Bus) -> u8 {
+        self.r[15] = self.r[15].wrapping_add(1);
+        let r15 = self.r[15];
+        let next = self.read_opcode(bus, r15);
+        let value = mem::replace(&mut self.pipeline, next);
+        self.r15_modified = false;
+        value
+    }
+
+    /// Writes a register. Writing R14 reloads the ROM buffer, writing R15 performs a (delayed)
+    /// jump.
+    fn set_reg(&mut self, bus: &Bus, reg: u8, value: u16) {
+        self.r[reg as usize] = value;
+        match reg {
+            14 => {
+                self.rom_buffer = bus.read(self.rombr, value);
+                self.cy += MEM_CYCLES;
+            }
+            15 => self.r15_modified = true,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&mut self, bus: &Bus, addr: u16) -> u8 {
+        self.cy += MEM_CYCLES;
+        read_mirrored(bus.ram, ram_offset(self.rambr, addr))
+    }
+
+    fn write_ram(&mut self, bus: &mut Bus, addr: u16, value: u8) {
+        self.cy += MEM_CYCLES;
+        write_mirrored(bus.ram, ram_offset(self.rambr, addr), value);
+    }
+
+    fn read_ram_word(&mut self, bus: &Bus, addr: u16) -> u16 {
+        self.ram_addr = addr;
+        let lo = self.read_ram(bus, addr) as u16;
+        let hi = self.read_ram(bus, addr ^ 1) as u16;
+        hi << 8 | lo
+    }
+
+    fn write_ram_word(&mut self, bus: &mut Bus, addr: u16, value: u16) {
+        self.ram_addr = addr;
+        self.write_ram(bus, addr, value as u8);
+        self.write_ram(bus, addr ^ 1, (value >> 8) as u8);
+    }
+
+    /// Applies the `POR` nibble settings to a color written by `COLOR` or `GETC`.
+    fn color(&self, source: u8) -> u8 {
+        if self.por & POR_HIGH_NIBBLE != 0 {
+            self.colr & 0xf0 | source >> 4
+        } else if self.por & POR_FREEZE_HIGH != 0 {
+            self.colr & 0xf0 | source & 0x0f
+        } else {
+            source
+        }
+    }
+
+    /// Bits per pixel of the current screen mode
+    fn bpp(&self) -> usize {
+        match self.scmr & 0x03 {
+            0 => 2,
+            1 | 2 => 4,
+            _ => 8,
+        }
+    }
+
+    /// Returns the RAM address (in bank `$70`) of the row containing pixel (`x`, `y`) in the first
+    /// bitplane.
+    fn char_row_addr(&self, x: u8, y: u8) -> u16 {
+        let (x, y) = (x as u16, y as u16);
+        // Screen height (`SCMR.HT`), or OBJ mode
+        let height = if self.por & POR_OBJ != 0 {
+            3
+        } else {
+            (self.scmr >> 2) & 1 | (self.scmr >> 4) & 2
+        };
+        let cn = match height {
+            0 => ((x & 0xf8) << 1) + ((y & 0xf8) >> 3),
+            1 => ((x & 0xf8) << 1) + ((x & 0xf8) >> 1) + ((y & 0xf8) >> 3),
+            2 => ((x & 0xf8) << 1) + (x & 0xf8) + ((y & 0xf8) >> 3),
+            _ => ((y & 0x80) << 2) + ((x & 0x80) << 1) + ((y & 0x78) << 1) + ((x & 0x78) >> 3),
+        };
+        let char_size = self.bpp() as u16 * 8;
+        cn.wrapping_mul(char_size)
+            .wrapping_add((self.scbr as u16) << 10)
+            .wrapping_add((y & 7) * 2)
+    }
+
+    /// Offset of bitplane `n` relative to the first bitplane of a character row
+    fn plane_offset(n: usize) -> u16 { (((n >> 1) << 4) + (n & 1)) as u16 }
+
+    /// Writes a pixel cache to RAM.
+    fn flush_pixel_cache(&mut self, bus: &mut Bus, index: usize) {
+        let cache = self.pixel_caches[index];
+        if cache.bitpend == 0 { return; }
+
+        let x = (cache.offset << 3) as u8;
+        let y = (cache.offset >> 5) as u8;
+        let row = self.char_row_addr(x, y);
+        for n in 0..self.bpp() {
+            let addr = row.wrapping_add(Self::plane_offset(n));
+            let mut data = 0;
+            for x in 0..8 {
+                data |= ((cache.data[x] >> n) & 1) << x;
+            }
+            if cache.bitpend != 0xff {
+                data &= cache.bitpend;
+                data |= read_mirrored(bus.ram, addr as usize) & !cache.bitpend;
+                self.cy += MEM_CYCLES;
+            }
+            write_mirrored(bus.ram, addr as usize, data);
+            self.cy += MEM_CYCLES;
+        }
+        self.pixel_caches[index].bitpend = 0;
+    }
+
+    /// Plots a pixel in the current color (`PLOT`).
+    fn plot(&mut self, bus: &mut Bus, x: u8, y: u8) {
+        if self.por & POR_TRANSPARENT == 0 {
+            let transparent = if self.scmr & 0x03 == 3 && self.por & POR_FREEZE_HIGH == 0 {
+                self.colr == 0
+            } else {
+                self.colr & 0x0f == 0
+            };
+            if transparent { return; }
+        }
+
+        let mut color = self.colr;
+        if self.por & POR_DITHER != 0 && self.scmr & 0x03 != 3 {
+            if (x ^ y) & 1 != 0 { color >>= 4; }
+            color &= 0x0f;
+        }
+
+        let offset = ((y as u16) << 5) + (x as u16 >> 3);
+        if self.pixel_caches[0].offset != offset {
+            self.flush_pixel_cache(bus, 1);
+            self.pixel_caches[1] = self.pixel_caches[0];
+            self.pixel_caches[0].bitpend = 0;
+            self.pixel_caches[0].offset = offset;
+        }
+
+        let bit = (x & 7) ^ 7;
+        self.pixel_caches[0].data[bit as usize] = color;
+        self.pixel_caches[0].bitpend |= 1 << bit;
+        if self.pixel_caches[0].bitpend == 0xff {
+            self.flush_pixel_cache(bus, 1);
+            self.pixel_caches[1] = self.pixel_caches[0];
+            self.pixel_caches[0].bitpend = 0;
+        }
+    }
+
+    /// Reads the color of a pixel (`RPIX`).
+    fn rpix(&mut self, bus: &mut Bus, x: u8, y: u8) -> u8 {
+        self.flush_pixel_cache(bus, 1);
+        self.flush_pixel_cache(bus, 0);
+
+        let row = self.char_row_addr(x, y);
+        let bit = (x & 7) ^ 7;
+        let mut color = 0;
+        for n in 0..self.bpp() {
+            let plane = read_mirrored(bus.ram, row.wrapping_add(Self::plane_offset(n)) as usize);
+            color |= ((plane >> bit) & 1) << n;
+            self.cy += MEM_CYCLES;
+        }
+        color
+    }
+}
+
+/// Instruction implementations
+impl SuperFx {
+    fn sreg(&self) -> u16 { self.r[self.sreg as usize] }
+
+    fn set_dreg(&mut self, bus: &Bus, value: u16) {
+        let dreg = self.dreg;
+        self.set_reg(bus, dreg, value);
+    }
+
+    /// Sets the S and Z flags according to a 16-bit result.
+    fn set_sz(&mut self, value: u16) {
+        self.set_flag(S, value & 0x8000 != 0);
+        self.set_flag(Z, value == 0);
+    }
+
+    /// Writes a result to the destination register and sets S and Z.
+    fn set_dreg_sz(&mut self, bus: &Bus, value: u16) {
+        self.set_dreg(bus, value);
+        self.set_sz(value);
+    }
+
+    /// Clears the prefix flags and register selections after an instruction completes.
+    fn reset_prefix(&mut self) {
+        self.sfr &= !(B | ALT1 | ALT2);
+        self.sreg = 0;
+        self.dreg = 0;
+    }
+
+    fn branch(&mut self, bus: &Bus, cond: bool) {
+        let disp = self.pipe(bus) as i8;
+        if cond {
+            let target = self.r[15].wrapping_add(disp as u16);
+            self.set_reg(bus, 15, target);
+        }
+    }
+
+    /// Cycles taken by an 8x8 multiplication (`CFGR.MS0` selects high-speed multiplication)
+    fn mult_cycles(&self) -> u32 { if self.cfgr & 0x20 != 0 { 0 } else { 1 } }
+
+    fn execute(&mut self, bus: &mut Bus, op: u8) {
+        let alt1 = self.flag(ALT1);
+        let alt2 = self.flag(ALT2);
+        let n = op & 0x0f;
+
+        match op {
+            // STOP
+            0x00 => {
+                self.flush_pixel_cache(bus, 1);
+                self.flush_pixel_cache(bus, 0);
+                if self.cfgr & 0x80 == 0 {
+                    self.sfr |= IRQ;
+                }
+                self.sfr &= !G;
+                self.pipeline = 0x01;
+                self.reset_prefix();
+            }
+            // NOP
+            0x01 => self.reset_prefix(),
+            // CACHE
+            0x02 => {
+                let base = self.r[15] & 0xfff0;
+                if self.cbr != base {
+                    self.cbr = base;
+                    self.flush_cache();
+                }
+                self.reset_prefix();
+            }
+            // LSR
+            0x03 => {
+                let s = self.sreg();
+                self.set_flag(CY, s & 1 != 0);
+                self.set_dreg_sz(bus, s >> 1);
+                self.reset_prefix();
+            }
+            // ROL
+            0x04 => {
+                let s = self.sreg();
+                let result = s << 1 | self.flag(CY) as u16;
+                self.set_flag(CY, s & 0x8000 != 0);
+                self.set_dreg_sz(bus, result);
+                self.reset_prefix();
+            }
+            // BRA, BGE, BLT, BNE, BEQ, BPL, BMI, BCC, BCS, BVC, BVS
+            0x05 => self.branch(bus, true),
+            0x06 => { let c = self.flag(S) == self.flag(OV); self.branch(bus, c) }
+            0x07 => { let c = self.flag(S) != self.flag(OV); self.branch(bus, c) }
+            0x08 => { let c = !self.flag(Z); self.branch(bus, c) }
+            0x09 => { let c = self.flag(Z); self.branch(bus, c) }
+            0x0a => { let c = !self.flag(S); self.branch(bus, c) }
+            0x0b => { let c = self.flag(S); self.branch(bus, c) }
+            0x0c => { let c = !self.flag(CY); self.branch(bus, c) }
+            0x0d => { let c = self.flag(CY); self.branch(bus, c) }
+            0x0e => { let c = !self.flag(OV); self.branch(bus, c) }
+            0x0f => { let c = self.flag(OV); self.branch(bus, c) }
+            // TO Rn / MOVE Rn, Rs
+            0x10 ... 0x1f => {
+                if self.flag(B) {
+                    let s = self.sreg();
+                    self.set_reg(bus, n, s);
+                    self.reset_prefix();
+                } else {
+                    self.dreg = n;
+                }
+            }
+            // WITH Rn
+            0x20 ... 0x2f => {
+                self.sreg = n;
+                self.dreg = n;
+                self.sfr |= B;
+            }
+            // STW (Rn) / STB (Rn)
+            0x30 ... 0x3b => {
+                let addr = self.r[n as usize];
+                let s = self.sreg();
+                if alt1 {
+                    self.ram_addr = addr;
+                    self.write_ram(bus, addr, s as u8);
+                } else {
+                    self.write_ram_word(bus, addr, s);
+                }
+                self.reset_prefix();
+            }
+            // LOOP
+            0x3c => {
+                let r12 = self.r[12].wrapping_sub(1);
+                self.r[12] = r12;
+                self.set_sz(r12);
+                if r12 != 0 {
+                    let r13 = self.r[13];
+                    self.set_reg(bus, 15, r13);
+                }
+                self.reset_prefix();
+            }
+            // ALT1, ALT2, ALT3
+            0x3d => self.sfr = self.sfr & !B | ALT1,
+            0x3e => self.sfr = self.sfr & !B | ALT2,
+            0x3f => self.sfr = self.sfr & !B | ALT1 | ALT2,
+            // LDW (Rn) / LDB (Rn)
+            0x40 ... 0x4b => {
+                let addr = self.r[n as usize];
+                let value = if alt1 {
+                    self.ram_addr = addr;
+                    self.read_ram(bus, addr) as u16
+                } else {
+                    self.read_ram_word(bus, addr)
+                };
+                self.set_dreg(bus, value);
+                self.reset_prefix();
+            }
+            // PLOT / RPIX
+            0x4c => {
+                let (x, y) = (self.r[1] as u8, self.r[2] as u8);
+                if alt1 {
+                    let color = self.rpix(bus, x, y) as u16;
+                    self.set_dreg_sz(bus, color);
+                } else {
+                    self.plot(bus, x, y);
+                    self.r[1] = self.r[1].wrapping_add(1);
+                }
+                self.reset_prefix();
+            }
+            // SWAP
+            0x4d => {
+                let s = self.sreg();
+                self.set_dreg_sz(bus, s.rotate_left(8));
+                self.reset_prefix();
+            }
+            // COLOR / CMODE
+            0x4e => {
+                let s = self.sreg();
+                if alt1 {
+                    self.por = s as u8;
+                } else {
+                    self.colr = self.color(s as u8);
+                }
+                self.reset_prefix();
+            }
+            // NOT
+            0x4f => {
+                let s = self.sreg();
+                self.set_dreg_sz(bus, !s);
+                self.reset_prefix();
+            }
+            // ADD Rn / ADC Rn / ADD #n / ADC #n
+            0x50 ... 0x5f => {
+                let s = self.sreg() as u32;
+                let operand = if alt2 { n as u32 } else { self.r[n as usize] as u32 };
+                let carry = if alt1 { self.flag(CY) as u32 } else { 0 };
+                let result = s + operand + carry;
+                self.set_flag(OV, !(s ^ operand) & (operand ^ result) & 0x8000 != 0);
+                self.set_flag(CY, result >= 0x10000);
+                self.set_dreg_sz(bus, result as u16);
+                self.reset_prefix();
+            }
+            // SUB Rn / SBC Rn / SUB #n / CMP Rn
+            0x60 ... 0x6f => {
+                let s = self.sreg() as i32;
+                let operand = if alt2 && !alt1 { n as i32 } else { self.r[n as usize] as i32 };
+                let borrow = if alt1 && !alt2 { !self.flag(CY) as i32 } else { 0 };
+                let result = s - operand - borrow;
+                self.set_flag(OV, (s ^ operand) & (s ^ result) & 0x8000 != 0);
+                self.set_flag(CY, result >= 0);
+                if alt1 && alt2 {
+                    self.set_sz(result as u16);
+                } else {
+                    self.set_dreg_sz(bus, result as u16);
+                }
+                self.reset_prefix();
+            }
+            // MERGE
+            0x70 => {
+                let result = self.r[7] & 0xff00 | self.r[8] >> 8;
+                self.set_dreg(bus, result);
+                self.set_flag(OV, result & 0xc0c0 != 0);
+                self.set_flag(S, result & 0x8080 != 0);
+                self.set_flag(CY, result & 0xe0e0 != 0);
+                self.set_flag(Z, result & 0xf0f0 != 0);
+                self.reset_prefix();
+            }
+            // AND Rn / BIC Rn / AND #n / BIC #n
+            0x71 ... 0x7f => {
+                let operand = if alt2 { n as u16 } else { self.r[n as usize] };
+                let operand = if alt1 { !operand } else { operand };
+                let result = self.sreg() & operand;
+                self.set_dreg_sz(bus, result);
+                self.reset_prefix();
+            }
+            // MULT Rn / UMULT Rn / MULT #n / UMULT #n
+            0x80 ... 0x8f => {
+                let operand = if alt2 { n as u16 } else { self.r[n as usize] };
+                let s = self.sreg();
+                let result = if alt1 {
+                    (s as u8 as u16).wrapping_mul(operand as u8 as u16)
+                } else {
+                    (s as u8 as i8 as i16).wrapping_mul(operand as u8 as i8 as i16) as u16
+                };
+                self.set_dreg_sz(bus, result);
+                self.cy += self.mult_cycles();
+                self.reset_prefix();
+            }
+            // SBK
+            0x90 => {
+                let (addr, s) = (self.ram_addr, self.sreg());
+                self.write_ram_word(bus, addr, s);
+                self.reset_prefix();
+            }
+            // LINK #n
+            0x91 ... 0x94 => {
+                self.r[11] = self.r[15].wrapping_add(n as u16);
+                self.reset_prefix();
+            }
+            // SEX
+            0x95 => {
+                let s = self.sreg();
+                self.set_dreg_sz(bus, s as u8 as i8 as u16);
+                self.reset_prefix();
+            }
+            // ASR / DIV2
+            0x96 => {
+                let s = self.sreg();
+                self.set_flag(CY, s & 1 != 0);
+                let mut result = (s as i16 >> 1) as u16;
+                // DIV2 rounds -1 to 0
+                if alt1 && s == 0xffff { result = 0; }
+                self.set_dreg_sz(bus, result);
+                self.reset_prefix();
+            }
+            // ROR
+            0x97 => {
+                let s = self.sreg();
+                let result = (self.flag(CY) as u16) << 15 | s >> 1;
+                self.set_flag(CY, s & 1 != 0);
+                self.set_dreg_sz(bus, result);
+                self.reset_prefix();
+            }
+            // JMP Rn / LJMP Rn
+            0x98 ... 0x9d => {
+                let target = self.r[n as usize];
+                if alt1 {
+                    self.pbr = target as u8 & 0x7f;
+                    let s = self.sreg();
+                    self.set_reg(bus, 15, s);
+                    self.cbr = s & 0xfff0;
+                    self.flush_cache();
+                } else {
+                    self.set_reg(bus, 15, target);
+                }
+                self.reset_prefix();
+            }
+            // LOB
+            0x9e => {
+                let result = self.sreg() & 0xff;
+                self.set_dreg(bus, result);
+                self.set_flag(S, result & 0x80 != 0);
+                self.set_flag(Z, result == 0);
+                self.reset_prefix();
+            }
+            // FMULT / LMULT
+            0x9f => {
+                let result = (self.sreg() as i16 as i32 * self.r[6] as i16 as i32) as u32;
+                if alt1 {
+                    self.r[4] = result as u16;
+                }
+                let hi = (result >> 16) as u16;
+                self.set_dreg_sz(bus, hi);
+                self.set_flag(CY, result & 0x8000 != 0);
+                self.cy += 4 + 3 * self.mult_cycles();
+                self.reset_prefix();
+            }
+            // IBT Rn, #pp / LMS Rn, (yy) / SMS (yy), Rn
+            0xa0 ... 0xaf => {
+                let operand = self.pipe(bus);
+                if alt1 {
+                    let value = self.read_ram_word(bus, (operand as u16) << 1);
+                    self.set_reg(bus, n, value);
+                } else if alt2 {
+                    let value = self.r[n as usize];
+                    self.write_ram_word(bus, (operand as u16) << 1, value);
+                } else {
+                    self.set_reg(bus, n, operand as i8 as u16);
+                }
+                self.reset_prefix();
+            }
+            // FROM Rn / MOVES Rn
+            0xb0 ... 0xbf => {
+                if self.flag(B) {
+                    let value = self.r[n as usize];
+                    self.set_dreg_sz(bus, value);
+                    self.set_flag(OV, value & 0x80 != 0);
+                    self.reset_prefix();
+                } else {
+                    self.sreg = n;
+                }
+            }
+            // HIB
+            0xc0 => {
+                let result = self.sreg() >> 8;
+                self.set_dreg(bus, result);
+                self.set_flag(S, result & 0x80 != 0);
+                self.set_flag(Z, result == 0);
+                self.reset_prefix();
+            }
+            // OR Rn / XOR Rn / OR #n / XOR #n
+            0xc1 ... 0xcf => {
+                let operand = if alt2 { n as u16 } else { self.r[n as usize] };
+                let s = self.sreg();
+                let result = if alt1 { s ^ operand } else { s | operand };
+                self.set_dreg_sz(bus, result);
+                self.reset_prefix();
+            }
+            // INC Rn
+            0xd0 ... 0xde => {
+                let result = self.r[n as usize].wrapping_add(1);
+                self.set_reg(bus, n, result);
+                self.set_sz(result);
+                self.reset_prefix();
+            }
+            // GETC / RAMB / ROMB
+            0xdf => {
+                match (alt1, alt2) {
+                    (true, true) => self.rombr = self.sreg() as u8 & 0x7f,
+                    (false, true) => self.rambr = self.sreg() as u8 & 0x01,
+                    _ => self.colr = self.color(self.rom_buffer),
+                }
+                self.reset_prefix();
+            }
+            // DEC Rn
+            0xe0 ... 0xee => {
+                let result = self.r[n as usize].wrapping_sub(1);
+                self.set_reg(bus, n, result);
+                self.set_sz(result);
+                self.reset_prefix();
+            }
+            // GETB / GETBH / GETBL / GETBS
+            0xef => {
+                let (s, byte) = (self.sreg(), self.rom_buffer as u16);
+                let result = match (alt1, alt2) {
+                    (false, false) => byte,
+                    (true, false) => byte << 8 | s & 0x00ff,
+                    (false, true) => s & 0xff00 | byte,
+                    (true, true) => byte as u8 as i8 as u16,
+                };
+                self.set_dreg(bus, result);
+                self.reset_prefix();
+            }
+            // IWT Rn, #xx / LM Rn, (xx) / SM (xx), Rn
+            0xf0 ... 0xff => {
+                let lo = self.pipe(bus) as u16;
+                let hi = self.pipe(bus) as u16;
+                let operand = hi << 8 | lo;
+                if alt1 {
+                    let value = self.read_ram_word(bus, operand);
+                    self.set_reg(bus, n, value);
+                } else if alt2 {
+                    let value = self.r[n as usize];
+                    self.write_ram_word(bus, operand, value);
+                } else {
+                    self.set_reg(bus, n, operand);
+                }
+                self.reset_prefix();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `program` (at `$00:8000`) until it stops.
+    fn run(fx: &mut SuperFx, rom: &mut Vec<u8>, ram: &mut [u8], program: &[u8]) {
+        if rom.len() < 0x8000 { rom.resize(0x8000, 0x01); }
+        rom[..program.len()].copy_from_slice(program);
+        fx.store_io(0x301e, 0x00);
+        fx.store_io(0x301f, 0x80);
+        fx.run(rom, ram, 1_000_000);
+        assert_eq!(fx.sfr & G, 0, "program didn't stop");
+    }
+
+    /// Runs `program` on a new GSU with empty memory.
+    fn execute(program: &[u8]) -> SuperFx {
+        let mut fx = SuperFx::new();
+        run(&mut fx, &mut Vec::new(), &mut vec![0; 0x10000], program);
+        fx
+    }
+
+    #[test]
+    fn add_sub_flags() {
+        let fx = execute(&[
+            0xf1, 0xff, 0x7f,   // iwt r1, #$7fff
+            0xf2, 0x01, 0x00,   // iwt r2, #$0001
+            0xb1, 0x13, 0x52,   // from r1 ; to r3 ; add r2
+            0x00, 0x01,         // stop ; nop
+        ]);
+        assert_eq!(fx.r[3], 0x8000);
+        assert_eq!(fx.sfr & (Z | CY | S | OV), S | OV);
+
+        let fx = execute(&[
+            0xa1, 0x05,         // ibt r1, #5
+            0xb1, 0x13, 0x61,   // from r1 ; to r3 ; sub r1
+            0x00, 0x01,
+        ]);
+        assert_eq!(fx.r[3], 0);
+        assert_eq!(fx.sfr & (Z | CY | S | OV), Z | CY);
+
+        let fx = execute(&[
+            0xa1, 0x05,         // ibt r1, #5
+            0xf2, 0x00, 0x80,   // iwt r2, #$8000
+            0xb1, 0x3f, 0x62,   // from r1 ; cmp r2
+            0x00, 0x01,
+        ]);
+        // CMP only sets the flags: 5 - (-32768) overflows
+        assert_eq!(fx.r[0], 0);
+        assert_eq!(fx.sfr & (Z | CY | S | OV), S | OV);
+    }
+
+    #[test]
+    fn prefixes() {
+        let fx = execute(&[
+            0xa1, 0x12,         // ibt r1, #$12
+            0x21, 0x17,         // with r1 ; to r7 (move r7, r1)
+            0xa2, 0xfd,         // ibt r2, #-3
+            0x22, 0x3e, 0x84,   // with r2 ; mult #4
+            0x3d, 0x4f,         // alt1 ; not (alt1 is reset by the instruction)
+            0x00, 0x01,
+        ]);
+        assert_eq!(fx.r[7], 0x12);
+        assert_eq!(fx.r[2], -12i16 as u16);
+        assert_eq!(fx.r[0], 0xffff);
+        assert_eq!(fx.sfr & (ALT1 | ALT2 | B), 0);
+    }
+
+    #[test]
+    fn branch_delay_slot() {
+        let fx = execute(&[
+            0xa0, 0x00,         // ibt r0, #0
+            0x05, 0x02,         // bra +2
+            0xd0,               // inc r0 (executed in the delay slot)
+            0xd0,               // inc r0 (skipped)
+            0x00, 0x01,
+        ]);
+        assert_eq!(fx.r[0], 1);
+        // STOP raises an IRQ
+        assert!(fx.irq_pending());
+    }
+
+    /// Plots a full row of 8 pixels at (8, 3), then a single pixel at (0, 0), and reads back
+    /// (9, 3).
+    fn plot(mode: u8, color: u8) -> (Vec<u8>, SuperFx) {
+        let mut fx = SuperFx::new();
+        fx.store_io(0x303a, mode);
+        let mut ram = vec![0x55; 0x10000];
+        run(&mut fx, &mut Vec::new(), &mut ram, &[
+            0xa0, color, 0x4e,      // ibt r0, #color ; color
+            0xa1, 0x08, 0xa2, 0x03, // ibt r1, #8 ; ibt r2, #3
+            0x4c, 0x4c, 0x4c, 0x4c, 0x4c, 0x4c, 0x4c, 0x4c,
+            0xa1, 0x00, 0xa2, 0x00, // ibt r1, #0 ; ibt r2, #0
+            0x4c,
+            0xa1, 0x09, 0xa2, 0x03, // ibt r1, #9 ; ibt r2, #3
+            0x13, 0x3d, 0x4c,       // to r3 ; rpix
+            0x00, 0x01,
+        ]);
+        (ram, fx)
+    }
+
+    #[test]
+    fn plot_depths() {
+        for &(mode, bpp, color) in &[(0x00, 2, 0x02), (0x01, 4, 0x0a), (0x03, 8, 0xa5)] {
+            let (ram, fx) = plot(mode, color);
+            for n in 0..bpp {
+                let plane_offset = (n >> 1) * 16 + (n & 1);
+                let bit = (color >> n) & 1;
+                // (8, 3) is the 3rd row of character 16
+                let full = 16 * bpp * 8 + 3 * 2 + plane_offset;
+                assert_eq!(ram[full], if bit != 0 { 0xff } else { 0x00 }, "{} bpp", bpp);
+                // The single pixel is merged with the existing data
+                assert_eq!(ram[plane_offset], 0x55 & 0x7f | bit << 7, "{} bpp", bpp);
+            }
+            // The RAM following the last bitplane wasn't touched
+            assert_eq!(ram[16 * bpp * 8 + 3 * 2 + bpp * 8], 0x55);
+            assert_eq!(fx.r[3], color as u16);
+        }
+    }
+
+    #[test]
+    fn plot_transparent() {
+        // Color 0 isn't plotted, unless the POR transparency bit is set
+        let (ram, _) = plot(0x01, 0x00);
+        assert_eq!(ram[16 * 32 + 6], 0x55);
+        assert_eq!(ram[0], 0x55);
+    }
+
+    #[test]
+    fn rom_buffer() {
+        let mut fx = SuperFx::new();
+        let mut rom = vec![0x01; 0x10000];
+        rom[0x100] = 0x9a;
+        rom[0x101] = 0xbc;
+        rom[0x8000] = 0xde;
+        run(&mut fx, &mut rom, &mut vec![0; 0x10000], &[
+            0xfe, 0x00, 0x81,   // iwt r14, #$8100 (loads the ROM buffer)
+            0x13, 0xef,         // to r3 ; getb
+            0xde,               // inc r14
+            0x14, 0xef,         // to r4 ; getb
+            0x24, 0x3d, 0xef,   // with r4 ; alt1 ; getbh
+            0xa0, 0x01,         // ibt r0, #1
+            0x3f, 0xdf,         // romb
+            0xfe, 0x00, 0x80,   // iwt r14, #$8000
+            0x15, 0xef,         // to r5 ; getb
+            0x00, 0x01,
+        ]);
+        assert_eq!(fx.r[3], 0x9a);
+        assert_eq!(fx.r[4], 0xbcbc);
+        assert_eq!(fx.rombr, 1);
+        assert_eq!(fx.r[5], 0xde);
+    }
+
+    #[test]
+    fn ram_access() {
+        let mut fx = SuperFx::new();
+        let mut ram = vec![0; 0x10000];
+        ram[0x40] = 0xcd;
+        ram[0x41] = 0xab;
+        run(&mut fx, &mut Vec::new(), &mut ram, &[
+            0xf1, 0x34, 0x12,   // iwt r1, #$1234
+            0xf2, 0x00, 0x01,   // iwt r2, #$0100
+            0xb1, 0x32,         // from r1 ; stw (r2)
+            0x13, 0x42,         // to r3 ; ldw (r2)
+            0xf4, 0x78, 0x56,   // iwt r4, #$5678
+            0xb4, 0x90,         // from r4 ; sbk (writes to $0100 again)
+            0x3e, 0xf4, 0x00, 0x02, // sm ($0200), r4
+            0x3d, 0xa5, 0x20,   // lms r5, ($0040)
+            0xf6, 0x01, 0x03,   // iwt r6, #$0301
+            0xb1, 0x3d, 0x36,   // from r1 ; stb (r6)
+            0x00, 0x01,
+        ]);
+        assert_eq!(fx.r[3], 0x1234);
+        assert_eq!(&ram[0x100..0x102], &[0x78, 0x56]);
+        assert_eq!(&ram[0x200..0x202], &[0x78, 0x56]);
+        assert_eq!(fx.r[5], 0xabcd);
+        assert_eq!(&ram[0x300..0x303], &[0x00, 0x34, 0x00]);
+    }
+
+    #[test]
+    fn bus_ownership() {
+        let mut fx = SuperFx::new();
+        let rom = vec![0xaa; 0x8000];
+        let ram = vec![0xbb; 0x10000];
+        fx.store_io(0x303a, SCMR_RON | SCMR_RAN);
+        assert_eq!(fx.load(&rom, &ram, 0x00, 0xffea), 0xaa);
+        assert_eq!(fx.load(&rom, &ram, 0x70, 0x0000), 0xbb);
+
+        // While the GSU runs, the SNES reads the vectors to WRAM and RAM reads return 0
+        fx.store_io(0x301f, 0x80);
+        assert_eq!(fx.load(&rom, &ram, 0x00, 0xffea), 0x08);
+        assert_eq!(fx.load(&rom, &ram, 0x00, 0xffeb), 0x01);
+        assert_eq!(fx.load(&rom, &ram, 0x70, 0x0000), 0x00);
+
+        // Clearing GO stops it
+        fx.store_io(0x3030, 0x00);
+        assert_eq!(fx.load(&rom, &ram, 0x00, 0xffea), 0xaa);
+    }
+}
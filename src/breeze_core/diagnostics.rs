@@ -0,0 +1,148 @@
+//! Diagnostics about the emulated program
+//!
+//! Components record things the game does that aren't emulated (or are probably bugs) in their
+//! `Diagnostics`, using the `diag!` macro. After each CPU instruction, the `Snes` moves them into
+//! its `Report`, together with the address of the instruction. The report counts how often each
+//! kind of diagnostic occurred, so it shows which hardware features a game actually uses.
+//!
+//! Errors are added to the report as well when emulation continues after them (with
+//! `ErrorPolicy::Continue`).
+//!
+//! Every kind of diagnostic is logged when it first occurs in an emulator instance.
+
+use error::Component;
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// Records a diagnostic in a `Diagnostics` collector.
+///
+/// The format string identifies the kind of diagnostic, so the arguments should only contain
+/// details (like the value written). Only the message of the first occurrence is kept.
+macro_rules! diag {
+    ( $diag:expr, $fmt:expr ) => {
+        $diag.record($fmt, format_args!($fmt))
+    };
+    ( $diag:expr, $fmt:expr, $($arg:tt)+ ) => {
+        $diag.record($fmt, format_args!($fmt, $($arg)+))
+    };
+}
+
+/// A kind of diagnostic and how often it occurred
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    /// The format string of the message
+    pub kind: &'static str,
+    /// The message of the first occurrence
+    pub message: String,
+    pub count: u64,
+    /// Address of the instruction that caused the first occurrence (of the SPC700 for
+    /// `Component::Apu`)
+    pub first_pc: u32,
+}
+
+/// A diagnostic that wasn't added to the `Report` yet
+#[derive(Clone, Debug)]
+struct Pending {
+    kind: &'static str,
+    message: String,
+    count: u64,
+}
+
+/// Collects the diagnostics of a component until the `Snes` adds them to its `Report`
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    pending: Vec<Pending>,
+}
+
+impl Diagnostics {
+    /// Records an occurrence of `kind` (use the `diag!` macro instead of calling this).
+    pub fn record(&mut self, kind: &'static str, message: fmt::Arguments) {
+        match self.pending.iter_mut().find(|p| p.kind == kind) {
+            Some(pending) => pending.count += 1,
+            None => self.pending.push(Pending {
+                kind: kind,
+                message: fmt::format(message),
+                count: 1,
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.pending.is_empty() }
+
+    /// Moves the diagnostics recorded in `other` into `self`.
+    pub fn append(&mut self, other: &mut Diagnostics) {
+        for pending in other.pending.drain(..) {
+            match self.pending.iter_mut().find(|p| p.kind == pending.kind) {
+                Some(existing) => existing.count += pending.count,
+                None => self.pending.push(pending),
+            }
+        }
+    }
+}
+
+/// All diagnostics of an emulator instance
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Diagnostics in the order they first occurred
+    entries: Vec<(Component, Diagnostic)>,
+}
+
+impl Report {
+    /// Adds `count` occurrences of `kind` in `component`, caused by the instruction at `pc`.
+    pub fn add(&mut self, component: Component, kind: &'static str, count: u64, pc: u32,
+               message: fmt::Arguments) {
+        let existing = self.entries.iter_mut()
+            .find(|&&mut (c, ref diag)| c == component && diag.kind == kind);
+        match existing {
+            Some(&mut (_, ref mut diag)) => diag.count += count,
+            None => {
+                let message = fmt::format(message);
+                warn!("{}", message);
+                self.entries.push((component, Diagnostic {
+                    kind: kind,
+                    message: message,
+                    count: count,
+                    first_pc: pc,
+                }));
+            }
+        }
+    }
+
+    /// Moves the diagnostics recorded by a component into the report. They're attributed to the
+    /// instruction at `pc`.
+    pub fn collect(&mut self, component: Component, diagnostics: &mut Diagnostics, pc: u32) {
+        for pending in diagnostics.pending.drain(..) {
+            self.add(component, pending.kind, pending.count, pc,
+                format_args!("{}", pending.message));
+        }
+    }
+
+    /// Returns the diagnostics in the order they first occurred.
+    pub fn entries(&self) -> &[(Component, Diagnostic)] { &self.entries }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Removes all diagnostics.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Writes the report as a table, one diagnostic per line.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if self.entries.is_empty() {
+            return writeln!(w, "no diagnostics");
+        }
+        try!(writeln!(w, "{:>8}  {:<9}  {:<8}  {}", "count", "component", "first PC", "message"));
+        for &(component, ref diag) in &self.entries {
+            let pc = if component == Component::Apu {
+                format!("${:04X}", diag.first_pc)
+            } else {
+                format!("${:02X}:{:04X}", diag.first_pc >> 16, diag.first_pc & 0xffff)
+            };
+            try!(writeln!(w, "{:>8}  {:<9}  {:<8}  {}", diag.count, component.name(), pc,
+                diag.message));
+        }
+        Ok(())
+    }
+}
@@ -13,10 +13,9 @@
 
 use std::cell::Cell;
 
+use event_log::EventKind;
 use snes::Peripherals;
 
-use wdc65816::Mem;
-
 /// The configuration of a DMA channel
 #[derive(Clone, Copy)]
 pub struct DmaChannel {
@@ -106,9 +105,9 @@ use self::TransferMode::*;
 
 impl DmaChannel {
     /// Load from `$43xN`, where `x` is the number of this DMA channel, and `N` is passed as
-    /// `reg`.
-    pub fn load(&self, reg: u8) -> u8 {
-        match reg {
+    /// `reg`. Returns `None` for unused registers.
+    pub fn load(&self, reg: u8) -> Option<u8> {
+        Some(match reg {
             0x0 => self.params,
             0x1 => self.b_addr,
             0x2 => self.a_addr as u8,
@@ -117,11 +116,15 @@ impl DmaChannel {
             0x5 => self.dma_size as u8,
             0x6 => (self.dma_size >> 8) as u8,
             0x7 => self.hdma_indirect_bank,
-            _ => panic!("invalid DMA channel register ${:02X}", reg),
-        }
+            0x8 => self.hdma_addr as u8,
+            0x9 => (self.hdma_addr >> 8) as u8,
+            0xa => self.hdma_flags,
+            _ => return None,
+        })
     }
 
-    pub fn store(&mut self, reg: u8, val: u8) {
+    /// Store to `$43xN`. Returns `false` for unused registers (the write is ignored).
+    pub fn store(&mut self, reg: u8, val: u8) -> bool {
         match reg {
             0x0 => self.params = val,
             0x1 => self.b_addr = val,
@@ -131,8 +134,12 @@ impl DmaChannel {
             0x5 => self.dma_size = (self.dma_size & 0xff00) | val as u16,
             0x6 => self.dma_size = (self.dma_size & 0x00ff) | ((val as u16) << 8),
             0x7 => self.hdma_indirect_bank = val,
-            _ => panic!("invalid DMA channel register ${:02X}", reg),
+            0x8 => self.hdma_addr = (self.hdma_addr & 0xff00) | val as u16,
+            0x9 => self.hdma_addr = (self.hdma_addr & 0x00ff) | ((val as u16) << 8),
+            0xa => self.hdma_flags = val,
+            _ => return false,
         }
+        true
     }
 
     /// Returns `true` if this channel is configured to read from address bus B and write to bus A.
@@ -267,7 +274,7 @@ pub fn do_dma(p: &mut Peripherals, channels: u8) -> u32 {
                 } else {
                     (a_bank, a_addr.get())
                 };
-                let byte = p.load(src_bank, src_addr);
+                let byte = p.load_dma(src_bank, src_addr);
                 if !write_to_a {
                     // adjust `a_addr`
                     a_addr.set((a_addr.get() as i32 + a_addr_inc as i32) as u16);
@@ -281,7 +288,7 @@ pub fn do_dma(p: &mut Peripherals, channels: u8) -> u32 {
                 } else {
                     (0, b_addr)
                 };
-                p.store(dest_bank, dest_addr, byte);
+                p.store_dma(dest_bank, dest_addr, byte);
                 bytes.set(bytes.get() - 1);
                 if write_to_a {
                     // adjust `a_addr`
@@ -289,10 +296,23 @@ pub fn do_dma(p: &mut Peripherals, channels: u8) -> u32 {
                 }
             };
 
+            p.log_event(EventKind::Dma {
+                channel: i as u8,
+                a_addr: (a_bank as u32) << 16 | a_addr.get() as u32,
+                b_reg: b_addr,
+                size: bytes.get(),
+                b_to_a: write_to_a,
+            });
+
             dma_cy += bytes.get() * 8;  // 8 master cycles per byte
+            // The cartridge might want to replace the data that's read (S-DD1 decompression)
+            if !write_to_a {
+                p.rom.dma_start(i as u8, a_bank, a_addr.get());
+            }
             while bytes.get() > 0 {
                 dma_transfer(p, mode, b_addr, &mut read_byte, &mut write_byte);
             }
+            p.rom.dma_end();
 
             p.dma[i].dma_size = 0;
         }
@@ -323,7 +343,7 @@ pub fn init_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
 
             // Get line count & repeat from table
             let (i_bank, i_addr) = (p.dma[i].a_addr_bank, p.dma[i].hdma_addr);
-            let repeat_and_count = p.load(i_bank, i_addr);
+            let repeat_and_count = p.load_dma(i_bank, i_addr);
 
             // and bump table address to first entry
             p.dma[i].hdma_addr += 1;
@@ -336,8 +356,8 @@ pub fn init_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
 
             // If indirect, load first value address and bump table address to next line count.
             if p.dma[i].params & 0x40 != 0 {
-                let addr_low = p.load(i_bank, i_addr + 1);
-                let addr_high = p.load(i_bank, i_addr + 2);
+                let addr_low = p.load_dma(i_bank, i_addr + 1);
+                let addr_high = p.load_dma(i_bank, i_addr + 2);
 
                 p.dma[i].hdma_addr += 2;
                 
@@ -382,14 +402,15 @@ pub fn do_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
 
             // Each round is a full round, so no counting. A->B only.
             let mut read_byte = |p: &mut Peripherals, _| -> u8 {
-                let byte = p.load(a_bank, a_addr.get());
+                let byte = p.load_dma(a_bank, a_addr.get());
                 a_addr.set((a_addr.get() as i32 + 1 as i32) as u16);
 
                 byte
             };
             
             let mut write_byte = |p: &mut Peripherals, byte, b_addr| {
-                p.store(0, b_addr, byte);
+                p.store_dma(0, b_addr, byte);
+                p.log_event(EventKind::Hdma { channel: i as u8, reg: b_addr, value: byte });
 
                 cy += 8;
             };
@@ -418,13 +439,13 @@ pub fn do_hdma(p: &mut Peripherals, channel_mask: u8) -> u32 {
                 
                 let addr = p.dma[i].hdma_addr;
                 
-                p.dma[i].hdma_flags = p.load(bank, addr);
+                p.dma[i].hdma_flags = p.load_dma(bank, addr);
                 // Next scanline will not occur if hdma_flags == 0
 
                 if indirect {
                     // Apparently, we do this even if hdma_flags == 0 and we're about to stop HDMA.
-                    let low_byte = p.load(bank, addr + 1);
-                    let high_byte = p.load(bank, addr + 2);
+                    let low_byte = p.load_dma(bank, addr + 1);
+                    let high_byte = p.load_dma(bank, addr + 2);
                     
                     p.dma[i].dma_size = ((high_byte as u16) << 8) | (low_byte as u16);
 
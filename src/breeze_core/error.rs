@@ -0,0 +1,137 @@
+//! Errors caused by the emulated program
+//!
+//! Games (and homebrew) sometimes do things the emulator can't handle: They write to addresses
+//! nothing is mapped to, set register bits that must be 0 or use hardware features that aren't
+//! emulated yet. Instead of panicking, the emulated components record an `Error` and do what the
+//! hardware would (probably) do, like ignoring the write. What happens next is decided by the
+//! `ErrorPolicy`: Emulation either stops and `Snes::render_frame` returns the error, or the error
+//! is logged and emulation continues.
+
+use std::error;
+use std::fmt;
+
+/// The part of the console an error or diagnostic occurred in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Component {
+    /// CPU and its internal registers (`$4200-$43FF`), and unmapped parts of the address space
+    Cpu,
+    Dma,
+    Ppu,
+    Apu,
+    /// Controller ports and auto-joypad read
+    Input,
+    Cartridge,
+}
+
+impl Component {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Component::Cpu => "CPU",
+            Component::Dma => "DMA",
+            Component::Ppu => "PPU",
+            Component::Apu => "APU",
+            Component::Input => "input",
+            Component::Cartridge => "cartridge",
+        }
+    }
+}
+
+/// What went wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Read from an address that can't be read (the read returned a hardware-like value)
+    InvalidLoad,
+    /// Write of the contained value to an address that can't be written (it was ignored)
+    InvalidStore(u8),
+    /// A register was written with an invalid value (the invalid bits were ignored)
+    InvalidValue(u8),
+    /// The program uses a hardware feature that isn't emulated
+    Unimplemented(&'static str),
+}
+
+impl ErrorKind {
+    /// Describes the kind of error without its details.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorKind::InvalidLoad => "invalid read",
+            ErrorKind::InvalidStore(_) => "invalid write",
+            ErrorKind::InvalidValue(_) => "invalid value written",
+            ErrorKind::Unimplemented(feature) => feature,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::InvalidLoad => write!(f, "invalid read"),
+            ErrorKind::InvalidStore(value) => write!(f, "invalid write of ${:02X}", value),
+            ErrorKind::InvalidValue(value) => write!(f, "invalid value ${:02X} written", value),
+            ErrorKind::Unimplemented(feature) => write!(f, "{} is not implemented", feature),
+        }
+    }
+}
+
+/// An error caused by the emulated program
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub component: Component,
+    /// The address that was accessed. 24-bit address on the CPU bus, or a 16-bit address in the
+    /// APU's address space for `Component::Apu`.
+    pub addr: u32,
+    /// Address of the instruction that caused the error (of the SPC700 for `Component::Apu`)
+    pub pc: u32,
+}
+
+impl Error {
+    /// Creates an error. The PC is filled in by the `Snes` after the instruction has finished.
+    pub fn new(kind: ErrorKind, component: Component, addr: u32) -> Self {
+        Error {
+            kind: kind,
+            component: component,
+            addr: addr,
+            pc: 0,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.component == Component::Apu {
+            write!(f, "APU error at ${:04X} (PC ${:04X}): {}", self.addr, self.pc, self.kind)
+        } else {
+            write!(f, "{} error at ${:02X}:{:04X} (PC ${:02X}:{:04X}): {}", self.component.name(),
+                self.addr >> 16, self.addr & 0xffff, self.pc >> 16, self.pc & 0xffff, self.kind)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str { "emulation error" }
+}
+
+/// What to do when the emulated program causes an `Error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop emulation: `Snes::render_frame` returns the error
+    Stop,
+    /// Add the error to the diagnostics report (`Snes::diagnostics`) and continue with
+    /// hardware-like behaviour
+    Continue,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self { ErrorPolicy::Stop }
+}
+
+impl ErrorPolicy {
+    /// Looks up a policy by name (`stop` or `continue`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stop" => Some(ErrorPolicy::Stop),
+            "continue" => Some(ErrorPolicy::Continue),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//! Per-frame event log
+//!
+//! The event log records everything happening during a single frame that's interesting for
+//! debugging raster effects: CPU writes to PPU registers and APU ports, DMA and HDMA transfers, and
+//! interrupts. Every event is tagged with the PPU position (`v_counter`, `h_counter`) and the
+//! address of the CPU instruction that caused it (or was executing when it happened).
+//!
+//! Logging is started by setting `Peripherals::events` to an `EventLog`. It will wait for the
+//! configured frame to start, record events until the frame ends, and then stop. The log can be
+//! exported as CSV or JSON, or rendered as a timeline image with one dot per event.
+
+use ppu::SCREEN_HEIGHT;
+use region::Region;
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// Width of the timeline image (dots per scanline)
+pub const TIMELINE_WIDTH: usize = 340;
+
+/// The different kinds of logged events
+#[derive(Clone, Copy, Debug)]
+pub enum EventKind {
+    /// The CPU wrote `value` to PPU register `reg` (`$2100-$2133`)
+    PpuWrite { reg: u16, value: u8 },
+    /// The CPU wrote `value` to APU port `port` (`$2140-$2143`)
+    ApuWrite { port: u8, value: u8 },
+    /// A general purpose DMA transfer on `channel` was performed
+    Dma {
+        channel: u8,
+        /// 24-bit A bus address the transfer started at
+        a_addr: u32,
+        /// B bus register (`$21xx`)
+        b_reg: u16,
+        /// Number of bytes transferred
+        size: u32,
+        /// Whether the transfer went from the B bus to the A bus
+        b_to_a: bool,
+    },
+    /// HDMA `channel` wrote `value` to B bus register `reg`
+    Hdma { channel: u8, reg: u16, value: u8 },
+    /// A non-maskable interrupt was raised
+    Nmi,
+    /// An IRQ was raised
+    Irq,
+}
+
+impl EventKind {
+    /// Short name of the event type, as used in the CSV and JSON output.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventKind::PpuWrite { .. } => "ppu",
+            EventKind::ApuWrite { .. } => "apu",
+            EventKind::Dma { .. } => "dma",
+            EventKind::Hdma { .. } => "hdma",
+            EventKind::Nmi => "nmi",
+            EventKind::Irq => "irq",
+        }
+    }
+
+    /// The (A or B bus) address accessed by this event, if any.
+    fn address(&self) -> Option<u32> {
+        match *self {
+            EventKind::PpuWrite { reg, .. } | EventKind::Hdma { reg, .. } => Some(reg as u32),
+            EventKind::ApuWrite { port, .. } => Some(0x2140 + port as u32),
+            EventKind::Dma { b_reg, .. } => Some(b_reg as u32),
+            EventKind::Nmi | EventKind::Irq => None,
+        }
+    }
+
+    /// The value written by this event, if any.
+    fn value(&self) -> Option<u8> {
+        match *self {
+            EventKind::PpuWrite { value, .. } |
+            EventKind::ApuWrite { value, .. } |
+            EventKind::Hdma { value, .. } => Some(value),
+            EventKind::Dma { .. } | EventKind::Nmi | EventKind::Irq => None,
+        }
+    }
+
+    /// Color of the dot in the timeline image (RGB)
+    fn color(&self) -> [u8; 3] {
+        match *self {
+            EventKind::PpuWrite { .. } => [0x40, 0xc0, 0xff],
+            EventKind::ApuWrite { .. } => [0xff, 0x80, 0x20],
+            EventKind::Dma { .. } => [0xff, 0xff, 0x40],
+            EventKind::Hdma { .. } => [0x40, 0xff, 0x40],
+            EventKind::Nmi => [0xff, 0x30, 0x30],
+            EventKind::Irq => [0xff, 0x40, 0xff],
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EventKind::PpuWrite { reg, value } => write!(f, "${:02X} -> ${:04X}", value, reg),
+            EventKind::ApuWrite { port, value } => write!(f, "${:02X} -> APU port {}", value, port),
+            EventKind::Dma { channel, a_addr, b_reg, size, b_to_a } => {
+                let a = format!("${:02X}:{:04X}", a_addr >> 16, a_addr as u16);
+                let b = format!("${:04X}", b_reg);
+                let (from, to) = if b_to_a { (b, a) } else { (a, b) };
+                write!(f, "channel {}: {} bytes {} -> {}", channel, size, from, to)
+            }
+            EventKind::Hdma { channel, reg, value } => {
+                write!(f, "channel {}: ${:02X} -> ${:04X}", channel, value, reg)
+            }
+            EventKind::Nmi => write!(f, "NMI"),
+            EventKind::Irq => write!(f, "IRQ"),
+        }
+    }
+}
+
+/// A logged event
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    /// Scanline the event happened on
+    pub v: u16,
+    /// Dot (X position) the event happened at
+    pub h: u16,
+    /// 24-bit address of the CPU instruction that was executing
+    pub pc: u32,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for the given number of frames to start before recording
+    Waiting(u32),
+    Recording,
+    Done,
+}
+
+/// Log of the events of a single frame
+pub struct EventLog {
+    events: Vec<Event>,
+    state: State,
+    /// Scanlines of the recorded frame (262 for NTSC, 312 for PAL)
+    scanlines: u16,
+    /// Last visible scanline of the recorded frame (224, or 239 with overscan)
+    height: u16,
+}
+
+impl EventLog {
+    /// Creates an event log that records the frame after `skip_frames` further frames have
+    /// started (`0` records the next frame).
+    pub fn new(skip_frames: u32) -> Self {
+        EventLog {
+            events: Vec::new(),
+            state: State::Waiting(skip_frames),
+            scanlines: Region::default().scanlines(),
+            height: SCREEN_HEIGHT as u16,
+        }
+    }
+
+    /// Called when a new frame with `scanlines` lines starts, `height` of which are visible.
+    pub fn frame_start(&mut self, scanlines: u16, height: u16) {
+        self.state = match self.state {
+            State::Waiting(0) => {
+                self.scanlines = scanlines;
+                self.height = height;
+                State::Recording
+            }
+            State::Waiting(n) => State::Waiting(n - 1),
+            State::Recording | State::Done => State::Done,
+        };
+    }
+
+    /// Returns `true` if events are currently being recorded.
+    pub fn is_recording(&self) -> bool { self.state == State::Recording }
+
+    /// Returns `true` once a complete frame has been recorded.
+    pub fn is_done(&self) -> bool { self.state == State::Done }
+
+    /// Records an event if the log is recording.
+    pub fn log(&mut self, v: u16, h: u16, pc: u32, kind: EventKind) {
+        if self.is_recording() {
+            self.events.push(Event {
+                v: v,
+                h: h,
+                pc: pc,
+                kind: kind,
+            });
+        }
+    }
+
+    /// Returns all recorded events in the order they happened.
+    pub fn events(&self) -> &[Event] { &self.events }
+
+    /// Writes the events as CSV, with a header line.
+    pub fn write_csv(&self, w: &mut Write) -> io::Result<()> {
+        try!(writeln!(w, "v,h,pc,type,address,value,description"));
+        for event in &self.events {
+            let addr = event.kind.address().map(|a| format!("{:04X}", a)).unwrap_or_default();
+            let value = event.kind.value().map(|v| format!("{:02X}", v)).unwrap_or_default();
+            try!(writeln!(w, "{},{},{:06X},{},{},{},\"{}\"", event.v, event.h, event.pc,
+                event.kind.name(), addr, value, event.kind));
+        }
+        Ok(())
+    }
+
+    /// Writes the events as a JSON array of objects.
+    ///
+    /// Each object has the keys `v`, `h`, `pc`, `type` and `description`, and optionally
+    /// `address` and `value` (numbers are written as decimal integers).
+    pub fn write_json(&self, w: &mut Write) -> io::Result<()> {
+        try!(writeln!(w, "["));
+        for (i, event) in self.events.iter().enumerate() {
+            try!(write!(w, "  {{\"v\": {}, \"h\": {}, \"pc\": {}, \"type\": \"{}\"", event.v,
+                event.h, event.pc, event.kind.name()));
+            if let Some(addr) = event.kind.address() {
+                try!(write!(w, ", \"address\": {}", addr));
+            }
+            if let Some(value) = event.kind.value() {
+                try!(write!(w, ", \"value\": {}", value));
+            }
+            // Descriptions never contain characters that need escaping
+            try!(write!(w, ", \"description\": \"{}\"}}", event.kind));
+            try!(writeln!(w, "{}", if i + 1 == self.events.len() { "" } else { "," }));
+        }
+        writeln!(w, "]")
+    }
+
+    /// Height of the timeline image: The number of scanlines of the recorded frame.
+    pub fn timeline_height(&self) -> usize { self.scanlines as usize }
+
+    /// Renders the events as a timeline image of `TIMELINE_WIDTH` by `timeline_height()` pixels in
+    /// RGB24 format. Each pixel corresponds to one dot of a scanline, and each event is drawn as a
+    /// colored 3x3 dot at its position. The visible area of the screen is drawn in dark gray.
+    pub fn render_timeline(&self) -> Vec<u8> {
+        let height = self.timeline_height();
+        let mut img = vec![0; TIMELINE_WIDTH * height * 3];
+        for y in 1..self.height as usize + 1 {
+            for x in 0..256 {
+                let start = (y * TIMELINE_WIDTH + x) * 3;
+                img[start..start + 3].copy_from_slice(&[0x30, 0x30, 0x30]);
+            }
+        }
+
+        for event in &self.events {
+            let (x, y) = (event.h as i32, event.v as i32);
+            for dy in -1..2 {
+                for dx in -1..2 {
+                    let (x, y) = (x + dx, y + dy);
+                    if x >= 0 && y >= 0 && x < TIMELINE_WIDTH as i32 && y < height as i32 {
+                        let start = (y as usize * TIMELINE_WIDTH + x as usize) * 3;
+                        img[start..start + 3].copy_from_slice(&event.kind.color());
+                    }
+                }
+            }
+        }
+
+        img
+    }
+
+    /// Writes the timeline image (see `render_timeline`) as a binary PPM (`P6`) file.
+    pub fn write_timeline_ppm(&self, w: &mut Write) -> io::Result<()> {
+        try!(write!(w, "P6\n{} {}\n255\n", TIMELINE_WIDTH, self.timeline_height()));
+        w.write_all(&self.render_timeline())
+    }
+}
@@ -0,0 +1,421 @@
+//! Game database
+//!
+//! The ROM header doesn't always tell us how to run a game: The mapping is guessed from the header
+//! location (see `Rom::from_bytes`), some headers specify the wrong RAM size or destination code,
+//! and some coprocessors (like the DSP-2) can't be told apart by their header at all. The game
+//! database stores the right values for such ROMs, keyed by the CRC32 and/or SHA-1 of the ROM
+//! image (without copier header). Its values are used instead of the header's.
+//!
+//! A database is bundled with Breeze (`gamedb.txt`), more entries can be loaded from files in the
+//! same format. Entries start with the hashes of the ROM in brackets, followed by `key = value`
+//! lines:
+//!
+//! ```text
+//! # Comment
+//! [0123abcd]
+//! name = Example Game (USA)
+//! mapping = lorom
+//! ram = 2
+//! region = ntsc
+//! coprocessor = none
+//! hack = wram-init $55
+//! status = good
+//! ```
+//!
+//! All keys are optional. `ram` is the cartridge RAM size in KB, `coprocessor` is one of the names
+//! in `coprocessor::NAMES`. `status` is `good` (the default) or `bad` for known bad dumps. The hash
+//! may be a CRC32, a SHA-1, or both (separated by whitespace). If both are given, both must match.
+
+use coprocessor;
+use region::Region;
+use rom::RomType;
+use rom_info::{crc32, sha1};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The bundled database
+const BUNDLED: &'static str = include_str!("gamedb.txt");
+
+/// A workaround needed to run a game
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hack {
+    /// Fill the work RAM with this value on power-on, instead of zeros. Some games read RAM before
+    /// initializing it.
+    WramInit(u8),
+}
+
+impl Hack {
+    /// Parses a hack as written in the database (eg. `wram-init $55`).
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("wram-init"), Some(value), None) => {
+                u8::from_str_radix(value.trim_left_matches('$'), 16).ok().map(Hack::WramInit)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Hack {
+    /// Writes the hack like it's written in the database.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Hack::WramInit(value) => write!(f, "wram-init ${:02X}", value),
+        }
+    }
+}
+
+/// Whether a ROM image is a good dump of a game
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpStatus {
+    /// The ROM isn't in the game database
+    Unknown,
+    /// The ROM is a good dump
+    Good,
+    /// The ROM is a known bad dump (eg. overdumped, corrupted or with a modified header)
+    Bad,
+}
+
+impl Default for DumpStatus {
+    fn default() -> Self { DumpStatus::Unknown }
+}
+
+impl DumpStatus {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DumpStatus::Unknown => "unknown",
+            DumpStatus::Good => "good",
+            DumpStatus::Bad => "bad",
+        }
+    }
+}
+
+/// A game database entry. `None` fields don't override the header.
+#[derive(Clone, Debug, Default)]
+pub struct GameEntry {
+    pub crc32: Option<u32>,
+    pub sha1: Option<[u8; 20]>,
+    pub name: Option<String>,
+    pub rom_type: Option<RomType>,
+    /// Cartridge RAM size in bytes
+    pub ram_size: Option<u32>,
+    pub region: Option<Region>,
+    /// Name of the coprocessor (see `coprocessor::NAMES`)
+    pub coprocessor: Option<String>,
+    pub hacks: Vec<Hack>,
+    pub status: DumpStatus,
+}
+
+impl GameEntry {
+    fn matches(&self, crc: u32, sha1: &mut FnMut() -> [u8; 20]) -> bool {
+        self.crc32.map_or(true, |c| c == crc) && self.sha1.map_or(true, |s| s == sha1())
+    }
+
+    /// Sets a field from a `key = value` line.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "name" => self.name = Some(value.to_owned()),
+            "mapping" => {
+                self.rom_type = Some(match value {
+                    "lorom" => RomType::LoRom,
+                    "hirom" => RomType::HiRom,
+                    "exlorom" => RomType::ExLoRom,
+                    "exhirom" => RomType::ExHiRom,
+                    _ => return Err(format!("unknown mapping '{}'", value)),
+                });
+            }
+            "ram" => {
+                let kb = try!(value.parse::<u32>().map_err(|_| "invalid RAM size".to_string()));
+                self.ram_size = Some(kb * 1024);
+            }
+            "region" => {
+                self.region = Some(try!(Region::from_name(value)
+                    .ok_or_else(|| format!("unknown region '{}'", value))));
+            }
+            "coprocessor" => {
+                if !coprocessor::NAMES.contains(&value) {
+                    return Err(format!("unknown coprocessor '{}' (known: {})", value,
+                        coprocessor::NAMES.join(", ")));
+                }
+                self.coprocessor = Some(value.to_owned());
+            }
+            "hack" => {
+                let hack = try!(Hack::parse(value).ok_or_else(|| "invalid hack".to_string()));
+                self.hacks.push(hack);
+            }
+            "status" => {
+                self.status = match value {
+                    "good" => DumpStatus::Good,
+                    "bad" => DumpStatus::Bad,
+                    _ => return Err(format!("unknown status '{}'", value)),
+                };
+            }
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Parses a hex string of exactly `out.len()` bytes into `out`.
+fn parse_hex(s: &str, out: &mut [u8]) -> bool {
+    if s.len() != out.len() * 2 || !s.is_ascii() { return false; }
+    for (i, b) in out.iter_mut().enumerate() {
+        match u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+            Ok(value) => *b = value,
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+/// A collection of `GameEntry`s
+#[derive(Default)]
+pub struct GameDatabase {
+    entries: Vec<GameEntry>,
+}
+
+impl GameDatabase {
+    /// Creates an empty database.
+    pub fn new() -> Self {
+        GameDatabase::default()
+    }
+
+    /// Creates a database containing the bundled entries.
+    pub fn bundled() -> Self {
+        let mut db = GameDatabase::new();
+        db.parse(BUNDLED).expect("bundled game database is invalid");
+        db
+    }
+
+    /// Number of entries in the database
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Loads the entries from a database file. Entries for the same ROM as an existing entry take
+    /// precedence.
+    pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        self.parse(&text).map_err(|e| io::Error::new(e.kind(),
+            format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses database entries and adds them to the database.
+    pub fn parse(&mut self, text: &str) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |msg: &str| io::Error::new(io::ErrorKind::InvalidData,
+                format!("game database line {}: {} (in '{}')", i + 1, msg, line));
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let mut entry = GameEntry { status: DumpStatus::Good, ..GameEntry::default() };
+                for hash in line[1..line.len() - 1].split_whitespace() {
+                    let mut crc = [0; 4];
+                    let mut digest = [0; 20];
+                    if parse_hex(hash, &mut crc) {
+                        entry.crc32 = Some(crc.iter().fold(0, |acc, &b| acc << 8 | b as u32));
+                    } else if parse_hex(hash, &mut digest) {
+                        entry.sha1 = Some(digest);
+                    } else {
+                        return Err(err("expected a CRC32 or SHA-1"));
+                    }
+                }
+                if entry.crc32.is_none() && entry.sha1.is_none() {
+                    return Err(err("entry has no hash"));
+                }
+                entries.push(entry);
+                continue;
+            }
+
+            let entry = try!(entries.last_mut().ok_or_else(|| err("expected an entry header")));
+            let eq = try!(line.find('=').ok_or_else(|| err("expected `key = value`")));
+            try!(entry.set(line[..eq].trim(), line[eq + 1..].trim()).map_err(|e| err(&e)));
+        }
+
+        // Later entries are looked up first
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Looks up the entry for a ROM image (without copier header).
+    pub fn lookup(&self, rom: &[u8]) -> Option<&GameEntry> {
+        let crc = crc32(rom);
+        // The SHA-1 is only computed if an entry needs it
+        let mut digest = None;
+        let mut get_sha1 = || *digest.get_or_insert_with(|| sha1(rom));
+        self.entries.iter().rev().find(|entry| entry.matches(crc, &mut get_sha1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rom::Rom;
+
+    /// Builds a LoROM image of `size` bytes with a valid header. If it's 64 KB or larger, it also
+    /// contains a HiROM header with a wrong checksum complement (so it loses the detection).
+    fn rom(size: usize) -> Vec<u8> {
+        let mut rom = vec![0; size];
+        rom[0x7fc0..0x7fd5].copy_from_slice(b"LOROM TEST           ");
+        rom[0x7fd7] = 0x08;
+        rom[0x7fdc..0x7fe0].copy_from_slice(&[0xff, 0xff, 0x00, 0x00]);
+        if size >= 0x10000 {
+            rom[0xffc0..0xffd5].copy_from_slice(b"HIROM TEST           ");
+            rom[0xffd5] = 0x21;
+            rom[0xffd7] = 0x08;
+            rom[0xffd8] = 0x03;
+        }
+        rom
+    }
+
+    fn database(rom: &[u8], lines: &str) -> GameDatabase {
+        let mut db = GameDatabase::new();
+        db.parse(&format!("[{:08x}]\n{}", crc32(rom), lines)).unwrap();
+        db
+    }
+
+    fn hex(digest: [u8; 20]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn load(rom: &[u8], db: &GameDatabase) -> Rom {
+        Rom::from_bytes_with_database(rom, Vec::new(), db).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let mut db = GameDatabase::new();
+        db.parse("# Comment\n\
+            [0123abcd]\n\
+            name = Example Game (USA)\n\
+            mapping = hirom\n\
+            ram = 2\n\
+            region = pal\n\
+            coprocessor = dsp2\n\
+            hack = wram-init $55\n\
+            status = bad\n\
+            \n\
+            [a9993e364706816aba3e25717850c26c9cd0d89d]\n\
+            [0123abcd A9993E364706816ABA3E25717850C26C9CD0D89D]\n").unwrap();
+        assert_eq!(db.len(), 3);
+
+        let entry = &db.entries[0];
+        assert_eq!(entry.crc32, Some(0x0123abcd));
+        assert_eq!(entry.sha1, None);
+        assert_eq!(entry.name.as_ref().unwrap(), "Example Game (USA)");
+        assert_eq!(entry.rom_type, Some(RomType::HiRom));
+        assert_eq!(entry.ram_size, Some(2048));
+        assert_eq!(entry.region, Some(Region::Pal));
+        assert_eq!(entry.coprocessor.as_ref().unwrap(), "dsp2");
+        assert_eq!(entry.hacks, [Hack::WramInit(0x55)]);
+        assert_eq!(entry.status, DumpStatus::Bad);
+
+        let entry = &db.entries[1];
+        assert_eq!(entry.crc32, None);
+        assert_eq!(entry.sha1, Some(sha1(b"abc")));
+        assert_eq!(entry.status, DumpStatus::Good);
+        assert!(entry.name.is_none() && entry.rom_type.is_none() && entry.hacks.is_empty());
+
+        let entry = &db.entries[2];
+        assert_eq!(entry.crc32, Some(0x0123abcd));
+        assert_eq!(entry.sha1, Some(sha1(b"abc")));
+
+        assert!(GameDatabase::bundled().len() > 0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in &["name = No Entry", "[0123abc]", "[]", "[0123abcd]\nname", "[0123abcd]\nx = 1",
+                "[0123abcd]\nmapping = midrom", "[0123abcd]\nram = 2 KB",
+                "[0123abcd]\nregion = secam", "[0123abcd]\ncoprocessor = dsp5",
+                "[0123abcd]\nhack = wram-init", "[0123abcd]\nstatus = ok"] {
+            let mut db = GameDatabase::new();
+            let err = db.parse(text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", text);
+            // Nothing is added if the text contains an error
+            assert!(db.is_empty());
+        }
+    }
+
+    #[test]
+    fn hacks() {
+        assert_eq!(Hack::parse("wram-init $55"), Some(Hack::WramInit(0x55)));
+        assert_eq!(Hack::parse("wram-init ff"), Some(Hack::WramInit(0xff)));
+        assert_eq!(Hack::parse("wram-init $55 $66"), None);
+        assert_eq!(Hack::parse("sram-init $55"), None);
+        assert_eq!(Hack::WramInit(0x0f).to_string(), "wram-init $0F");
+    }
+
+    #[test]
+    fn lookup() {
+        let rom = rom(0x8000);
+        let other = [0; 0x8000];
+        let mut db = database(&rom, "name = CRC32");
+        db.parse(&format!("[{}]\nname = SHA-1", hex(sha1(&rom)))).unwrap();
+        db.parse(&format!("[{:08x} {}]\nname = Both", crc32(&rom), hex(sha1(&other)))).unwrap();
+
+        // Later entries take precedence, and both hashes must match
+        assert_eq!(db.lookup(&rom).unwrap().name.as_ref().unwrap(), "SHA-1");
+        assert!(db.lookup(&other).is_none());
+        assert!(GameDatabase::new().lookup(&rom).is_none());
+    }
+
+    #[test]
+    fn forced_mapping() {
+        let rom = rom(0x10000);
+        let detected = load(&rom, &GameDatabase::new());
+        assert_eq!(detected.info().rom_type, RomType::LoRom);
+        assert_eq!(detected.info().title, "LOROM TEST");
+        assert_eq!(detected.info().dump_status, DumpStatus::Unknown);
+
+        let forced = load(&rom, &database(&rom, "mapping = hirom\nram = 8"));
+        let info = forced.info();
+        assert_eq!(info.rom_type, RomType::HiRom);
+        assert_eq!(info.title, "HIROM TEST");
+        assert_eq!(info.sram_size, 8 * 1024);
+        assert_eq!(info.dump_status, DumpStatus::Good);
+    }
+
+    #[test]
+    fn forced_mapping_without_header() {
+        // A 32 KB image has no HiROM header. The detected header is kept, with the forced mapping.
+        let rom = rom(0x8000);
+        let forced = load(&rom, &database(&rom, "mapping = hirom"));
+        let info = forced.info();
+        assert_eq!(info.rom_type, RomType::HiRom);
+        assert_eq!(info.title, "LOROM TEST");
+        assert_eq!(info.rom_size, 0x40000);
+        assert!(info.warnings.contains(
+            &"game database specifies HiROM mapping, but the ROM image has no HiROM header"
+            .to_string()), "{:?}", info.warnings);
+    }
+
+    #[test]
+    fn forced_coprocessor_and_region() {
+        let rom = rom(0x8000);
+        let detected = load(&rom, &GameDatabase::new());
+        assert_eq!(detected.info().coprocessor, None);
+        assert!(!detected.info().is_pal());
+
+        let db = database(&rom, "coprocessor = dsp2\nregion = pal\nhack = wram-init $55\n\
+            status = bad");
+        let forced = load(&rom, &db);
+        let info = forced.info();
+        assert_eq!(info.coprocessor, Some("DSP-2"));
+        assert!(info.is_pal());
+        assert_eq!(Region::for_rom(info), Region::Pal);
+        assert_eq!(info.hacks(), [Hack::WramInit(0x55)]);
+        assert_eq!(info.dump_status, DumpStatus::Bad);
+        assert!(info.warnings.contains(&"ROM is a known bad dump".to_string()));
+    }
+}
@@ -16,6 +16,7 @@ mod port;
 
 pub use self::port::Peripheral;
 
+use diagnostics::Diagnostics;
 use record::{Recorder, Replayer};
 
 use std::ops::{Index, IndexMut};
@@ -80,9 +81,13 @@ pub struct Input {
     /// Current latch state. Peripherals will have `set_latch` called when this changes.
     latch: bool,
     latched_this_frame: bool,
+
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
 }
 
-impl_save_state!(Input { auto_read_data, latch, latched_this_frame } ignore { ports, mode });
+impl_save_state!(Input { auto_read_data, latch, latched_this_frame }
+    ignore { ports, mode, diagnostics });
 
 impl Input {
     /// Start recording input to a `Write` implementor, often a file.
@@ -121,8 +126,8 @@ impl Input {
 
     pub fn new_frame(&mut self) {
         if self.latch {
-            once!(warn!("latch still active from older frame (might interfere with \
-                         recording); latch might be changed by emulator!"));
+            diag!(self.diagnostics, "latch still active from older frame (might interfere with \
+                                     recording); latch might be changed by emulator!");
         }
 
         if !self.latched_this_frame {
@@ -145,8 +150,8 @@ impl Input {
         match self.ports[port] {
             Some(ref mut cpa) => {
                 if !self.latched_this_frame {
-                    once!(warn!("reading data lines without prior latching (this can interfere \
-                                 with input recording)"));
+                    diag!(self.diagnostics, "reading data lines without prior latching (this can \
+                                             interfere with input recording)");
                 }
 
                 cpa.read_bit()
@@ -189,8 +194,8 @@ impl Input {
                 // Latch changed state
                 if new_latch {
                     if self.latched_this_frame {
-                        once!(warn!("already latched input in this frame! (this might interfere \
-                                     with recording)"));
+                        diag!(self.diagnostics, "already latched input in this frame! (this might \
+                                                 interfere with recording)");
                     }
                     self.latched_this_frame = true;
                 }
@@ -4,6 +4,7 @@
 #[macro_use] extern crate log;
 extern crate byteorder;
 extern crate slicevec;
+extern crate inflate;
 
 #[macro_use] #[no_link] extern crate byte_array;
 #[macro_use] extern crate libsavestate;
@@ -11,11 +12,28 @@ extern crate wdc65816;
 extern crate spc700;
 extern crate breeze_backend;
 
-#[macro_use] mod log_util;
+mod alu;
+mod log_util;
+#[macro_use] pub mod diagnostics;
+pub mod archive;
+pub mod cheat;
+pub mod cdl;
+pub mod coprocessor;
 pub mod dma;
+pub mod error;
+pub mod event_log;
+pub mod gamedb;
 pub mod record;
+pub mod region;
 pub mod ppu;
 pub mod input;
+pub mod patch;
+pub mod profiler;
+pub mod ram_search;
 pub mod rom;
+pub mod rom_info;
 pub mod save;
 pub mod snes;
+pub mod sram;
+
+pub use error::Error;
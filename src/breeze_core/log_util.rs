@@ -1,22 +1,10 @@
-//! Logging utility macros
+//! Logging utilities
 
 use std::cell::Cell;
 use std::ops::Deref;
 use std::fmt::Debug;
 use std::thread;
 
-/// Evaluates the given expression once (when first reached).
-macro_rules! once {
-    ( $e:expr ) => {{
-        use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
-
-        static REACHED: AtomicBool = ATOMIC_BOOL_INIT;
-        if REACHED.swap(true, Ordering::SeqCst) == false {
-            $e;
-        }
-    }}
-}
-
 /// Wraps a `Cell<T>` and writes its contents to stdout if dropped while panicking.
 pub struct LogOnPanic<T: Copy + Debug> {
     name: &'static str,
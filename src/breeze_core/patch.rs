@@ -0,0 +1,436 @@
+//! ROM patch application (IPS, BPS and UPS)
+//!
+//! Translations and ROM hacks are distributed as patches against the original ROM image. They are
+//! applied to the raw file contents before the ROM is loaded, so the header of the patched ROM is
+//! used (see `Rom::from_bytes`).
+//!
+//! BPS and UPS patches contain CRC32 checksums of the original and patched ROM, which we verify.
+//! They're usually made for ROMs without a copier header, so if the checksum doesn't match and the
+//! ROM has one, the patch is applied to the ROM without the header. IPS patches have no checksums
+//! and are applied as is.
+
+use rom_info::crc32;
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Largest ROM size a patch may produce (BPS and UPS store the size in the patch)
+const MAX_TARGET_SIZE: usize = 64 * 1024 * 1024;
+
+/// Patch file extensions looked for by `find_patches`, in the order they're applied
+const EXTENSIONS: &'static [&'static str] = &["bps", "ups", "ips"];
+
+fn invalid_data<S: Into<String>>(err: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.into())
+}
+
+/// Returns the patches next to the ROM at `rom_path`: Files with the same name and the extension
+/// `.bps`, `.ups` or `.ips`.
+pub fn find_patches(rom_path: &Path) -> Vec<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Applies a patch to `rom`, returning the patched ROM. The patch format is detected from the
+/// magic number at the start of `patch`.
+///
+/// Returns an error if the patch is malformed, or if the checksums stored in the patch don't match
+/// the ROM or the result.
+pub fn apply(rom: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_checked(rom, patch, "BPS", apply_bps)
+    } else if patch.starts_with(b"UPS1") {
+        apply_checked(rom, patch, "UPS", apply_ups)
+    } else {
+        Err(invalid_data("unknown patch format (expected an IPS, BPS or UPS file)"))
+    }
+}
+
+/// Applies a BPS or UPS patch. These have the same footer: The CRC32 of the source ROM, of the
+/// target ROM and of the patch itself (excluding the last 4 bytes).
+fn apply_checked<F>(rom: &[u8], patch: &[u8], format: &str, apply_fn: F) -> io::Result<Vec<u8>>
+where F: Fn(&[u8], &[u8]) -> io::Result<Vec<u8>> {
+    if patch.len() < 4 + 12 {
+        return Err(invalid_data(format!("{} patch is truncated", format)));
+    }
+    let footer = &patch[patch.len() - 12..];
+    let source_crc = read_u32(&footer[0..4]);
+    let target_crc = read_u32(&footer[4..8]);
+    let patch_crc = read_u32(&footer[8..12]);
+
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != patch_crc {
+        return Err(invalid_data(format!("{} patch is corrupted: checksum is {:08x}, expected \
+            {:08x}", format, actual, patch_crc)));
+    }
+
+    let mut source = rom;
+    let mut actual = crc32(source);
+    if actual != source_crc && rom.len() % 1024 == 512 && crc32(&rom[512..]) == source_crc {
+        info!("applying {} patch to the ROM without its copier header", format);
+        source = &rom[512..];
+        actual = source_crc;
+    }
+    if actual != source_crc {
+        return Err(invalid_data(format!("{} patch was made for a different ROM: ROM checksum \
+            is {:08x}, expected {:08x}", format, actual, source_crc)));
+    }
+
+    let target = try!(apply_fn(source, &patch[4..patch.len() - 12]));
+
+    let actual = crc32(&target);
+    if actual != target_crc {
+        return Err(invalid_data(format!("patched ROM has the wrong checksum: {:08x}, expected \
+            {:08x}", actual, target_crc)));
+    }
+    Ok(target)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Reads bytes from a patch, failing with an error instead of panicking at the end.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data: data, pos: 0 }
+    }
+
+    fn at_end(&self) -> bool { self.pos >= self.data.len() }
+
+    fn remaining(&self) -> usize { self.data.len() - self.pos }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.remaining() < len {
+            return Err(invalid_data("unexpected end of patch"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    /// Reads a big-endian number of `len` bytes (used by IPS).
+    fn big_endian(&mut self, len: usize) -> io::Result<usize> {
+        let bytes = try!(self.bytes(len));
+        Ok(bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    /// Reads a variable-length number (used by BPS and UPS). Each byte contributes 7 bits, the
+    /// last byte has bit 7 set.
+    fn varint(&mut self) -> io::Result<usize> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = try!(self.byte());
+            let next = ((byte & 0x7f) as usize).checked_mul(shift)
+                .and_then(|v| v.checked_add(value));
+            value = try!(next.ok_or_else(|| invalid_data("number in patch is too large")));
+            if byte & 0x80 != 0 { return Ok(value); }
+            let next = shift.checked_mul(128).and_then(|s| value.checked_add(s).map(|v| (s, v)));
+            let (s, v) = try!(next.ok_or_else(|| invalid_data("number in patch is too large")));
+            shift = s;
+            value = v;
+        }
+    }
+
+    /// Reads a signed variable-length number (bit 0 is the sign).
+    fn signed_varint(&mut self) -> io::Result<isize> {
+        let value = try!(self.varint());
+        let magnitude = (value >> 1) as isize;
+        Ok(if value & 1 != 0 { -magnitude } else { magnitude })
+    }
+}
+
+/// Checks a size stored in a patch.
+fn check_size(size: usize) -> io::Result<usize> {
+    if size > MAX_TARGET_SIZE {
+        Err(invalid_data(format!("patched ROM would be too large ({} bytes)", size)))
+    } else {
+        Ok(size)
+    }
+}
+
+/// Applies an IPS patch.
+///
+/// IPS patches consist of records that overwrite data at a 24-bit offset (or fill it with a single
+/// byte, for RLE records), terminated by `EOF`. An optional 24-bit size after `EOF` truncates the
+/// ROM.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let mut target = rom.to_vec();
+    let mut r = Reader::new(&patch[5..]);
+    loop {
+        if try!(r.bytes(3)) == b"EOF" {
+            break;
+        }
+        r.pos -= 3;
+        let offset = try!(r.big_endian(3));
+        let len = try!(r.big_endian(2));
+        let (len, rle_value) = if len == 0 {
+            (try!(r.big_endian(2)), Some(try!(r.byte())))
+        } else {
+            (len, None)
+        };
+
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        match rle_value {
+            Some(value) => for b in &mut target[offset..offset + len] { *b = value; },
+            None => target[offset..offset + len].copy_from_slice(try!(r.bytes(len))),
+        }
+    }
+
+    if r.remaining() == 3 {
+        let size = try!(r.big_endian(3));
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+/// Applies a BPS patch (`data` is the patch without magic and footer).
+///
+/// The target is built by a sequence of actions that copy data from the source, the patch or the
+/// already written part of the target.
+fn apply_bps(source: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut r = Reader::new(data);
+    let source_size = try!(r.varint());
+    let target_size = try!(check_size(try!(r.varint())));
+    let metadata_size = try!(r.varint());
+    try!(r.bytes(metadata_size));
+
+    if source_size != source.len() {
+        return Err(invalid_data(format!("BPS patch expects a ROM of {} bytes, got {}",
+            source_size, source.len())));
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_rel: isize = 0;
+    let mut target_rel: isize = 0;
+    while !r.at_end() {
+        let action = try!(r.varint());
+        let len = (action >> 2) + 1;
+        if target.len() + len > target_size {
+            return Err(invalid_data("BPS patch writes past the end of the ROM"));
+        }
+        match action & 3 {
+            // SourceRead
+            0 => {
+                let start = target.len();
+                if start + len > source.len() {
+                    return Err(invalid_data("BPS patch reads past the end of the ROM"));
+                }
+                target.extend_from_slice(&source[start..start + len]);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(try!(r.bytes(len))),
+            // SourceCopy
+            2 => {
+                source_rel += try!(r.signed_varint());
+                if source_rel < 0 || source_rel as usize + len > source.len() {
+                    return Err(invalid_data("BPS patch reads past the end of the ROM"));
+                }
+                let start = source_rel as usize;
+                target.extend_from_slice(&source[start..start + len]);
+                source_rel += len as isize;
+            }
+            // TargetCopy (may overlap the written data, so copy byte by byte)
+            _ => {
+                target_rel += try!(r.signed_varint());
+                if target_rel < 0 || target_rel as usize >= target.len() {
+                    return Err(invalid_data("BPS patch copies from outside the patched ROM"));
+                }
+                for _ in 0..len {
+                    let byte = target[target_rel as usize];
+                    target.push(byte);
+                    target_rel += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(invalid_data(format!("BPS patch produced {} bytes, expected {}",
+            target.len(), target_size)));
+    }
+    Ok(target)
+}
+
+/// Applies a UPS patch (`data` is the patch without magic and footer).
+///
+/// UPS patches store the XOR of source and target, as runs of non-zero bytes separated by the
+/// number of unchanged bytes.
+fn apply_ups(source: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut r = Reader::new(data);
+    let source_size = try!(r.varint());
+    let target_size = try!(check_size(try!(r.varint())));
+
+    if source_size != source.len() {
+        return Err(invalid_data(format!("UPS patch expects a ROM of {} bytes, got {}",
+            source_size, source.len())));
+    }
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut pos: usize = 0;
+    while !r.at_end() {
+        pos = try!(pos.checked_add(try!(r.varint()))
+            .ok_or_else(|| invalid_data("UPS patch offset is too large")));
+        loop {
+            let byte = try!(r.byte());
+            if pos < target.len() {
+                target[pos] ^= byte;
+            }
+            pos = pos.saturating_add(1);
+            if byte == 0 { break; }
+        }
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+    use rom_info::crc32;
+
+    /// Encodes a BPS/UPS variable-length number.
+    fn varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let bits = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | bits);
+                return;
+            }
+            out.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8,
+            (value >> 24) as u8]);
+    }
+
+    /// Appends the BPS/UPS footer (checksums of source, target and patch).
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        push_u32(&mut patch, crc32(source));
+        push_u32(&mut patch, crc32(target));
+        let patch_crc = crc32(&patch);
+        push_u32(&mut patch, patch_crc);
+        patch
+    }
+
+    #[test]
+    fn ips() {
+        let rom = [0; 16];
+        let mut patch = b"PATCH".to_vec();
+        // Write "abc" at 2
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x03, b'a', b'b', b'c']);
+        // RLE: Fill 10 bytes at 8 with $FF (grows the ROM to 18 bytes)
+        patch.extend_from_slice(&[0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x0a, 0xff]);
+        patch.extend_from_slice(b"EOF");
+        let mut expected = vec![0, 0, b'a', b'b', b'c', 0, 0, 0];
+        expected.extend_from_slice(&[0xff; 10]);
+        assert_eq!(apply(&rom, &patch).unwrap(), expected);
+
+        // Truncate to 12 bytes
+        patch.extend_from_slice(&[0x00, 0x00, 0x0c]);
+        assert_eq!(apply(&rom, &patch).unwrap(),
+            [0, 0, b'a', b'b', b'c', 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+
+        // Missing `EOF`
+        patch.truncate(patch.len() - 6);
+        assert!(apply(&rom, &patch).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"hello world";
+        let target = b"hello there, hello!!!!";
+        let mut patch = b"BPS1".to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        varint(&mut patch, 0);
+        // SourceRead "hello "
+        varint(&mut patch, (6 - 1) << 2);
+        // TargetRead "there, "
+        varint(&mut patch, (7 - 1) << 2 | 1);
+        patch.extend_from_slice(b"there, ");
+        // SourceCopy "hello" from offset 0
+        varint(&mut patch, (5 - 1) << 2 | 2);
+        varint(&mut patch, 0);
+        // TargetRead "!", then TargetCopy "!!!" from the byte just written (overlapping)
+        varint(&mut patch, 1);
+        patch.push(b'!');
+        varint(&mut patch, (3 - 1) << 2 | 3);
+        varint(&mut patch, (target.len() - 4) << 1);
+        let patch = finish(patch, source, target);
+        assert_eq!(apply(source, &patch).unwrap(), &target[..]);
+
+        // Wrong source ROM
+        assert!(apply(b"hello World", &patch).is_err());
+
+        // Wrong target checksum (the patch checksum is fixed up so only the target check fails)
+        let mut bad = patch[..patch.len() - 4].to_vec();
+        let len = bad.len();
+        bad[len - 4] ^= 1;
+        let patch_crc = crc32(&bad);
+        bad.extend_from_slice(&[patch_crc as u8, (patch_crc >> 8) as u8,
+            (patch_crc >> 16) as u8, (patch_crc >> 24) as u8]);
+        assert!(apply(source, &bad).is_err());
+
+        // Corrupted patch
+        let mut bad = patch.clone();
+        bad[10] ^= 1;
+        assert!(apply(source, &bad).is_err());
+    }
+
+    #[test]
+    fn bps_copier_header() {
+        let source = [0x55; 1024];
+        let target = [0xaa; 1024];
+        let mut patch = b"BPS1".to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        varint(&mut patch, 0);
+        varint(&mut patch, (1024 - 1) << 2 | 1);
+        patch.extend_from_slice(&target);
+        let patch = finish(patch, &source, &target);
+
+        let mut rom = vec![0; 512];
+        rom.extend_from_slice(&source);
+        assert_eq!(apply(&rom, &patch).unwrap(), &target[..]);
+    }
+
+    #[test]
+    fn ups() {
+        let source = b"abcd";
+        let target = b"abXdef";
+        let mut patch = b"UPS1".to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        // Skip 2 bytes, XOR 'c' to 'X' (the terminating 0 skips 'd')
+        varint(&mut patch, 2);
+        patch.extend_from_slice(&[b'c' ^ b'X', 0]);
+        // Append "ef" (XOR with the zeros the ROM is extended with)
+        varint(&mut patch, 0);
+        patch.extend_from_slice(&[b'e', b'f', 0]);
+        let patch = finish(patch, source, target);
+        assert_eq!(apply(source, &patch).unwrap(), &target[..]);
+
+        assert!(apply(b"abce", &patch).is_err());
+    }
+}
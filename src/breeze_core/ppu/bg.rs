@@ -237,8 +237,7 @@ impl Ppu {
     }
 
     fn render_mode7_scanline(&mut self) {
-        // TODO Figure out how to integrate EXTBG
-        assert!(self.setini & 0x40 == 0, "NYI: Mode 7 EXTBG");
+        // TODO Figure out how to integrate EXTBG (for now, BG1 is rendered as if it was disabled)
 
         // FIXME consider changing the type of `Ppu.m7a,...` to `i16`
 
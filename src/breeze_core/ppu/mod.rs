@@ -1,7 +1,8 @@
 //! Emulates the Picture Processing Unit.
 //!
-//! We emulate the NTSC console, which has a refresh rate of 60 Hz and a screen resolution of
-//! 256x224 pixels by default.
+//! The PPU renders 256x224 pixels by default (256x239 with overscan enabled). The number of
+//! scanlines per frame depends on the console's `Region`: NTSC consoles run 262 scanlines at 60 Hz,
+//! PAL consoles 312 scanlines at 50 Hz.
 //!
 //! Documentation mostly taken from http://emu-docs.org/Super%20NES/General/snesdoc.html and
 //! http://wiki.superfamicom.org/
@@ -16,16 +17,20 @@ mod sprites;
 
 pub use self::rgb::{Rgb, SnesRgb};
 
+use diagnostics::Diagnostics;
+use error::ErrorKind;
+use region::Region;
+
 use self::sprites::SpriteRenderState;
 use self::bg::BgCache;
 use self::oam::Oam;
 use self::cgram::Cgram;
 
-pub use breeze_backend::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use breeze_backend::ppu::{MAX_SCREEN_HEIGHT, SCREEN_HEIGHT, SCREEN_WIDTH};
 
 /// VRAM size in Bytes
 pub const VRAM_SIZE: usize = 64 * 1024;
-const FRAME_BUF_SIZE: usize = SCREEN_WIDTH as usize * SCREEN_HEIGHT as usize * 3;
+const FRAME_BUF_SIZE: usize = SCREEN_WIDTH as usize * MAX_SCREEN_HEIGHT as usize * 3;
 byte_array!(pub Vram[VRAM_SIZE] with u16 indexing, save state please);
 byte_array!(pub FrameBuf[FRAME_BUF_SIZE]);
 
@@ -36,7 +41,8 @@ pub struct Ppu {
     /// component and the third byte is the blue component. The fourth byte is then the red
     /// component of the second pixel (at coordinate `(1,0)`), and so on.
     ///
-    // FIXME The size can change depending on the PPU config, make sure all frames fit in
+    /// The buffer is large enough for 239 lines, but only the first `screen_height()` lines are
+    /// part of the frame (see `frame`).
     // FIXME How would this work in high resolution modes?
     pub framebuf: FrameBuf,
 
@@ -85,12 +91,17 @@ pub struct Ppu {
     /// Character data locations are set with the registers `$210B` (BG1/2) and `$210C` (BG3/4).
     pub vram: Vram,
 
+    /// The region of the console, which determines the number of scanlines per frame
+    region: Region,
+
     /// Scanline counter
     ///
     /// "The SNES runs 1 scanline every 1364 master cycles, except in non-interlace mode scanline
     /// $f0 of every other frame (those with $213f.7=1) is only 1360 cycles. Frames are 262
     /// scanlines in non-interlace mode, while in interlace mode frames with $213f.7=0 are 263
     /// scanlines. V-Blank runs from either scanline $e1 or $f0 until the end of the frame."
+    ///
+    /// (PAL consoles run 312 scanlines per frame)
     scanline: u16,
 
     /// Horizontal pixel counter
@@ -269,6 +280,8 @@ pub struct Ppu {
     /// Store the low byte to write to the current CGRAM position after the high byte is written by
     /// the CPU (writes are always done in pairs - like the low 512 bytes of OAM).
     cg_low_buf: Option<u8>,
+    /// Whether the next read from `$213b` returns the high byte of the current CGRAM word
+    cg_read_high: bool,
 
     /// `$2123` Window Mask Settings for BG1 and BG2
     /// `ABCDabcd`
@@ -414,6 +427,17 @@ pub struct Ppu {
     ///
     /// Reset on read if `$4201` bit 7 is set.
     ext_latch: bool,
+
+    /// PPU1 open bus: The last value read from a PPU1 register (`$2134-$2136`, `$2138-$213a` and
+    /// `$213e`). Returned (in parts) by reads from some write-only registers and by the unused
+    /// bits of `$213e`.
+    ppu1_mdr: u8,
+    /// PPU2 open bus: The last value read from a PPU2 register (`$213b-$213d` and `$213f`).
+    /// Returned by the unused bits of these registers.
+    ppu2_mdr: u8,
+
+    /// Diagnostics that weren't collected by the `Snes` yet
+    pub diagnostics: Diagnostics,
 }
 
 impl_save_state!(Ppu {
@@ -423,46 +447,87 @@ impl_save_state!(Ppu {
     m7b, m7b_last, m7c, m7d, m7x, m7y, cgadd, cg_low_buf, w12sel, w34sel, wobjsel, wh0, wh1, wh2,
     wh3, wbglog, wobjlog, tm, ts, tmw, tsw, cgwsel, cgadsub, coldata_r, coldata_g, coldata_b,
     setini, ophct, ophct_high, opvct, opvct_high, can_latch_counters, scanline, x, time_over,
-    range_over, interlace_field, ext_latch
+    range_over, interlace_field, ext_latch, cg_read_high, ppu1_mdr, ppu2_mdr
 } ignore {
-    framebuf, sprite_render_state, bg_cache
+    framebuf, sprite_render_state, bg_cache, region, diagnostics
 });
 
 impl Ppu {
-    /// Load a PPU register (addresses `$2134` to `$213f`)
-    pub fn load(&mut self, addr: u16) -> u8 {
+    /// Load a PPU register (addresses `$2100` to `$213f`).
+    ///
+    /// `mdr` is the value currently on the CPU data bus, which is read from registers that aren't
+    /// driven by either PPU.
+    pub fn load(&mut self, addr: u16, mdr: u8) -> u8 {
         match addr {
+            // Write-only registers. Reads from `$21x4-$21x6` and `$21x8-$21xa` return the PPU1
+            // open bus, the others aren't connected at all.
+            0x2100 ... 0x2133 => match addr & 0x0f {
+                0x4 ... 0x6 | 0x8 ... 0xa => self.ppu1_mdr,
+                _ => mdr,
+            },
             // `$2134` - `$2136`: Multiplication Result of `self.m7a * self.m7b_last`
             // MPYL - Low Byte
-            0x2134 => (self.m7a as u32 * self.m7b_last as u32) as u8,
+            0x2134 => self.ppu1_load((self.m7a as u32 * self.m7b_last as u32) as u8),
             // MPYM - Middle Byte
-            0x2135 => ((self.m7a as u32 * self.m7b_last as u32) >> 8) as u8,
+            0x2135 => self.ppu1_load(((self.m7a as u32 * self.m7b_last as u32) >> 8) as u8),
             // MPYH - High Byte
-            0x2136 => ((self.m7a as u32 * self.m7b_last as u32) >> 16) as u8,
+            0x2136 => self.ppu1_load(((self.m7a as u32 * self.m7b_last as u32) >> 16) as u8),
+            // SLHV: Only latches the counters, the value read is CPU open bus
             0x2137 => {
                 self.latch_counters();
-                0   // FIXME The data read is open bus, which isn't yet emulated
+                mdr
             }
             // RDOAM
-            0x2138 => self.oam_load(),
-            0x2139 => self.vram_load_low(),
-            0x213a => self.vram_load_high(),
-            // OPHCT
+            0x2138 => {
+                let value = self.oam_load();
+                self.ppu1_load(value)
+            }
+            0x2139 => {
+                let value = self.vram_load_low();
+                self.ppu1_load(value)
+            }
+            0x213a => {
+                let value = self.vram_load_high();
+                self.ppu1_load(value)
+            }
+            // RDCGRAM: Low byte, then high byte (bit 7 is PPU2 open bus)
+            0x213b => {
+                let value = if self.cg_read_high {
+                    let high = self.cgram[self.cgadd as u16 * 2 + 1];
+                    self.cgadd = self.cgadd.wrapping_add(1);
+                    high & 0x7f | self.ppu2_mdr & 0x80
+                } else {
+                    self.cgram[self.cgadd as u16 * 2]
+                };
+                self.cg_read_high = !self.cg_read_high;
+                self.ppu2_load(value)
+            }
+            // OPHCT (the high byte only has 1 valid bit, the rest is PPU2 open bus)
             0x213c => {
-                let value = if self.ophct_high { (self.ophct >> 8) as u8 } else { self.ophct as u8};
+                let value = if self.ophct_high {
+                    (self.ophct >> 8) as u8 & 0x01 | self.ppu2_mdr & 0xfe
+                } else {
+                    self.ophct as u8
+                };
                 self.ophct_high = !self.ophct_high;
-                value
+                self.ppu2_load(value)
             }
             // OPVCT
             0x213d => {
-                let value = if self.opvct_high { (self.opvct >> 8) as u8 } else { self.opvct as u8};
+                let value = if self.opvct_high {
+                    (self.opvct >> 8) as u8 & 0x01 | self.ppu2_mdr & 0xfe
+                } else {
+                    self.opvct as u8
+                };
                 self.opvct_high = !self.opvct_high;
-                value
+                self.ppu2_load(value)
             }
             0x213e => {
-                (if self.time_over { 0x80 } else { 0x00 })
-                | (if self.range_over { 0x40 } else { 0x00 })
-                | 0x01
+                let value = (if self.time_over { 0x80 } else { 0x00 })
+                    | (if self.range_over { 0x40 } else { 0x00 })
+                    | self.ppu1_mdr & 0x10
+                    | 0x01;
+                self.ppu1_load(value)
             }
             0x213f => {
                 let interlace = if self.interlace_field { 0x80 } else { 0x00 };
@@ -471,15 +536,33 @@ impl Ppu {
                 self.ophct_high = false;
                 self.opvct_high = false;
 
-                // FIXME Does PAL/NTSC have significance? Or the version we return?
-                interlace | latch | 0x02
+                let pal = if self.region == Region::Pal { 0x10 } else { 0x00 };
+
+                // FIXME Does the version we return have significance?
+                let value = interlace | latch | self.ppu2_mdr & 0x20 | pal | 0x02;
+                self.ppu2_load(value)
             }
             _ => panic!("invalid/unimplemented PPU load from ${:04X}", addr),
         }
     }
 
-    /// Store a byte in a PPU register (addresses `$2100` - `$2133`)
-    pub fn store(&mut self, addr: u16, value: u8) {
+    /// Records a value read from a PPU1 register on the PPU1 open bus.
+    fn ppu1_load(&mut self, value: u8) -> u8 {
+        self.ppu1_mdr = value;
+        value
+    }
+
+    /// Records a value read from a PPU2 register on the PPU2 open bus.
+    fn ppu2_load(&mut self, value: u8) -> u8 {
+        self.ppu2_mdr = value;
+        value
+    }
+
+    /// Store a byte in a PPU register (addresses `$2100` - `$2133`).
+    ///
+    /// Returns an error if the write enables a feature that isn't emulated (the register is still
+    /// written, the feature just stays disabled).
+    pub fn store(&mut self, addr: u16, value: u8) -> Result<(), ErrorKind> {
         match addr {
             0x2100 => self.inidisp = value,
             0x2101 => self.obsel = value,
@@ -515,6 +598,7 @@ impl Ppu {
             0x2121 => {
                 self.cgadd = value;
                 self.cg_low_buf = None;
+                self.cg_read_high = false;
             }
             0x2122 => match self.cg_low_buf {
                 None => self.cg_low_buf = Some(value),
@@ -534,23 +618,33 @@ impl Ppu {
             0x2129 => self.wh3 = value,
             0x212a => self.wbglog = value,
             0x212b => {
-                if value & 0xf0 != 0 { once!(warn!("invalid value for $212b: ${:02X}", value)); }
+                if value & 0xf0 != 0 {
+                    diag!(self.diagnostics, "invalid value for $212b: ${:02X}", value);
+                }
                 self.wobjlog = value;
             }
             0x212c => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212c: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    diag!(self.diagnostics, "invalid value for $212c: ${:02X}", value);
+                }
                 self.tm = value;
             }
             0x212d => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212d: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    diag!(self.diagnostics, "invalid value for $212d: ${:02X}", value);
+                }
                 self.ts = value;
             }
             0x212e => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212e: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    diag!(self.diagnostics, "invalid value for $212e: ${:02X}", value);
+                }
                 self.tmw = value;
             }
             0x212f => {
-                if value & 0xe0 != 0 { once!(warn!("invalid value for $212f: ${:02X}", value)); }
+                if value & 0xe0 != 0 {
+                    diag!(self.diagnostics, "invalid value for $212f: ${:02X}", value);
+                }
                 self.tsw = value;
             }
             0x2130 => self.cgwsel = value,
@@ -562,15 +656,21 @@ impl Ppu {
                 if value & 0x20 != 0 { self.coldata_r = color; }
             }
             0x2133 => {
-                assert!(value & 0x80 == 0, "ext. sync not yet implemented");
-                assert!(value & 0x40 == 0, "Mode 7 EXTBG not yet implemented");
-                if value & 0x08 != 0 { once!(warn!("pseudo-hires mode not yet implemented")); }
-                if value & 0x04 != 0 { once!(warn!("overscan not yet implemented")); }
-                if value & 0x03 != 0 { once!(warn!("interlace not yet implemented")); }
+                if value & 0x08 != 0 {
+                    diag!(self.diagnostics, "pseudo-hires mode not yet implemented");
+                }
+                if value & 0x03 != 0 { diag!(self.diagnostics, "interlace not yet implemented"); }
                 self.setini = value;
+                if value & 0x80 != 0 {
+                    return Err(ErrorKind::Unimplemented("external sync"));
+                }
+                if value & 0x40 != 0 {
+                    return Err(ErrorKind::Unimplemented("Mode 7 EXTBG"));
+                }
             }
-            _ => panic!("invalid or unimplemented PPU store: ${:02X} to ${:04X}", value, addr),
+            _ => return Err(ErrorKind::InvalidStore(value)),
         }
+        Ok(())
     }
 
     /// Latches the H/V counters if `$4201` bit 7 is set (otherwise, no latching can occur)
@@ -602,7 +702,7 @@ impl Ppu {
             // End of H-Blank
             self.x = 0;
             self.scanline += 1;
-            if self.scanline == 262 {
+            if self.scanline == self.region.scanlines() {
                 // V-Blank ends now. The next `update` call will render the first visible pixel of
                 // a new frame.
                 self.scanline = 0;
@@ -619,10 +719,28 @@ impl Ppu {
 
     pub fn in_h_blank(&self) -> bool { self.x >= 256 }
     // Scanline 0 is displayed, but not rendered (usually cut off by TVs)
-    pub fn in_v_blank(&self) -> bool { self.scanline == 0 || self.scanline as u32 >= SCREEN_HEIGHT }
+    pub fn in_v_blank(&self) -> bool { self.scanline == 0 || self.scanline >= self.screen_height() }
     pub fn forced_blank(&self) -> bool { self.inidisp & 0x80 != 0 }
     fn brightness(&self) -> u8 { self.inidisp & 0xf }
 
+    pub fn region(&self) -> Region { self.region }
+
+    /// Sets the console region. This changes the number of scanlines per frame and the PAL flag in
+    /// `$213F`.
+    pub fn set_region(&mut self, region: Region) { self.region = region; }
+
+    /// Returns the height of the frame in lines: 224, or 239 if the overscan bit in `$2133` is
+    /// set.
+    pub fn screen_height(&self) -> u16 {
+        if self.setini & 0x04 != 0 { MAX_SCREEN_HEIGHT as u16 } else { SCREEN_HEIGHT as u16 }
+    }
+
+    /// Returns the part of the frame buffer containing the current frame (`screen_height()`
+    /// lines).
+    pub fn frame(&self) -> &[u8] {
+        &self.framebuf[..SCREEN_WIDTH as usize * self.screen_height() as usize * 3]
+    }
+
     /// Returns the current X position
     pub fn h_counter(&self) -> u16 { self.x }
     /// Returns the current Y position (scanline)
@@ -740,14 +858,19 @@ impl Ppu {
         // * 01 = Remap addressing aaaaaaaaBBBccccc => aaaaaaaacccccBBB
         // * 10 = Remap addressing aaaaaaaBBBcccccc => aaaaaaaccccccBBB
         // * 11 = Remap addressing aaaaaaBBBccccccc => aaaaaacccccccBBB
-        let trans = (self.vmain & 0b1100) >> 2;
-        match trans {
-            0b00 => addr,
-            0b01 => panic!("NYI: VRAM address translation"),   // FIXME
-            0b10 => panic!("NYI: VRAM address translation"),
-            0b11 => panic!("NYI: VRAM address translation"),
+        // The remapping is done on the word address
+        let bits = match (self.vmain & 0b1100) >> 2 {
+            0b00 => return addr,
+            0b01 => 8,
+            0b10 => 9,
+            0b11 => 10,
             _ => unreachable!(),
-        }
+        };
+        let word = addr >> 1;
+        let mask = (1 << bits) - 1;
+        let low = word & mask;
+        let word = word & !mask | (low << 3) & mask | low >> (bits - 3);
+        word << 1 | addr & 1
     }
     /// Store to `$2118`. This writes the Byte to the current VRAM word address and increments it
     /// accordingly.
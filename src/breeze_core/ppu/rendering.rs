@@ -296,7 +296,7 @@ impl Ppu {
     /// the current pixel is on the screen.
     pub fn render_pixel(&mut self) -> Rgb {
         assert!(self.x < super::SCREEN_WIDTH as u16);
-        assert!(self.scanline < super::SCREEN_HEIGHT as u16);
+        assert!(self.scanline < super::MAX_SCREEN_HEIGHT as u16);
 
         if self.forced_blank() {
             return Rgb {r: 0, g: 0, b: 0};
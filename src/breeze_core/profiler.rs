@@ -0,0 +1,365 @@
+//! Execution profiler
+//!
+//! The profiler accumulates the master cycles spent by the CPU per instruction address and per
+//! subroutine. Subroutines are entered by `JSR`/`JSL`, `BRK`/`COP` and interrupts. A subroutine is
+//! left when the stack pointer rises above its value right after the call, which happens when the
+//! return address is popped: By `RTS`/`RTL`/`RTI`, but also by code that pulls the return address
+//! and jumps somewhere else. Returns without a matching call are ignored, and the call stack is
+//! limited to `MAX_DEPTH` subroutines, so code that never returns can't grow it without bound.
+//!
+//! Profiling is enabled with `Snes::enable_profiler`. After (or during) a run, the results can be
+//! written as a flat profile, an inclusive call tree, or as "folded stacks" that can be fed into
+//! flamegraph tools like `flamegraph.pl` or `inferno`. Addresses are annotated with the labels in
+//! the passed `Symbols` table.
+
+use wdc65816::symbols::Symbols;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Maximum number of nested subroutines tracked. Deeper calls are counted towards their caller.
+const MAX_DEPTH: usize = 256;
+
+/// How a subroutine was entered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntryKind {
+    /// `JSR` or `JSL`
+    Call,
+    /// Non-maskable interrupt
+    Nmi,
+    /// IRQ
+    Irq,
+    /// `BRK` or `COP` instruction
+    Break,
+}
+
+/// A subroutine, identified by its entry point and the way it was entered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Subroutine {
+    /// 24-bit address of the first instruction
+    pub addr: u32,
+    pub kind: EntryKind,
+}
+
+impl Subroutine {
+    /// Returns a description of this subroutine, including its label if it has one.
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        match symbols.get((self.addr >> 16) as u8, self.addr as u16) {
+            Some(label) => format!("{} <{}>", self, label),
+            None => self.to_string(),
+        }
+    }
+
+    /// Returns the subroutine's label (with a prefix for interrupt handlers), or its address if
+    /// it has no label.
+    fn short_name(&self, symbols: &Symbols) -> String {
+        match symbols.get((self.addr >> 16) as u8, self.addr as u16) {
+            Some(label) => match self.kind {
+                EntryKind::Call => label.to_string(),
+                _ => format!("{}@{}", self.kind_prefix(), label),
+            },
+            None => self.to_string(),
+        }
+    }
+
+    fn kind_prefix(&self) -> &'static str {
+        match self.kind {
+            EntryKind::Call => "",
+            EntryKind::Nmi => "NMI",
+            EntryKind::Irq => "IRQ",
+            EntryKind::Break => "BRK",
+        }
+    }
+}
+
+impl fmt::Display for Subroutine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            EntryKind::Call => {}
+            _ => try!(write!(f, "{}@", self.kind_prefix())),
+        }
+        write!(f, "${:02X}:{:04X}", self.addr >> 16, self.addr as u16)
+    }
+}
+
+/// A node in the call tree
+struct Node {
+    /// The subroutine this node represents (`None` for the root node, which contains all code
+    /// executed outside of any known subroutine)
+    sub: Option<Subroutine>,
+    parent: usize,
+    children: HashMap<Subroutine, usize>,
+    /// Master cycles spent in this node, excluding children
+    self_cy: u64,
+}
+
+/// Collects execution statistics. See the module documentation for details.
+pub struct Profiler {
+    /// Master cycles spent per 24-bit PC
+    per_pc: HashMap<u32, u64>,
+    /// Call tree. `nodes[0]` is the root.
+    nodes: Vec<Node>,
+    /// Index of the node of the currently executing subroutine
+    current: usize,
+    /// The stack pointer right after each active subroutine was entered (innermost last)
+    entry_sp: Vec<u16>,
+    total_cy: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            per_pc: HashMap::new(),
+            nodes: vec![Node {
+                sub: None,
+                parent: 0,
+                children: HashMap::new(),
+                self_cy: 0,
+            }],
+            current: 0,
+            entry_sp: Vec::new(),
+            total_cy: 0,
+        }
+    }
+
+    /// Records the execution of an instruction.
+    ///
+    /// `pc` is the 24-bit address the instruction was fetched from, `opcode` its opcode (if it
+    /// could be read without side effects), and `new_pc` and `sp` the 24-bit program counter and
+    /// the stack pointer after the instruction was executed.
+    pub fn record(&mut self, pc: u32, opcode: Option<u8>, master_cy: u64, new_pc: u32, sp: u16) {
+        *self.per_pc.entry(pc).or_insert(0) += master_cy;
+        self.nodes[self.current].self_cy += master_cy;
+        self.total_cy += master_cy;
+
+        if new_pc == pc {
+            // The instruction wasn't executed (eg. the CPU is waiting for an interrupt)
+            return;
+        }
+
+        self.leave(sp);
+        match opcode {
+            // JSR abs, JSL long, JSR (abs,X)
+            Some(0x20) | Some(0x22) | Some(0xfc) => self.enter(new_pc, EntryKind::Call, sp),
+            // BRK, COP
+            Some(0x00) | Some(0x02) => self.enter(new_pc, EntryKind::Break, sp),
+            _ => {}
+        }
+    }
+
+    /// Records that an interrupt was handled and execution continues at `new_pc`, with the stack
+    /// pointer at `sp`.
+    pub fn interrupt(&mut self, new_pc: u32, kind: EntryKind, sp: u16) {
+        self.enter(new_pc, kind, sp);
+    }
+
+    fn enter(&mut self, addr: u32, kind: EntryKind, sp: u16) {
+        if self.entry_sp.len() == MAX_DEPTH { return; }
+        self.entry_sp.push(sp);
+
+        let sub = Subroutine { addr: addr, kind: kind };
+        let current = self.current;
+        let next_index = self.nodes.len();
+        let index = *self.nodes[current].children.entry(sub).or_insert(next_index);
+        if index == next_index {
+            self.nodes.push(Node {
+                sub: Some(sub),
+                parent: current,
+                children: HashMap::new(),
+                self_cy: 0,
+            });
+        }
+        self.current = index;
+    }
+
+    /// Leaves all subroutines whose return address was popped off the stack.
+    fn leave(&mut self, sp: u16) {
+        while self.entry_sp.last().map_or(false, |&entry_sp| sp > entry_sp) {
+            self.entry_sp.pop();
+            self.current = self.nodes[self.current].parent;
+        }
+    }
+
+    /// Returns the total number of master cycles recorded.
+    pub fn total_cycles(&self) -> u64 { self.total_cy }
+
+    /// Computes the inclusive cycle count of every node.
+    fn inclusive_cycles(&self) -> Vec<u64> {
+        // Children always have a higher index than their parent, so we can sum up the counts by
+        // walking the nodes in reverse.
+        let mut incl: Vec<u64> = self.nodes.iter().map(|node| node.self_cy).collect();
+        for i in (1..self.nodes.len()).rev() {
+            let parent = self.nodes[i].parent;
+            incl[parent] += incl[i];
+        }
+        incl
+    }
+
+    fn percent(&self, cy: u64) -> f64 {
+        if self.total_cy == 0 { 0.0 } else { cy as f64 * 100.0 / self.total_cy as f64 }
+    }
+
+    /// Writes a flat profile: The `limit` most expensive instruction addresses, followed by the
+    /// `limit` most expensive subroutines (by self and inclusive cycles).
+    ///
+    /// The inclusive time of recursive subroutines is counted once per active invocation.
+    pub fn write_flat(&self, w: &mut Write, limit: usize, symbols: &Symbols) -> io::Result<()> {
+        let mut pcs: Vec<_> = self.per_pc.iter().map(|(&pc, &cy)| (pc, cy)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        try!(writeln!(w, "Flat profile ({} master cycles total)", self.total_cy));
+        try!(writeln!(w, "{:>14} {:>7}  address", "cycles", "%"));
+        for &(pc, cy) in pcs.iter().take(limit) {
+            try!(writeln!(w, "{:>14} {:>6.2}%  {}", cy, self.percent(cy),
+                symbols.describe((pc >> 16) as u8, pc as u16)));
+        }
+
+        // Sum up all nodes belonging to the same subroutine
+        let incl = self.inclusive_cycles();
+        let mut subs: HashMap<Subroutine, (u64, u64)> = HashMap::new();
+        for (node, &incl_cy) in self.nodes.iter().zip(incl.iter()) {
+            if let Some(sub) = node.sub {
+                let entry = subs.entry(sub).or_insert((0, 0));
+                entry.0 += node.self_cy;
+                entry.1 += incl_cy;
+            }
+        }
+        let mut subs: Vec<_> = subs.into_iter().collect();
+        subs.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then((a.0).addr.cmp(&(b.0).addr)));
+
+        try!(writeln!(w, ""));
+        try!(writeln!(w, "Subroutines"));
+        try!(writeln!(w, "{:>14} {:>7} {:>14} {:>7}  subroutine", "self", "%", "inclusive", "%"));
+        for &(sub, (self_cy, incl_cy)) in subs.iter().take(limit) {
+            try!(writeln!(w, "{:>14} {:>6.2}% {:>14} {:>6.2}%  {}", self_cy, self.percent(self_cy),
+                incl_cy, self.percent(incl_cy), sub.describe(symbols)));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the call tree, annotated with inclusive and self cycle counts. Children are sorted
+    /// by inclusive cycles. Subtrees taking less than `min_percent` percent of the total time are
+    /// omitted.
+    pub fn write_call_tree(&self, w: &mut Write, min_percent: f64, symbols: &Symbols)
+    -> io::Result<()> {
+        let incl = self.inclusive_cycles();
+
+        try!(writeln!(w, "Call tree ({} master cycles total)", self.total_cy));
+        try!(writeln!(w, "{:>7} {:>7}  subroutine", "incl %", "self %"));
+
+        // Depth-first traversal with an explicit stack (the tree can get deep)
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index];
+            if self.percent(incl[index]) < min_percent { continue; }
+
+            let name = match node.sub {
+                Some(sub) => sub.describe(symbols),
+                None => "(root)".to_string(),
+            };
+            try!(writeln!(w, "{:>6.2}% {:>6.2}%  {:indent$}{}", self.percent(incl[index]),
+                self.percent(node.self_cy), "", name, indent = depth * 2));
+
+            let mut children: Vec<usize> = node.children.values().cloned().collect();
+            // Push the most expensive child last, so it's printed first
+            children.sort_by(|&a, &b| incl[a].cmp(&incl[b]));
+            stack.extend(children.into_iter().map(|child| (child, depth + 1)));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the call tree in the "folded stacks" format understood by flamegraph tools. Each
+    /// line contains a `;`-separated call stack, followed by the number of master cycles spent in
+    /// the last subroutine of the stack.
+    pub fn write_folded(&self, w: &mut Write, symbols: &Symbols) -> io::Result<()> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.self_cy == 0 { continue; }
+
+            let mut names = Vec::new();
+            let mut i = index;
+            while i != 0 {
+                names.push(self.nodes[i].sub.unwrap().short_name(symbols));
+                i = self.nodes[i].parent;
+            }
+            names.push("(root)".to_string());
+            names.reverse();
+
+            try!(writeln!(w, "{} {}", names.join(";"), node.self_cy));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryKind, Profiler, MAX_DEPTH};
+
+    const JSR: Option<u8> = Some(0x20);
+    const RTS: Option<u8> = Some(0x60);
+    const PLA: Option<u8> = Some(0x68);
+    const NOP: Option<u8> = Some(0xea);
+
+    #[test]
+    fn call_and_return() {
+        let mut p = Profiler::new();
+        p.record(0x8000, JSR, 6, 0x9000, 0x1fd);
+        assert_eq!(p.entry_sp, [0x1fd]);
+        p.record(0x9000, NOP, 2, 0x9001, 0x1fd);
+        p.record(0x9001, RTS, 6, 0x8003, 0x1ff);
+        assert_eq!(p.current, 0);
+        assert!(p.entry_sp.is_empty());
+
+        // A second call reuses the node
+        p.record(0x8003, JSR, 6, 0x9000, 0x1fd);
+        p.record(0x9000, RTS, 6, 0x8006, 0x1ff);
+        assert_eq!(p.nodes.len(), 2);
+        assert_eq!(p.nodes[0].self_cy, 12);
+        assert_eq!(p.nodes[1].self_cy, 14);
+
+        // Returns without a call are ignored
+        p.record(0x8006, RTS, 6, 0x1234, 0x201);
+        assert_eq!(p.current, 0);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut p = Profiler::new();
+        p.record(0x8000, JSR, 6, 0x9000, 0x1fd);
+        p.interrupt(0xa000, EntryKind::Nmi, 0x1f9);
+        assert_eq!(p.entry_sp, [0x1fd, 0x1f9]);
+        // RTI
+        p.record(0xa000, Some(0x40), 6, 0x9000, 0x1fd);
+        assert_eq!(p.entry_sp, [0x1fd]);
+        assert_eq!(p.nodes[p.current].sub.unwrap().addr, 0x9000);
+    }
+
+    #[test]
+    fn call_without_return() {
+        // The subroutine pulls its return address and jumps back to the loop
+        let mut p = Profiler::new();
+        for _ in 0..1000 {
+            p.record(0x8000, JSR, 6, 0x9000, 0x1fd);
+            p.record(0x9000, PLA, 4, 0x9001, 0x1fe);
+            p.record(0x9001, PLA, 4, 0x9002, 0x1ff);
+            // JMP $8000
+            p.record(0x9002, Some(0x4c), 3, 0x8000, 0x1ff);
+        }
+        assert_eq!(p.current, 0);
+        assert_eq!(p.nodes.len(), 2);
+    }
+
+    #[test]
+    fn depth_limit() {
+        // Calls that never pop their return address (eg. after the stack pointer was reset)
+        let mut p = Profiler::new();
+        for i in 0..1000 {
+            p.record(0x8000 + i, JSR, 6, 0x8001 + i, 0x1fd);
+        }
+        assert_eq!(p.entry_sp.len(), MAX_DEPTH);
+        assert_eq!(p.nodes.len(), MAX_DEPTH + 1);
+    }
+}
@@ -0,0 +1,404 @@
+//! RAM search
+//!
+//! Finds out where a game keeps a value (like the number of lives) by watching how the memory
+//! changes: A search starts with every address of a memory as a candidate and takes a snapshot of
+//! its contents. Each `RamSearch::filter` compares the current contents with the last snapshot,
+//! removes the candidates that don't pass the filter, and takes a new snapshot. After a few rounds
+//! of playing and filtering ("lives decreased", "lives unchanged", ...), few candidates remain.
+//!
+//! Values can be viewed as 8, 16 or 24-bit little-endian integers, signed or unsigned. A found
+//! value can be frozen (see `Snes::freeze`), which writes it to memory every frame. Freezes of
+//! WRAM values can be turned into cheats to keep them (see `Freeze::to_cheat`).
+
+use cheat::Cheat;
+use snes::Snes;
+
+/// A RAM that can be searched
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Memory {
+    /// The 128 KB of work RAM
+    Wram,
+    /// RAM on the cartridge (battery-backed or not)
+    CartridgeRam,
+    /// The 64 KB of APU RAM
+    Aram,
+}
+
+impl Memory {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Memory::Wram => "wram",
+            Memory::CartridgeRam => "cart",
+            Memory::Aram => "aram",
+        }
+    }
+
+    /// Looks up a memory by name (`wram`, `cart` or `aram`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wram" => Some(Memory::Wram),
+            "cart" => Some(Memory::CartridgeRam),
+            "aram" => Some(Memory::Aram),
+            _ => None,
+        }
+    }
+
+    /// Formats `offset` as an address: A 24-bit CPU address for WRAM, an offset for the
+    /// cartridge RAM (its mapping depends on the cartridge) and a 16-bit address for ARAM.
+    pub fn format_addr(&self, offset: usize) -> String {
+        match *self {
+            Memory::Wram => format!("${:06X}", 0x7e0000 + offset),
+            Memory::CartridgeRam => format!("+${:05X}", offset),
+            Memory::Aram => format!("${:04X}", offset),
+        }
+    }
+}
+
+/// Size of the searched values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    Word,
+    Long,
+}
+
+impl Size {
+    /// Returns the size for the given number of bits (8, 16 or 24).
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            8 => Some(Size::Byte),
+            16 => Some(Size::Word),
+            24 => Some(Size::Long),
+            _ => None,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match *self {
+            Size::Byte => 1,
+            Size::Word => 2,
+            Size::Long => 3,
+        }
+    }
+}
+
+/// How the current value of a candidate must relate to its value in the last snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    /// The current value must be the given one (the snapshot value doesn't matter)
+    Value(i64),
+}
+
+impl Filter {
+    /// Parses a filter: `equal`, `changed`, `increased`, `decreased`, or a value (decimal, or hex
+    /// when prefixed with `$`).
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "equal" => Some(Filter::Equal),
+            "changed" => Some(Filter::Changed),
+            "increased" => Some(Filter::Increased),
+            "decreased" => Some(Filter::Decreased),
+            _ => parse_value(s).map(Filter::Value),
+        }
+    }
+
+    fn matches(&self, previous: i64, current: i64) -> bool {
+        match *self {
+            Filter::Equal => current == previous,
+            Filter::Changed => current != previous,
+            Filter::Increased => current > previous,
+            Filter::Decreased => current < previous,
+            Filter::Value(value) => current == value,
+        }
+    }
+}
+
+/// Parses a decimal value (may be negative) or a hex value prefixed with `$`.
+pub fn parse_value(s: &str) -> Option<i64> {
+    if s.starts_with('$') {
+        i64::from_str_radix(&s[1..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// A search for a value in one of the RAMs
+#[derive(Clone, Debug)]
+pub struct RamSearch {
+    memory: Memory,
+    size: Size,
+    signed: bool,
+    /// Memory contents when the last filter was applied
+    snapshot: Vec<u8>,
+    /// Offsets of the remaining candidates
+    candidates: Vec<usize>,
+}
+
+impl RamSearch {
+    /// Starts a search in `memory`, with all values of the given size as candidates.
+    pub fn new(snes: &Snes, memory: Memory, size: Size, signed: bool) -> Self {
+        let mut search = RamSearch {
+            memory: memory,
+            size: size,
+            signed: signed,
+            snapshot: Vec::new(),
+            candidates: Vec::new(),
+        };
+        search.reset(snes);
+        search
+    }
+
+    /// Starts over: All values become candidates again and a new snapshot is taken.
+    pub fn reset(&mut self, snes: &Snes) {
+        self.snapshot = snes.memory(self.memory).to_vec();
+        let count = (self.snapshot.len() + 1).saturating_sub(self.size.bytes());
+        self.candidates = (0..count).collect();
+    }
+
+    pub fn memory(&self) -> Memory { self.memory }
+
+    pub fn size(&self) -> Size { self.size }
+
+    pub fn signed(&self) -> bool { self.signed }
+
+    /// Returns the offsets of the remaining candidates in the memory.
+    pub fn candidates(&self) -> &[usize] { &self.candidates }
+
+    /// Removes the candidates whose current value doesn't pass `filter` and takes a new snapshot.
+    pub fn filter(&mut self, snes: &Snes, filter: Filter) {
+        let current = snes.memory(self.memory);
+        {
+            let (snapshot, size, signed) = (&self.snapshot, self.size, self.signed);
+            self.candidates.retain(|&offset| {
+                filter.matches(decode(snapshot, offset, size, signed),
+                    decode(current, offset, size, signed))
+            });
+        }
+        self.snapshot.clear();
+        self.snapshot.extend_from_slice(current);
+    }
+
+    /// Returns the value at `offset` in the last snapshot.
+    pub fn previous(&self, offset: usize) -> i64 {
+        decode(&self.snapshot, offset, self.size, self.signed)
+    }
+
+    /// Returns the current value at `offset`.
+    pub fn current(&self, snes: &Snes, offset: usize) -> i64 {
+        decode(snes.memory(self.memory), offset, self.size, self.signed)
+    }
+
+    /// Creates a freeze that keeps the value at `offset` at `value` (or at its current value).
+    pub fn freeze(&self, snes: &Snes, offset: usize, value: Option<i64>) -> Freeze {
+        let value = value.unwrap_or_else(|| self.current(snes, offset));
+        Freeze {
+            memory: self.memory,
+            offset: offset,
+            bytes: (0..self.size.bytes()).map(|i| (value >> (i * 8)) as u8).collect(),
+        }
+    }
+}
+
+/// Reads a little-endian value of the given size from `data`.
+fn decode(data: &[u8], offset: usize, size: Size, signed: bool) -> i64 {
+    let bytes = &data[offset..offset + size.bytes()];
+    let value = bytes.iter().rev().fold(0, |acc, &byte| acc << 8 | byte as i64);
+    let bits = size.bytes() * 8;
+    if signed && value & 1 << (bits - 1) != 0 {
+        value - (1 << bits)
+    } else {
+        value
+    }
+}
+
+/// Bytes that are written to a RAM every frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Freeze {
+    pub memory: Memory,
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl Freeze {
+    /// Writes the bytes to `memory` (the contents of `self.memory`). Bytes beyond its end are
+    /// ignored.
+    pub fn apply(&self, memory: &mut [u8]) {
+        for (byte, value) in memory.iter_mut().skip(self.offset).zip(&self.bytes) {
+            *byte = *value;
+        }
+    }
+
+    /// Returns a cheat with the same effect, if the freeze is in WRAM.
+    pub fn to_cheat(&self) -> Option<Cheat> {
+        if self.memory != Memory::Wram {
+            return None;
+        }
+        let codes: Vec<_> = self.bytes.iter().enumerate()
+            .map(|(i, value)| format!("{:06x}={:02x}", 0x7e0000 + self.offset + i, value))
+            .collect();
+        let code = codes.join("+");
+        Some(Cheat::new(&format!("Freeze {}", self.memory.format_addr(self.offset)), &code)
+            .expect("generated an invalid cheat code"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cheat::Code;
+    use rom::Rom;
+
+    /// Creates a console with a 32 KB LoROM cartridge with 2 KB of RAM.
+    fn snes() -> Snes {
+        let mut bytes = vec![0; 0x8000];
+        {
+            let header = &mut bytes[0x7fc0..0x7fe0];
+            header[..21].copy_from_slice(b"RAM SEARCH TEST      ");
+            header[21] = 0x20;
+            header[22] = 0x02;
+            header[23] = 0x05;
+            header[24] = 0x01;
+            header[28] = 0xff;
+            header[29] = 0xff;
+        }
+        Snes::new(Rom::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn decode_sizes() {
+        let data = [0x80, 0xff, 0x7f, 0x01];
+        assert_eq!(decode(&data, 0, Size::Byte, false), 0x80);
+        assert_eq!(decode(&data, 0, Size::Byte, true), -0x80);
+        assert_eq!(decode(&data, 1, Size::Byte, false), 0xff);
+        assert_eq!(decode(&data, 1, Size::Byte, true), -1);
+        assert_eq!(decode(&data, 2, Size::Byte, true), 0x7f);
+
+        assert_eq!(decode(&data, 0, Size::Word, false), 0xff80);
+        assert_eq!(decode(&data, 0, Size::Word, true), -0x80);
+        assert_eq!(decode(&data, 1, Size::Word, true), 0x7fff);
+        assert_eq!(decode(&data, 2, Size::Word, true), 0x017f);
+
+        assert_eq!(decode(&data, 0, Size::Long, false), 0x7fff80);
+        assert_eq!(decode(&data, 0, Size::Long, true), 0x7fff80);
+        assert_eq!(decode(&data, 1, Size::Long, false), 0x017fff);
+        assert_eq!(decode(&[0, 0, 0x80], 0, Size::Long, false), 0x800000);
+        assert_eq!(decode(&[0, 0, 0x80], 0, Size::Long, true), -0x800000);
+        assert_eq!(decode(&[0xff; 3], 0, Size::Long, true), -1);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Size::from_bits(8), Some(Size::Byte));
+        assert_eq!(Size::from_bits(16), Some(Size::Word));
+        assert_eq!(Size::from_bits(24), Some(Size::Long));
+        assert_eq!(Size::from_bits(32), None);
+
+        assert_eq!(Filter::parse("equal"), Some(Filter::Equal));
+        assert_eq!(Filter::parse("changed"), Some(Filter::Changed));
+        assert_eq!(Filter::parse("increased"), Some(Filter::Increased));
+        assert_eq!(Filter::parse("decreased"), Some(Filter::Decreased));
+        assert_eq!(Filter::parse("-3"), Some(Filter::Value(-3)));
+        assert_eq!(Filter::parse("$1f"), Some(Filter::Value(0x1f)));
+        assert_eq!(Filter::parse("same"), None);
+        assert_eq!(parse_value("$"), None);
+
+        for &memory in &[Memory::Wram, Memory::CartridgeRam, Memory::Aram] {
+            assert_eq!(Memory::from_name(memory.name()), Some(memory));
+        }
+        assert_eq!(Memory::Wram.format_addr(0x1234), "$7E1234");
+        assert_eq!(Memory::CartridgeRam.format_addr(0x123), "+$00123");
+        assert_eq!(Memory::Aram.format_addr(0x1ff), "$01FF");
+    }
+
+    #[test]
+    fn candidates_at_end() {
+        let snes = snes();
+        // Values must fit into the memory
+        for &(memory, len) in &[(Memory::Wram, 0x20000), (Memory::CartridgeRam, 0x800),
+                (Memory::Aram, 0x10000)] {
+            assert_eq!(snes.memory(memory).len(), len);
+            for &size in &[Size::Byte, Size::Word, Size::Long] {
+                let search = RamSearch::new(&snes, memory, size, false);
+                let count = len + 1 - size.bytes();
+                assert_eq!(search.candidates().len(), count);
+                assert_eq!(search.candidates().last(), Some(&(count - 1)));
+                search.current(&snes, count - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let mut snes = snes();
+        snes.memory_mut(Memory::Wram)[..4].copy_from_slice(&[1, 1, 1, 7]);
+        let unsigned = RamSearch::new(&snes, Memory::Wram, Size::Byte, false);
+        let signed = RamSearch::new(&snes, Memory::Wram, Size::Byte, true);
+        snes.memory_mut(Memory::Wram)[..4].copy_from_slice(&[1, 2, 0xff, 7]);
+
+        let filter = |search: &RamSearch, filter| {
+            let mut search = search.clone();
+            search.filter(&snes, filter);
+            search
+        };
+        let first = |search: RamSearch| {
+            search.candidates().iter().take(4).cloned().collect::<Vec<_>>()
+        };
+
+        let equal = filter(&unsigned, Filter::Equal);
+        assert_eq!(equal.candidates().len(), 0x20000 - 2);
+        assert_eq!(first(equal), [0, 3, 4, 5]);
+        assert_eq!(first(filter(&unsigned, Filter::Changed)), [1, 2]);
+        assert_eq!(first(filter(&unsigned, Filter::Increased)), [1, 2]);
+        assert_eq!(first(filter(&unsigned, Filter::Decreased)), []);
+        assert_eq!(first(filter(&signed, Filter::Increased)), [1]);
+        assert_eq!(first(filter(&signed, Filter::Decreased)), [2]);
+        assert_eq!(first(filter(&unsigned, Filter::Value(7))), [3]);
+        assert_eq!(first(filter(&unsigned, Filter::Value(0xff))), [2]);
+        assert_eq!(first(filter(&signed, Filter::Value(-1))), [2]);
+
+        // Filtering takes a new snapshot
+        let mut search = filter(&unsigned, Filter::Changed);
+        assert_eq!(search.previous(2), 0xff);
+        snes.memory_mut(Memory::Wram)[1] = 3;
+        assert_eq!(search.current(&snes, 1), 3);
+        search.filter(&snes, Filter::Equal);
+        assert_eq!(search.candidates(), [2]);
+
+        search.reset(&snes);
+        assert_eq!(search.candidates().len(), 0x20000);
+        assert_eq!(search.previous(1), 3);
+    }
+
+    #[test]
+    fn freeze() {
+        let mut snes = snes();
+        snes.memory_mut(Memory::Wram)[0x1234..0x1236].copy_from_slice(&[0x34, 0x12]);
+        let search = RamSearch::new(&snes, Memory::Wram, Size::Word, false);
+        let freeze = search.freeze(&snes, 0x1234, None);
+        assert_eq!(freeze.memory, Memory::Wram);
+        assert_eq!(freeze.offset, 0x1234);
+        assert_eq!(freeze.bytes, [0x34, 0x12]);
+        let freeze = search.freeze(&snes, 0x1234, Some(-2));
+        assert_eq!(freeze.bytes, [0xfe, 0xff]);
+
+        let mut memory = [0; 0x1235];
+        freeze.apply(&mut memory);
+        assert_eq!(memory[0x1234], 0xfe);
+
+        let cheat = freeze.to_cheat().unwrap();
+        assert_eq!(cheat.description, "Freeze $7E1234");
+        assert_eq!(cheat.codes(), [
+            Code { addr: 0x7e1234, value: 0xfe, compare: None },
+            Code { addr: 0x7e1235, value: 0xff, compare: None },
+        ]);
+
+        let search = RamSearch::new(&snes, Memory::Aram, Size::Byte, false);
+        assert!(search.freeze(&snes, 0x100, Some(1)).to_cheat().is_none());
+        let search = RamSearch::new(&snes, Memory::CartridgeRam, Size::Byte, false);
+        assert!(search.freeze(&snes, 0x100, Some(1)).to_cheat().is_none());
+    }
+}
@@ -0,0 +1,125 @@
+//! Console regions
+//!
+//! NTSC and PAL consoles run at slightly different master clock frequencies, and PAL consoles run
+//! 312 scanlines per frame instead of 262, which results in a refresh rate of 50 Hz instead of
+//! 60 Hz. Games can tell them apart by reading `$213F`, and many refuse to run on the wrong one.
+
+use rom_info::RomInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Default for Region {
+    fn default() -> Self { Region::Ntsc }
+}
+
+impl Region {
+    /// Looks up a region by name (`ntsc` or `pal`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            _ => None,
+        }
+    }
+
+    /// Returns the region of the console the ROM was made for, based on the destination code in
+    /// its header.
+    pub fn for_rom(info: &RomInfo) -> Self {
+        if info.is_pal() { Region::Pal } else { Region::Ntsc }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+        }
+    }
+
+    /// Master clock cycles per second
+    pub fn master_clock_freq(&self) -> u32 {
+        match *self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal => 21_281_370,
+        }
+    }
+
+    /// Number of scanlines per (non-interlaced) frame
+    pub fn scanlines(&self) -> u16 {
+        match *self {
+            Region::Ntsc => 262,
+            Region::Pal => 312,
+        }
+    }
+
+    /// Returns the number of frames per second (a scanline takes 1364 master cycles).
+    pub fn refresh_rate(&self) -> f32 {
+        self.master_clock_freq() as f32 / (self.scanlines() as f32 * 1364.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ppu::Ppu;
+    use rom::Rom;
+    use snes::Snes;
+
+    /// Creates a 32 KB LoROM image with the given destination code.
+    fn rom(destination: u8) -> Rom {
+        let mut bytes = vec![0; 0x8000];
+        {
+            let header = &mut bytes[0x7fc0..0x7fe0];
+            header[..21].copy_from_slice(b"REGION TEST          ");
+            header[21] = 0x20;
+            header[23] = 0x05;
+            header[25] = destination;
+            header[28] = 0xff;
+            header[29] = 0xff;
+        }
+        Rom::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn detect() {
+        // Japan, USA, Europe, Korea, Australia
+        for &(destination, region) in &[(0x00, Region::Ntsc), (0x01, Region::Ntsc),
+                                        (0x02, Region::Pal), (0x0d, Region::Ntsc),
+                                        (0x11, Region::Pal)] {
+            let rom = rom(destination);
+            assert_eq!(Region::for_rom(rom.info()), region);
+            assert_eq!(Snes::new(rom).region(), region);
+        }
+    }
+
+    #[test]
+    fn timing() {
+        assert_eq!(Region::from_name("ntsc"), Some(Region::Ntsc));
+        assert_eq!(Region::from_name("pal"), Some(Region::Pal));
+        assert_eq!(Region::from_name("PAL"), None);
+
+        assert_eq!(Region::Ntsc.master_clock_freq(), 21_477_272);
+        assert_eq!(Region::Pal.master_clock_freq(), 21_281_370);
+        assert!((Region::Ntsc.refresh_rate() - 60.10).abs() < 0.01);
+        assert!((Region::Pal.refresh_rate() - 50.01).abs() < 0.01);
+    }
+
+    #[test]
+    fn ppu_scanlines() {
+        for &region in &[Region::Ntsc, Region::Pal] {
+            let mut ppu = Ppu::default();
+            ppu.set_region(region);
+            for _ in 0..340 * (region.scanlines() as u32 - 1) {
+                ppu.update();
+            }
+            assert_eq!(ppu.v_counter(), region.scanlines() - 1);
+            for _ in 0..340 {
+                ppu.update();
+            }
+            assert_eq!(ppu.v_counter(), 0);
+        }
+    }
+}
@@ -1,13 +1,18 @@
 //! ROM image loading code
 
+use cheat::Code;
+use coprocessor::Coprocessor;
+use coprocessor::necdsp::{NecDsp, Port as DspPort};
+use coprocessor::rtc::DateTime;
+use diagnostics::Diagnostics;
+use gamedb::{DumpStatus, GameDatabase};
+use rom_info::{self, RomInfo};
+
 use std::cmp;
 use std::str;
 use std::i16;
 use std::io;
-
-fn invalid_data(err: String) -> io::Error {
-    io::Error::new(io::ErrorKind::InvalidData, err)
-}
+use std::path::Path;
 
 /// The (decoded) SNES header
 #[derive(Clone)]
@@ -16,14 +21,113 @@ pub struct RomHeader {
     title: [u8; 21],
     rom_size: u32,
     ram_size: u32,
+    /// Size of the battery-backed RAM stored in `.srm` files. 0 if the header doesn't specify any
+    /// RAM (`ram_size` is never 0).
+    sram_size: u32,
     checksum: u16,
+    checksum_complement: u16,
     rom_type: RomType,
+    /// Map mode byte (`$FFD5`)
+    map_mode: u8,
+    fast_rom: bool,
+    /// Chipset byte (`$FFD6`), indicates the coprocessor used by the cartridge (if any)
+    chipset: u8,
+    /// Destination code (`$FFD9`), see `RomInfo::region`
+    destination: u8,
+    /// Maker code (`$FFDA`)
+    maker: u8,
+    version: u8,
+    /// The extended header at `$FFB0-$FFBF` (only valid if `maker` is `$33`, except for the
+    /// chipset subtype at `$FFBF`)
+    extended: [u8; 16],
+    /// Chipset subtype (`$FFBF`, part of the extended header)
+    chip_subtype: u8,
 }
 
+/// The memory mapping of a ROM
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum RomType {
+pub enum RomType {
     LoRom,
     HiRom,
+    /// LoROM with more than 4 MB. The first 4 MB are mapped to banks `$80-$FF`, the rest to banks
+    /// `$00-$7D`.
+    ExLoRom,
+    /// HiROM with more than 4 MB. The first 4 MB are mapped to banks `$C0-$FF`, the rest to banks
+    /// `$40-$7D`.
+    ExHiRom,
+}
+
+impl RomType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RomType::LoRom => "LoROM",
+            RomType::HiRom => "HiROM",
+            RomType::ExLoRom => "ExLoROM",
+            RomType::ExHiRom => "ExHiROM",
+        }
+    }
+
+    /// Offset of the header in the ROM image, if the ROM uses this mapping.
+    fn header_offset(&self) -> usize {
+        match *self {
+            RomType::LoRom => 0x7fc0,
+            RomType::HiRom => 0xffc0,
+            RomType::ExLoRom => 0x407fc0,
+            RomType::ExHiRom => 0x40ffc0,
+        }
+    }
+}
+
+/// The kind of a 512 byte header added by a copier device
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CopierHeader {
+    /// Super Wild Card (also used by Super Magicom `.smc` files)
+    Swc,
+    /// Pro Fighter
+    Fig,
+    /// Game Doctor SF 3
+    Gd3,
+    /// Unknown header (probably all zeros)
+    Unknown,
+}
+
+impl CopierHeader {
+    /// Identifies a copier header by the magic values it contains.
+    fn detect(header: &[u8]) -> Self {
+        match (&header[..16], header[4], header[5], header[8], header[9], header[10]) {
+            (b"GAME DOCTOR SF 3", _, _, _, _, _) => CopierHeader::Gd3,
+            (_, _, _, 0xaa, 0xbb, 0x04) => CopierHeader::Swc,
+            // Bytes 4 and 5 are the emulation mode (a little-endian word): `$8000`/`$8377`
+            // without DSP (LoROM/HiROM), `$8347`/`$0211`/`$82DD`/`$02DD`/`$83F7`/`$82FD` with DSP
+            (_, 0x00, 0x80, _, _, _) | (_, 0x77, 0x83, _, _, _) | (_, 0x47, 0x83, _, _, _) |
+            (_, 0x11, 0x02, _, _, _) | (_, 0xdd, 0x82, _, _, _) | (_, 0xdd, 0x02, _, _, _) |
+            (_, 0xf7, 0x83, _, _, _) | (_, 0xfd, 0x82, _, _, _) => CopierHeader::Fig,
+            _ => CopierHeader::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CopierHeader::Swc => "SWC",
+            CopierHeader::Fig => "FIG",
+            CopierHeader::Gd3 => "GD3",
+            CopierHeader::Unknown => "unknown",
+        }
+    }
+}
+
+/// De-interleaves a HiROM image stored by a copier. Interleaved images contain the upper 32 KB
+/// of all 64 KB banks, followed by the lower 32 KB.
+fn deinterleave(bytes: &[u8]) -> Vec<u8> {
+    let banks = bytes.len() / 0x10000;
+    let half = bytes.len() / 2;
+    let mut rom = Vec::with_capacity(bytes.len());
+    for bank in 0..banks {
+        let offset = bank * 0x8000;
+        rom.extend_from_slice(&bytes[half + offset..half + offset + 0x8000]);
+        rom.extend_from_slice(&bytes[offset..offset + 0x8000]);
+    }
+    rom
 }
 
 impl RomHeader {
@@ -32,6 +136,76 @@ impl RomHeader {
         info!("{} KB ROM / {} KB Cartridge RAM", self.rom_size / 1024, self.ram_size / 1024);
     }
 
+    /// Creates a `RomInfo` from the header. `rom` is the ROM image the hashes are computed from.
+    ///
+    /// The fields that don't come from the header are filled in by `Rom::from_bytes`.
+    fn info(&self, coprocessor: &Coprocessor, rom: &[u8]) -> RomInfo {
+        // The maker and game codes are only present in the extended header
+        let (maker_code, game_code) = if self.maker == 0x33 {
+            (Some(String::from_utf8_lossy(&self.extended[0..2]).into_owned()),
+             Some(String::from_utf8_lossy(&self.extended[2..6]).trim_right().to_owned()))
+        } else {
+            (None, None)
+        };
+
+        RomInfo {
+            title: str::from_utf8(&self.title).unwrap_or("").trim_right().to_owned(),
+            rom_type: self.rom_type,
+            map_mode: self.map_mode,
+            fast_rom: self.fast_rom,
+            chipset: self.chipset,
+            chip_subtype: self.chip_subtype,
+            coprocessor: coprocessor.name(),
+            battery: match self.chipset & 0x0f {
+                2 | 5 | 6 | 9 | 0xa => true,
+                _ => false,
+            },
+            rom_size: self.rom_size,
+            sram_size: self.sram_size,
+            destination: self.destination,
+            maker: self.maker,
+            maker_code: maker_code,
+            game_code: game_code,
+            version: self.version,
+            checksum: self.checksum,
+            checksum_complement: self.checksum_complement,
+            computed_checksum: 0,
+            copier_header: None,
+            file_size: rom.len(),
+            scores: Vec::new(),
+            warnings: Vec::new(),
+            dump_status: DumpStatus::Unknown,
+            database_entry: None,
+            crc32: rom_info::crc32(rom),
+            sha1: rom_info::sha1(rom),
+        }
+    }
+
+    /// Tries all header locations and returns the header that's probably right, along with the
+    /// scores of all locations.
+    fn find(bytes: &[u8]) -> (RomHeader, Vec<(RomType, i16)>) {
+        // Oh how much I wish there was a real standard for this.
+        // FIXME: We might want to... like... not play *literally every file* but warn instead :)
+        // The extended mappings come first: ROMs larger than 4 MB might also contain a valid
+        // header at the LoROM/HiROM location, but the CPU will read its vectors from the extended
+        // one. On a tie, the earlier header wins.
+        let mut header: Option<(RomHeader, i16)> = None;
+        let mut scores = Vec::new();
+        for &rom_type in &[RomType::ExHiRom, RomType::ExLoRom, RomType::HiRom, RomType::LoRom] {
+            let (candidate, score) = RomHeader::load(bytes, rom_type);
+            info!("{:?} score: {}", rom_type, score);
+            scores.push((rom_type, score));
+            let better = match header {
+                Some((_, best)) => score > best,
+                None => true,
+            };
+            if better {
+                header = Some((candidate, score));
+            }
+        }
+        (header.unwrap().0, scores)
+    }
+
     /// Loads the ROM header from the given ROM byte slice.
     ///
     /// `rom_type` is the expected type of the ROM header, based on its location. This method
@@ -48,23 +222,34 @@ impl RomHeader {
                 title: [0; 21],
                 rom_size: 0,
                 ram_size: 0,
+                sram_size: 0,
                 checksum: 0,
+                checksum_complement: 0,
                 rom_type: RomType::LoRom,
+                map_mode: 0,
+                fast_rom: false,
+                chipset: 0,
+                destination: 0,
+                maker: 0,
+                version: 0,
+                extended: [0; 16],
+                chip_subtype: 0,
             }, i16::MIN)
         }
 
         // Extract header slice
-        let bytes = match rom_type {
-            RomType::LoRom => if bytes.len() < 0x8000 {
-                return dummy_result();
-            } else {
-                &bytes[0x7FFF - 63..0x7FFF + 1]
-            },
-            RomType::HiRom => if bytes.len() < 0x10000 {
-                return dummy_result();
-            } else {
-                &bytes[0xFFFF - 63..0xFFFF + 1]
-            }
+        let start = rom_type.header_offset();
+        let mut extended = [0; 16];
+        if bytes.len() > start {
+            extended.copy_from_slice(&bytes[start - 16..start]);
+        }
+        let chip_subtype = extended[15];
+        // Expansion RAM size (`$FFBD`), used by SuperFX games
+        let expansion_ram = extended[13];
+        let bytes = if bytes.len() < start + 64 {
+            return dummy_result();
+        } else {
+            &bytes[start..start + 64]
         };
 
         // The header size must be correct (the ROM loader won't pass a wrong size)
@@ -118,11 +303,15 @@ impl RomHeader {
         //  * `0011`: LoROM + SA-1
         //  * `0101`: ExHiROM
         //  * `1010`: HiROM + SPC7110
-        // (TODO)
+        // ExLoROM has no map mode of its own, these ROMs specify LoROM (or `0010`). SA-1 and
+        // SuperFX ROMs are LoROM as well (the coprocessor does its own mapping). The S-DD1 and
+        // SPC7110 also do their own mapping, but their headers are at the LoROM and HiROM
+        // locations.
 
         let header_rom_type = match bytes[21] & 0x0f {
-            0 => RomType::LoRom,
-            1 => RomType::HiRom,
+            0 | 2 | 3 => RomType::LoRom,
+            1 | 0xa => RomType::HiRom,
+            5 => RomType::ExHiRom,
             t => {
                 debug!("unknown / unimplemented ROM type {}", t);
                 score -= 10;    // until we actually implement this (FIXME Dirty hack)
@@ -130,14 +319,15 @@ impl RomHeader {
             }
         };
 
-        if header_rom_type == rom_type {
+        if header_rom_type == rom_type ||
+                (header_rom_type == RomType::LoRom && rom_type == RomType::ExLoRom) {
             debug!("type: {:?}", rom_type);
         } else {
             debug!("expected rom type {:?}, got {:?}", rom_type, header_rom_type);
             score -= 3;
         }
 
-        // bytes[22] is the chipset info. For now, we don't care about that.
+        // bytes[22] is the chipset info, which tells us which coprocessor is used
         debug!("chipset: 0x{:02X}", bytes[22]);
 
         debug!("ROM/RAM size values: {:02X} {:02X}", bytes[23], bytes[24]);
@@ -146,78 +336,179 @@ impl RomHeader {
         let rom_size = 0x400 << (bytes[23] as u32 & 0x0f);
         let ram_size = 0x400 << (bytes[24] as u32 & 0x0f);
         debug!("{} KB of ROM, {} KB of cartridge RAM", rom_size / 1024, ram_size / 1024);
+        // The expansion RAM size is only valid if the maker code is `$33` (extended header)
+        let sram_size = match (bytes[24], bytes[26], expansion_ram) {
+            (0, 0x33, 1 ... 0x0f) => 0x400 << expansion_ram,
+            (0, _, _) => 0,
+            _ => ram_size,
+        };
 
-        // bytes[25-26] is a vendor code (doesn't matter)
-        debug!("vendor code: 0x{:02X}{:02X}", bytes[25], bytes[26]);
-        // 27 = version (also doesn't matter for us)
+        // bytes[25] is the destination code, bytes[26] the maker code (`$33` if the extended
+        // header is present), bytes[27] the version
+        debug!("destination: 0x{:02X}, maker: 0x{:02X}", bytes[25], bytes[26]);
         debug!("version: 0x{:02X}", bytes[27]);
 
         (RomHeader {
             title: title,
             rom_size: rom_size,
             ram_size: ram_size,
+            sram_size: sram_size,
             checksum: rom_checksum,
+            checksum_complement: check_inv,
             rom_type: rom_type,
+            map_mode: bytes[21],
+            fast_rom: bytes[21] & 0x10 != 0,
+            chipset: bytes[22],
+            destination: bytes[25],
+            maker: bytes[26],
+            version: bytes[27],
+            extended: extended,
+            chip_subtype: chip_subtype,
         }, score)
     }
 }
 
+/// A location in cartridge memory, as resolved from a CPU address
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    /// Offset into the ROM image
+    Rom(usize),
+    /// Offset into the cartridge RAM
+    Ram(usize),
+    /// A register or the data RAM of a DSP coprocessor
+    Dsp(DspPort),
+}
+
 /// A ROM image
 #[derive(Clone)]
 pub struct Rom {
     header: RomHeader,
     ram: Vec<u8>,
     rom: Vec<u8>,
+    /// The coprocessor on the cartridge. If there is one, it handles all memory accesses.
+    coprocessor: Coprocessor,
+    info: RomInfo,
+    /// Enabled cheat codes that replace the values read from the cartridge
+    cheats: Vec<Code>,
 }
 
-// NB: If we want to support "realistic" saves, we'd just save the cartridge RAM and nothing else
-impl_save_state!(Rom { ram } ignore { header, rom });
+// NB: "Realistic" saves (`.srm` files) only contain the cartridge RAM, see `sram`
+impl_save_state!(Rom { ram, coprocessor } ignore { header, rom, info, cheats });
 
 impl Rom {
-    /// Loads a ROM from raw data.
-    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Rom> {
-        // Would it be useful if we returned the warnings somehow?
+    /// Loads a ROM from raw data, using the bundled game database.
+    ///
+    /// Problems that don't prevent loading the ROM are logged and collected in the `RomInfo` (see
+    /// `Rom::info`).
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Rom> {
+        Self::from_bytes_with_database(bytes, Vec::new(), &GameDatabase::bundled())
+    }
+
+    /// Loads a ROM from raw data, like `from_bytes`. `warnings` are problems found while reading
+    /// the ROM file (see `archive::read_rom_file`), they're reported along with the others.
+    ///
+    /// If the ROM is in the game database `db`, the values from its entry are used instead of the
+    /// ones from the header.
+    pub fn from_bytes_with_database(bytes: &[u8], mut warnings: Vec<String>, db: &GameDatabase)
+    -> io::Result<Rom> {
+        let file_size = bytes.len();
+        // Holds the ROM if it needs to be de-interleaved (`bytes` might point to it)
+        let deinterleaved;
+        let mut bytes = bytes;
 
         debug!("raw size: {} bytes (${:X})", bytes.len(), bytes.len());
 
-        // ROMs may begin with a 512 Bytes SMC header. It needs to go.
-        match bytes.len() % 1024 {
+        // ROMs may begin with a 512 Bytes copier header. It needs to go.
+        let copier_header = match bytes.len() % 1024 {
             512 => {
-                info!("stripping SMC header");
+                let kind = CopierHeader::detect(&bytes[..512]);
+                let msg = format!("stripped 512 byte {} copier header", kind.name());
+                info!("{}", msg);
+                warnings.push(msg);
                 bytes = &bytes[512..];
+                Some(kind)
             }
-            0 => {},
+            0 => None,
             n => {
-                let fmt = format!("len() % 1024 == {} (expected 512 or 0)", n);
-                error!("{}", fmt);
-                return Err(invalid_data(fmt));
+                let msg = format!("ROM size is not a multiple of 1 KB ({} extra bytes), the \
+                    image might be corrupted", n);
+                warn!("{}", msg);
+                warnings.push(msg);
+                None
+            }
+        };
+
+        let mut entry = db.lookup(bytes);
+        let (mut header, mut scores) = RomHeader::find(bytes);
+
+        // Some copiers store HiROM games interleaved: The upper halves of all 64 KB banks (which
+        // contain the header) come first. This makes the header appear at the LoROM location,
+        // but specify HiROM. (Known ROMs are never interleaved, so this is only checked for
+        // unknown ones.)
+        if entry.is_none() && header.rom_type == RomType::LoRom && header.map_mode & 0x0f == 1 &&
+                bytes.len() % 0x10000 == 0 {
+            deinterleaved = deinterleave(bytes);
+            let (hi_header, hi_scores) = RomHeader::find(&deinterleaved);
+            if hi_header.rom_type == RomType::HiRom {
+                let msg = "ROM image is interleaved, de-interleaved it".to_string();
+                warn!("{}", msg);
+                warnings.push(msg);
+                bytes = &deinterleaved;
+                header = hi_header;
+                scores = hi_scores;
+                entry = db.lookup(bytes);
             }
         }
 
-        // Try all header locations and pick the one that's probably right.
-        // Oh how much I wish there was a real standard for this.
-        // FIXME: We might want to... like... not play *literally every file* but warn instead :)
-        let (lo_header, lo_score) = RomHeader::load(bytes,
-                                                    RomType::LoRom);
-        let (hi_header, hi_score) = RomHeader::load(bytes,
-                                                    RomType::HiRom);
-
-        info!("LoROM/HiROM scores: {}, {}", lo_score, hi_score);
-        let header = if lo_score > hi_score {
-            lo_header
-        } else {
-            hi_header
-        };
+        // Apply the overrides from the game database
+        if let Some(entry) = entry {
+            info!("found ROM in game database: {}", entry.name.as_ref().map_or("(no name)", |n| n));
+            if let Some(rom_type) = entry.rom_type {
+                if rom_type != header.rom_type {
+                    info!("game database overrides mapping: {}", rom_type.name());
+                    let (forced, score) = RomHeader::load(bytes, rom_type);
+                    if score == i16::MIN {
+                        // The image is too small to contain a header at that location. Use the
+                        // mapping anyway, but keep the sizes from the detected header.
+                        let msg = format!("game database specifies {} mapping, but the ROM \
+                            image has no {} header", rom_type.name(), rom_type.name());
+                        warn!("{}", msg);
+                        warnings.push(msg);
+                        header.rom_type = rom_type;
+                    } else {
+                        header = forced;
+                    }
+                }
+            }
+            if let Some(ram_size) = entry.ram_size {
+                info!("game database overrides RAM size: {} KB", ram_size / 1024);
+                header.ram_size = cmp::max(ram_size, 0x400);
+                header.sram_size = ram_size;
+            }
+            if entry.status == DumpStatus::Bad {
+                let msg = "ROM is a known bad dump".to_string();
+                warn!("{}", msg);
+                warnings.push(msg);
+            }
+        }
 
         header.dump();
 
         if bytes.len() != header.rom_size as usize {
-            warn!("raw ROM is {} KB, but header specifies {} KB",
+            let msg = format!("raw ROM is {} KB, but header specifies {} KB",
                 bytes.len() / 1024, header.rom_size / 1024);
+            warn!("{}", msg);
+            warnings.push(msg);
         }
 
+        let rom_size = header.rom_size as usize;
+        let coprocessor = entry.and_then(|entry| entry.coprocessor.as_ref())
+            .and_then(|name| Coprocessor::from_name(name, header.rom_type, rom_size))
+            .unwrap_or_else(|| Coprocessor::detect(header.map_mode, header.chipset, header.maker,
+                header.chip_subtype, header.rom_size as usize));
+
         // Create the right amount of RAM...
-        let ram = vec![0; header.ram_size as usize];
+        let ram = vec![0; cmp::max(header.ram_size as usize, coprocessor.min_ram_size())];
         // ...and copy the ROM
         let rom = bytes.iter().cloned().cycle()
             .take(cmp::max(header.rom_size as usize, bytes.len())).collect();
@@ -231,112 +522,603 @@ impl Rom {
         info!("computed checksum: ${:04X}", checksum);
 
         if header.checksum != checksum {
-            warn!("incorrect checksum: computed ${:04X}, expected ${:04X}",
+            let msg = format!("incorrect checksum: computed ${:04X}, expected ${:04X}",
                 checksum, header.checksum);
+            warn!("{}", msg);
+            warnings.push(msg);
         }
+        if header.checksum_complement != !header.checksum {
+            warnings.push(format!("checksum complement ${:04X} doesn't match checksum ${:04X}",
+                header.checksum_complement, header.checksum));
+        }
+
+        let mut info = header.info(&coprocessor, bytes);
+        info.computed_checksum = checksum;
+        info.copier_header = copier_header;
+        info.file_size = file_size;
+        info.scores = scores;
+        info.warnings = warnings;
+        info.dump_status = entry.map_or(DumpStatus::Unknown, |entry| entry.status);
+        info.database_entry = entry.cloned();
 
         Ok(Rom {
             header: header,
             ram: ram,
             rom: rom,
+            coprocessor: coprocessor,
+            info: info,
+            cheats: Vec::new(),
         })
     }
 
+    /// Returns information about the ROM image (decoded header, hashes and loading problems).
+    pub fn info(&self) -> &RomInfo { &self.info }
+
     pub fn get_title(&self) -> Option<&str> {
         str::from_utf8(&self.header.title).ok().map(|s| s.trim_right())
     }
 
-    fn resolve_lorom(&mut self, bank: u8, addr: u16) -> &mut u8 {
+    fn resolve_lorom(&self, bank: u8, addr: u16) -> Option<Location> {
         match addr {
             0x0000 ... 0x7fff => {
                 // Cartridge RAM mapped to the low 32 KB
                 // (there's other stuff here, but that's handled much earlier than we are called)
                 match bank {
                     0x70 ... 0x7d => {
-                        let a = (bank as u32 - 0x70) * 0x8000 + addr as u32;
-                        self.ram.get_mut(a as usize).unwrap_or_else(|| out_of_ram_bounds(bank, addr, a))
+                        Some(Location::Ram((bank as usize - 0x70) * 0x8000 + addr as usize))
                     }
                     0xfe ... 0xff => {
                         // last 64k of RAM
-                        let start = self.ram.len() as u32 - 64 * 1024;
-                        let a = start + (bank - 0xfe) as u32 * 0x8000 + addr as u32;
-                        self.ram.get_mut(a as usize).unwrap_or_else(|| out_of_ram_bounds(bank, addr, a))
-                    }
-                    _ => {
-                        // 0x40 ... 0x6f | 0x7e ... 0xfd
-                        panic!("attempted to access unmapped address: ${:02X}:{:04X}", bank, addr);
+                        self.ram.len().checked_sub(64 * 1024).map(|start| {
+                            Location::Ram(start + (bank - 0xfe) as usize * 0x8000 + addr as usize)
+                        })
                     }
+                    // 0x40 ... 0x6f | 0x7e ... 0xfd
+                    _ => None,
                 }
             },
             0x8000 ... 0xffff => match bank {
                 // LoROM is mapped to the higher 8 pages
-                0xfe => {
-                    let a = 0x3f0000 + addr as u32 - 0x8000;
-                    self.rom.get_mut(a as usize).unwrap_or_else(|| out_of_rom_bounds(bank, addr, a))
-                }
-                0xff => {
-                    let a = 0x3f8000 + addr as u32 - 0x8000;
-                    self.rom.get_mut(a as usize).unwrap_or_else(|| out_of_rom_bounds(bank, addr, a))
-                }
+                0xfe => Some(Location::Rom(0x3f0000 + addr as usize - 0x8000)),
+                0xff => Some(Location::Rom(0x3f8000 + addr as usize - 0x8000)),
                 0x80 ... 0xfd | 0x00 ... 0x7d => {
                     // `& !0x80` because 0x80-0xFD mirrors 0x00-0x7D
-                    let a = (bank as u32 & !0x80) * 0x8000 + addr as u32 - 0x8000;
-                    self.rom.get_mut(a as usize).unwrap_or_else(|| out_of_rom_bounds(bank, addr, a))
+                    Some(Location::Rom((bank as usize & !0x80) * 0x8000 + addr as usize - 0x8000))
                 }
-                _ => panic!("attempted to access unmapped address: ${:02X}:{:04X}", bank, addr)
+                _ => None,
             },
             _ => unreachable!()
         }
     }
 
-    fn resolve_hirom(&mut self, bank: u8, addr: u16) -> &mut u8 {
+    fn resolve_exlorom(&self, bank: u8, addr: u16) -> Option<Location> {
+        match (bank, addr) {
+            // Banks `$00-$7D` contain the part after the first 4 MB
+            (0x00 ... 0x7d, 0x8000 ... 0xffff) => {
+                Some(Location::Rom(0x400000 + bank as usize * 0x8000 + addr as usize - 0x8000))
+            }
+            // The rest (RAM and the first 4 MB in banks `$80-$FF`) is mapped like LoROM
+            _ => self.resolve_lorom(bank, addr),
+        }
+    }
+
+    fn resolve_hirom(&self, bank: u8, addr: u16) -> Option<Location> {
         let addr = addr as usize;
         match bank {
             0x00 ... 0x3f | 0x80 ... 0xbf if addr >= 0x8000 => {
-                &mut self.rom[(bank as usize & 0x3f) << 16 | addr]
+                Some(Location::Rom((bank as usize & 0x3f) << 16 | addr))
             }
             0x20 ... 0x3f | 0xa0 ... 0xbf if addr >= 0x6000 && addr <= 0x7fff => {
                 // `addr` is masked with `0x1fff` since HiROM seems to have up to 8K mirrored RAM
-                &mut self.ram[addr & 0x1fff]
+                Some(Location::Ram(addr & 0x1fff))
             }
             0x40 ... 0x7d | 0xc0 ... 0xfd => {
-                &mut self.rom[((bank as usize & 0x7f) - 0x40) << 16 | addr]
+                Some(Location::Rom(((bank as usize & 0x7f) - 0x40) << 16 | addr))
             }
             0x7e ... 0x7f => unreachable!(),    // WRAM banks
             0xfe ... 0xff => {
-                &mut self.rom[(bank as usize - 0xfe + 0x3e) << 16 | addr]
+                Some(Location::Rom((bank as usize - 0xfe + 0x3e) << 16 | addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn resolve_exhirom(&self, bank: u8, addr: u16) -> Option<Location> {
+        let addr = addr as usize;
+        match bank {
+            // Banks `$00-$3F` and `$40-$7D` contain the part after the first 4 MB
+            0x00 ... 0x3f if addr >= 0x8000 => {
+                Some(Location::Rom(0x400000 + ((bank as usize) << 16 | addr)))
             }
-            _ => panic!("attempted to access unmapped address: ${:02}:{:04X}", bank, addr),
+            0x40 ... 0x7d => Some(Location::Rom(0x400000 + ((bank as usize - 0x40) << 16 | addr))),
+            // Banks `$80-$BF` and `$C0-$FF` contain the first 4 MB
+            0x80 ... 0xbf if addr >= 0x8000 => {
+                Some(Location::Rom((bank as usize & 0x3f) << 16 | addr))
+            }
+            0xc0 ... 0xff => Some(Location::Rom((bank as usize - 0xc0) << 16 | addr)),
+            // Cartridge RAM is mapped like in HiROM
+            _ => self.resolve_hirom(bank, addr as u16),
         }
     }
 
-    fn resolve_addr(&mut self, bank: u8, addr: u16) -> &mut u8 {
+    /// Translates a CPU address to a location in ROM or cartridge RAM. Returns `None` if the
+    /// address isn't mapped to anything.
+    fn resolve_addr(&self, bank: u8, addr: u16) -> Option<Location> {
+        // DSP registers are mapped on top of the normal mapping
+        if let Coprocessor::NecDsp(ref dsp) = self.coprocessor {
+            if let Some(port) = dsp.board().port(bank, addr) {
+                return Some(Location::Dsp(port));
+            }
+        }
         match self.header.rom_type {
             RomType::LoRom => self.resolve_lorom(bank, addr),
             RomType::HiRom => self.resolve_hirom(bank, addr),
+            RomType::ExLoRom => self.resolve_exlorom(bank, addr),
+            RomType::ExHiRom => self.resolve_exhirom(bank, addr),
+        }
+    }
+
+    /// Returns the offset into the ROM image `bank:addr` is mapped to, or `None` if the address
+    /// isn't mapped to ROM.
+    pub fn rom_offset(&self, bank: u8, addr: u16) -> Option<usize> {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) | Coprocessor::Cx4(_) => {}
+            Coprocessor::Obc1(_) | Coprocessor::Srtc(_) => {}
+            Coprocessor::Sa1(ref sa1) => return sa1.rom_offset(self.rom.len(), bank, addr),
+            Coprocessor::Sdd1(ref sdd1) => return sdd1.rom_offset(self.rom.len(), bank, addr),
+            Coprocessor::Spc7110(ref spc) => return spc.rom_offset(self.rom.len(), bank, addr),
+            Coprocessor::SuperFx(ref fx) => return fx.rom_offset(self.rom.len(), bank, addr),
+        }
+        match self.resolve_addr(bank, addr) {
+            Some(Location::Rom(offset)) if offset < self.rom.len() => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of the ROM image in bytes (after mirroring it to the size specified in the
+    /// header).
+    pub fn rom_size(&self) -> usize { self.rom.len() }
+
+    fn get(&self, loc: Location) -> Option<&u8> {
+        match loc {
+            Location::Rom(a) => self.rom.get(a),
+            Location::Ram(a) => self.ram.get(a),
+            Location::Dsp(_) => None,
+        }
+    }
+
+    fn get_mut(&mut self, loc: Location) -> Option<&mut u8> {
+        match loc {
+            Location::Rom(a) => self.rom.get_mut(a),
+            Location::Ram(a) => self.ram.get_mut(a),
+            Location::Dsp(_) => None,
+        }
+    }
+
+    /// Returns the DSP coprocessor. Must only be called after an address resolved to a
+    /// `Location::Dsp`.
+    fn dsp(&self) -> &NecDsp {
+        match self.coprocessor {
+            Coprocessor::NecDsp(ref dsp) => dsp,
+            _ => unreachable!(),
+        }
+    }
+
+    fn dsp_mut(&mut self) -> &mut NecDsp {
+        match self.coprocessor {
+            Coprocessor::NecDsp(ref mut dsp) => dsp,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Resolves `bank:addr` to the byte it's mapped to. Returns `None` if there is none (the
+    /// address is unmapped, out of bounds of the ROM or RAM, or a DSP port).
+    fn resolve_mut(&mut self, bank: u8, addr: u16) -> Option<&mut u8> {
+        match self.resolve_addr(bank, addr) {
+            Some(loc) => self.get_mut(loc),
+            None => None,
         }
     }
 }
 
 impl Rom {
-    pub fn load(&mut self, bank: u8, addr: u16) -> u8 {
-        *self.resolve_addr(bank, addr)
+    /// Loads a byte from the cartridge. Returns `None` if nothing is mapped to `bank:addr` (the
+    /// CPU reads the open bus then).
+    ///
+    /// Enabled cheat codes for `bank:addr` replace the value that was read.
+    pub fn load(&mut self, bank: u8, addr: u16) -> Option<u8> {
+        let value = self.load_mapped(bank, addr);
+        if self.cheats.is_empty() {
+            return value;
+        }
+        let full_addr = (bank as u32) << 16 | addr as u32;
+        match self.cheats.iter().find(|code| code.addr == full_addr) {
+            Some(code) => value.map(|value| code.apply(value)),
+            None => value,
+        }
     }
 
-    pub fn store(&mut self, bank: u8, addr: u16, value: u8) {
+    /// Sets the cheat codes applied by `load` (see the `cheat` module).
+    pub fn set_cheats(&mut self, codes: Vec<Code>) {
+        self.cheats = codes;
+    }
+
+    fn load_mapped(&mut self, bank: u8, addr: u16) -> Option<u8> {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) | Coprocessor::Srtc(_) => {}
+            Coprocessor::Cx4(ref mut cx4) => if let Some(value) = cx4.load(bank, addr) {
+                return Some(value);
+            },
+            Coprocessor::Obc1(ref obc1) => if let Some(value) = obc1.load(&self.ram, bank, addr) {
+                return Some(value);
+            },
+            Coprocessor::Sa1(ref mut sa1) => {
+                return Some(sa1.load(&mut self.rom, &mut self.ram, bank, addr));
+            }
+            Coprocessor::Sdd1(ref mut sdd1) => {
+                return Some(sdd1.load(&self.rom, &self.ram, bank, addr));
+            }
+            Coprocessor::Spc7110(ref mut spc) => {
+                return Some(spc.load(&self.rom, &self.ram, bank, addr));
+            }
+            Coprocessor::SuperFx(ref mut fx) => {
+                return Some(fx.load(&self.rom, &self.ram, bank, addr));
+            }
+        }
+        match self.resolve_addr(bank, addr) {
+            Some(Location::Dsp(port)) => Some(self.dsp_mut().read(port)),
+            Some(_) => self.resolve_mut(bank, addr).map(|byte| *byte),
+            None => None,
+        }
+    }
+
+    /// Stores a byte in the cartridge. Returns `false` if nothing is mapped to `bank:addr` (the
+    /// write is ignored).
+    pub fn store(&mut self, bank: u8, addr: u16, value: u8) -> bool {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) | Coprocessor::Srtc(_) => {}
+            Coprocessor::Cx4(ref mut cx4) => if cx4.store(&self.rom, bank, addr, value) {
+                return true;
+            },
+            Coprocessor::Obc1(ref mut obc1) => if obc1.store(&mut self.ram, bank, addr, value) {
+                return true;
+            },
+            Coprocessor::Sa1(ref mut sa1) => {
+                sa1.store(&mut self.rom, &mut self.ram, bank, addr, value);
+                return true;
+            }
+            Coprocessor::Sdd1(ref mut sdd1) => {
+                sdd1.store(&mut self.ram, bank, addr, value);
+                return true;
+            }
+            Coprocessor::Spc7110(ref mut spc) => {
+                spc.store(&mut self.ram, bank, addr, value);
+                return true;
+            }
+            Coprocessor::SuperFx(ref mut fx) => {
+                fx.store(&mut self.ram, bank, addr, value);
+                return true;
+            }
+        }
+        if let Some(Location::Dsp(port)) = self.resolve_addr(bank, addr) {
+            self.dsp_mut().write(port, value);
+            return true;
+        }
         if addr >= 0x8000 {
             warn!("writing ${:02X} to ROM address ${:02X}:{:04X}", value, bank, addr);
         }
-        *self.resolve_addr(bank, addr) = value;
+        match self.resolve_mut(bank, addr) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reads the byte mapped to `bank:addr` without any side effects. Returns `None` if nothing is
+    /// mapped there.
+    pub fn peek(&self, bank: u8, addr: u16) -> Option<u8> {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) | Coprocessor::Srtc(_) => {}
+            Coprocessor::Cx4(ref cx4) => if let Some(value) = cx4.peek(bank, addr) {
+                return Some(value);
+            },
+            Coprocessor::Obc1(ref obc1) => if let Some(value) = obc1.load(&self.ram, bank, addr) {
+                return Some(value);
+            },
+            Coprocessor::Sa1(ref sa1) => return sa1.peek(&self.rom, &self.ram, bank, addr),
+            Coprocessor::Sdd1(ref sdd1) => return sdd1.peek(&self.rom, &self.ram, bank, addr),
+            Coprocessor::Spc7110(ref spc) => return spc.peek(&self.rom, &self.ram, bank, addr),
+            Coprocessor::SuperFx(ref fx) => return fx.peek(&self.rom, &self.ram, bank, addr),
+        }
+        match self.resolve_addr(bank, addr) {
+            Some(Location::Dsp(port)) => self.dsp().peek(port),
+            loc => loc.and_then(|loc| self.get(loc)).cloned(),
+        }
+    }
+
+    /// Overwrites the byte mapped to `bank:addr` (this includes ROM). Returns `false` if nothing is
+    /// mapped there.
+    pub fn poke(&mut self, bank: u8, addr: u16, value: u8) -> bool {
+        match self.coprocessor {
+            // The OBC-1 registers are just RAM, so poking them doesn't have side effects
+            Coprocessor::None | Coprocessor::NecDsp(_) | Coprocessor::Srtc(_) => {}
+            Coprocessor::Cx4(ref mut cx4) => if cx4.poke(bank, addr, value) {
+                return true;
+            },
+            Coprocessor::Obc1(ref mut obc1) => if obc1.store(&mut self.ram, bank, addr, value) {
+                return true;
+            },
+            Coprocessor::Sa1(ref mut sa1) => {
+                return sa1.poke(&mut self.rom, &mut self.ram, bank, addr, value);
+            }
+            Coprocessor::Sdd1(ref mut sdd1) => {
+                return sdd1.poke(&mut self.rom, &mut self.ram, bank, addr, value);
+            }
+            Coprocessor::Spc7110(ref mut spc) => {
+                return spc.poke(&mut self.rom, &mut self.ram, bank, addr, value);
+            }
+            Coprocessor::SuperFx(ref mut fx) => {
+                return fx.poke(&mut self.rom, &mut self.ram, bank, addr, value);
+            }
+        }
+        match self.resolve_addr(bank, addr).and_then(|loc| self.get_mut(loc)) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
     }
 }
 
-fn out_of_ram_bounds(bank: u8, addr: u16, abs: u32) -> ! {
-    panic!("RAM access out of bounds at {:02X}:{:04X} -> {:04X}",
-        bank, addr, abs)
+/// Battery-backed RAM
+impl Rom {
+    /// Returns all of the cartridge RAM (including RAM that isn't battery-backed).
+    pub fn ram(&self) -> &[u8] { &self.ram }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] { &mut self.ram }
+
+    /// Returns the part of the cartridge RAM that is battery-backed (and stored in `.srm` files).
+    /// Empty if the header says the cartridge has no RAM.
+    pub fn sram(&self) -> &[u8] {
+        let size = cmp::min(self.header.sram_size as usize, self.ram.len());
+        &self.ram[..size]
+    }
+
+    /// Overwrites the battery-backed RAM with `data`. If `data` is shorter than the RAM, the rest
+    /// is left unchanged, if it's longer, the extra bytes are ignored.
+    pub fn load_sram(&mut self, data: &[u8]) {
+        let size = cmp::min(self.sram().len(), data.len());
+        self.ram[..size].copy_from_slice(&data[..size]);
+    }
 }
 
-fn out_of_rom_bounds(bank: u8, addr: u16, abs: u32) -> ! {
-    panic!("ROM access out of bounds at {:02X}:{:04X} -> {:06X}",
-        bank, addr, abs)
+/// Cartridge I/O and coprocessor emulation
+impl Rom {
+    /// Handles a load from `$2200-$3FFF` or `$4800-$48FF` in the system banks, where coprocessors
+    /// map their registers. Returns `None` if nothing is mapped there.
+    pub fn load_io(&mut self, addr: u16) -> Option<u8> {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) => None,
+            Coprocessor::Cx4(_) | Coprocessor::Obc1(_) => None,
+            Coprocessor::Sa1(ref mut sa1) => sa1.load_io(&mut self.rom, &mut self.ram, addr),
+            Coprocessor::Sdd1(ref mut sdd1) => sdd1.load_io(addr),
+            Coprocessor::Spc7110(ref mut spc) => spc.load_io(&self.rom, addr),
+            Coprocessor::Srtc(ref mut srtc) => srtc.load_io(addr),
+            Coprocessor::SuperFx(ref mut fx) => fx.load_io(addr),
+        }
+    }
+
+    /// Handles a store to `$2200-$3FFF` or `$4800-$48FF` in the system banks. Returns `false` if
+    /// nothing is mapped there.
+    pub fn store_io(&mut self, addr: u16, value: u8) -> bool {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) => false,
+            Coprocessor::Cx4(_) | Coprocessor::Obc1(_) => false,
+            Coprocessor::Sa1(ref mut sa1) => {
+                sa1.store_io(&mut self.rom, &mut self.ram, addr, value)
+            }
+            Coprocessor::Sdd1(ref mut sdd1) => sdd1.store_io(addr, value),
+            Coprocessor::Spc7110(ref mut spc) => spc.store_io(&self.rom, addr, value),
+            Coprocessor::Srtc(ref mut srtc) => srtc.store_io(addr, value),
+            Coprocessor::SuperFx(ref mut fx) => fx.store_io(addr, value),
+        }
+    }
+
+    /// Loads the firmware needed by the coprocessor from `dir`. Does nothing if the coprocessor
+    /// doesn't need any (or there's none).
+    pub fn load_firmware(&mut self, dir: &Path) -> io::Result<()> {
+        match self.coprocessor {
+            Coprocessor::NecDsp(ref mut dsp) => dsp.load_firmware(dir),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the diagnostics the coprocessor recorded since they were last collected (if it
+    /// records any).
+    pub fn diagnostics_mut(&mut self) -> Option<&mut Diagnostics> {
+        self.coprocessor.diagnostics_mut()
+    }
+
+    /// Runs the coprocessor (if any) for `master_cy` master clock cycles.
+    pub fn run_coprocessor(&mut self, master_cy: u32) {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::Sdd1(_) => {}
+            // The Cx4 is emulated at a high level, its commands complete immediately
+            Coprocessor::Cx4(_) | Coprocessor::Obc1(_) => {}
+            Coprocessor::NecDsp(ref mut dsp) => dsp.run(master_cy),
+            Coprocessor::Sa1(ref mut sa1) => sa1.run(&mut self.rom, &mut self.ram, master_cy),
+            Coprocessor::Spc7110(ref mut spc) => spc.run(master_cy),
+            Coprocessor::Srtc(ref mut srtc) => srtc.run(master_cy),
+            Coprocessor::SuperFx(ref mut fx) => fx.run(&self.rom, &mut self.ram, master_cy),
+        }
+    }
+
+    /// Called before a DMA transfer from `bank:addr` to the B bus starts on `channel`. This allows
+    /// the cartridge to replace the data read by the DMA controller (the S-DD1 decompresses it).
+    pub fn dma_start(&mut self, channel: u8, bank: u8, addr: u16) {
+        if let Coprocessor::Sdd1(ref mut sdd1) = self.coprocessor {
+            sdd1.dma_start(&self.rom, channel, bank, addr);
+        }
+    }
+
+    /// Called after a DMA transfer started with `dma_start` has finished.
+    pub fn dma_end(&mut self) {
+        if let Coprocessor::Sdd1(ref mut sdd1) = self.coprocessor {
+            sdd1.dma_end();
+        }
+    }
+
+    /// Sets the real-time clock on the cartridge (if any) to `time`. Used to make input recordings
+    /// independent of the time they were recorded at.
+    pub fn set_rtc_time(&mut self, time: DateTime) {
+        self.coprocessor.set_time(time);
+    }
+
+    /// Sets the master clock frequency the real-time clock on the cartridge (if any) is driven
+    /// by, so it keeps time on both NTSC and PAL consoles.
+    pub fn set_master_clock_freq(&mut self, freq: u32) {
+        if let Some(clock) = self.coprocessor.clock_mut() {
+            clock.set_master_clock_freq(freq);
+        }
+    }
+
+    /// Returns the state of the real-time clock on the cartridge, which is stored after the
+    /// cartridge RAM in save files. Returns `None` if the cartridge has no clock.
+    pub fn rtc_data(&self) -> Option<Vec<u8>> {
+        self.coprocessor.clock().map(|clock| clock.save_data())
+    }
+
+    /// Returns how often the real-time clock on the cartridge was set (by the game or with
+    /// `set_time`). Save files compare this to find out whether the clock must be written again.
+    /// Returns 0 if the cartridge has no clock.
+    pub fn rtc_adjustments(&self) -> u32 {
+        self.coprocessor.clock().map_or(0, |clock| clock.adjustments())
+    }
+
+    /// Restores the clock state saved by `rtc_data`, catching up on the time that has passed since
+    /// then. Returns `false` if the cartridge has no clock or `data` is invalid.
+    pub fn load_rtc_data(&mut self, data: &[u8]) -> bool {
+        match self.coprocessor.clock_mut() {
+            Some(clock) => clock.load_data(data),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if the cartridge asserts the IRQ line of the CPU.
+    pub fn irq_pending(&self) -> bool {
+        match self.coprocessor {
+            Coprocessor::None | Coprocessor::NecDsp(_) => false,
+            Coprocessor::Sdd1(_) | Coprocessor::Spc7110(_) => false,
+            Coprocessor::Cx4(_) | Coprocessor::Obc1(_) | Coprocessor::Srtc(_) => false,
+            Coprocessor::Sa1(ref sa1) => sa1.irq_pending(),
+            Coprocessor::SuperFx(ref fx) => fx.irq_pending(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a 128 KB HiROM image with a valid header, filling bank `n` with `n + 1`
+    fn hirom() -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..0x20000).map(|i| (i / 0x10000) as u8 + 1).collect();
+        let header = &mut rom[0xffc0..0x10000];
+        header[..21].copy_from_slice(b"HIROM TEST           ");
+        header[21] = 0x21;
+        header[22] = 0x00;
+        header[23] = 0x07;
+        header[24] = 0x00;
+        header[28] = 0xff;
+        header[29] = 0xff;
+        header[30] = 0x00;
+        header[31] = 0x00;
+        // The checksum and its complement always add up to `$1FE`, like the placeholder
+        let checksum = rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        rom[0xffdc] = !checksum as u8;
+        rom[0xffdd] = (!checksum >> 8) as u8;
+        rom[0xffde] = checksum as u8;
+        rom[0xffdf] = (checksum >> 8) as u8;
+        rom
+    }
+
+    fn copier_header(bytes: &[(usize, u8)]) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        for &(i, b) in bytes {
+            header[i] = b;
+        }
+        header
+    }
+
+    #[test]
+    fn detect_copier_header() {
+        let swc = copier_header(&[(0, 0x10), (2, 0x0c), (8, 0xaa), (9, 0xbb), (10, 0x04)]);
+        assert_eq!(CopierHeader::detect(&swc), CopierHeader::Swc);
+
+        for &(lo, hi) in &[(0x00, 0x80), (0x77, 0x83), (0x47, 0x83), (0x11, 0x02), (0xdd, 0x82),
+                (0xdd, 0x02), (0xf7, 0x83), (0xfd, 0x82)] {
+            let fig = copier_header(&[(0, 0x10), (4, lo), (5, hi)]);
+            assert_eq!(CopierHeader::detect(&fig), CopierHeader::Fig, "${:02X}{:02X}", hi, lo);
+        }
+
+        let mut gd3 = vec![0; 512];
+        gd3[..16].copy_from_slice(b"GAME DOCTOR SF 3");
+        assert_eq!(CopierHeader::detect(&gd3), CopierHeader::Gd3);
+
+        assert_eq!(CopierHeader::detect(&[0; 512]), CopierHeader::Unknown);
+        let other = copier_header(&[(4, 0x12), (5, 0x34), (8, 0xaa), (9, 0xbb), (10, 0x05)]);
+        assert_eq!(CopierHeader::detect(&other), CopierHeader::Unknown);
+    }
+
+    #[test]
+    fn strip_copier_header() {
+        let mut bytes = copier_header(&[(8, 0xaa), (9, 0xbb), (10, 0x04)]);
+        bytes.extend_from_slice(&hirom());
+        let rom = Rom::from_bytes(&bytes).unwrap();
+        let info = rom.info();
+        assert_eq!(info.copier_header, Some(CopierHeader::Swc));
+        assert_eq!(info.file_size, 0x20200);
+        assert_eq!(info.rom_type, RomType::HiRom);
+        assert!(info.warnings.contains(&"stripped 512 byte SWC copier header".to_string()));
+        assert_eq!(rom.rom, hirom());
+
+        let rom = Rom::from_bytes(&hirom()).unwrap();
+        assert_eq!(rom.info().copier_header, None);
+        assert!(rom.info().warnings.is_empty(), "{:?}", rom.info().warnings);
+    }
+
+    #[test]
+    fn deinterleave_banks() {
+        let interleaved: Vec<u8> = (0..0x40000).map(|i| (i / 0x8000) as u8).collect();
+        let rom = deinterleave(&interleaved);
+        assert_eq!(rom.len(), interleaved.len());
+        // Bank `n` consists of the halves `n + 4` and `n`
+        for (i, half) in rom.chunks(0x8000).enumerate() {
+            let expected = if i % 2 == 0 { i / 2 + 4 } else { i / 2 } as u8;
+            assert!(half.iter().all(|&b| b == expected), "half {}", i);
+        }
+    }
+
+    #[test]
+    fn interleaved_rom() {
+        let hirom = hirom();
+        let mut interleaved = Vec::new();
+        for bank in hirom.chunks(0x10000) {
+            interleaved.extend_from_slice(&bank[0x8000..]);
+        }
+        for bank in hirom.chunks(0x10000) {
+            interleaved.extend_from_slice(&bank[..0x8000]);
+        }
+        assert_eq!(deinterleave(&interleaved), hirom);
+
+        let rom = Rom::from_bytes(&interleaved).unwrap();
+        assert_eq!(rom.info().rom_type, RomType::HiRom);
+        assert_eq!(rom.info().title, "HIROM TEST");
+        assert!(rom.info().warnings.contains(
+            &"ROM image is interleaved, de-interleaved it".to_string()));
+        assert_eq!(rom.rom, hirom);
+    }
 }
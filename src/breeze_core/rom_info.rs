@@ -0,0 +1,331 @@
+//! Information about a ROM image
+//!
+//! `RomInfo` contains everything we know about a ROM after loading it: The decoded header, how
+//! the header location was chosen, the problems found while loading it and hashes of the ROM
+//! image (computed without the copier header, like the hashes in ROM databases). It can be written
+//! as text or as JSON (see the `breeze info` command).
+
+use gamedb::{DumpStatus, GameEntry, Hack};
+use region::Region;
+use rom::{CopierHeader, RomType};
+
+use std::io::{self, Write};
+
+/// Information about a loaded ROM image
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    /// The title from the header, without trailing spaces
+    pub title: String,
+    /// The memory mapping used by the cartridge
+    pub rom_type: RomType,
+    /// Map mode byte (`$FFD5`)
+    pub map_mode: u8,
+    /// Whether the ROM is meant to be accessed at FastROM speed
+    pub fast_rom: bool,
+    /// Chipset byte (`$FFD6`)
+    pub chipset: u8,
+    /// Chipset subtype (`$FFBF`)
+    pub chip_subtype: u8,
+    /// Name of the emulated coprocessor (if any)
+    pub coprocessor: Option<&'static str>,
+    /// Whether the chipset byte indicates a battery
+    pub battery: bool,
+    /// ROM size specified in the header, in bytes
+    pub rom_size: u32,
+    /// Size of the battery-backed cartridge RAM in bytes (0 if the cartridge has none)
+    pub sram_size: u32,
+    /// Destination code (`$FFD9`)
+    pub destination: u8,
+    /// Old maker code (`$FFDA`), `$33` if the extended header is present
+    pub maker: u8,
+    /// Maker code from the extended header (`$FFB0`, 2 ASCII characters)
+    pub maker_code: Option<String>,
+    /// Game code from the extended header (`$FFB2`, 4 ASCII characters)
+    pub game_code: Option<String>,
+    /// Version (`$FFDB`)
+    pub version: u8,
+    /// Checksum stored in the header
+    pub checksum: u16,
+    /// Checksum complement stored in the header
+    pub checksum_complement: u16,
+    /// Checksum computed from the ROM image
+    pub computed_checksum: u16,
+    /// The 512 byte copier header the file started with (it was removed)
+    pub copier_header: Option<CopierHeader>,
+    /// Size of the ROM file in bytes (including any copier header)
+    pub file_size: usize,
+    /// Scores of the header candidates at the possible locations. The header with the highest
+    /// score is used.
+    pub scores: Vec<(RomType, i16)>,
+    /// Problems found while loading the ROM
+    pub warnings: Vec<String>,
+    /// Whether the ROM is a good dump, according to the game database
+    pub dump_status: DumpStatus,
+    /// The game database entry of the ROM. Its values have been applied to the other fields.
+    pub database_entry: Option<GameEntry>,
+    /// CRC32 of the ROM image
+    pub crc32: u32,
+    /// SHA-1 of the ROM image
+    pub sha1: [u8; 20],
+}
+
+impl RomInfo {
+    /// Returns the region the game was released in, based on the destination code.
+    pub fn region(&self) -> &'static str {
+        match self.destination {
+            0x00 => "Japan",
+            0x01 => "North America",
+            0x02 => "Europe",
+            0x03 => "Sweden",
+            0x04 => "Finland",
+            0x05 => "Denmark",
+            0x06 => "France",
+            0x07 => "Netherlands",
+            0x08 => "Spain",
+            0x09 => "Germany",
+            0x0a => "Italy",
+            0x0b => "China",
+            0x0c => "Indonesia",
+            0x0d => "South Korea",
+            0x0e => "International",
+            0x0f => "Canada",
+            0x10 => "Brazil",
+            0x11 => "Australia",
+            _ => "Unknown",
+        }
+    }
+
+    /// Returns `true` if the game was released for PAL consoles (based on the destination code,
+    /// unless the game database says otherwise).
+    pub fn is_pal(&self) -> bool {
+        if let Some(region) = self.database_entry.as_ref().and_then(|entry| entry.region) {
+            return region == Region::Pal;
+        }
+        match self.destination {
+            0x02 ... 0x0c | 0x11 => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the hacks needed to run the game (from the game database).
+    pub fn hacks(&self) -> &[Hack] {
+        self.database_entry.as_ref().map_or(&[], |entry| &entry.hacks)
+    }
+
+    /// Returns the SHA-1 as a hex string.
+    pub fn sha1_hex(&self) -> String {
+        self.sha1.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Writes the information as human-readable text, one field per line.
+    pub fn write_text(&self, w: &mut Write) -> io::Result<()> {
+        try!(writeln!(w, "Title:          {}", self.title));
+        try!(writeln!(w, "Mapping:        {} (map mode ${:02X}, {})", self.rom_type.name(),
+            self.map_mode, if self.fast_rom { "FastROM" } else { "SlowROM" }));
+        try!(writeln!(w, "Chipset:        ${:02X} (subtype ${:02X}){}{}", self.chipset,
+            self.chip_subtype, self.coprocessor.map(|c| format!(", {}", c)).unwrap_or_default(),
+            if self.battery { ", battery" } else { "" }));
+        try!(writeln!(w, "ROM size:       {} KB", self.rom_size / 1024));
+        try!(writeln!(w, "SRAM size:      {} KB", self.sram_size / 1024));
+        try!(writeln!(w, "Region:         {} (destination ${:02X}, {})", self.region(),
+            self.destination, if self.is_pal() { "PAL" } else { "NTSC" }));
+        try!(write!(w, "Maker:          ${:02X}", self.maker));
+        if let Some(ref code) = self.maker_code {
+            try!(write!(w, " ({})", code));
+        }
+        try!(writeln!(w, ""));
+        if let Some(ref code) = self.game_code {
+            try!(writeln!(w, "Game code:      {}", code));
+        }
+        try!(writeln!(w, "Version:        1.{}", self.version));
+        try!(writeln!(w, "Checksum:       ${:04X} (complement ${:04X}, computed ${:04X})",
+            self.checksum, self.checksum_complement, self.computed_checksum));
+        try!(write!(w, "File size:      {} bytes", self.file_size));
+        if let Some(copier_header) = self.copier_header {
+            try!(write!(w, " (with {} copier header)", copier_header.name()));
+        }
+        try!(writeln!(w, ""));
+        try!(writeln!(w, "CRC32:          {:08x}", self.crc32));
+        try!(writeln!(w, "SHA-1:          {}", self.sha1_hex()));
+        let scores: Vec<_> = self.scores.iter()
+            .map(|&(rom_type, score)| format!("{} {}", rom_type.name(), score))
+            .collect();
+        try!(writeln!(w, "Header scores:  {}", scores.join(", ")));
+        try!(write!(w, "Database:       {} dump", self.dump_status.name()));
+        if let Some(name) = self.database_entry.as_ref().and_then(|entry| entry.name.as_ref()) {
+            try!(write!(w, " ({})", name));
+        }
+        try!(writeln!(w, ""));
+        for hack in self.hacks() {
+            try!(writeln!(w, "Hack:           {}", hack));
+        }
+        for warning in &self.warnings {
+            try!(writeln!(w, "Warning:        {}", warning));
+        }
+        Ok(())
+    }
+
+    /// Writes the information as a JSON object.
+    ///
+    /// Numbers are written as decimal integers, the hashes as lowercase hex strings. Optional
+    /// fields are `null` if they're missing.
+    pub fn write_json(&self, w: &mut Write) -> io::Result<()> {
+        let opt_str = |s: &Option<String>| match *s {
+            Some(ref s) => json_string(s),
+            None => "null".to_string(),
+        };
+
+        try!(writeln!(w, "{{"));
+        try!(writeln!(w, "  \"title\": {},", json_string(&self.title)));
+        try!(writeln!(w, "  \"mapping\": \"{}\",", self.rom_type.name()));
+        try!(writeln!(w, "  \"map_mode\": {},", self.map_mode));
+        try!(writeln!(w, "  \"fast_rom\": {},", self.fast_rom));
+        try!(writeln!(w, "  \"chipset\": {},", self.chipset));
+        try!(writeln!(w, "  \"chip_subtype\": {},", self.chip_subtype));
+        try!(writeln!(w, "  \"coprocessor\": {},",
+            opt_str(&self.coprocessor.map(|c| c.to_string()))));
+        try!(writeln!(w, "  \"battery\": {},", self.battery));
+        try!(writeln!(w, "  \"rom_size\": {},", self.rom_size));
+        try!(writeln!(w, "  \"sram_size\": {},", self.sram_size));
+        try!(writeln!(w, "  \"destination\": {},", self.destination));
+        try!(writeln!(w, "  \"region\": \"{}\",", self.region()));
+        try!(writeln!(w, "  \"pal\": {},", self.is_pal()));
+        try!(writeln!(w, "  \"maker\": {},", self.maker));
+        try!(writeln!(w, "  \"maker_code\": {},", opt_str(&self.maker_code)));
+        try!(writeln!(w, "  \"game_code\": {},", opt_str(&self.game_code)));
+        try!(writeln!(w, "  \"version\": {},", self.version));
+        try!(writeln!(w, "  \"checksum\": {},", self.checksum));
+        try!(writeln!(w, "  \"checksum_complement\": {},", self.checksum_complement));
+        try!(writeln!(w, "  \"computed_checksum\": {},", self.computed_checksum));
+        try!(writeln!(w, "  \"copier_header\": {},",
+            opt_str(&self.copier_header.map(|c| c.name().to_string()))));
+        try!(writeln!(w, "  \"file_size\": {},", self.file_size));
+        try!(writeln!(w, "  \"crc32\": \"{:08x}\",", self.crc32));
+        try!(writeln!(w, "  \"sha1\": \"{}\",", self.sha1_hex()));
+        let scores: Vec<_> = self.scores.iter()
+            .map(|&(rom_type, score)| format!("\"{}\": {}", rom_type.name(), score))
+            .collect();
+        try!(writeln!(w, "  \"scores\": {{{}}},", scores.join(", ")));
+        try!(writeln!(w, "  \"dump_status\": \"{}\",", self.dump_status.name()));
+        try!(writeln!(w, "  \"database_name\": {},",
+            opt_str(&self.database_entry.as_ref().and_then(|entry| entry.name.clone()))));
+        let hacks: Vec<_> = self.hacks().iter().map(|h| json_string(&h.to_string())).collect();
+        try!(writeln!(w, "  \"hacks\": [{}],", hacks.join(", ")));
+        let warnings: Vec<_> = self.warnings.iter().map(|w| json_string(w)).collect();
+        try!(writeln!(w, "  \"warnings\": [{}]", warnings.join(", ")));
+        writeln!(w, "}}")
+    }
+}
+
+/// Quotes and escapes a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Computes the CRC32 (as used by zip files) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+        *entry = crc;
+    }
+
+    !data.iter().fold(!0, |crc, &b| table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Computes the SHA-1 of `data`.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    // Append the padding and the message length in bits
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    let bits = (data.len() as u64).wrapping_mul(8);
+    for i in (0..8).rev() {
+        msg.push((bits >> (i * 8)) as u8);
+    }
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (chunk[i * 4] as u32) << 24 | (chunk[i * 4 + 1] as u32) << 16 |
+                (chunk[i * 4 + 2] as u32) << 8 | chunk[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0 ... 19 => ((b & c) | (!b & d), 0x5a827999),
+                20 ... 39 => (b ^ c ^ d, 0x6ed9eba1),
+                40 ... 59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&[(word >> 24) as u8, (word >> 16) as u8,
+            (word >> 8) as u8, *word as u8]);
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1_hex(data: &[u8]) -> String {
+        sha1(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn crc32_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"abc"), 0x352441c2);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414fa339);
+    }
+
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // The padding doesn't fit into the first block
+        assert_eq!(sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        // Multiple blocks
+        assert_eq!(sha1_hex(&[b'a'; 1000]), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    }
+}
@@ -1,18 +1,32 @@
 //! This module glues everything together and coordinates emulation.
 
+use alu::Alu;
+use cdl::{self, CodeDataLog};
+use cheat::{Cheat, CheatList};
+use diagnostics::{Diagnostics, Report};
 use dma::*;
+use error::{Component, Error, ErrorKind, ErrorPolicy};
+use event_log::{EventKind, EventLog};
+use gamedb::Hack;
 use input::Input;
 use log_util::LogOnPanic;
-use ppu::{FrameBuf, Ppu};
+use ppu::Ppu;
+use profiler::{EntryKind, Profiler};
+use ram_search::{Freeze, Memory};
+use region::Region;
 use rom::Rom;
 use save::SaveStateFormat;
+use sram::SramFile;
 
 use spc700::Spc700;
 use wdc65816::{Cpu, Mem};
+use wdc65816::symbols::Symbols;
+use wdc65816::trace::TraceWriter;
 use breeze_backend::{BackendAction, BackendResult, Renderer, AudioSink};
 
 use std::cmp;
 use std::env;
+use std::mem;
 use std::fs::File;
 use std::io::BufReader;
 
@@ -32,6 +46,10 @@ pub struct Peripherals {
     pub wram: Wram,
     pub input: Input,
 
+    /// Memory data register: The last value transferred over the CPU data bus. Reads from
+    /// addresses nothing responds to ("open bus") return this value.
+    mdr: u8,
+
     /// `$2181` - WMADDL: WRAM Address low byte
     wmaddl: u8,
     /// `$2182` - WMADDM: WRAM Address middle byte
@@ -60,18 +78,8 @@ pub struct Peripherals {
     /// Any bit set to 0 will be 0 when read from `$4213`. If `a` is 0, reading `$2137` will not
     /// latch the H/V Counters.
     wrio: u8,
-    /// `$4202` - WRMPYA: Multiplicand 1
-    wrmpya: u8,
-    /// `$4203` - WRMPYB: Multiplicand 2
-    ///
-    /// Writing here will trigger the multiplication
-    wrmpyb: u8,
-    /// `$4204`/`$4205` - WRDIVH/WRDIVL: Dividend
-    wrdiv: u16,
-    /// `$4216`/`$4217` - RDDIVH/RDDIVL: Unsigned Division Result (Quotient)
-    rddiv: u16,
-    /// `$4216`/`$4217` - RDMPYH/RDMPYL: Unsigned Division Remainder / Multiply Product
-    rdmpy: u16,
+    /// `$4202-$4206`, `$4214-$4217` - Multiplication and division unit
+    alu: Alu,
     /// `$4207`/`$4208` - HTIMEL/HTIMEH: H Timer (9-bit value)
     htime: u16,
     /// `$4209`/`$420a` - VTIMEL/VTIMEH: V Timer (9-bit value)
@@ -93,22 +101,59 @@ pub struct Peripherals {
     /// Additional cycles spent doing IO (in master clock cycles). This is added to the cycle count
     /// returned by the CPU and then reset to 0.
     cy: u32,
+
+    /// Inclusive ranges of (24-bit) addresses that should be watched for writes
+    write_watches: Vec<(u32, u32)>,
+    /// Writes to watched addresses that weren't yet reported to the `Hooks`
+    pending_writes: Vec<(u32, u8)>,
+
+    /// The code/data log, if logging is enabled
+    pub cdl: Option<CodeDataLog>,
+    /// CDL flags to log for the current ROM access
+    cdl_access: u8,
+
+    /// The per-frame event log, if enabled
+    pub events: Option<EventLog>,
+    /// Address of the instruction the CPU is executing (only updated while logging events)
+    event_pc: u32,
+    /// Set while the DMA controller writes to the B bus, to exclude these writes from the event
+    /// log
+    in_dma: bool,
+
+    /// Errors caused by the current instruction, handled by the `Snes` according to its
+    /// `ErrorPolicy`
+    errors: Vec<Error>,
+    /// Diagnostics that weren't collected by the `Snes` yet
+    diagnostics: Diagnostics,
 }
 
 impl_save_state!(Peripherals {
-    apu, ppu, rom, wram, dma, hdmaen, nmien, wrio, wrmpya, wrmpyb, wrdiv, rddiv, rdmpy, htime,
-    vtime, memsel, nmi, irq, cy, input, wmaddl, wmaddm, wmaddh
-} ignore {});
+    apu, ppu, rom, wram, dma, hdmaen, nmien, wrio, alu, htime,
+    vtime, memsel, nmi, irq, cy, input, wmaddl, wmaddm, wmaddh, mdr
+} ignore { write_watches, pending_writes, cdl, cdl_access, events, event_pc, in_dma, errors,
+    diagnostics });
 
 impl Peripherals {
     pub fn new(rom: Rom, input: Input) -> Peripherals {
+        let mut wram = Wram::default();
+        for hack in rom.info().hacks() {
+            match *hack {
+                Hack::WramInit(value) => {
+                    info!("hack: initializing WRAM with ${:02X}", value);
+                    for byte in wram.iter_mut() {
+                        *byte = value;
+                    }
+                }
+            }
+        }
+
         Peripherals {
             rom: rom,
             input: input,
+            mdr: 0,
             wmaddl: 0,
             wmaddm: 0,
             wmaddh: 0,
-            wrdiv: 0xffff,
             htime: 0x1ff,
             vtime: 0x1ff,
             memsel: false,
@@ -116,17 +161,151 @@ impl Peripherals {
 
             apu: Spc700::default(),
             ppu: Ppu::default(),
-            wram: Wram::default(),
+            wram: wram,
             dma: [DmaChannel::default(); 8],
             hdmaen: 0x00,
             nmien: 0x00,
-            wrmpya: 0xff,
-            wrmpyb: 0,
-            rddiv: 0,
-            rdmpy: 0,
+            alu: Alu::new(),
             nmi: false,
             irq: false,
             cy: 0,
+            write_watches: Vec::new(),
+            pending_writes: Vec::new(),
+            cdl: None,
+            cdl_access: cdl::DATA,
+            events: None,
+            event_pc: 0,
+            in_dma: false,
+            errors: Vec::new(),
+            diagnostics: Diagnostics::default(),
+        }
+    }
+
+    /// Reads a byte from memory without any side effects (no I/O cycles are spent and no
+    /// registers are touched). Only WRAM and cartridge memory can be accessed this way, `None` is
+    /// returned for anything else.
+    pub fn peek(&self, bank: u8, addr: u16) -> Option<u8> {
+        match bank {
+            0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
+                0x0000 ... 0x1fff => Some(self.wram[addr as usize]),
+                0x6000 ... 0xffff => self.rom.peek(bank, addr),
+                _ => None,
+            },
+            0x7e | 0x7f => Some(self.wram[(bank as usize - 0x7e) * 65536 + addr as usize]),
+            _ => self.rom.peek(bank, addr),
+        }
+    }
+
+    /// Returns the contents of one of the RAMs.
+    pub fn memory(&self, memory: Memory) -> &[u8] {
+        match memory {
+            Memory::Wram => &self.wram[..],
+            Memory::CartridgeRam => self.rom.ram(),
+            Memory::Aram => self.apu.ram(),
+        }
+    }
+
+    pub fn memory_mut(&mut self, memory: Memory) -> &mut [u8] {
+        match memory {
+            Memory::Wram => &mut self.wram[..],
+            Memory::CartridgeRam => self.rom.ram_mut(),
+            Memory::Aram => self.apu.ram_mut(),
+        }
+    }
+
+    /// Writes a byte to memory without any side effects. This can also be used to modify ROM.
+    ///
+    /// Returns `false` if the address isn't mapped to WRAM or cartridge memory.
+    pub fn poke(&mut self, bank: u8, addr: u16, value: u8) -> bool {
+        match bank {
+            0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
+                0x0000 ... 0x1fff => {
+                    self.wram[addr as usize] = value;
+                    true
+                }
+                0x6000 ... 0xffff => self.rom.poke(bank, addr, value),
+                _ => false,
+            },
+            0x7e | 0x7f => {
+                self.wram[(bank as usize - 0x7e) * 65536 + addr as usize] = value;
+                true
+            }
+            _ => self.rom.poke(bank, addr, value),
+        }
+    }
+
+    /// Starts watching the addresses `start` to `end` (inclusive) for writes. Whenever the CPU
+    /// (or DMA) writes to one of them, `Hooks::watched_write` will be called after the current
+    /// instruction has finished.
+    ///
+    /// Addresses are 24-bit values (`$BBAAAA`). Writes to the low mirror of WRAM (and writes via
+    /// `$2180`) are reported with their `$7E`/`$7F` address.
+    pub fn watch_writes(&mut self, start: u32, end: u32) {
+        self.write_watches.push((start, end));
+    }
+
+    /// Records the write if the address is being watched.
+    fn check_write_watch(&mut self, addr: u32, value: u8) {
+        if self.write_watches.iter().any(|&(start, end)| addr >= start && addr <= end) {
+            self.pending_writes.push((addr, value));
+        }
+    }
+
+    /// Loads a byte on behalf of the DMA controller. This is the same as `load`, but logs ROM
+    /// accesses as DMA reads.
+    pub fn load_dma(&mut self, bank: u8, addr: u16) -> u8 {
+        self.load_with_cdl_access(bank, addr, cdl::DMA)
+    }
+
+    /// Stores a byte on behalf of the DMA controller. B bus writes performed this way are not
+    /// recorded in the event log.
+    pub fn store_dma(&mut self, bank: u8, addr: u16, value: u8) {
+        self.in_dma = true;
+        self.store(bank, addr, value);
+        self.in_dma = false;
+    }
+
+    /// Records an error caused by an access to `bank:addr`.
+    fn error(&mut self, kind: ErrorKind, component: Component, bank: u8, addr: u16) {
+        self.errors.push(Error::new(kind, component, (bank as u32) << 16 | addr as u32));
+    }
+
+    /// Records an event in the event log (if it's enabled).
+    pub fn log_event(&mut self, kind: EventKind) {
+        if let Some(ref mut log) = self.events {
+            log.log(self.ppu.v_counter(), self.ppu.h_counter(), self.event_pc, kind);
+        }
+    }
+
+    fn load_with_cdl_access(&mut self, bank: u8, addr: u16, access: u8) -> u8 {
+        self.cdl_access = access;
+        let value = self.load(bank, addr);
+        self.cdl_access = cdl::DATA;
+        value
+    }
+
+    /// Loads a byte from the cartridge, logging the access in the CDL. Unmapped addresses read
+    /// the open bus.
+    fn load_rom(&mut self, bank: u8, addr: u16) -> u8 {
+        if let Some(ref mut log) = self.cdl {
+            if let Some(offset) = self.rom.rom_offset(bank, addr) {
+                log.mark(offset, self.cdl_access);
+            }
+        }
+        match self.rom.load(bank, addr) {
+            Some(value) => value,
+            None => {
+                diag!(self.diagnostics, "open bus load from unmapped address ${:02X}:{:04X}", bank,
+                    addr);
+                self.mdr
+            }
+        }
+    }
+
+    /// Stores a byte in the cartridge. Writes to unmapped addresses are ignored.
+    fn store_rom(&mut self, bank: u8, addr: u16, value: u8) {
+        if !self.rom.store(bank, addr, value) {
+            self.error(ErrorKind::InvalidStore(value), Component::Cartridge, bank, addr);
         }
     }
 
@@ -174,39 +353,44 @@ impl Peripherals {
 impl Mem for Peripherals {
     fn load(&mut self, bank: u8, addr: u16) -> u8 {
         self.do_io_cycle(bank, addr);
-        match bank {
+        let value = match bank {
             0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
                 // Mirror of first 8k of WRAM
                 0x0000 ... 0x1fff => self.wram[addr as usize],
-                // PPU
-                0x2100 ... 0x2133 => {
-                    once!(warn!("read from write-only PPU register ${:04X}", addr));
-                    0
-                }
-                0x2134 ... 0x213f => self.ppu.load(addr),
+                // PPU (including open bus reads from its write-only registers)
+                0x2100 ... 0x213f => self.ppu.load(addr, self.mdr),
                 // APU IO registers
                 0x2140 ... 0x217f => self.apu.read_port((addr & 0b11) as u8),
                 0x2180 => {
                     let addr = self.get_and_inc_wram_addr();
                     self.wram[addr]
                 }
-                0x2181 ... 0x2183 => {
-                    once!(warn!("open-bus load from WRAM register ${:02X}", addr));
-                    0   // FIXME Emulate open-bus
-                }
-                0x4016 | 0x4017 => self.input.load(addr),
-                0x4202 => self.wrmpya,
-                0x4203 => self.wrmpyb,
+                // Write-only WRAM address registers
+                0x2181 ... 0x2183 => self.mdr,
+                // Cartridge I/O (coprocessor registers and memory)
+                0x2200 ... 0x3fff | 0x4800 ... 0x48ff => match self.rom.load_io(addr) {
+                    Some(value) => value,
+                    None => {
+                        diag!(self.diagnostics, "invalid/unimplemented load from ${:02X}:{:04X}",
+                            bank, addr);
+                        self.mdr
+                    }
+                },
+                // Only the low bits of the joypad ports are driven (see `Input::load`)
+                0x4016 => self.input.load(addr) | self.mdr & 0xfc,
+                0x4017 => self.input.load(addr) | self.mdr & 0xe0,
+                0x4214 ... 0x4217 => self.alu.load(addr).unwrap_or(self.mdr),
                 0x4210 => {
+                    // `n---vvvv`, the unused bits are open bus
                     const CPU_VERSION: u8 = 2;  // FIXME Is 2 okay in all cases? Does anyone care?
                     let nmi = if self.nmi { 0x80 } else { 0 };
                     self.nmi = false;   // Cleared on read
-                    nmi | CPU_VERSION
+                    nmi | self.mdr & 0x70 | CPU_VERSION
                 }
                 0x4211 => {
                     let val = if self.irq { 0x80 } else { 0 };
                     self.irq = false;
-                    val
+                    val | self.mdr & 0x7f
                 }
                 // HVBJOY - PPU Status
                 0x4212 => {
@@ -214,43 +398,86 @@ impl Mem for Peripherals {
                     // V-Blank, H-Blank, Auto-Joypad-Read in progress
                     // FIXME: Use exact timings and set `a`
                     (if self.ppu.in_v_blank() { 0x80 } else { 0 }) +
-                    (if self.ppu.in_h_blank() { 0x40 } else { 0 })
+                    (if self.ppu.in_h_blank() { 0x40 } else { 0 }) +
+                    (self.mdr & 0x3e)
                 }
-                // RDDIVL - Unsigned Division Result (Quotient) (lower 8bit)
-                0x4214 => self.rddiv as u8,
-                // RDDIVH - Unsigned Division Result (Quotient) (upper 8bit)
-                0x4215 => (self.rddiv >> 8) as u8,
-                // RDMPYL
-                0x4216 => self.rdmpy as u8,
-                // RDMPYH
-                0x4217 => (self.rdmpy >> 8) as u8,
                 // Input ports
                 0x4218 ... 0x421f => self.input.load(addr),
                 // DMA channels (0x43xr, where x is the channel and r is the channel register)
-                0x4300 ... 0x43ff => self.dma[(addr as usize & 0x00f0) >> 4].load(addr as u8 & 0xf),
-                0x6000 ... 0xffff => self.rom.load(bank, addr),
-                _ => {
-                    once!(warn!("invalid/unimplemented load from ${:02X}:{:04X}", bank, addr));
-                    0
+                0x4300 ... 0x43ff => {
+                    match self.dma[(addr as usize & 0x00f0) >> 4].load(addr as u8 & 0xf) {
+                        Some(value) => value,
+                        None => {
+                            self.error(ErrorKind::InvalidLoad, Component::Dma, bank, addr);
+                            self.mdr
+                        }
+                    }
                 }
+                0x6000 ... 0xffff => self.load_rom(bank, addr),
+                // Unused B bus addresses and write-only CPU registers
+                _ => self.mdr,
             },
             // WRAM banks. The first 8k are mapped into the start of all banks.
             0x7e | 0x7f => self.wram[(bank as usize - 0x7e) * 65536 + addr as usize],
-            0x40 ... 0x7d | 0xc0 ... 0xff => self.rom.load(bank, addr),
+            0x40 ... 0x7d | 0xc0 ... 0xff => self.load_rom(bank, addr),
             _ => unreachable!(),    // Rust should know this!
-        }
+        };
+        self.mdr = value;
+        value
+    }
+
+    fn trace_position(&self) -> Option<(u16, u16)> {
+        Some((self.ppu.v_counter(), self.ppu.h_counter()))
+    }
+
+    fn load_opcode(&mut self, bank: u8, addr: u16, p: u8) -> u8 {
+        let flags = p & (cdl::ACC_8BIT | cdl::INDEX_8BIT);
+        self.load_with_cdl_access(bank, addr, cdl::OPCODE | flags)
+    }
+
+    fn load_operand(&mut self, bank: u8, addr: u16, p: u8) -> u8 {
+        let flags = p & (cdl::ACC_8BIT | cdl::INDEX_8BIT);
+        self.load_with_cdl_access(bank, addr, cdl::OPERAND | flags)
     }
 
     fn store(&mut self, bank: u8, addr: u16, value: u8) {
         self.do_io_cycle(bank, addr);
+        self.mdr = value;
+        if !self.write_watches.is_empty() {
+            let full_addr = match (bank, addr) {
+                (0x00 ... 0x3f, 0x0000 ... 0x1fff) |
+                (0x80 ... 0xbf, 0x0000 ... 0x1fff) => 0x7e0000 | addr as u32,
+                (0x00 ... 0x3f, 0x2180) | (0x80 ... 0xbf, 0x2180) => {
+                    0x7e0000 + ((self.wmaddh as u32) << 16 | (self.wmaddm as u32) << 8 |
+                                self.wmaddl as u32)
+                }
+                _ => (bank as u32) << 16 | addr as u32,
+            };
+            self.check_write_watch(full_addr, value);
+        }
         match bank {
             0x00 ... 0x3f | 0x80 ... 0xbf => match addr {
                 0x0000 ... 0x1fff => self.wram[addr as usize] = value,
                 // PPU registers. Let it deal with the access.
-                0x2100 ... 0x2133 => self.ppu.store(addr, value),
-                0x2134 ... 0x213f => once!(warn!("store to read-only PPU register ${:04X}", addr)),
+                0x2100 ... 0x2133 => {
+                    if !self.in_dma {
+                        self.log_event(EventKind::PpuWrite { reg: addr, value: value });
+                    }
+                    if let Err(kind) = self.ppu.store(addr, value) {
+                        self.error(kind, Component::Ppu, bank, addr);
+                    }
+                }
+                0x2134 ... 0x213f => {
+                    diag!(self.diagnostics, "store to read-only PPU register ${:04X}", addr)
+                }
                 // APU IO registers.
-                0x2140 ... 0x217f => self.apu.store_port((addr & 0b11) as u8, value),
+                0x2140 ... 0x217f => {
+                    let port = (addr & 0b11) as u8;
+                    if !self.in_dma {
+                        self.log_event(EventKind::ApuWrite { port: port, value: value });
+                    }
+                    self.apu.store_port(port, value);
+                }
                 0x2180 => {
                     let addr = self.get_and_inc_wram_addr();
                     self.wram[addr] = value;
@@ -258,8 +485,14 @@ impl Mem for Peripherals {
                 0x2181 => self.wmaddl = value,
                 0x2182 => self.wmaddm = value,
                 0x2183 => self.wmaddh = value & 1,
-                0x2184 ... 0x21ff => once!(warn!("invalid store: ${:02X} to ${:02X}:{:04X}", value,
-                    bank, addr)),
+                0x2184 ... 0x21ff => {
+                    diag!(self.diagnostics, "invalid store: ${:02X} to ${:02X}:{:04X}", value, bank,
+                        addr)
+                }
+                0x2200 ... 0x3fff | 0x4800 ... 0x48ff => if !self.rom.store_io(addr, value) {
+                    diag!(self.diagnostics, "invalid store: ${:02X} to ${:02X}:{:04X}", value,
+                        bank, addr);
+                },
                 0x4016 => self.input.store(addr, value),
                 0x4200 => {
                     // NMITIMEN - NMI/IRQ enable
@@ -270,7 +503,9 @@ impl Mem for Peripherals {
                     // J: Enable Auto-Joypad-Read
 
                     // Check useless bits
-                    if value & 0x4e != 0 { once!(warn!("Invalid value for NMIEN: ${:02X}", value)) }
+                    if value & 0x4e != 0 {
+                        diag!(self.diagnostics, "Invalid value for NMIEN: ${:02X}", value);
+                    }
                     self.nmien = value;
                 }
                 0x4201 => {
@@ -278,28 +513,24 @@ impl Mem for Peripherals {
                     self.wrio = value;
                     self.ppu.can_latch_counters = value & 0x80 != 0;
                 }
-                0x4202 => self.wrmpya = value,
-                // WRMPYB: Performs multiplication on write
-                0x4203 => {
-                    self.wrmpyb = value;
-                    self.rdmpy = self.wrmpya as u16 * value as u16;
-                }
-                0x4204 => self.wrdiv = (self.wrdiv & 0xff00) | value as u16,
-                0x4205 => self.wrdiv = ((value as u16) << 8) | (self.wrdiv & 0xff),
-                // WRDIVB: Performs division on write
-                0x4206 => {
-                    self.rddiv = if value == 0 { 0xffff } else { self.wrdiv / value as u16 };
-                    self.rdmpy = if value == 0 { value as u16 } else { self.wrdiv % value as u16 };
-                }
+                // Writing `$4203` or `$4206` starts a multiplication or division
+                0x4202 ... 0x4206 => if !self.alu.store(addr, value) {
+                    self.error(ErrorKind::InvalidStore(value), Component::Cpu, bank, addr);
+                },
                 0x4207 => self.htime = (self.htime & 0xff00) | value as u16,
                 0x4208 => {
-                    assert!(value & 0x01 == value, "invalid value for $4207: ${:02X}", value);
-                    self.htime = ((value as u16) << 8) | (self.htime & 0xff);
+                    // Only bit 0 exists
+                    if value & 0x01 != value {
+                        self.error(ErrorKind::InvalidValue(value), Component::Cpu, bank, addr);
+                    }
+                    self.htime = ((value as u16 & 0x01) << 8) | (self.htime & 0xff);
                 }
                 0x4209 => self.vtime = (self.vtime & 0xff00) | value as u16,
                 0x420a => {
-                    assert!(value & 0x01 == value, "invalid value for $4209: ${:02X}", value);
-                    self.vtime = ((value as u16) << 8) | (self.vtime & 0xff);
+                    if value & 0x01 != value {
+                        self.error(ErrorKind::InvalidValue(value), Component::Cpu, bank, addr);
+                    }
+                    self.vtime = ((value as u16 & 0x01) << 8) | (self.vtime & 0xff);
                 }
                 // MDMAEN - Party enable
                 0x420b => self.cy += do_dma(self, value),
@@ -310,19 +541,37 @@ impl Mem for Peripherals {
                 0x420d => self.memsel = value & 0x01 != 0,
                 // DMA channels (0x43xr, where x is the channel and r is the channel register)
                 0x4300 ... 0x43ff => {
-                    self.dma[(addr as usize & 0x00f0) >> 4].store(addr as u8 & 0xf, value);
+                    if !self.dma[(addr as usize & 0x00f0) >> 4].store(addr as u8 & 0xf, value) {
+                        self.error(ErrorKind::InvalidStore(value), Component::Dma, bank, addr);
+                    }
                 }
-                0x8000 ... 0xffff => self.rom.store(bank, addr, value),
-                _ => panic!("invalid store: ${:02X} to ${:02X}:{:04X}", value, bank, addr)
+                0x6000 ... 0xffff => self.store_rom(bank, addr, value),
+                // Nothing is mapped here, the write has no effect
+                _ => self.error(ErrorKind::InvalidStore(value), Component::Cpu, bank, addr),
             },
             // WRAM main banks
             0x7e | 0x7f => self.wram[(bank as usize - 0x7e) * 65536 + addr as usize] = value,
-            0x40 ... 0x7d | 0xc0 ... 0xff => self.rom.store(bank, addr, value),
+            0x40 ... 0x7d | 0xc0 ... 0xff => self.store_rom(bank, addr, value),
             _ => unreachable!(),    // Rust should know this!
         }
     }
 }
 
+/// Callbacks into external tools (debuggers, scripts, ...) that are invoked at well-defined points
+/// during emulation.
+///
+/// All methods have empty default implementations, so implementors only need to override the
+/// callbacks they're interested in. Install an implementation with `Snes::set_hooks`.
+pub trait Hooks {
+    /// Called when a frame has been completed, right before it is passed to the renderer. The
+    /// frame buffer (`snes.peripherals_mut().ppu.framebuf`) may be modified here.
+    fn frame_done(&mut self, _snes: &mut Snes) {}
+
+    /// Called after an instruction wrote `value` to an address registered with
+    /// `Peripherals::watch_writes`.
+    fn watched_write(&mut self, _snes: &mut Snes, _addr: u32, _value: u8) {}
+}
+
 /// SNES system state
 ///
 /// Contains all registers, RAMs, cartridge memory, timing information, latches, flip-flops, etc.
@@ -333,25 +582,242 @@ pub struct Snes {
     apu_master_cy_debt: i32,
     /// Master clock cycles for the PPU not yet accounted for (can be negative)
     ppu_master_cy_debt: i32,
+    /// Master clock cycles for the ALU not yet accounted for (less than a CPU cycle)
+    alu_master_cy_debt: i32,
     /// Master cycle at which the emulator should enable CPU and APU tracing. This will print all
     /// opcodes as they are executed (as long as the `trace` log level is enabled).
     trace_start: u64,
+    /// External callbacks, if installed
+    hooks: Option<Box<Hooks>>,
+    /// Execution profiler, if enabled
+    profiler: Option<Profiler>,
+    /// What to do when the emulated program causes an error
+    error_policy: ErrorPolicy,
+    /// Diagnostics collected from all components, and errors with `ErrorPolicy::Continue`
+    diagnostics: Report,
+    /// Cheats of the loaded game
+    cheats: CheatList,
+    /// Whether the enabled cheats are applied
+    cheats_enabled: bool,
+    /// Values written to RAM every frame
+    freezes: Vec<Freeze>,
 }
 
-impl_save_state!(Snes { cpu, master_cy, apu_master_cy_debt, ppu_master_cy_debt }
-    ignore { trace_start });
+impl_save_state!(Snes { cpu, master_cy, apu_master_cy_debt, ppu_master_cy_debt,
+    alu_master_cy_debt }
+    ignore { trace_start, hooks, profiler, error_policy, diagnostics,
+    cheats, cheats_enabled, freezes });
 
 impl Snes {
+    /// Creates an SNES with the given cartridge. The console region is detected from the ROM
+    /// header, use `set_region` to override it.
     pub fn new(rom: Rom) -> Self {
-        Snes {
+        let region = Region::for_rom(rom.info());
+        let mut snes = Snes {
             cpu: Cpu::new(Peripherals::new(rom, Input::default())),
             master_cy: 0,
             apu_master_cy_debt: 0,
             ppu_master_cy_debt: 0,
+            alu_master_cy_debt: 0,
             trace_start: !0,
+            hooks: None,
+            profiler: None,
+            error_policy: ErrorPolicy::default(),
+            diagnostics: Report::default(),
+            cheats: CheatList::new(),
+            cheats_enabled: true,
+            freezes: Vec::new(),
+        };
+        snes.set_region(region);
+        snes
+    }
+
+    pub fn region(&self) -> Region { self.cpu.mem.ppu.region() }
+
+    /// Switches the console to NTSC or PAL timing.
+    pub fn set_region(&mut self, region: Region) {
+        info!("using {} timing ({:.2} Hz)", region.name(), region.refresh_rate());
+        self.cpu.mem.ppu.set_region(region);
+        self.cpu.mem.rom.set_master_clock_freq(region.master_clock_freq());
+    }
+
+    /// Starts profiling CPU execution (see the `profiler` module). Any previously collected data
+    /// is discarded.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    /// Starts recording the events of a single frame (see the `event_log` module), after
+    /// `skip_frames` frames have passed. Any previously recorded events are discarded.
+    pub fn record_events(&mut self, skip_frames: u32) {
+        self.cpu.mem.events = Some(EventLog::new(skip_frames));
+    }
+
+    /// Installs a writer that receives a trace line for every executed CPU instruction, or removes
+    /// it when passed `None`.
+    pub fn set_trace_writer(&mut self, writer: Option<TraceWriter>) {
+        self.cpu.trace_writer = writer;
+    }
+
+    /// Returns the debug symbols used to annotate traces and profiles.
+    pub fn symbols(&self) -> &Symbols { &self.cpu.symbols }
+
+    /// Returns the debug symbols mutably, so that more symbols can be loaded.
+    pub fn symbols_mut(&mut self) -> &mut Symbols { &mut self.cpu.symbols }
+
+    /// Returns the profiler, if profiling is enabled.
+    pub fn profiler(&self) -> Option<&Profiler> { self.profiler.as_ref() }
+
+    /// Disables profiling and returns the profiler with the collected data.
+    pub fn take_profiler(&mut self) -> Option<Profiler> { self.profiler.take() }
+
+    pub fn error_policy(&self) -> ErrorPolicy { self.error_policy }
+
+    /// Sets what to do when the emulated program causes an error (see the `error` module).
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Handles the errors recorded while executing the CPU instruction at `pc` (and while running
+    /// the other components afterwards). With `ErrorPolicy::Stop`, the first error is returned.
+    fn handle_errors(&mut self, pc: u32) -> Result<(), Error> {
+        let errors = mem::replace(&mut self.cpu.mem.errors, Vec::new());
+        for mut error in errors {
+            // APU errors come with the SPC700's PC
+            if error.component != Component::Apu {
+                error.pc = pc;
+            }
+            match self.error_policy {
+                ErrorPolicy::Stop => return Err(error),
+                ErrorPolicy::Continue => {
+                    self.diagnostics.add(error.component, error.kind.name(), 1, error.pc,
+                        format_args!("{}", error));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the diagnostics the emulated program caused so far (see the `diagnostics` module).
+    pub fn diagnostics(&self) -> &Report { &self.diagnostics }
+
+    /// Discards all diagnostics collected so far.
+    pub fn clear_diagnostics(&mut self) {
+        self.diagnostics.clear();
+    }
+
+    /// Moves the diagnostics recorded by the components into the report. Diagnostics of the
+    /// CPU-side components are attributed to the instruction at `pc`.
+    fn collect_diagnostics(&mut self, pc: u32) {
+        let mem = &mut self.cpu.mem;
+        let report = &mut self.diagnostics;
+        if !mem.diagnostics.is_empty() {
+            report.collect(Component::Cpu, &mut mem.diagnostics, pc);
+        }
+        if !mem.ppu.diagnostics.is_empty() {
+            report.collect(Component::Ppu, &mut mem.ppu.diagnostics, pc);
+        }
+        if !mem.input.diagnostics.is_empty() {
+            report.collect(Component::Input, &mut mem.input.diagnostics, pc);
+        }
+        if let Some(diagnostics) = mem.rom.diagnostics_mut() {
+            if !diagnostics.is_empty() {
+                report.collect(Component::Cartridge, diagnostics, pc);
+            }
+        }
+        for diag in mem.apu.take_diagnostics() {
+            report.add(Component::Apu, diag.kind, diag.count, diag.pc as u32,
+                format_args!("{}", diag.message));
+        }
+    }
+
+    /// Returns the cheats of the game (see the `cheat` module).
+    pub fn cheats(&self) -> &CheatList { &self.cheats }
+
+    /// Replaces the cheat list.
+    pub fn set_cheats(&mut self, cheats: CheatList) {
+        self.cheats = cheats;
+        self.update_cheats();
+    }
+
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.add(cheat);
+        self.update_cheats();
+    }
+
+    /// Enables or disables the cheat at `index` in the cheat list.
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) {
+        self.cheats.set_enabled(index, enabled);
+        self.update_cheats();
+    }
+
+    pub fn cheats_enabled(&self) -> bool { self.cheats_enabled }
+
+    /// Turns all cheats on or off, without changing which cheats are enabled.
+    pub fn set_cheats_enabled(&mut self, enabled: bool) {
+        self.cheats_enabled = enabled;
+        self.update_cheats();
+    }
+
+    /// Passes the codes the cartridge applies to the `Rom`.
+    fn update_cheats(&mut self) {
+        let codes = if self.cheats_enabled { self.cheats.rom_codes() } else { Vec::new() };
+        self.cpu.mem.rom.set_cheats(codes);
+    }
+
+    /// Returns the contents of one of the RAMs (see the `ram_search` module).
+    pub fn memory(&self, memory: Memory) -> &[u8] { self.cpu.mem.memory(memory) }
+
+    pub fn memory_mut(&mut self, memory: Memory) -> &mut [u8] { self.cpu.mem.memory_mut(memory) }
+
+    /// Returns the values that are written to RAM every frame.
+    pub fn freezes(&self) -> &[Freeze] { &self.freezes }
+
+    /// Writes `freeze` to its RAM every frame, starting with the next one.
+    pub fn freeze(&mut self, freeze: Freeze) {
+        self.freezes.push(freeze);
+    }
+
+    /// Stops writing the freeze at `index` (panics if it doesn't exist).
+    pub fn unfreeze(&mut self, index: usize) -> Freeze {
+        self.freezes.remove(index)
+    }
+
+    /// 24-bit address of the next instruction to be executed
+    fn pc24(&self) -> u32 { (self.cpu.pbr as u32) << 16 | self.cpu.pc as u32 }
+
+    /// Installs the given `Hooks`, replacing any previously installed ones.
+    pub fn set_hooks(&mut self, hooks: Box<Hooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Removes and returns the installed `Hooks`.
+    pub fn take_hooks(&mut self) -> Option<Box<Hooks>> {
+        self.hooks.take()
+    }
+
+    /// Calls `f` with the installed hooks (if any). The hooks are removed from `self` while `f`
+    /// runs, so they can be passed a mutable reference to the emulator.
+    fn with_hooks<F>(&mut self, f: F) where F: FnOnce(&mut Hooks, &mut Snes) {
+        if let Some(mut hooks) = self.hooks.take() {
+            f(&mut *hooks, self);
+            // Don't overwrite hooks the callback might have installed
+            if self.hooks.is_none() {
+                self.hooks = Some(hooks);
+            }
         }
     }
 
+    /// Reports pending writes to watched addresses to the hooks.
+    fn report_watched_writes(&mut self) {
+        let writes = mem::replace(&mut self.cpu.mem.pending_writes, Vec::new());
+        self.with_hooks(|hooks, snes| {
+            for (addr, value) in writes {
+                hooks.watched_write(snes, addr, value);
+            }
+        });
+    }
+
     /// Get a reference to the `Peripherals` instance
     pub fn peripherals(&self) -> &Peripherals { &self.cpu.mem }
 
@@ -360,10 +826,11 @@ impl Snes {
 
     /// Runs emulation until the next frame is completed.
     pub fn render_frame<F>(&mut self, mut render: F) -> BackendResult<Vec<BackendAction>>
-    where F: FnMut(&FrameBuf) -> BackendResult<Vec<BackendAction>> {
-        /// Approximated APU clock divider. It's actually somewhere around 20.9..., which is why we
-        /// can't directly use `MASTER_CLOCK_FREQ / APU_CLOCK_FREQ` (it would round down, which
-        /// might not be critical, but better safe than sorry).
+    where F: FnMut(&[u8]) -> BackendResult<Vec<BackendAction>> {
+        /// Approximated APU clock divider. It's actually somewhere around 20.9... (20.7... on PAL
+        /// consoles, the APU clock is the same in both regions), which is why we can't directly use
+        /// `Region::master_clock_freq() / APU_CLOCK_FREQ` (it would round down, which might not be
+        /// critical, but better safe than sorry).
         const APU_DIVIDER: i32 = 21;
 
         let working_cy = LogOnPanic::new("cycle count", self.master_cy);
@@ -378,10 +845,25 @@ impl Snes {
                 self.cpu.mem.apu.trace = true;
             }
 
+            let pc = self.pc24();
+            // The profiler needs to know the opcode to track subroutine calls
+            let opcode = match self.profiler {
+                Some(_) => self.cpu.mem.peek(self.cpu.pbr, self.cpu.pc),
+                None => None,
+            };
+            if self.cpu.mem.events.is_some() {
+                self.cpu.mem.event_pc = pc;
+            }
+
             // Run a CPU instruction and calculate the master cycles elapsed
-            let cpu_master_cy = self.cpu.dispatch() as i32 * CPU_CYCLE + self.cpu.mem.cy as i32;
+            let cpu_cycles = self.cpu.dispatch();
+            let cpu_master_cy = cpu_cycles as i32 * CPU_CYCLE + self.cpu.mem.cy as i32;
             self.cpu.mem.cy = 0;
 
+            if !self.cpu.mem.pending_writes.is_empty() {
+                self.report_watched_writes();
+            }
+
             // In case the CPU did no work, we pretend that it still took a few cycles. This happens
             // if a WAI instruction was executed and the CPU is doing nothing while waiting for an
             // interrupt. We need to emulate the rest of the SNES to some degree or everything
@@ -389,6 +871,22 @@ impl Snes {
             let cpu_master_cy = cmp::max(3, cpu_master_cy); // HACK: Use at least 3 master cycles
             self.master_cy += cpu_master_cy as u64;
 
+            // The ALU computes one bit per CPU cycle, and also while the CPU is stalled
+            self.alu_master_cy_debt += cpu_master_cy;
+            self.cpu.mem.alu.run((self.alu_master_cy_debt / CPU_CYCLE) as u32);
+            self.alu_master_cy_debt %= CPU_CYCLE;
+
+            // Let cartridge coprocessors catch up
+            self.cpu.mem.rom.run_coprocessor(cpu_master_cy as u32);
+
+            let new_pc = self.pc24();
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.record(pc, opcode, cpu_master_cy as u64, new_pc, self.cpu.s);
+            }
+
+            // Interrupt that was raised while catching up with the PPU
+            let mut interrupt = None;
+
             // Now we "owe" the other components a few cycles:
             self.apu_master_cy_debt += cpu_master_cy;
             self.ppu_master_cy_debt += cpu_master_cy;
@@ -399,44 +897,68 @@ impl Snes {
                 // only run it if we owe it `APU_DIVIDER` master cycles - or one SPC700 cycle)
                 let apu_master_cy = self.cpu.mem.apu.dispatch() as i32 * APU_DIVIDER;
                 self.apu_master_cy_debt -= apu_master_cy;
+                if let Some(fault) = self.cpu.mem.apu.take_fault() {
+                    let kind = match fault.value {
+                        Some(value) => ErrorKind::InvalidStore(value),
+                        None => ErrorKind::InvalidLoad,
+                    };
+                    let mut error = Error::new(kind, Component::Apu, fault.addr as u32);
+                    error.pc = fault.pc as u32;
+                    self.cpu.mem.errors.push(error);
+                }
             }
             while self.ppu_master_cy_debt > 0 {
                 let cy = self.cpu.mem.ppu.update();
                 self.ppu_master_cy_debt -= cy as i32;
 
                 let (v, h) = (self.cpu.mem.ppu.v_counter(), self.cpu.mem.ppu.h_counter());
+                // Last visible scanline (224 or 239, depending on overscan)
+                let height = self.cpu.mem.ppu.screen_height();
                 match (v, h) {
-                    (0, 0) => self.cpu.mem.nmi = false,
+                    (0, 0) => {
+                        self.cpu.mem.nmi = false;
+                        let scanlines = self.cpu.mem.ppu.region().scanlines();
+                        if let Some(ref mut log) = self.cpu.mem.events {
+                            log.frame_start(scanlines, height);
+                        }
+                    }
                     (0, 6) => {
                         let channels = self.cpu.mem.hdmaen;
                         self.cpu.mem.cy += init_hdma(&mut self.cpu.mem, channels);
                     }
-                    (0 ... 224, 278) => {
-                        // FIXME: 224 or 239, depending on overscan
+                    (_, 278) if v <= height => {
                         let channels = self.cpu.mem.hdmaen;
                         self.cpu.mem.cy += do_hdma(&mut self.cpu.mem, channels);
                     }
-                    (224, 256) => {
+                    (_, 256) if v == height => {
                         // Last pixel in the current frame was rendered
-                        for action in try!(render(&self.cpu.mem.ppu.framebuf)) {
+                        self.with_hooks(|hooks, snes| hooks.frame_done(snes));
+                        for action in try!(render(self.cpu.mem.ppu.frame())) {
                             actions.push(action);
                         }
                         frame_rendered = true;
                     }
-                    (225, 0) => {
+                    (_, 0) if v == height + 1 => {
                         // First V-Blank pixel
                         self.cpu.mem.input.new_frame();
+                        if self.cheats_enabled && !self.cheats.is_empty() {
+                            self.cheats.apply_to_wram(&mut self.cpu.mem.wram[..]);
+                        }
+                        for freeze in &self.freezes {
+                            freeze.apply(self.cpu.mem.memory_mut(freeze.memory));
+                        }
 
                         // FIXME This timing is wrong, the NMI flag is set later
                         self.cpu.mem.nmi = true;
                         if self.cpu.mem.nmi_enabled() {
                             self.cpu.trigger_nmi();
+                            interrupt = Some(EntryKind::Nmi);
                             // XXX Break to handle the NMI immediately. Let's hope we don't owe the PPU
                             // too many cycles.
                             break;
                         }
                     }
-                    (225, 50) => {
+                    (_, 50) if v == height + 1 => {
                         // Auto-Joypad read
                         // "This begins between dots 32.5 and 95.5 of the first V-Blank scanline,
                         // and ends 4224 master cycles later."
@@ -458,18 +980,44 @@ impl Snes {
                     if cpu.mem.ppu.v_counter() == cpu.mem.vtime && cpu.mem.v_irq_enabled() {
                         //trace!("V-IRQ at V={}", cpu.mem.ppu.v_counter());
                         cpu.mem.irq = true;
-                        cpu.trigger_irq();
+                        if cpu.trigger_irq() {
+                            interrupt = Some(EntryKind::Irq);
+                        }
                         break;
                     }
                     if cpu.mem.ppu.h_counter() == cpu.mem.htime && cpu.mem.h_irq_enabled() {
                         //trace!("H-IRQ at H={}", cpu.mem.ppu.h_counter());
                         cpu.mem.irq = true;
-                        cpu.trigger_irq();
+                        if cpu.trigger_irq() {
+                            interrupt = Some(EntryKind::Irq);
+                        }
                         break;
                     }
                 }
             }
 
+            // Cartridge coprocessors hold the IRQ line until the game acknowledges the interrupt
+            // in one of their registers, so the IRQ is raised again after the handler returns.
+            if interrupt.is_none() && self.cpu.mem.rom.irq_pending() && self.cpu.trigger_irq() {
+                interrupt = Some(EntryKind::Irq);
+            }
+
+            if let Some(kind) = interrupt {
+                self.cpu.mem.log_event(match kind {
+                    EntryKind::Nmi => EventKind::Nmi,
+                    _ => EventKind::Irq,
+                });
+                let new_pc = self.pc24();
+                if let Some(ref mut profiler) = self.profiler {
+                    profiler.interrupt(new_pc, kind, self.cpu.s);
+                }
+            }
+
+            if !self.cpu.mem.errors.is_empty() {
+                try!(self.handle_errors(pc));
+            }
+            self.collect_diagnostics(pc);
+
             if frame_rendered { return Ok(actions); }
 
             working_cy.set(self.master_cy);
@@ -484,6 +1032,8 @@ pub struct Emulator<R: Renderer, A: AudioSink> {
     /// The audio sink to be used for APU output
     pub audio: A,
     pub snes: Snes,
+    /// The save file for the cartridge RAM (if it should be saved)
+    pub sram: Option<SramFile>,
     #[allow(dead_code)]
     priv_: (),
 }
@@ -520,6 +1070,7 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
             renderer: renderer,
             audio: audio,
             snes: snes,
+            sram: None,
             priv_: (),
         }
     }
@@ -550,6 +1101,11 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
                     info!("restored save state");
                 }
             }
+            BackendAction::ToggleCheats => {
+                let enabled = !self.snes.cheats_enabled();
+                self.snes.set_cheats_enabled(enabled);
+                info!("cheats {}", if enabled { "enabled" } else { "disabled" });
+            }
         }
 
         false
@@ -562,9 +1118,15 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
     pub fn render_frame(&mut self) -> BackendResult<bool> {
         let actions = {
             let renderer = &mut self.renderer;
-            self.snes.render_frame(|framebuf| renderer.render(&**framebuf))
+            self.snes.render_frame(|frame| renderer.render(frame))
         };
 
+        if let Some(ref mut sram) = self.sram {
+            if let Err(e) = sram.update(&self.snes.cpu.mem.rom) {
+                error!("couldn't save cartridge RAM: {}", e);
+            }
+        }
+
         for action in try!(actions) {
             if self.handle_action(action) { return Ok(true); }
         }
@@ -577,7 +1139,25 @@ impl<R: Renderer, A: AudioSink> Emulator<R, A> {
     /// This will emulate the system and render frames until the backend signals that the emulator
     /// should exit.
     pub fn run(&mut self) -> BackendResult<()> {
-        while !try!(self.render_frame()) {}
+        loop {
+            match self.render_frame() {
+                Ok(false) => {}
+                Ok(true) => break,
+                Err(e) => {
+                    // Don't lose the game's progress when emulation stops because of an error
+                    try!(self.flush_sram());
+                    return Err(e);
+                }
+            }
+        }
+        self.flush_sram()
+    }
+
+    /// Writes the cartridge RAM to the save file if it was modified since it was last saved.
+    pub fn flush_sram(&mut self) -> BackendResult<()> {
+        if let Some(ref mut sram) = self.sram {
+            try!(sram.flush(&self.snes.cpu.mem.rom));
+        }
         Ok(())
     }
 }
@@ -0,0 +1,265 @@
+//! Battery-backed cartridge RAM persistence
+//!
+//! The battery-backed part of the cartridge RAM (see `Rom::sram`) is stored in a `.srm` file next
+//! to the ROM. The file contains the raw RAM contents, like the `.srm` files of Snes9x and bsnes,
+//! so they can be exchanged with these emulators. If the cartridge contains a real-time clock, its
+//! state is appended to the RAM (see `Rom::rtc_data`).
+//!
+//! Saving is enabled by setting `Emulator::sram` to `Some(SramFile)`. The file is written when the
+//! emulator exits and whenever the game has stopped modifying the RAM (or setting the clock) for a
+//! few seconds.
+
+use rom::Rom;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of frames the RAM must stay unmodified before it's written to the file (~3 seconds)
+const SAVE_DELAY_FRAMES: u32 = 180;
+
+/// A `.srm` file that is kept in sync with the cartridge RAM
+pub struct SramFile {
+    path: PathBuf,
+    /// The RAM contents at the end of the last frame
+    last: Vec<u8>,
+    /// `Rom::rtc_adjustments` at the end of the last frame. The clock keeps running, so instead of
+    /// comparing the clock data we check whether it was set.
+    last_rtc_adjustments: u32,
+    /// Number of frames since the RAM was last modified, or `None` if it's unchanged since it was
+    /// written to the file
+    idle_frames: Option<u32>,
+}
+
+impl SramFile {
+    /// Opens the save file at `path`, loading its contents into the cartridge RAM of `rom` (if
+    /// the file exists).
+    pub fn open<P: AsRef<Path>>(path: P, rom: &mut Rom) -> io::Result<Self> {
+        let path = path.as_ref();
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut data = Vec::new();
+                try!(file.read_to_end(&mut data));
+                if data.len() < rom.sram().len() {
+                    warn!("save file '{}' is too small ({} bytes, expected {})",
+                        path.display(), data.len(), rom.sram().len());
+                }
+                rom.load_sram(&data);
+
+                if data.len() > rom.sram().len() {
+                    let rtc_data = &data[rom.sram().len()..];
+                    if !rom.load_rtc_data(rtc_data) {
+                        warn!("ignoring invalid clock data in save file '{}'", path.display());
+                    }
+                }
+                info!("loaded cartridge RAM from '{}'", path.display());
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(SramFile::new(path, rom))
+    }
+
+    /// Creates a save file at `path` without loading it, so the cartridge RAM of `rom` keeps its
+    /// current contents. An existing file is overwritten once the game modifies the RAM.
+    pub fn new<P: AsRef<Path>>(path: P, rom: &Rom) -> Self {
+        SramFile {
+            path: path.as_ref().to_owned(),
+            last: rom.sram().to_vec(),
+            last_rtc_adjustments: rom.rtc_adjustments(),
+            idle_frames: None,
+        }
+    }
+
+    /// Called after every frame. Writes the RAM to the file if it was modified, but hasn't changed
+    /// for `SAVE_DELAY_FRAMES` frames.
+    pub fn update(&mut self, rom: &Rom) -> io::Result<()> {
+        if self.is_modified(rom) {
+            self.last.copy_from_slice(rom.sram());
+            self.last_rtc_adjustments = rom.rtc_adjustments();
+            self.idle_frames = Some(0);
+            return Ok(());
+        }
+
+        match self.idle_frames {
+            Some(frames) if frames + 1 >= SAVE_DELAY_FRAMES => self.write(rom),
+            Some(frames) => {
+                self.idle_frames = Some(frames + 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the RAM to the file if it was modified since it was last written.
+    pub fn flush(&mut self, rom: &Rom) -> io::Result<()> {
+        if self.is_modified(rom) || self.idle_frames.is_some() {
+            self.write(rom)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns `true` if the RAM or the clock changed since the end of the last frame.
+    fn is_modified(&self, rom: &Rom) -> bool {
+        rom.sram() != &self.last[..] || rom.rtc_adjustments() != self.last_rtc_adjustments
+    }
+
+    fn write(&mut self, rom: &Rom) -> io::Result<()> {
+        let mut file = try!(File::create(&self.path));
+        try!(file.write_all(rom.sram()));
+        if let Some(rtc_data) = rom.rtc_data() {
+            try!(file.write_all(&rtc_data));
+        }
+        self.last.copy_from_slice(rom.sram());
+        self.last_rtc_adjustments = rom.rtc_adjustments();
+        self.idle_frames = None;
+        debug!("wrote cartridge RAM to '{}'", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coprocessor::rtc::DateTime;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Creates a 32 KB LoROM image with 2 KB of battery-backed RAM and the given chipset.
+    fn rom(chipset: u8) -> Rom {
+        let mut bytes = vec![0; 0x8000];
+        {
+            let header = &mut bytes[0x7fc0..0x7fe0];
+            header[..21].copy_from_slice(b"SRAM TEST            ");
+            header[21] = 0x20;
+            header[22] = chipset;
+            header[23] = 0x05;
+            header[24] = 0x01;
+            header[28] = 0xff;
+            header[29] = 0xff;
+        }
+        Rom::from_bytes(&bytes).unwrap()
+    }
+
+    /// Returns a path for a save file that doesn't exist yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("breeze-test-{}-{}.srm", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read(path: &Path) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn open() {
+        let path = temp_path("open");
+        let mut rom = rom(0x02);
+        assert_eq!(rom.sram().len(), 0x800);
+
+        // A missing file leaves the RAM unchanged and isn't created until the RAM is modified
+        let mut sram = SramFile::open(&path, &mut rom).unwrap();
+        sram.flush(&rom).unwrap();
+        assert!(!path.exists());
+
+        // A short file only overwrites the start of the RAM
+        File::create(&path).unwrap().write_all(&[1, 2, 3]).unwrap();
+        rom.store(0x70, 3, 4);
+        SramFile::open(&path, &mut rom).unwrap();
+        assert_eq!(&rom.sram()[..5], &[1, 2, 3, 4, 0]);
+
+        // `new` doesn't load the file
+        let mut rom = self::rom(0x02);
+        SramFile::new(&path, &rom);
+        assert_eq!(&rom.sram()[..3], &[0, 0, 0]);
+        SramFile::open(&path, &mut rom).unwrap();
+        assert_eq!(&rom.sram()[..3], &[1, 2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update() {
+        let path = temp_path("update");
+        let mut rom = rom(0x02);
+        let mut sram = SramFile::open(&path, &mut rom).unwrap();
+
+        // The file is written once the RAM stays unmodified for `SAVE_DELAY_FRAMES` frames
+        rom.store(0x70, 0, 1);
+        for _ in 0..SAVE_DELAY_FRAMES {
+            sram.update(&rom).unwrap();
+            assert!(!path.exists());
+        }
+        sram.update(&rom).unwrap();
+        let data = read(&path);
+        assert_eq!(data.len(), 0x800);
+        assert_eq!(data[0], 1);
+
+        // Modifications restart the delay
+        rom.store(0x70, 1, 2);
+        sram.update(&rom).unwrap();
+        for _ in 0..SAVE_DELAY_FRAMES / 2 { sram.update(&rom).unwrap(); }
+        rom.store(0x70, 1, 3);
+        for _ in 0..SAVE_DELAY_FRAMES { sram.update(&rom).unwrap(); }
+        assert_eq!(read(&path)[1], 0);
+        sram.update(&rom).unwrap();
+        assert_eq!(read(&path)[1], 3);
+
+        // Unmodified RAM isn't written again
+        fs::remove_file(&path).unwrap();
+        for _ in 0..SAVE_DELAY_FRAMES * 2 { sram.update(&rom).unwrap(); }
+        sram.flush(&rom).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn flush() {
+        let path = temp_path("flush");
+        let mut rom = rom(0x02);
+        let mut sram = SramFile::open(&path, &mut rom).unwrap();
+
+        // Pending and unseen modifications are written immediately
+        rom.store(0x70, 0, 1);
+        sram.update(&rom).unwrap();
+        sram.flush(&rom).unwrap();
+        assert_eq!(read(&path)[0], 1);
+        rom.store(0x70, 0, 2);
+        sram.flush(&rom).unwrap();
+        assert_eq!(read(&path)[0], 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn clock_data() {
+        let path = temp_path("clock");
+        // S-RTC
+        let mut rom = rom(0x55);
+        let mut sram = SramFile::open(&path, &mut rom).unwrap();
+
+        // Setting the clock counts as a modification, its state is appended to the RAM
+        rom.set_rtc_time(DateTime { year: 2001, month: 2, day: 3, ..DateTime::default() });
+        rom.store(0x70, 0, 1);
+        sram.flush(&rom).unwrap();
+        let data = read(&path);
+        assert_eq!(data.len(), 0x800 + 16);
+        assert_eq!(&data[0x800..0x804], &[0xd1, 0x07, 2, 3]);
+
+        let mut rom = self::rom(0x55);
+        SramFile::open(&path, &mut rom).unwrap();
+        assert_eq!(rom.sram()[0], 1);
+        assert_eq!(&rom.rtc_data().unwrap()[..4], &[0xd1, 0x07, 2, 3]);
+
+        // Invalid clock data is ignored
+        File::create(&path).unwrap().write_all(&[0; 0x800 + 3]).unwrap();
+        SramFile::open(&path, &mut rom).unwrap();
+        assert_eq!(rom.sram()[0], 0);
+        assert_eq!(&rom.rtc_data().unwrap()[..4], &[0xd1, 0x07, 2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+}
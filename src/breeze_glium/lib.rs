@@ -129,16 +129,22 @@ impl Renderer for GliumRenderer {
     }
 
     fn render(&mut self, frame_data: &[u8]) -> BackendResult<Vec<BackendAction>> {
+        // The frame is 224 or 239 lines high. The texture is recreated when that changes.
+        let height = frame_data.len() as u32 / (SCREEN_WIDTH * 3);
+        if self.texture.get_height() != Some(height) {
+            self.texture = try!(SrgbTexture2d::empty(&self.display, SCREEN_WIDTH, height));
+        }
+
         // upload new texture data
         self.texture.write(Rect {
             left: 0,
             bottom: 0,
             width: SCREEN_WIDTH,
-            height: SCREEN_HEIGHT,
+            height: height,
         }, RawImage2d {
             data: Cow::Borrowed(frame_data),
             width: SCREEN_WIDTH,
-            height: SCREEN_HEIGHT,
+            height: height,
             format: ClientFormat::U8U8U8,
         });
 
@@ -0,0 +1,95 @@
+//! A tiny 3x5 pixel font used to draw text on top of the emulator output.
+//!
+//! Only upper case glyphs exist, lower case letters are drawn as upper case ones.
+
+/// Width of a glyph in pixels
+pub const GLYPH_WIDTH: u32 = 3;
+/// Height of a glyph in pixels
+pub const GLYPH_HEIGHT: u32 = 5;
+
+/// Glyphs for the printable ASCII characters `' '` to `'~'`, without lower case letters.
+///
+/// Each glyph is made of 5 rows (top to bottom). The 3 lowest bits of each row are the pixels
+/// (bit 2 is the leftmost one).
+static GLYPHS: [[u8; 5]; 69] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000],    // ' '
+    [0b010, 0b010, 0b010, 0b000, 0b010],    // '!'
+    [0b101, 0b101, 0b000, 0b000, 0b000],    // '"'
+    [0b101, 0b111, 0b101, 0b111, 0b101],    // '#'
+    [0b011, 0b110, 0b010, 0b011, 0b110],    // '$'
+    [0b101, 0b001, 0b010, 0b100, 0b101],    // '%'
+    [0b010, 0b101, 0b010, 0b101, 0b011],    // '&'
+    [0b010, 0b010, 0b000, 0b000, 0b000],    // '''
+    [0b001, 0b010, 0b010, 0b010, 0b001],    // '('
+    [0b100, 0b010, 0b010, 0b010, 0b100],    // ')'
+    [0b000, 0b101, 0b010, 0b101, 0b000],    // '*'
+    [0b000, 0b010, 0b111, 0b010, 0b000],    // '+'
+    [0b000, 0b000, 0b000, 0b010, 0b100],    // ','
+    [0b000, 0b000, 0b111, 0b000, 0b000],    // '-'
+    [0b000, 0b000, 0b000, 0b000, 0b010],    // '.'
+    [0b001, 0b001, 0b010, 0b100, 0b100],    // '/'
+    [0b111, 0b101, 0b101, 0b101, 0b111],    // '0'
+    [0b010, 0b110, 0b010, 0b010, 0b111],    // '1'
+    [0b111, 0b001, 0b111, 0b100, 0b111],    // '2'
+    [0b111, 0b001, 0b111, 0b001, 0b111],    // '3'
+    [0b101, 0b101, 0b111, 0b001, 0b001],    // '4'
+    [0b111, 0b100, 0b111, 0b001, 0b111],    // '5'
+    [0b111, 0b100, 0b111, 0b101, 0b111],    // '6'
+    [0b111, 0b001, 0b001, 0b001, 0b001],    // '7'
+    [0b111, 0b101, 0b111, 0b101, 0b111],    // '8'
+    [0b111, 0b101, 0b111, 0b001, 0b111],    // '9'
+    [0b000, 0b010, 0b000, 0b010, 0b000],    // ':'
+    [0b000, 0b010, 0b000, 0b010, 0b100],    // ';'
+    [0b001, 0b010, 0b100, 0b010, 0b001],    // '<'
+    [0b000, 0b111, 0b000, 0b111, 0b000],    // '='
+    [0b100, 0b010, 0b001, 0b010, 0b100],    // '>'
+    [0b111, 0b001, 0b011, 0b000, 0b010],    // '?'
+    [0b010, 0b101, 0b111, 0b100, 0b011],    // '@'
+    [0b010, 0b101, 0b111, 0b101, 0b101],    // 'A'
+    [0b110, 0b101, 0b110, 0b101, 0b110],    // 'B'
+    [0b011, 0b100, 0b100, 0b100, 0b011],    // 'C'
+    [0b110, 0b101, 0b101, 0b101, 0b110],    // 'D'
+    [0b111, 0b100, 0b110, 0b100, 0b111],    // 'E'
+    [0b111, 0b100, 0b110, 0b100, 0b100],    // 'F'
+    [0b011, 0b100, 0b101, 0b101, 0b011],    // 'G'
+    [0b101, 0b101, 0b111, 0b101, 0b101],    // 'H'
+    [0b111, 0b010, 0b010, 0b010, 0b111],    // 'I'
+    [0b001, 0b001, 0b001, 0b101, 0b010],    // 'J'
+    [0b101, 0b101, 0b110, 0b101, 0b101],    // 'K'
+    [0b100, 0b100, 0b100, 0b100, 0b111],    // 'L'
+    [0b101, 0b111, 0b111, 0b101, 0b101],    // 'M'
+    [0b110, 0b101, 0b101, 0b101, 0b101],    // 'N'
+    [0b010, 0b101, 0b101, 0b101, 0b010],    // 'O'
+    [0b110, 0b101, 0b110, 0b100, 0b100],    // 'P'
+    [0b010, 0b101, 0b101, 0b110, 0b011],    // 'Q'
+    [0b110, 0b101, 0b110, 0b101, 0b101],    // 'R'
+    [0b011, 0b100, 0b010, 0b001, 0b110],    // 'S'
+    [0b111, 0b010, 0b010, 0b010, 0b010],    // 'T'
+    [0b101, 0b101, 0b101, 0b101, 0b111],    // 'U'
+    [0b101, 0b101, 0b101, 0b101, 0b010],    // 'V'
+    [0b101, 0b101, 0b111, 0b111, 0b101],    // 'W'
+    [0b101, 0b101, 0b010, 0b101, 0b101],    // 'X'
+    [0b101, 0b101, 0b010, 0b010, 0b010],    // 'Y'
+    [0b111, 0b001, 0b010, 0b100, 0b111],    // 'Z'
+    [0b110, 0b100, 0b100, 0b100, 0b110],    // '['
+    [0b100, 0b100, 0b010, 0b001, 0b001],    // '\'
+    [0b011, 0b001, 0b001, 0b001, 0b011],    // ']'
+    [0b010, 0b101, 0b000, 0b000, 0b000],    // '^'
+    [0b000, 0b000, 0b000, 0b000, 0b111],    // '_'
+    [0b100, 0b010, 0b000, 0b000, 0b000],    // '`'
+    [0b011, 0b010, 0b110, 0b010, 0b011],    // '{'
+    [0b010, 0b010, 0b010, 0b010, 0b010],    // '|'
+    [0b110, 0b010, 0b011, 0b010, 0b110],    // '}'
+    [0b000, 0b011, 0b110, 0b000, 0b000],    // '~'
+];
+
+/// Returns the glyph used to draw `c`. Characters without a glyph are drawn as `'?'`.
+pub fn glyph(c: char) -> &'static [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    let index = match c {
+        ' ' ... '`' => c as usize - ' ' as usize,
+        '{' ... '~' => c as usize - '{' as usize + 65,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &GLYPHS[index]
+}
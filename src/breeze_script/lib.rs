@@ -0,0 +1,411 @@
+//! Scripting support for Breeze, using the [Rhai](https://rhai.rs) language.
+//!
+//! A script is a Rhai file that's executed once when it is loaded. It can then register callbacks
+//! that are invoked while the emulator runs. The following functions are available to scripts:
+//!
+//! * `read_u8(addr)`, `read_u16(addr)`: Read memory (WRAM or cartridge memory) without side
+//!   effects. Addresses are 24-bit CPU addresses (eg. `0x7E0010`).
+//! * `write_u8(addr, value)`, `write_u16(addr, value)`: Write to memory (this can also modify ROM).
+//! * `press([port,] button)`, `release([port,] button)`: Hold or release a joypad button (`"A"`,
+//!   `"B"`, `"X"`, `"Y"`, `"L"`, `"R"`, `"Start"`, `"Select"`, `"Up"`, `"Down"`, `"Left"` or
+//!   `"Right"`). The port defaults to 0. Held buttons are combined with the user's input.
+//! * `on_frame(fn)`: Call `fn()` after every emulated frame.
+//! * `on_write(addr, fn)`, `on_write(start, end, fn)`: Call `fn(addr, value)` after an instruction
+//!   wrote to the given address (range).
+//! * `save_state(path)`, `load_state(path)`: Create or restore a save state.
+//! * `draw_text(x, y, text[, color])`: Draw text on the current frame. `color` is a `0xRRGGBB`
+//!   value and defaults to white. Text is only drawn for a single frame, so it has to be drawn
+//!   again by each `on_frame` callback.
+//! * `frame_count()`: Number of frames emulated since the script was loaded.
+//!
+//! `print` and `debug` output is sent to the logger.
+
+#![deny(warnings)]
+#![deny(unused_import_braces, unused_qualifications, unused_extern_crates)]
+
+#[macro_use] extern crate log;
+extern crate rhai;
+extern crate breeze_core;
+extern crate breeze_backend;
+
+mod font;
+
+use breeze_core::input::Peripheral;
+use breeze_core::ppu::{MAX_SCREEN_HEIGHT, SCREEN_WIDTH};
+use breeze_core::save::SaveStateFormat;
+use breeze_core::snes::{Hooks, Snes};
+use breeze_backend::input::joypad::{JoypadButton, JoypadImpl, JoypadState};
+
+use rhai::{Engine, EvalAltResult, FnPtr, AST};
+
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::ptr;
+use std::rc::Rc;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Text queued for drawing at the end of the current frame
+struct Text {
+    x: i64,
+    y: i64,
+    text: String,
+    color: u32,
+}
+
+/// State shared between the `Script` and the functions registered with the Rhai engine.
+struct Context {
+    /// The emulator the script may currently access. This is only non-null while `Script::enter`
+    /// runs.
+    snes: Cell<*mut Snes>,
+    frame_callbacks: RefCell<Vec<FnPtr>>,
+    /// Inclusive address range and callback
+    write_callbacks: RefCell<Vec<(u32, u32, FnPtr)>>,
+    texts: RefCell<Vec<Text>>,
+    /// Buttons held by the script, for each controller port
+    held: [Rc<RefCell<Vec<JoypadButton>>>; 2],
+    /// Whether the `ScriptJoypad` was already plugged into the port
+    joypad_attached: [Cell<bool>; 2],
+    frames: Cell<u64>,
+}
+
+impl Context {
+    /// Runs `f` with the emulator the script is attached to.
+    fn with_snes<T, F>(&self, f: F) -> ScriptResult<T> where F: FnOnce(&mut Snes) -> ScriptResult<T> {
+        let snes = self.snes.get();
+        if snes.is_null() {
+            return Err("the emulator can not be accessed right now".into());
+        }
+
+        // The pointer is only set by `Script::enter`, which holds the `&mut Snes` it was created
+        // from and doesn't touch it while the script runs.
+        f(unsafe { &mut *snes })
+    }
+
+    fn read_u8(&self, addr: i64) -> ScriptResult<u8> {
+        let addr = try!(check_addr(addr));
+        self.with_snes(|snes| {
+            snes.peripherals().peek((addr >> 16) as u8, addr as u16).ok_or_else(|| {
+                format!("address ${:02X}:{:04X} can not be read", addr >> 16, addr as u16).into()
+            })
+        })
+    }
+
+    fn write_u8(&self, addr: i64, value: i64) -> ScriptResult<()> {
+        let addr = try!(check_addr(addr));
+        self.with_snes(|snes| {
+            if snes.peripherals_mut().poke((addr >> 16) as u8, addr as u16, value as u8) {
+                Ok(())
+            } else {
+                Err(format!("address ${:02X}:{:04X} can not be written", addr >> 16, addr as u16)
+                    .into())
+            }
+        })
+    }
+
+    fn set_button(&self, port: i64, button: &str, pressed: bool) -> ScriptResult<()> {
+        let button = try!(parse_button(button));
+        let port = match port {
+            0 | 1 => port as u8,
+            _ => return Err(format!("invalid controller port {} (must be 0 or 1)", port).into()),
+        };
+
+        if !self.joypad_attached[port as usize].get() {
+            let held = self.held[port as usize].clone();
+            try!(self.with_snes(|snes| {
+                attach_joypad(snes, port, held);
+                Ok(())
+            }));
+            self.joypad_attached[port as usize].set(true);
+        }
+
+        let mut held = self.held[port as usize].borrow_mut();
+        held.retain(|&b| b != button);
+        if pressed {
+            held.push(button);
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a script-provided address fits in 24 bits.
+fn check_addr(addr: i64) -> ScriptResult<u32> {
+    if addr >= 0 && addr <= 0xffffff {
+        Ok(addr as u32)
+    } else {
+        Err(format!("invalid address {:#X} (must be a 24-bit value)", addr).into())
+    }
+}
+
+fn parse_button(name: &str) -> ScriptResult<JoypadButton> {
+    Ok(match &*name.to_lowercase() {
+        "a" => JoypadButton::A,
+        "b" => JoypadButton::B,
+        "x" => JoypadButton::X,
+        "y" => JoypadButton::Y,
+        "l" => JoypadButton::L,
+        "r" => JoypadButton::R,
+        "start" => JoypadButton::Start,
+        "select" => JoypadButton::Select,
+        "up" => JoypadButton::Up,
+        "down" => JoypadButton::Down,
+        "left" => JoypadButton::Left,
+        "right" => JoypadButton::Right,
+        _ => return Err(format!("unknown joypad button '{}'", name).into()),
+    })
+}
+
+/// Joypad implementation that adds the buttons held by the script to the state reported by the
+/// joypad that was previously plugged into the port (if any).
+struct ScriptJoypad {
+    inner: Option<Box<JoypadImpl>>,
+    held: Rc<RefCell<Vec<JoypadButton>>>,
+}
+
+impl JoypadImpl for ScriptJoypad {
+    fn update_state(&mut self) -> JoypadState {
+        let mut state = match self.inner {
+            Some(ref mut imp) => imp.update_state(),
+            None => JoypadState::new(),
+        };
+        for &button in self.held.borrow().iter() {
+            state.set(button, true);
+        }
+        state
+    }
+}
+
+/// Plugs a `ScriptJoypad` into the given port, wrapping the joypad that's already there.
+fn attach_joypad(snes: &mut Snes, port: u8, held: Rc<RefCell<Vec<JoypadButton>>>) {
+    let ports = &mut snes.peripherals_mut().input.ports;
+    let inner = match ports[port].take() {
+        Some(Peripheral::Joypad { imp, .. }) => Some(imp),
+        None => None,
+    };
+    ports[port] = Some(Peripheral::new_joypad(Box::new(ScriptJoypad {
+        inner: inner,
+        held: held,
+    })));
+}
+
+/// Draws `text` into an `RGB24` frame buffer. A dark shadow is drawn below and to the right of
+/// each glyph to keep the text readable on any background.
+fn draw_text(framebuf: &mut [u8], text: &Text) {
+    fn set_pixel(framebuf: &mut [u8], x: i64, y: i64, color: u32) {
+        if x >= 0 && y >= 0 && x < SCREEN_WIDTH as i64 && y < MAX_SCREEN_HEIGHT as i64 {
+            let start = (y as usize * SCREEN_WIDTH as usize + x as usize) * 3;
+            framebuf[start] = (color >> 16) as u8;
+            framebuf[start + 1] = (color >> 8) as u8;
+            framebuf[start + 2] = color as u8;
+        }
+    }
+
+    for &(offset, color) in &[(1, 0x000000), (0, text.color)] {
+        let (mut x, mut y) = (text.x, text.y);
+        for c in text.text.chars() {
+            if c == '\n' {
+                x = text.x;
+                y += font::GLYPH_HEIGHT as i64 + 1;
+                continue;
+            }
+
+            let glyph = font::glyph(c);
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..font::GLYPH_WIDTH {
+                    if bits & (1 << (font::GLYPH_WIDTH - 1 - col)) != 0 {
+                        set_pixel(framebuf, x + col as i64 + offset, y + row as i64 + offset,
+                            color);
+                    }
+                }
+            }
+            x += font::GLYPH_WIDTH as i64 + 1;
+        }
+    }
+}
+
+/// A loaded script. Install it with `Snes::set_hooks` to run its callbacks.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    ctx: Rc<Context>,
+}
+
+impl Script {
+    /// Loads and runs the script at `path`. The script may access `snes` while it runs.
+    pub fn load(path: &str, snes: &mut Snes) -> Result<Script, Box<Error>> {
+        let ctx = Rc::new(Context {
+            snes: Cell::new(ptr::null_mut()),
+            frame_callbacks: RefCell::new(Vec::new()),
+            write_callbacks: RefCell::new(Vec::new()),
+            texts: RefCell::new(Vec::new()),
+            held: [Rc::new(RefCell::new(Vec::new())), Rc::new(RefCell::new(Vec::new()))],
+            joypad_attached: [Cell::new(false), Cell::new(false)],
+            frames: Cell::new(0),
+        });
+
+        let engine = create_engine(&ctx);
+        let ast = try!(engine.compile_file(PathBuf::from(path)));
+        let script = Script {
+            engine: engine,
+            ast: ast,
+            ctx: ctx,
+        };
+
+        info!("running script '{}'", path);
+        let mut result = Ok(());
+        script.enter(snes, |script| result = script.engine.run_ast(&script.ast));
+        try!(result);
+
+        Ok(script)
+    }
+
+    /// Allows the script to access `snes` while `f` runs.
+    fn enter<F>(&self, snes: &mut Snes, f: F) where F: FnOnce(&Script) {
+        self.ctx.snes.set(snes as *mut Snes);
+        f(self);
+        self.ctx.snes.set(ptr::null_mut());
+    }
+
+    /// Calls a callback registered by the script. Errors are logged, but otherwise ignored.
+    fn call<A: rhai::FuncArgs>(&self, callback: &FnPtr, args: A) {
+        if let Err(e) = callback.call::<rhai::Dynamic>(&self.engine, &self.ast, args) {
+            error!("script error in '{}': {}", callback.fn_name(), e);
+        }
+    }
+}
+
+impl Hooks for Script {
+    fn frame_done(&mut self, snes: &mut Snes) {
+        self.ctx.frames.set(self.ctx.frames.get() + 1);
+
+        // Clone the list, so the callbacks can register new callbacks
+        let callbacks = self.ctx.frame_callbacks.borrow().clone();
+        self.enter(snes, |script| for callback in &callbacks {
+            script.call(callback, ());
+        });
+
+        let framebuf = &mut snes.peripherals_mut().ppu.framebuf;
+        for text in self.ctx.texts.borrow_mut().drain(..) {
+            draw_text(&mut **framebuf, &text);
+        }
+    }
+
+    fn watched_write(&mut self, snes: &mut Snes, addr: u32, value: u8) {
+        let callbacks = self.ctx.write_callbacks.borrow().iter()
+            .filter(|&&(start, end, _)| addr >= start && addr <= end)
+            .map(|&(_, _, ref callback)| callback.clone())
+            .collect::<Vec<_>>();
+        self.enter(snes, |script| for callback in &callbacks {
+            script.call(callback, (addr as i64, value as i64));
+        });
+    }
+}
+
+/// Creates a Rhai `Engine` with all emulator functions registered.
+fn create_engine(ctx: &Rc<Context>) -> Engine {
+    let mut engine = Engine::new();
+    engine.on_print(|s| info!("[script] {}", s));
+    engine.on_debug(|s, _, pos| debug!("[script] {} @ {}", s, pos));
+
+    // Memory access
+    let c = ctx.clone();
+    engine.register_fn("read_u8", move |addr: i64| c.read_u8(addr).map(|b| b as i64));
+    let c = ctx.clone();
+    engine.register_fn("read_u16", move |addr: i64| -> ScriptResult<i64> {
+        let lo = try!(c.read_u8(addr)) as i64;
+        let hi = try!(c.read_u8((addr + 1) & 0xffffff)) as i64;
+        Ok(hi << 8 | lo)
+    });
+    let c = ctx.clone();
+    engine.register_fn("write_u8", move |addr: i64, value: i64| c.write_u8(addr, value));
+    let c = ctx.clone();
+    engine.register_fn("write_u16", move |addr: i64, value: i64| -> ScriptResult<()> {
+        try!(c.write_u8(addr, value & 0xff));
+        c.write_u8((addr + 1) & 0xffffff, (value >> 8) & 0xff)
+    });
+
+    // Input
+    let c = ctx.clone();
+    engine.register_fn("press", move |button: &str| c.set_button(0, button, true));
+    let c = ctx.clone();
+    engine.register_fn("press", move |port: i64, button: &str| c.set_button(port, button, true));
+    let c = ctx.clone();
+    engine.register_fn("release", move |button: &str| c.set_button(0, button, false));
+    let c = ctx.clone();
+    engine.register_fn("release", move |port: i64, button: &str| {
+        c.set_button(port, button, false)
+    });
+
+    // Callbacks
+    let c = ctx.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| {
+        c.frame_callbacks.borrow_mut().push(callback);
+    });
+    let c = ctx.clone();
+    engine.register_fn("on_write", move |start: i64, end: i64, callback: FnPtr| -> ScriptResult<()> {
+        let (start, end) = (try!(check_addr(start)), try!(check_addr(end)));
+        try!(c.with_snes(|snes| {
+            snes.peripherals_mut().watch_writes(start, end);
+            Ok(())
+        }));
+        c.write_callbacks.borrow_mut().push((start, end, callback));
+        Ok(())
+    });
+    let c = ctx.clone();
+    engine.register_fn("on_write", move |addr: i64, callback: FnPtr| -> ScriptResult<()> {
+        let addr = try!(check_addr(addr));
+        try!(c.with_snes(|snes| {
+            snes.peripherals_mut().watch_writes(addr, addr);
+            Ok(())
+        }));
+        c.write_callbacks.borrow_mut().push((addr, addr, callback));
+        Ok(())
+    });
+
+    // Save states
+    let c = ctx.clone();
+    engine.register_fn("save_state", move |path: &str| {
+        c.with_snes(|snes| {
+            let mut file = try!(File::create(path).map_err(|e| e.to_string()));
+            try!(snes.create_save_state(SaveStateFormat::default(), &mut file)
+                .map_err(|e| e.to_string()));
+            info!("script created a save state in '{}'", path);
+            Ok(())
+        })
+    });
+    let c = ctx.clone();
+    engine.register_fn("load_state", move |path: &str| {
+        c.with_snes(|snes| {
+            {
+                let input = &snes.peripherals().input;
+                if input.is_recording() || input.is_replaying() {
+                    return Err("cannot load a save state while recording or replaying input!"
+                        .into());
+                }
+            }
+            let file = try!(File::open(path).map_err(|e| e.to_string()));
+            try!(snes.restore_save_state(SaveStateFormat::default(), &mut BufReader::new(file))
+                .map_err(|e| e.to_string()));
+            info!("script restored save state '{}'", path);
+            Ok(())
+        })
+    });
+
+    // Drawing
+    let c = ctx.clone();
+    engine.register_fn("draw_text", move |x: i64, y: i64, text: &str| {
+        c.texts.borrow_mut().push(Text { x: x, y: y, text: text.to_string(), color: 0xffffff });
+    });
+    let c = ctx.clone();
+    engine.register_fn("draw_text", move |x: i64, y: i64, text: &str, color: i64| {
+        c.texts.borrow_mut().push(Text { x: x, y: y, text: text.to_string(), color: color as u32 });
+    });
+
+    let c = ctx.clone();
+    engine.register_fn("frame_count", move || c.frames.get() as i64);
+
+    engine
+}
@@ -7,7 +7,7 @@ extern crate libc;
 
 use breeze_backend::{BackendAction, BackendResult};
 use breeze_backend::input::joypad::{JoypadImpl, JoypadState, JoypadButton};
-use breeze_backend::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use breeze_backend::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT, MAX_SCREEN_HEIGHT};
 use breeze_backend::viewport::Viewport;
 
 use sdl2::{EventPump, Sdl};
@@ -81,6 +81,9 @@ impl SdlManager {
                 KeyDown { scancode: Some(Scancode::F5), .. } => {
                     return Ok(vec![BackendAction::SaveState]);
                 }
+                KeyDown { scancode: Some(Scancode::F6), .. } => {
+                    return Ok(vec![BackendAction::ToggleCheats]);
+                }
                 KeyDown { scancode: Some(Scancode::F9), .. } => {
                     return Ok(vec![BackendAction::LoadState]);
                 }
@@ -135,7 +138,7 @@ impl ::breeze_backend::Renderer for SdlRenderer {
                 PixelFormatEnum::RGB24,
                 TextureAccess::Static,
                 SCREEN_WIDTH,
-                SCREEN_HEIGHT).map_err(|e| format!("{:?}", e)));    // FIXME missing Error impl
+                MAX_SCREEN_HEIGHT).map_err(|e| format!("{:?}", e)));    // FIXME missing Error impl
 
             let mut this = SdlRenderer {
                 renderer: renderer,
@@ -152,10 +155,14 @@ impl ::breeze_backend::Renderer for SdlRenderer {
             self.resize_to(w, h)
         }
 
+        // The frame is 224 or 239 lines high, only that part of the texture is used
+        let height = frame_data.len() as u32 / (SCREEN_WIDTH * 3);
+        let rect = Rect::new(0, 0, SCREEN_WIDTH, height);
+
         // FIXME Can this be done with fewer copies?
-        self.texture.update(None, frame_data, SCREEN_WIDTH as usize * 3).unwrap();
+        self.texture.update(Some(rect), frame_data, SCREEN_WIDTH as usize * 3).unwrap();
         self.renderer.clear();
-        self.renderer.copy(&self.texture, None, None).unwrap();
+        self.renderer.copy(&self.texture, Some(rect), None).unwrap();
         self.renderer.present();
 
         SDL.with(|sdl| sdl.borrow_mut().update())
@@ -0,0 +1,236 @@
+//! Compares two CPU traces and reports the first divergence.
+//!
+//! Traces can be written by bsnes, Mesen-S or Breeze (`--trace` or the `BREEZE_TRACE` log) and
+//! are parsed with `wdc65816::trace::TraceLine`. Lines without an instruction address are skipped.
+
+#![deny(warnings)]
+#![deny(unused_import_braces, unused_qualifications)]
+
+extern crate clap;
+extern crate wdc65816;
+
+use wdc65816::trace::TraceLine;
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines};
+use std::process;
+
+/// A parsed trace line along with its position in the file
+struct Entry {
+    line_no: usize,
+    text: String,
+    parsed: TraceLine,
+}
+
+/// Reads the instruction lines of a trace file.
+struct Trace {
+    lines: Lines<BufReader<File>>,
+    line_no: usize,
+}
+
+impl Trace {
+    fn open(path: &str) -> io::Result<Self> {
+        Ok(Trace {
+            lines: BufReader::new(try!(File::open(path))).lines(),
+            line_no: 0,
+        })
+    }
+
+    /// Returns the next instruction line, or `None` at the end of the file.
+    fn next(&mut self) -> io::Result<Option<Entry>> {
+        while let Some(line) = self.lines.next() {
+            let line = try!(line);
+            self.line_no += 1;
+            if let Some(parsed) = TraceLine::parse(&line) {
+                return Ok(Some(Entry {
+                    line_no: self.line_no,
+                    text: line,
+                    parsed: parsed,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Options controlling which fields are compared
+struct Options {
+    timing: bool,
+    ignore: Vec<String>,
+}
+
+/// Returns a list of `(field, expected, actual)` for all fields that differ. Fields missing in one
+/// of the lines aren't compared.
+fn compare(expected: &TraceLine, actual: &TraceLine, opts: &Options) -> Vec<(&'static str, String, String)> {
+    let mut diffs = Vec::new();
+    if expected.pc != actual.pc {
+        diffs.push(("PC", format!("{:06X}", expected.pc), format!("{:06X}", actual.pc)));
+    }
+
+    {
+        let mut check = |name: &'static str, e: Option<u16>, a: Option<u16>, width: usize| {
+            if opts.ignore.iter().any(|i| i.eq_ignore_ascii_case(name)) { return }
+            if let (Some(e), Some(a)) = (e, a) {
+                if e != a {
+                    diffs.push((name, format!("{:01$X}", e, width), format!("{:01$X}", a, width)));
+                }
+            }
+        };
+        check("A", expected.a, actual.a, 4);
+        check("X", expected.x, actual.x, 4);
+        check("Y", expected.y, actual.y, 4);
+        check("S", expected.s, actual.s, 4);
+        check("D", expected.d, actual.d, 4);
+        check("DB", expected.dbr.map(|v| v as u16), actual.dbr.map(|v| v as u16), 2);
+        check("P", expected.p.map(|v| v as u16), actual.p.map(|v| v as u16), 2);
+    }
+
+    if opts.timing {
+        let mut check = |name: &'static str, e: Option<u16>, a: Option<u16>| {
+            if let (Some(e), Some(a)) = (e, a) {
+                if e != a {
+                    diffs.push((name, e.to_string(), a.to_string()));
+                }
+            }
+        };
+        check("V", expected.v, actual.v);
+        check("H", expected.h, actual.h);
+    }
+
+    diffs
+}
+
+fn print_entry(prefix: &str, entry: &Entry) {
+    println!("{} {:>8}: {}", prefix, entry.line_no, entry.text);
+}
+
+/// Compares the traces. Returns `true` if they match.
+fn run(args: &clap::ArgMatches) -> Result<bool, Box<Error>> {
+    let context: usize = try!(args.value_of("context").unwrap_or("5").parse());
+    let opts = Options {
+        timing: args.is_present("timing"),
+        ignore: args.values_of("ignore").map(|v| v.map(|s| s.to_string()).collect())
+            .unwrap_or_default(),
+    };
+
+    let mut expected = try!(Trace::open(args.value_of("expected").unwrap()));
+    let mut actual = try!(Trace::open(args.value_of("actual").unwrap()));
+
+    for _ in 0..try!(args.value_of("skip-expected").unwrap_or("0").parse::<usize>()) {
+        try!(expected.next());
+    }
+    for _ in 0..try!(args.value_of("skip-actual").unwrap_or("0").parse::<usize>()) {
+        try!(actual.next());
+    }
+
+    // The last `context` matching lines
+    let mut history: VecDeque<(Entry, Entry)> = VecDeque::new();
+    let mut count = 0;
+    loop {
+        let (e, a) = match (try!(expected.next()), try!(actual.next())) {
+            (Some(e), Some(a)) => (e, a),
+            (None, None) => {
+                println!("traces match ({} instructions compared)", count);
+                return Ok(true);
+            }
+            (Some(_), None) => {
+                println!("actual trace ended after {} instructions, but the expected trace \
+                          continues", count);
+                return Ok(false);
+            }
+            (None, Some(_)) => {
+                println!("expected trace ended after {} instructions, but the actual trace \
+                          continues", count);
+                return Ok(false);
+            }
+        };
+
+        let diffs = compare(&e.parsed, &a.parsed, &opts);
+        if diffs.is_empty() {
+            count += 1;
+            history.push_back((e, a));
+            if history.len() > context {
+                history.pop_front();
+            }
+            continue;
+        }
+
+        println!("traces diverge at instruction {} (expected line {}, actual line {})",
+            count + 1, e.line_no, a.line_no);
+        println!("");
+        for &(ref e, _) in &history {
+            print_entry(" ", e);
+        }
+        print_entry("-", &e);
+        print_entry("+", &a);
+        for _ in 0..context {
+            match (try!(expected.next()), try!(actual.next())) {
+                (Some(e), Some(a)) => {
+                    print_entry("-", &e);
+                    print_entry("+", &a);
+                }
+                (Some(e), None) => print_entry("-", &e),
+                (None, Some(a)) => print_entry("+", &a),
+                (None, None) => break,
+            }
+        }
+        println!("");
+        for (name, e, a) in diffs {
+            println!("{}: expected {}, actual {}", name, e, a);
+        }
+
+        return Ok(false);
+    }
+}
+
+fn main() {
+    let args = clap::App::new("breeze-tracediff")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Finds the first difference between two CPU traces (bsnes, Mesen-S or Breeze format)")
+        .arg(clap::Arg::with_name("expected")
+            .required(true)
+            .value_name("EXPECTED")
+            .help("The known-good trace (eg. from a reference emulator)"))
+        .arg(clap::Arg::with_name("actual")
+            .required(true)
+            .value_name("ACTUAL")
+            .help("The trace to check"))
+        .arg(clap::Arg::with_name("context")
+            .short("C")
+            .long("context")
+            .takes_value(true)
+            .value_name("N")
+            .help("Number of instructions to show before and after the divergence (default: 5)"))
+        .arg(clap::Arg::with_name("timing")
+            .long("timing")
+            .help("Also compare the V and H counters"))
+        .arg(clap::Arg::with_name("ignore")
+            .long("ignore")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("REG")
+            .help("Don't compare the given register (A, X, Y, S, D, DB or P)"))
+        .arg(clap::Arg::with_name("skip-expected")
+            .long("skip-expected")
+            .takes_value(true)
+            .value_name("N")
+            .help("Skip the first N instructions of the expected trace"))
+        .arg(clap::Arg::with_name("skip-actual")
+            .long("skip-actual")
+            .takes_value(true)
+            .value_name("N")
+            .help("Skip the first N instructions of the actual trace"))
+        .get_matches();
+
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            println!("error: {}", e);
+            process::exit(2);
+        }
+    }
+}
@@ -62,6 +62,8 @@ unsafe impl TransmuteByteSafe for i16 {}
 unsafe impl TransmuteByteSafe for i32 {}
 unsafe impl TransmuteByteSafe for i64 {}
 unsafe impl TransmuteByteSafe for isize {}
+unsafe impl TransmuteByteSafe for f32 {}
+unsafe impl TransmuteByteSafe for f64 {}
 
 /// Everything that can be transmuted to/from fixed-size byte slices can trivially implement
 /// `SaveState`. This includes a large portion of the emulator state, since much of it consists of
@@ -155,7 +157,7 @@ macro_rules! impl_fixed_size_array {
 }
 
 impl_fixed_size_array!(
-    0 1 2 3 4 5 6 7 8
+    0 1 2 3 4 5 6 7 8 16 32
 );
 
 /// `Vec<T>`s `SaveState` impl will read/write the `Vec`s length first, followed by its contents.
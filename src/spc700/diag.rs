@@ -0,0 +1,55 @@
+//! Diagnostics about the SPC700 program (see `Spc700::take_diagnostics`)
+
+use std::fmt;
+use std::mem;
+
+/// Records a diagnostic in a `Diagnostics` collector. The format string identifies the kind of
+/// diagnostic.
+macro_rules! diag {
+    ( $diag:expr, $fmt:expr ) => {
+        $diag.record($fmt, format_args!($fmt))
+    };
+    ( $diag:expr, $fmt:expr, $($arg:tt)+ ) => {
+        $diag.record($fmt, format_args!($fmt, $($arg)+))
+    };
+}
+
+/// Something the SPC700 program did that isn't emulated or is probably a bug. Unlike `Fault`s,
+/// these are merely reported.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    /// The format string of the message
+    pub kind: &'static str,
+    /// The message of the first occurrence
+    pub message: String,
+    pub count: u64,
+    /// Address of the instruction that caused the first occurrence
+    pub pc: u16,
+}
+
+/// Collects diagnostics until they're taken by `Spc700::take_diagnostics`
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    /// Address of the instruction being executed, updated by `Spc700::dispatch`
+    pub pc: u16,
+    entries: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    /// Records an occurrence of `kind` (use the `diag!` macro instead of calling this).
+    pub fn record(&mut self, kind: &'static str, message: fmt::Arguments) {
+        match self.entries.iter_mut().find(|d| d.kind == kind) {
+            Some(diag) => diag.count += 1,
+            None => self.entries.push(Diagnostic {
+                kind: kind,
+                message: fmt::format(message),
+                count: 1,
+                pc: self.pc,
+            }),
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<Diagnostic> {
+        mem::replace(&mut self.entries, Vec::new())
+    }
+}
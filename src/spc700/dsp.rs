@@ -2,6 +2,8 @@
 
 #![allow(dead_code)]    // FIXME Implement the DSP
 
+use diag::Diagnostics;
+
 #[derive(Copy, Clone, Default)]
 struct Voice {
     // Registers
@@ -100,10 +102,10 @@ impl Dsp {
         }
     }
 
-    /// Load a value from a DSP register
-    pub fn load(&mut self, mut reg: u8) -> u8 {
+    /// Load a value from a DSP register. Returns `None` if the register doesn't exist.
+    pub fn load(&mut self, mut reg: u8) -> Option<u8> {
         reg &= 0x7f;
-        match reg {
+        Some(match reg {
             0x0c => self.lmvol,
             0x1c => self.rmvol,
             0x2c => self.levol,
@@ -133,15 +135,18 @@ impl Dsp {
                     0x08 => voice.env,
                     0x09 => voice.out,
                     0x0f => voice.fir,
-                    _ => panic!("invalid DSP read from ${:02X}", reg),
+                    _ => return None,
                 }
             }
-        }
+        })
     }
 
-    /// Store a value in a DSP register
-    pub fn store(&mut self, reg: u8, value: u8) {
+    /// Store a value in a DSP register. Returns `false` if the register doesn't exist (the write is
+    /// ignored then).
+    pub fn store(&mut self, reg: u8, value: u8, diagnostics: &mut Diagnostics) -> bool {
         match reg {
+            // Read-only mirror of `$00-$7f`
+            0x80 ... 0xff => return false,
             0x0c => self.lmvol = value,
             0x1c => self.rmvol = value,
             0x2c => self.levol = value,
@@ -168,13 +173,14 @@ impl Dsp {
                     0x05 => voice.adsr1 = value,
                     0x06 => voice.adsr2 = value,
                     0x07 => voice.gain = value,
-                    0x08 => once!(warn!("ignoring write to envelope value")),
-                    0x09 => once!(warn!("ignoring write to sample value")),
+                    0x08 => diag!(diagnostics, "ignoring write to envelope value"),
+                    0x09 => diag!(diagnostics, "ignoring write to sample value"),
                     0x0f => voice.fir = value,
-                    _ => panic!("invalid DSP write to ${:02X}", reg),
+                    _ => return false,
                 }
             }
         }
+        true
     }
 }
 
@@ -14,7 +14,7 @@
 #[macro_use] #[no_link] extern crate byte_array;
 #[macro_use] extern crate libsavestate;
 
-#[macro_use] mod once;
+#[macro_use] mod diag;
 mod addressing;
 mod dsp;
 mod ipl;
@@ -22,17 +22,33 @@ mod statusreg;
 mod timer;
 
 use addressing::AddressingMode;
+use diag::Diagnostics;
 use dsp::Dsp;
 use ipl::IPL_ROM;
 use statusreg::StatusReg;
 use timer::Timer;
 
+pub use diag::Diagnostic;
+
 
 const RAM_SIZE: usize = 65536;
 byte_array!(Ram[RAM_SIZE] with u16 indexing, save state please);
 
 const RESET_VEC: u16 = 0xFFFE;
 
+/// An invalid register access by the SPC700 program. The SPC700 doesn't panic on these, it behaves
+/// like the hardware (probably) would and records a `Fault`, which can be retrieved with
+/// `Spc700::take_fault`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fault {
+    /// The address that was accessed (DSP registers are accessed through `$f3`)
+    pub addr: u16,
+    /// The value that was written, or `None` if the access was a read
+    pub value: Option<u8>,
+    /// Address of the instruction that made the access
+    pub pc: u16,
+}
+
 /// The SPC700 is an 8-bit processor with a 16-bit address space.
 ///
 /// It has 64 KB of RAM shared with the DSP. The last 64 Bytes in its address space are mapped to
@@ -64,12 +80,18 @@ pub struct Spc700 {
     psw: StatusReg,
 
     cy: u8,
+    /// Address of the instruction being executed
+    op_pc: u16,
+    /// The first fault caused by the program since the last call to `take_fault`
+    fault: Option<Fault>,
+    /// Diagnostics recorded since the last call to `take_diagnostics`
+    diagnostics: Diagnostics,
 
     pub trace: bool,
 }
 
 impl_save_state!(Spc700 { mem, ipl_rom_mapped, reg_dsp_addr, io_vals, timers, dsp, a, x, y, sp, pc,
-    psw } ignore { cy, trace });
+    psw } ignore { cy, op_pc, fault, diagnostics, trace });
 
 impl Default for Spc700 {
     fn default() -> Self {
@@ -91,6 +113,9 @@ impl Default for Spc700 {
             pc: pc,
             psw: StatusReg(0),  // FIXME is 0 correct?
             cy: 0,
+            op_pc: pc,
+            fault: None,
+            diagnostics: Diagnostics::default(),
             trace: false,
         }
     }
@@ -105,6 +130,11 @@ impl Spc700 {
         self.io_vals[port as usize] = value;
     }
 
+    /// Returns the 64 KB of RAM shared by the SPC700 and the DSP.
+    pub fn ram(&self) -> &[u8] { &*self.mem }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] { &mut *self.mem }
+
     /// Load a byte from an IO port
     pub fn read_port(&mut self, port: u8) -> u8 {
         debug_assert!(port < 4);
@@ -112,20 +142,54 @@ impl Spc700 {
         val
     }
 
+    /// Returns (and clears) the first fault caused by the program since the last call.
+    pub fn take_fault(&mut self) -> Option<Fault> {
+        self.fault.take()
+    }
+
+    /// Returns (and clears) the diagnostics recorded since the last call.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.diagnostics.take()
+    }
+
+    /// Records an invalid access to `addr` by the current instruction.
+    fn fault(&mut self, addr: u16, value: Option<u8>) {
+        if self.fault.is_none() {
+            self.fault = Some(Fault {
+                addr: addr,
+                value: value,
+                pc: self.op_pc,
+            });
+        }
+    }
+
     fn load(&mut self, addr: u16) -> u8 {
         match addr {
-            0xf0 => panic!("undocumented register unimplemented"),
+            // TEST register, write-only
+            0xf0 => {
+                self.fault(addr, None);
+                0
+            }
             0xf1 => {
-                once!(warn!("read from write-only control register"));
+                diag!(self.diagnostics, "read from write-only control register ($f1)");
                 let t0 = if self.timers[0].enabled() { 0b001 } else { 0 };
                 let t1 = if self.timers[1].enabled() { 0b010 } else { 0 };
                 let t2 = if self.timers[2].enabled() { 0b100 } else { 0 };
                 t0 | t1 | t2    // not sure what else to return
             }
-            0xfa ... 0xfc =>
-                panic!("APU attempted read from write-only register ${:02X}", addr),
+            // Timer dividers, write-only
+            0xfa ... 0xfc => {
+                self.fault(addr, None);
+                0
+            }
             0xf2 => self.reg_dsp_addr,
-            0xf3 => self.dsp.load(self.reg_dsp_addr),
+            0xf3 => match self.dsp.load(self.reg_dsp_addr) {
+                Some(value) => value,
+                None => {
+                    self.fault(addr, None);
+                    0
+                }
+            },
             0xf4 ... 0xf7 => self.io_vals[addr as usize - 0xf4],
             0xfd => {
                 let val = self.timers[0].val;
@@ -155,12 +219,9 @@ impl Spc700 {
         match addr {
             0xf0 => {
                 if val != 0x0a {
-                    once!({
-                        warn!("SPC700 wrote ${:02X} to testing register ($f0)", val);
-                        warn!("As a safety measure, only $0a is allowed. This write will be \
-                            ignored! (This warning will only be printed for the first illegal \
-                            write)");
-                    });
+                    // As a safety measure, only $0a is allowed
+                    diag!(self.diagnostics, "ignoring write of ${:02X} to test register ($f0)",
+                        val);
                  }
             }
             0xf1 => {
@@ -178,11 +239,14 @@ impl Spc700 {
                 self.ipl_rom_mapped = val & 0x80 != 0;
             },
             0xf2 => self.reg_dsp_addr = val,
-            0xf3 => self.dsp.store(self.reg_dsp_addr, val),
+            0xf3 => if !self.dsp.store(self.reg_dsp_addr, val, &mut self.diagnostics) {
+                self.fault(addr, Some(val));
+            },
             0xfa => self.timers[0].div = val,
             0xfb => self.timers[1].div = val,
             0xfc => self.timers[2].div = val,
-            0xfd ... 0xff => panic!("APU attempted to write to read-only register ${:04X}", addr),
+            // Timer outputs, read-only (the write only goes to RAM)
+            0xfd ... 0xff => self.fault(addr, Some(val)),
             // NB: Stores to 0xf4 - 0xf9 are just sent to RAM
             _ => {}
         }
@@ -245,6 +309,8 @@ impl Spc700 {
         ];
 
         let pc = self.pc;
+        self.op_pc = pc;
+        self.diagnostics.pc = pc;
 
         macro_rules! e {
             ($e:expr) => ($e)
@@ -649,7 +715,7 @@ impl Spc700 {
         // Since all possible addresses are stored in IPL ROM area, it makes no sense to have it
         // mapped.
         if self.ipl_rom_mapped {
-            once!(warn!("`tcall {}` while IPL ROM is mapped!", p));
+            diag!(self.diagnostics, "`tcall {}` while IPL ROM is mapped", p);
         }
 
         let addr = self.loadw(0xffc0 + (15 - p as u16) * 2);
@@ -7,14 +7,40 @@ use libsavestate::SaveState;
 
 mod addressing;
 mod statusreg;
+pub mod symbols;
+pub mod trace;
 
 use addressing::AddressingMode;
 use statusreg::StatusReg;
+use symbols::Symbols;
+use trace::{TraceState, TraceWriter};
 
 /// Trait for devices attached to the 65816's address/data bus
 pub trait Mem {
     fn load(&mut self, bank: u8, addr: u16) -> u8;
     fn store(&mut self, bank: u8, addr: u16, value: u8);
+
+    /// Loads the opcode byte of an instruction. The last argument is the value of the status
+    /// register at the time of the fetch (and thus contains the M and X flags the instruction
+    /// executes with).
+    ///
+    /// The default implementation just calls `load`. Implementors can override this to find out
+    /// which memory locations contain code.
+    fn load_opcode(&mut self, bank: u8, addr: u16, _p: u8) -> u8 {
+        self.load(bank, addr)
+    }
+
+    /// Loads an operand byte of an instruction (any instruction byte after the opcode). The last
+    /// argument is the value of the status register at the time of the fetch.
+    ///
+    /// The default implementation just calls `load`.
+    fn load_operand(&mut self, bank: u8, addr: u16, _p: u8) -> u8 {
+        self.load(bank, addr)
+    }
+
+    /// Returns the current `(V, H)` position of the video beam, which is included in the lines
+    /// written by a `TraceWriter`. The default implementation returns `None`.
+    fn trace_position(&self) -> Option<(u16, u16)> { None }
 }
 
 // Emulation mode vectors
@@ -61,6 +87,11 @@ pub struct Cpu<M: Mem> {
     cy: u16,
 
     pub trace: bool,
+    /// Labels used to annotate the trace output
+    pub symbols: Symbols,
+    /// If set, a line is written to this writer for every executed instruction (independent of
+    /// `trace` and the log level)
+    pub trace_writer: Option<TraceWriter>,
     pub mem: M,
 }
 
@@ -68,7 +99,31 @@ pub struct Cpu<M: Mem> {
 impl<M: Mem + SaveState> SaveState for Cpu<M> {
     impl_save_state_fns!(Cpu {
         a, x, y, s, dbr, pbr, d, pc, p, emulation, wai, mem
-    } ignore { cy, trace });
+    } ignore { cy, trace, symbols, trace_writer });
+}
+
+// The trace writer can't be cloned, so the clone doesn't write a trace.
+impl<M: Mem + Clone> Clone for Cpu<M> {
+    fn clone(&self) -> Self {
+        Cpu {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            s: self.s,
+            dbr: self.dbr,
+            pbr: self.pbr,
+            d: self.d,
+            pc: self.pc,
+            p: self.p.clone(),
+            emulation: self.emulation,
+            wai: self.wai,
+            cy: self.cy,
+            trace: self.trace,
+            symbols: self.symbols.clone(),
+            trace_writer: None,
+            mem: self.mem.clone(),
+        }
+    }
 }
 
 impl<M: Mem> Cpu<M> {
@@ -98,10 +153,28 @@ impl<M: Mem> Cpu<M> {
             wai: false,
             cy: 0,
             trace: false,
+            symbols: Symbols::new(),
+            trace_writer: None,
             mem: mem,
         }
     }
 
+    /// Resets the CPU, like the RESET line does. The RESET vector is fetched from memory again and
+    /// the CPU is put back into emulation mode. The accumulator keeps its value.
+    pub fn reset(&mut self) {
+        let pc = self.loadw(0, RESET_VEC8);
+        self.pc = pc;
+        self.pbr = 0;
+        self.dbr = 0;
+        self.d = 0;
+        self.s = 0x0100 | (self.s & 0xff);
+        self.x &= 0xff;
+        self.y &= 0xff;
+        self.p = StatusReg::new();
+        self.emulation = true;
+        self.wai = false;
+    }
+
     /// Load a byte from memory.
     fn loadb(&mut self, bank: u8, addr: u16) -> u8 {
         // FIXME Remove?
@@ -129,10 +202,18 @@ impl<M: Mem> Cpu<M> {
         }
     }
 
-    /// Fetches the byte PC points at, then increments PC
+    /// Fetches the opcode byte PC points at, then increments PC
+    fn fetch_opcode(&mut self) -> u8 {
+        let (pbr, pc, p) = (self.pbr, self.pc, self.p.0);
+        let b = self.mem.load_opcode(pbr, pc, p);
+        self.pc += 1;
+        b
+    }
+
+    /// Fetches the operand byte PC points at, then increments PC
     fn fetchb(&mut self) -> u8 {
-        let (pbr, pc) = (self.pbr, self.pc);
-        let b = self.loadb(pbr, pc);
+        let (pbr, pc, p) = (self.pbr, self.pc, self.p.0);
+        let b = self.mem.load_operand(pbr, pc, p);
         self.pc += 1;
         b
     }
@@ -204,12 +285,47 @@ impl<M: Mem> Cpu<M> {
         self.emulation = value;
     }
 
-    fn trace_op(&self, pc: u16, raw: u8, op: &str, am: Option<&AddressingMode>) {
+    fn trace_op(&mut self, pc: u16, raw: u8, op: &str, am: Option<&AddressingMode>) {
         use log::LogLevel::Trace;
+
+        if let Some(mut writer) = self.trace_writer.take() {
+            let result = writer.write_op(&TraceState {
+                pbr: self.pbr,
+                pc: pc,
+                next_pc: self.pc,
+                op: op,
+                am: am,
+                a: self.a,
+                x: self.x,
+                y: self.y,
+                s: self.s,
+                d: self.d,
+                dbr: self.dbr,
+                p: &self.p,
+                position: self.mem.trace_position(),
+            });
+            match result {
+                Ok(()) => self.trace_writer = Some(writer),
+                Err(e) => error!("failed to write trace, stopping: {}", e),
+            }
+        }
+
         if !log_enabled!(Trace) || !self.trace { return }
 
+        if let Some(label) = self.symbols.get(self.pbr, pc) {
+            trace!("{}:", label);
+        }
+
         let opstr = match am {
-            Some(am) => format!("{} {}", op, am),
+            Some(am) => {
+                let label = self.static_target(op, am).and_then(|(bank, addr)| {
+                    self.symbols.get(bank, addr)
+                });
+                match label {
+                    Some(label) => format!("{} {} <{}>", op, am, label),
+                    None => format!("{} {}", op, am),
+                }
+            }
             None => op.to_string(),
         };
         trace!("${:02X}:{:04X} {:02X}  {:14} a:{:04X} x:{:04X} y:{:04X} s:{:04X} d:{:04X} dbr:{:02X} emu:{} {}",
@@ -228,6 +344,30 @@ impl<M: Mem> Cpu<M> {
         );
     }
 
+    /// Returns the address an instruction operand refers to, if it can be determined without
+    /// accessing memory (used to look up labels for the trace output). Must be called after the
+    /// operand was fetched.
+    fn static_target(&self, op: &str, am: &AddressingMode) -> Option<(u8, u16)> {
+        use addressing::AddressingMode::*;
+
+        // Jumps use the program bank instead of the data bank
+        let bank = match op {
+            "jmp" | "jsr" => self.pbr,
+            _ => self.dbr,
+        };
+        match *am {
+            Absolute(addr) | AbsIndexedX(addr) | AbsIndexedY(addr) => Some((bank, addr)),
+            AbsIndexedIndirect(addr) => Some((self.pbr, addr)),
+            AbsoluteIndirect(addr) | AbsoluteIndirectLong(addr) => Some((0, addr)),
+            AbsoluteLong(bank, addr) | AbsLongIndexedX(bank, addr) => Some((bank, addr)),
+            // PC already points to the next instruction
+            Rel(rel) => Some((self.pbr, (self.pc as i16).wrapping_add(rel as i16) as u16)),
+            RelLong(rel) => Some((self.pbr, (self.pc as i16).wrapping_add(rel) as u16)),
+            Direct(offset) => Some((0, self.d.wrapping_add(offset as u16))),
+            _ => None,
+        }
+    }
+
     /// Executes a single opcode and returns the number of CPU clock cycles used.
     ///
     /// Note that in case a WAI instruction was executed, this will *not* execute anything and
@@ -261,7 +401,7 @@ impl<M: Mem> Cpu<M> {
 
         let pc = self.pc;
         self.cy = 0;
-        let op = self.fetchb();
+        let op = self.fetch_opcode();
         self.cy += CYCLE_TABLE[op as usize] as u16;
 
         macro_rules! instr {
@@ -529,7 +669,7 @@ impl<M: Mem> Cpu<M> {
     /// Invokes the IRQ handler if interrupts are enabled. Returns whether the interrupt was
     /// generated.
     pub fn trigger_irq(&mut self) -> bool {
-        if !self.p.irq_disable() {
+        if self.p.irq_disable() {
             false
         } else {
             if self.emulation {
@@ -563,6 +703,8 @@ impl<M: Mem> Cpu<M> {
         if !self.emulation {
             self.p.set_decimal(false);
         }
+        // The handler runs with IRQs disabled (`rti` restores the old flag)
+        self.p.set_irq_disable(true);
 
         let handler = self.loadw(0, vector);
         self.pc = handler;
@@ -17,6 +17,7 @@ const IRQ_FLAG: u8 = 0x04;
 const ZERO_FLAG: u8 = 0x02;
 const CARRY_FLAG: u8 = 0x01;
 
+#[derive(Clone)]
 pub struct StatusReg(pub u8);
 
 impl_save_state_for_newtype!(StatusReg);
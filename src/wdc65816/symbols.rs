@@ -0,0 +1,233 @@
+//! Debug symbol (label) tables
+//!
+//! Symbols are used to annotate trace output with the names of the labels defined in the program's
+//! source code. The following formats are understood (and can be mixed in a single file):
+//!
+//! * WLA-DX `.sym` files: `[labels]` sections containing lines like `00:8000 Main`. Other sections
+//!   (eg. `[definitions]`) are ignored.
+//! * ca65/ld65 debug info files (`ld65 --dbgfile`): `sym` lines with `type=lab` are used.
+//! * ld65 label files (`ld65 -Ln`): Lines like `al 008000 .Main`.
+//! * A simple text format with one `bank:addr name` pair per line, eg. `$80:9A3C ResetHandler`
+//!   (the `$` is optional). This is compatible with WLA-DX's `[labels]` section.
+//!
+//! Empty lines and lines starting with `;` or `#` are ignored.
+
+use std::collections::HashMap;
+use std::io;
+
+/// A table mapping 24-bit addresses to label names.
+#[derive(Clone, Default)]
+pub struct Symbols {
+    by_addr: HashMap<u32, String>,
+    by_name: HashMap<String, u32>,
+}
+
+impl Symbols {
+    /// Creates an empty symbol table.
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    /// Parses symbols in any of the supported formats (see the module documentation) and adds
+    /// them to this table.
+    pub fn parse(&mut self, text: &str) -> io::Result<()> {
+        // `true` when we're in a WLA-DX section that doesn't contain labels
+        let mut skip_section = false;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+
+            let err = |msg: &str| io::Error::new(io::ErrorKind::InvalidData,
+                format!("symbol file line {}: {} (in '{}')", i + 1, msg, line));
+
+            if line.starts_with('[') {
+                // WLA-DX section header
+                skip_section = line != "[labels]";
+                continue;
+            }
+            if skip_section { continue; }
+
+            if line.starts_with("al ") {
+                // ld65 label file: `al 008000 .Main`
+                let mut parts = line.split_whitespace().skip(1);
+                let addr = try!(parts.next().and_then(|s| u32::from_str_radix(s, 16).ok())
+                    .ok_or_else(|| err("invalid address")));
+                let name = try!(parts.next().ok_or_else(|| err("missing label name")));
+                self.add(addr, name.trim_left_matches('.'));
+            } else if line.starts_with("sym\t") || line.starts_with("sym ") {
+                // ca65 debug info: `sym id=0,name="Main",...,val=0x8000,...,type=lab`
+                if let Some((addr, name)) = try!(parse_dbg_sym(&line[4..]).map_err(|e| err(e))) {
+                    self.add(addr, &name);
+                }
+            } else if line.contains('=') {
+                // Other ca65 debug info lines (`version`, `file`, `seg`, ...)
+                continue;
+            } else {
+                // WLA-DX label or simple format: `00:8000 Main`
+                let mut parts = line.split_whitespace();
+                let addr = try!(parts.next().ok_or_else(|| err("missing address")));
+                let name = try!(parts.next().ok_or_else(|| err("missing label name")));
+                let addr = addr.trim_left_matches('$');
+                let mut addr_parts = addr.splitn(2, ':');
+                let bank = addr_parts.next().and_then(|s| u8::from_str_radix(s, 16).ok());
+                let offset = addr_parts.next().and_then(|s| u16::from_str_radix(s, 16).ok());
+                match (bank, offset) {
+                    (Some(bank), Some(offset)) => self.add((bank as u32) << 16 | offset as u32, name),
+                    _ => return Err(err("expected address in `bank:addr` format")),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a label for the given 24-bit address.
+    ///
+    /// If the address already has a label, it is only replaced if the existing label is a local
+    /// label (starting with `_` or `@`) and the new one isn't.
+    pub fn add(&mut self, addr: u32, name: &str) {
+        fn is_local(name: &str) -> bool { name.starts_with('_') || name.starts_with('@') }
+
+        let replace = match self.by_addr.get(&addr) {
+            Some(existing) => is_local(existing) && !is_local(name),
+            None => true,
+        };
+        if replace {
+            self.by_addr.insert(addr, name.to_string());
+        }
+        self.by_name.entry(name.to_string()).or_insert(addr);
+    }
+
+    /// Returns the label at `bank:addr`.
+    ///
+    /// Banks `$80-$FF` mirror banks `$00-$7F` for this lookup, so a label at `$00:8000` will also
+    /// be found for `$80:8000` and vice versa. The low 8 KB of banks `$00-$3F` are looked up in
+    /// bank `$7E`, since that's where they are mapped.
+    pub fn get(&self, bank: u8, addr: u16) -> Option<&str> {
+        let lookup = |bank: u8| self.by_addr.get(&((bank as u32) << 16 | addr as u32));
+        lookup(bank)
+            .or_else(|| if bank & 0x7f < 0x7e { lookup(bank ^ 0x80) } else { None })
+            .or_else(|| if bank & 0x7f < 0x40 && addr < 0x2000 { lookup(0x7e) } else { None })
+            .map(|s| &**s)
+    }
+
+    /// Returns the address of the label called `name`.
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// Formats `bank:addr` as `$BB:AAAA`, followed by the label in brackets if there is one.
+    pub fn describe(&self, bank: u8, addr: u16) -> String {
+        match self.get(bank, addr) {
+            Some(name) => format!("${:02X}:{:04X} <{}>", bank, addr, name),
+            None => format!("${:02X}:{:04X}", bank, addr),
+        }
+    }
+
+    /// Returns the number of labels in this table.
+    pub fn len(&self) -> usize { self.by_addr.len() }
+
+    pub fn is_empty(&self) -> bool { self.by_addr.is_empty() }
+}
+
+/// Parses the attributes of a ca65 `sym` line. Returns `None` if the symbol isn't a label.
+fn parse_dbg_sym(attrs: &str) -> Result<Option<(u32, String)>, &'static str> {
+    let mut name = None;
+    let mut val = None;
+    let mut is_label = false;
+
+    for attr in attrs.split(',') {
+        let mut kv = attr.trim().splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some("name"), Some(v)) => name = Some(v.trim_matches('"').to_string()),
+            (Some("val"), Some(v)) => {
+                val = Some(try!(u32::from_str_radix(v.trim_left_matches("0x"), 16)
+                    .map_err(|_| "invalid symbol value")));
+            }
+            (Some("type"), Some(v)) => is_label = v == "lab",
+            _ => {}
+        }
+    }
+
+    if !is_label { return Ok(None); }
+    match (name, val) {
+        (Some(name), Some(val)) => Ok(Some((val & 0xffffff, name))),
+        _ => Err("label without name or value"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbols;
+
+    fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::new();
+        symbols.parse(text).unwrap();
+        symbols
+    }
+
+    #[test]
+    fn wla_dx() {
+        let symbols = parse("; WLA-DX symbolic information\n\
+                             \n\
+                             [labels]\n\
+                             00:8000 Main\n\
+                             00:8000 _local\n\
+                             01:9a3c Handler\n\
+                             \n\
+                             [definitions]\n\
+                             00000010 SOME_CONSTANT\n");
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.get(0x00, 0x8000), Some("Main"));
+        assert_eq!(symbols.get(0x01, 0x9a3c), Some("Handler"));
+        assert_eq!(symbols.find("_local"), Some(0x008000));
+        assert_eq!(symbols.find("SOME_CONSTANT"), None);
+    }
+
+    #[test]
+    fn ld65_dbg() {
+        let symbols = parse("version\tmajor=2,minor=0\n\
+                             file\tid=0,name=\"main.s\",size=100,mtime=0x5F000000,mod=0\n\
+                             sym\tid=0,name=\"Reset\",addrsize=absolute,scope=0,def=1,\
+                             val=0x8000,seg=0,type=lab\n\
+                             sym\tid=1,name=\"WIDTH\",addrsize=zeropage,scope=0,def=2,\
+                             val=0x20,type=equ\n\
+                             sym\tid=2,name=\"Far\",addrsize=far,scope=0,def=3,\
+                             val=0xC08010,seg=1,type=lab\n");
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.get(0x00, 0x8000), Some("Reset"));
+        assert_eq!(symbols.get(0xc0, 0x8010), Some("Far"));
+        assert_eq!(symbols.find("WIDTH"), None);
+    }
+
+    #[test]
+    fn ld65_labels() {
+        let symbols = parse("al 008000 .Reset\nal 00FFEA .NmiVector\n");
+        assert_eq!(symbols.get(0x00, 0x8000), Some("Reset"));
+        assert_eq!(symbols.find("NmiVector"), Some(0x00ffea));
+    }
+
+    #[test]
+    fn simple() {
+        let symbols = parse("# Comment\n$80:9A3C ResetHandler\n7e:0100 Counter\n");
+        assert_eq!(symbols.get(0x80, 0x9a3c), Some("ResetHandler"));
+        // Mirrors
+        assert_eq!(symbols.get(0x00, 0x9a3c), Some("ResetHandler"));
+        assert_eq!(symbols.get(0x80, 0x0100), Some("Counter"));
+        assert_eq!(symbols.get(0x40, 0x0100), None);
+        assert_eq!(symbols.describe(0x80, 0x9a3c), "$80:9A3C <ResetHandler>");
+        assert_eq!(symbols.describe(0x80, 0x9a3d), "$80:9A3D");
+    }
+
+    #[test]
+    fn errors() {
+        let mut symbols = Symbols::new();
+        assert!(symbols.parse("8000 Main").is_err());
+        assert!(symbols.parse("00:8000").is_err());
+        assert!(symbols.parse("al xyz .Main").is_err());
+        assert!(symbols.parse("sym\tid=0,name=\"Main\",type=lab").is_err());
+    }
+}
@@ -0,0 +1,323 @@
+//! Trace files compatible with other emulators
+//!
+//! A `TraceWriter` can be installed in `Cpu::trace_writer` to write one line per executed
+//! instruction, in a format that's compatible with the CPU traces of bsnes(-plus) or Mesen-S. The
+//! register state is printed as it was *before* the instruction was executed.
+//!
+//! `TraceLine::parse` reads a line in any of these formats (as well as the format Breeze logs when
+//! `BREEZE_TRACE` is used), which allows comparing traces written by different emulators.
+
+use addressing::AddressingMode;
+use statusreg::StatusReg;
+
+use std::io::{self, Write};
+
+/// Supported trace line formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// bsnes-plus style:
+    ///
+    /// ```text
+    /// 008000 sei                    A:0000 X:0000 Y:0000 S:01ff D:0000 DB:00 nvMXdIzc V:  0 H: 186
+    /// ```
+    Bsnes,
+    /// Mesen-S style:
+    ///
+    /// ```text
+    /// 00:8000 SEI                   A:0000 X:0000 Y:0000 S:01FF D:0000 DB:00 P:nvMXdIzc V:0   H:186
+    /// ```
+    Mesen,
+}
+
+impl TraceFormat {
+    /// Parses a format name (`bsnes` or `mesen`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match &*name.to_lowercase() {
+            "bsnes" => Some(TraceFormat::Bsnes),
+            "mesen" => Some(TraceFormat::Mesen),
+            _ => None,
+        }
+    }
+}
+
+/// CPU state passed to the `TraceWriter`
+pub struct TraceState<'a> {
+    pub pbr: u8,
+    /// Address of the opcode
+    pub pc: u16,
+    /// Address of the next instruction (the operand has already been fetched)
+    pub next_pc: u16,
+    pub op: &'a str,
+    pub am: Option<&'a AddressingMode>,
+    pub a: u16,
+    pub x: u16,
+    pub y: u16,
+    pub s: u16,
+    pub d: u16,
+    pub dbr: u8,
+    pub p: &'a StatusReg,
+    /// `(V, H)` position of the video beam, if known
+    pub position: Option<(u16, u16)>,
+}
+
+/// Writes trace lines in one of the supported `TraceFormat`s.
+pub struct TraceWriter {
+    format: TraceFormat,
+    out: Box<Write>,
+}
+
+impl TraceWriter {
+    pub fn new(format: TraceFormat, out: Box<Write>) -> Self {
+        TraceWriter {
+            format: format,
+            out: out,
+        }
+    }
+
+    /// Writes the trace line for an instruction.
+    pub fn write_op(&mut self, state: &TraceState) -> io::Result<()> {
+        let disasm = disassemble(state);
+        let flags = flag_string(state.p);
+        match self.format {
+            TraceFormat::Bsnes => {
+                try!(write!(self.out, "{:02x}{:04x} {:22} A:{:04x} X:{:04x} Y:{:04x} S:{:04x} \
+                                       D:{:04x} DB:{:02x} {}",
+                    state.pbr, state.pc, disasm, state.a, state.x, state.y, state.s, state.d,
+                    state.dbr, flags));
+                if let Some((v, h)) = state.position {
+                    try!(write!(self.out, " V:{:3} H:{:4}", v, h));
+                }
+            }
+            TraceFormat::Mesen => {
+                try!(write!(self.out, "{:02X}:{:04X} {:21} A:{:04X} X:{:04X} Y:{:04X} S:{:04X} \
+                                       D:{:04X} DB:{:02X} P:{}",
+                    state.pbr, state.pc, disasm.to_uppercase(), state.a, state.x, state.y,
+                    state.s, state.d, state.dbr, flags));
+                if let Some((v, h)) = state.position {
+                    try!(write!(self.out, " V:{:<3} H:{:<3}", v, h));
+                }
+            }
+        }
+        writeln!(self.out, "")
+    }
+}
+
+/// Formats the status register like bsnes and Mesen do: Set flags in upper case, cleared flags in
+/// lower case.
+fn flag_string(p: &StatusReg) -> String {
+    let flags = [
+        (p.negative(), 'n'), (p.overflow(), 'v'), (p.small_acc(), 'm'), (p.small_index(), 'x'),
+        (p.decimal(), 'd'), (p.irq_disable(), 'i'), (p.zero(), 'z'), (p.carry(), 'c'),
+    ];
+    flags.iter().map(|&(set, c)| if set { c.to_ascii_uppercase() } else { c }).collect()
+}
+
+/// Disassembles an instruction in bsnes syntax (branch targets are printed as absolute
+/// addresses).
+fn disassemble(state: &TraceState) -> String {
+    use addressing::AddressingMode::*;
+
+    let am = match state.am {
+        Some(am) => am,
+        None => return state.op.to_string(),
+    };
+    // Branch targets are relative to the next instruction
+    let branch = |rel: u16| format!("${:04x}", state.next_pc.wrapping_add(rel));
+    let operand = match *am {
+        Immediate(val) =>                format!("#${:04x}", val),
+        Immediate8(val) =>               format!("#${:02x}", val),
+        Absolute(addr) =>                format!("${:04x}", addr),
+        AbsoluteLong(bank, addr) =>      format!("${:02x}{:04x}", bank, addr),
+        AbsLongIndexedX(bank, addr) =>   format!("${:02x}{:04x},x", bank, addr),
+        AbsIndexedX(addr) =>             format!("${:04x},x", addr),
+        AbsIndexedY(addr) =>             format!("${:04x},y", addr),
+        AbsIndexedIndirect(addr) =>      format!("(${:04x},x)", addr),
+        AbsoluteIndirect(addr) =>        format!("(${:04x})", addr),
+        AbsoluteIndirectLong(addr) =>    format!("[${:04x}]", addr),
+        Rel(rel) =>                      branch(rel as i16 as u16),
+        RelLong(rel) =>                  branch(rel as u16),
+        Direct(offset) =>                format!("${:02x}", offset),
+        DirectIndexedX(offset) =>        format!("${:02x},x", offset),
+        DirectIndexedY(offset) =>        format!("${:02x},y", offset),
+        DirectIndexedIndirect(offset) => format!("(${:02x},x)", offset),
+        DirectIndirectIndexed(offset) => format!("(${:02x}),y", offset),
+        DirectIndirect(offset) =>        format!("(${:02x})", offset),
+        DirectIndirectLong(offset) =>    format!("[${:02x}]", offset),
+        DirectIndirectLongIdx(offset) => format!("[${:02x}],y", offset),
+        StackRel(offset) =>              format!("${:02x},s", offset),
+    };
+    format!("{} {}", state.op, operand)
+}
+
+/// The CPU state found in a single line of a trace file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct TraceLine {
+    /// 24-bit address of the instruction
+    pub pc: u32,
+    pub a: Option<u16>,
+    pub x: Option<u16>,
+    pub y: Option<u16>,
+    pub s: Option<u16>,
+    pub d: Option<u16>,
+    pub dbr: Option<u8>,
+    /// Status register
+    pub p: Option<u8>,
+    pub v: Option<u16>,
+    pub h: Option<u16>,
+}
+
+impl TraceLine {
+    /// Parses a trace line written by bsnes, Mesen-S or Breeze (both the `TraceWriter` formats and
+    /// the format of the `BREEZE_TRACE` log). Returns `None` if the line doesn't contain an
+    /// instruction address.
+    ///
+    /// Leading text that doesn't look like an address (such as a log prefix) is skipped. Registers
+    /// that can't be found are set to `None`.
+    pub fn parse(line: &str) -> Option<TraceLine> {
+        let mut tokens = line.split_whitespace().peekable();
+
+        // Find the instruction address: `008000`, `00:8000` or `$00:8000`
+        let mut pc = None;
+        while let Some(token) = tokens.next() {
+            let token = token.trim_left_matches('$');
+            let digits: String = token.chars().filter(|&c| c != ':').collect();
+            let colon_ok = !token.contains(':') ||
+                (token.len() == 7 && token.as_bytes()[2] == b':');
+            if digits.len() == 6 && colon_ok {
+                if let Ok(addr) = u32::from_str_radix(&digits, 16) {
+                    pc = Some(addr);
+                    break;
+                }
+            }
+        }
+
+        let mut result = match pc {
+            Some(pc) => TraceLine { pc: pc, ..TraceLine::default() },
+            None => return None,
+        };
+        while let Some(token) = tokens.next() {
+            let (key, value) = match token.find(':') {
+                Some(i) => (token[..i].to_lowercase(), &token[i + 1..]),
+                None => {
+                    // bsnes prints the flags without a key
+                    if let Some(p) = parse_flags(token) {
+                        result.p = Some(p);
+                    }
+                    continue;
+                }
+            };
+            // bsnes pads V/H values with spaces after the colon
+            let value = if value.is_empty() {
+                match tokens.peek() {
+                    Some(next) if !next.contains(':') => tokens.next().unwrap(),
+                    _ => value,
+                }
+            } else {
+                value
+            };
+
+            let hex16 = || u16::from_str_radix(value, 16).ok();
+            match &*key {
+                "a" => result.a = hex16(),
+                "x" => result.x = hex16(),
+                "y" => result.y = hex16(),
+                "s" | "sp" => result.s = hex16(),
+                "d" => result.d = hex16(),
+                "db" | "dbr" | "b" => result.dbr = u8::from_str_radix(value, 16).ok(),
+                "p" => result.p = parse_flags(value).or_else(|| u8::from_str_radix(value, 16).ok()),
+                "v" => result.v = value.parse().ok(),
+                "h" => result.h = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some(result)
+    }
+}
+
+/// Parses an 8-character flag string like `nvMXdIzc` or `--MX-I--` (upper case = set).
+fn parse_flags(s: &str) -> Option<u8> {
+    const NAMES: &'static [u8; 8] = b"nvmxdizc";
+    if s.len() != 8 { return None; }
+
+    let mut p = 0;
+    for (i, c) in s.bytes().enumerate() {
+        let lower = c.to_ascii_lowercase();
+        if lower != NAMES[i] && c != b'-' { return None; }
+        if c.is_ascii_uppercase() {
+            p |= 0x80 >> i;
+        }
+    }
+    Some(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_flags, TraceLine};
+
+    #[test]
+    fn parse_bsnes() {
+        let line = "008000 sei                    A:0000 X:0000 Y:0000 S:01ff D:0000 DB:00 \
+                    nvMXdIzc V:  0 H: 186";
+        assert_eq!(TraceLine::parse(line), Some(TraceLine {
+            pc: 0x008000,
+            a: Some(0),
+            x: Some(0),
+            y: Some(0),
+            s: Some(0x01ff),
+            d: Some(0),
+            dbr: Some(0),
+            p: Some(0x34),
+            v: Some(0),
+            h: Some(186),
+        }));
+    }
+
+    #[test]
+    fn parse_mesen() {
+        let line = "C0:1234 LDA #$12              A:1234 X:00FF Y:0001 S:1FF0 D:0100 DB:7E \
+                    P:NVmxDizC V:261 H:340";
+        assert_eq!(TraceLine::parse(line), Some(TraceLine {
+            pc: 0xc01234,
+            a: Some(0x1234),
+            x: Some(0x00ff),
+            y: Some(0x0001),
+            s: Some(0x1ff0),
+            d: Some(0x0100),
+            dbr: Some(0x7e),
+            p: Some(0xc9),
+            v: Some(261),
+            h: Some(340),
+        }));
+    }
+
+    #[test]
+    fn parse_address_forms() {
+        for &line in &["008000 nop", "00:8000 nop", "$00:8000 nop", "TRACE - $00:8000 nop"] {
+            assert_eq!(TraceLine::parse(line).map(|l| l.pc), Some(0x008000), "{}", line);
+        }
+        assert_eq!(TraceLine::parse("0:08000 nop"), None);
+        assert_eq!(TraceLine::parse("8000 nop"), None);
+        assert_eq!(TraceLine::parse(""), None);
+    }
+
+    #[test]
+    fn parse_non_ascii() {
+        // Multibyte characters must not be sliced in the middle
+        assert_eq!(TraceLine::parse("aé:bcd"), None);
+        assert_eq!(TraceLine::parse("0é:123"), None);
+        assert_eq!(TraceLine::parse("→ 00:8000 nop").map(|l| l.pc), Some(0x008000));
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(parse_flags("nvmxdizc"), Some(0));
+        assert_eq!(parse_flags("NVMXDIZC"), Some(0xff));
+        assert_eq!(parse_flags("nvMXdIzc"), Some(0x34));
+        assert_eq!(parse_flags("--M-----"), Some(0x20));
+        assert_eq!(parse_flags("nvmxdiz"), None);
+        assert_eq!(parse_flags("abcdefgh"), None);
+        // A numeric `P:` value is accepted as well
+        assert_eq!(TraceLine::parse("008000 nop P:34").and_then(|l| l.p), Some(0x34));
+    }
+}